@@ -0,0 +1,70 @@
+/// Compares the two ways `harpd` can decode an action frame: copying it into a
+/// `Bufferfish` and reading each string out, and `Action::decode`, which
+/// parses it in place.
+///
+/// ```bash
+/// cargo bench --bench decode
+/// ```
+use std::{hint::black_box, net::IpAddr};
+
+use bufferfish::Bufferfish;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use harp::{
+    action::{Action, Kind},
+    HarpId, Loggable,
+};
+use serde_json::json;
+use tokio_util::bytes::Bytes;
+
+struct BenchKind;
+
+impl Kind for BenchKind {
+    fn key(&self) -> &'static str {
+        "player_move"
+    }
+
+    fn static_key(&self) -> Option<&'static str> {
+        Some(self.key())
+    }
+}
+
+struct Player;
+
+impl Loggable for Player {
+    fn identifier(&self) -> HarpId {
+        (IpAddr::from([203, 0, 113, 7]), 4021)
+    }
+}
+
+/// Encodes an action shaped like typical game traffic, with a small detail.
+fn frame() -> Bytes {
+    let detail = json!({ "x": 1204.5, "y": -88.25, "zone": "harbor", "speed": 7 });
+    let mut action = Action::with_detail(BenchKind, detail, &Player);
+    action.sequence = Some(981_223);
+
+    Bufferfish::try_from(&action).expect("action should encode").into()
+}
+
+fn decode(c: &mut Criterion) {
+    let frame = frame();
+
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(1));
+
+    group.bench_function("bufferfish", |b| {
+        b.iter_batched(
+            || frame.clone(),
+            |frame| Action::try_from(Bufferfish::from(frame)).expect("frame should decode"),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("in_place", |b| {
+        b.iter(|| Action::decode(black_box(&frame)).expect("frame should decode"))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
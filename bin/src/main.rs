@@ -1,17 +1,17 @@
 #![forbid(unsafe_code)]
-#![feature(vec_push_within_capacity)]
 
-pub mod config;
-pub mod server;
-pub mod sql;
+use std::{path::PathBuf, process::exit};
 
-use std::process::exit;
-
-use harp::Result;
+use harp::{
+    encryption::DetailCipher,
+    server::{
+        logging, read_secret, Config, ExportFilter, ExportFormat, Server, DEFAULT_CONFIG,
+        DEFAULT_CONFIG_PATH,
+    },
+    Result,
+};
 use pico_args::Arguments;
-use tracing::metadata::LevelFilter;
-
-use crate::{config::Config, sql::CREATE_HARP_TABLE};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const HELP: &str = "\
@@ -20,29 +20,64 @@ Rob Wagner <rob@sombia.com>
 https://github.com/robertwayne/harp
 
 USAGE:
-    harpd [OPTIONS]
+    harpd [COMMAND] [OPTIONS]
+
+COMMANDS:
+    init                   Writes a default config if there is none, migrates the
+                           database, and exits
+    migrate                Runs pending database migrations and exits
+    backfill-rollups       Recomputes the hourly rollup table and exits
+    export                 Writes stored actions to a file and exits
+    import <FILE>          Inserts actions from a JSON-lines export and exits
 
 OPTIONS:
     -c, --config <FILE>    Sets a custom config file
+        --no-migrate       Skips running database migrations on startup
+        --ephemeral        Runs without a database server, storing actions in memory
+                           with SQLite and printing them; uses the default config
+                           unless -c is given
+        --ephemeral-db <FILE>
+                           Runs ephemerally, storing actions in this SQLite file
     -h, --help             Displays help information
     -v, --version          Displays version information
+
+EXPORT OPTIONS:
+        --out <FILE>       Sets the file to write to (required)
+        --format <FORMAT>  Sets the format: csv, jsonl, or parquet [default: csv]
+        --from <TIME>      Only exports actions created at or after an RFC 3339 time
+        --to <TIME>        Only exports actions created before an RFC 3339 time
+        --kind <KIND>      Only exports actions of this kind
+        --decrypt-key-file <FILE>
+                           Decrypts encrypted details with the key in this file
 ";
 
 #[derive(Debug)]
 struct Args {
+    command: Option<Command>,
     config_path: Option<String>,
+    no_migrate: bool,
+    ephemeral: bool,
+    ephemeral_db: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Init,
+    Migrate,
+    BackfillRollups,
+    Export {
+        filter: ExportFilter,
+        format: ExportFormat,
+        out: PathBuf,
+        decrypt_key_file: Option<PathBuf>,
+    },
+    Import {
+        path: PathBuf,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::SubscriberBuilder::default()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
-        .init();
-
     // TODO: Replace with const fn when stabilized.
     let help = HELP.replace("{VERSION}", VERSION);
 
@@ -54,17 +89,87 @@ async fn main() -> Result<()> {
         }
     };
 
-    let config = Config::load_from_file(args.config_path)?;
-
-    let pg = sqlx::postgres::PgPoolOptions::new()
-        .max_connections(config.get_max_connections())
-        .connect(&config.get_database_url())
-        .await?;
+    // Logging is set up from the config file before anything else runs. If
+    // the file can't be read, the defaults are used here, and the error is
+    // reported once the server loads it.
+    let logging = Config::load_from_file(args.config_path.as_ref())
+        .map(|config| config.logging)
+        .unwrap_or_default();
+    let logging = logging::init(&logging)?;
+
+    // Ephemeral servers need no config file, so the defaults are used unless
+    // one is given.
+    let default_config = args.ephemeral && args.config_path.is_none();
+    let config_path = args.config_path.unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+
+    let mut server = Server::builder().log_handle(logging.handle()).config_path(&config_path);
+    if default_config {
+        server = server.config(Config::load_from_str(DEFAULT_CONFIG)?);
+    }
 
-    sqlx::query(CREATE_HARP_TABLE).execute(&pg).await?;
+    match args.command {
+        Some(Command::Init) => {
+            let written = match server.init().await {
+                Ok(written) => written,
+                Err(e) => {
+                    tracing::error!("Error initializing: {e}");
+                    tracing::error!(
+                        "Check {config_path} and the database it points to, then run `harpd \
+                         init` again"
+                    );
+
+                    drop(logging);
+                    exit(1);
+                }
+            };
+
+            if written {
+                tracing::info!("Wrote a default config to {config_path}");
+            } else {
+                tracing::info!("Using the existing config at {config_path}");
+            }
+            tracing::info!("Connected to the database and ran migrations; ready to listen");
+            return Ok(());
+        }
+        Some(Command::Migrate) => {
+            server.migrate().await?;
+            tracing::info!("Migrations complete");
+            return Ok(());
+        }
+        Some(Command::BackfillRollups) => {
+            let rows = server.backfill_rollups().await?;
+            tracing::info!("Backfilled {rows} hourly rollups");
+            return Ok(());
+        }
+        Some(Command::Export { filter, format, out, decrypt_key_file }) => {
+            if let Some(path) = decrypt_key_file {
+                let key = read_secret(&path)?;
+                server = server.decrypt_exports(DetailCipher::from_base64(&key)?);
+            }
+
+            let rows = server.export(&filter, format, &out).await?;
+            tracing::info!("Exported {rows} actions to {}", out.display());
+            return Ok(());
+        }
+        Some(Command::Import { path }) => {
+            let rows = server.import(&path).await?;
+            tracing::info!("Imported {rows} actions from {}", path.display());
+            return Ok(());
+        }
+        None => {}
+    }
 
-    if let Err(e) = server::listen(config, pg).await {
+    // Locked-down production databases may not allow the harpd user to alter
+    // the schema, in which case migrations are run separately.
+    let mut server = server.skip_migrations(args.no_migrate).ephemeral(args.ephemeral);
+    if let Some(path) = args.ephemeral_db {
+        server = server.ephemeral_database(path);
+    }
+    if let Err(e) = server.listen().await {
         tracing::error!("Error listening: {e}");
+
+        // Exiting skips destructors, so log files are flushed first.
+        drop(logging);
         exit(1);
     }
 
@@ -84,7 +189,37 @@ fn parse_args(help: &str) -> Result<Args> {
         exit(0);
     }
 
-    let args = Args { config_path: pargs.opt_value_from_str(["-c", "--config"])? };
+    let subcommand = pargs.subcommand()?;
+
+    // Options must be taken before free arguments, such as the import path.
+    let config_path = pargs.opt_value_from_str(["-c", "--config"])?;
+    let no_migrate = pargs.contains("--no-migrate");
+    let ephemeral_db: Option<PathBuf> = pargs.opt_value_from_str("--ephemeral-db")?;
+    let ephemeral = pargs.contains("--ephemeral") || ephemeral_db.is_some();
+
+    let command = match subcommand.as_deref() {
+        Some("init") => Some(Command::Init),
+        Some("migrate") => Some(Command::Migrate),
+        Some("backfill-rollups") => Some(Command::BackfillRollups),
+        Some("export") => Some(Command::Export {
+            filter: ExportFilter {
+                from: pargs.opt_value_from_fn("--from", parse_timestamp)?,
+                to: pargs.opt_value_from_fn("--to", parse_timestamp)?,
+                kind: pargs.opt_value_from_str("--kind")?,
+            },
+            format: pargs.opt_value_from_str("--format")?.unwrap_or_default(),
+            out: pargs.value_from_str("--out")?,
+            decrypt_key_file: pargs.opt_value_from_str("--decrypt-key-file")?,
+        }),
+        Some("import") => Some(Command::Import { path: pargs.free_from_str()? }),
+        Some(other) => {
+            println!("Unknown command: {other}\n\n{help}");
+            exit(1);
+        }
+        None => None,
+    };
+
+    let args = Args { command, config_path, no_migrate, ephemeral, ephemeral_db };
 
     let remaining = pargs.finish();
     if !remaining.is_empty() {
@@ -94,3 +229,7 @@ fn parse_args(help: &str) -> Result<Args> {
 
     Ok(args)
 }
+
+fn parse_timestamp(value: &str) -> std::result::Result<OffsetDateTime, time::error::Parse> {
+    OffsetDateTime::parse(value, &Rfc3339)
+}
@@ -0,0 +1,268 @@
+/// A load generator for measuring how many actions a `harpd` deployment can
+/// sustain. It connects a number of simulated services, each sending a mix of
+/// action kinds as fast as possible (or at a fixed rate), and reports the
+/// throughput, per-action send latency, and the number of actions `harpd`
+/// returned because its queue was full.
+///
+/// ```bash
+/// cargo run --release --example harp-bench -- \
+///     --services 16 --duration 30 --rate 5000 --mix login=1,chat=10,move=50
+/// ```
+///
+/// | Option            | Default          | Notes                                  |
+/// |-------------------|------------------|----------------------------------------|
+/// | `--host`          | `127.0.0.1`      |                                        |
+/// | `--port`          | `7777`           |                                        |
+/// | `--services`      | `4`              | Number of simulated services.          |
+/// | `--duration`      | `10`             | Seconds to send for.                   |
+/// | `--rate`          | unlimited        | Actions per second, per service.       |
+/// | `--mix`           | `bench=1`        | Kinds and their relative weights.      |
+/// | `--detail-bytes`  | `64`             | Approximate size of each detail.       |
+///
+/// Actions are sent to whichever table `harpd` is configured with, so point it
+/// at a scratch database.
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bufferfish::Bufferfish;
+use futures_util::{SinkExt, StreamExt};
+use harp::{
+    action::{Action, Kind},
+    protocol::Handshake,
+    HarpId, Loggable,
+};
+use serde_json::json;
+use tokio::{
+    net::TcpStream,
+    time::{interval, Instant, MissedTickBehavior},
+};
+use tokio_util::codec::LengthDelimitedCodec;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+struct Options {
+    addr: SocketAddr,
+    services: usize,
+    duration: Duration,
+    rate: Option<u64>,
+    mix: Vec<(String, u32)>,
+    detail_bytes: usize,
+}
+
+impl Options {
+    fn from_args() -> Result<Self> {
+        let mut options = Self {
+            addr: SocketAddr::from(([127, 0, 0, 1], 7777)),
+            services: 4,
+            duration: Duration::from_secs(10),
+            rate: None,
+            mix: vec![("bench".into(), 1)],
+            detail_bytes: 64,
+        };
+
+        let mut host: IpAddr = options.addr.ip();
+        let mut port = options.addr.port();
+
+        let mut args = std::env::args().skip(1);
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| format!("missing value for {flag}"))?;
+
+            match flag.as_str() {
+                "--host" => host = value.parse()?,
+                "--port" => port = value.parse()?,
+                "--services" => options.services = value.parse()?,
+                "--duration" => options.duration = Duration::from_secs(value.parse()?),
+                "--rate" => options.rate = Some(value.parse()?),
+                "--detail-bytes" => options.detail_bytes = value.parse()?,
+                "--mix" => {
+                    options.mix = value
+                        .split(',')
+                        .map(|entry| {
+                            let (kind, weight) = entry.split_once('=').unwrap_or((entry, "1"));
+                            Ok((kind.to_string(), weight.parse()?))
+                        })
+                        .collect::<Result<_>>()?;
+                }
+                _ => return Err(format!("unknown option {flag}").into()),
+            }
+        }
+
+        options.addr = SocketAddr::new(host, port);
+
+        if options.mix.iter().all(|(_, weight)| *weight == 0) {
+            return Err("--mix needs at least one kind with a non-zero weight".into());
+        }
+
+        Ok(options)
+    }
+}
+
+struct BenchKind<'a>(&'a str);
+
+impl Kind for BenchKind<'_> {
+    fn key(&self) -> &str {
+        self.0
+    }
+}
+
+struct Player(u32);
+
+impl Loggable for Player {
+    fn identifier(&self) -> HarpId {
+        (IpAddr::from([127, 0, 0, 1]), self.0)
+    }
+}
+
+/// What a single simulated service observed.
+#[derive(Default)]
+struct Report {
+    sent: u64,
+    returned: u64,
+    errors: u64,
+    latencies: Vec<Duration>,
+}
+
+/// Connects a single service and sends actions until the deadline, counting
+/// any actions `harpd` hands back.
+async fn run_service(service: usize, options: &Options, deadline: Instant) -> Result<Report> {
+    let stream = TcpStream::connect(options.addr).await?;
+    stream.set_nodelay(true)?;
+
+    let framed = LengthDelimitedCodec::builder().length_field_type::<u16>().new_framed(stream);
+    let (mut sink, mut returned) = framed.split();
+
+    let handshake = Bufferfish::try_from(Handshake::new(Some(format!("bench-{service}"))))?;
+    sink.send(handshake.into()).await?;
+
+    // Returned actions arrive on the same connection whenever harpd's queue
+    // is full.
+    let returned_count = Arc::new(AtomicU64::new(0));
+    let counter = {
+        let returned_count = Arc::clone(&returned_count);
+        tokio::spawn(async move {
+            while let Some(Ok(_)) = returned.next().await {
+                returned_count.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+    };
+
+    let mut ticker = options.rate.map(|rate| {
+        let mut ticker = interval(Duration::from_secs_f64(1.0 / rate as f64));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+        ticker
+    });
+
+    let total_weight: u32 = options.mix.iter().map(|(_, weight)| weight).sum();
+    let padding = "x".repeat(options.detail_bytes);
+    let mut report = Report::default();
+
+    while Instant::now() < deadline {
+        if let Some(ticker) = &mut ticker {
+            ticker.tick().await;
+        }
+
+        let mut roll = fastrand::u32(0..total_weight);
+        let kind = options
+            .mix
+            .iter()
+            .find(|(_, weight)| {
+                let found = roll < *weight;
+                roll = roll.saturating_sub(*weight);
+                found
+            })
+            .map_or("bench", |(kind, _)| kind);
+
+        let player = Player(fastrand::u32(1..10_000));
+        let detail = json!({ "service": service, "padding": padding });
+        let bf = Bufferfish::try_from(&Action::with_detail(BenchKind(kind), detail, &player))?;
+
+        let start = Instant::now();
+        match sink.send(bf.into()).await {
+            Ok(()) => {
+                report.sent += 1;
+                report.latencies.push(start.elapsed());
+            }
+            Err(e) => {
+                eprintln!("Service {service} disconnected: {e}");
+                report.errors += 1;
+                break;
+            }
+        }
+    }
+
+    // Give harpd a moment to return anything still in flight before closing
+    // the connection.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    drop(sink);
+    counter.abort();
+    report.returned = returned_count.load(Ordering::Relaxed);
+
+    Ok(report)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = Arc::new(Options::from_args()?);
+    let deadline = Instant::now() + options.duration;
+
+    println!(
+        "Running {} services against {} for {}s",
+        options.services,
+        options.addr,
+        options.duration.as_secs()
+    );
+
+    let started = Instant::now();
+    let tasks: Vec<_> = (0..options.services)
+        .map(|service| {
+            let options = Arc::clone(&options);
+            tokio::spawn(async move { run_service(service, &options, deadline).await })
+        })
+        .collect();
+
+    let mut total = Report::default();
+    for task in tasks {
+        match task.await? {
+            Ok(report) => {
+                total.sent += report.sent;
+                total.returned += report.returned;
+                total.errors += report.errors;
+                total.latencies.extend(report.latencies);
+            }
+            Err(e) => eprintln!("Service failed: {e}"),
+        }
+    }
+    let elapsed = started.elapsed().min(options.duration);
+
+    total.latencies.sort_unstable();
+    let accepted = total.sent.saturating_sub(total.returned);
+
+    println!("Sent:       {} actions", total.sent);
+    println!("Accepted:   {:.0} actions/sec", accepted as f64 / elapsed.as_secs_f64());
+    println!("Returned:   {} (queue full)", total.returned);
+    println!("Errors:     {}", total.errors);
+    println!(
+        "Latency:    p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(&total.latencies, 0.50),
+        percentile(&total.latencies, 0.90),
+        percentile(&total.latencies, 0.99),
+        total.latencies.last().copied().unwrap_or_default()
+    );
+
+    Ok(())
+}
@@ -12,6 +12,8 @@
 ///
 /// Finally, you will need to have an instance of `harpd` running. A pre-built
 /// binary from the releases page or built from the source code can be used.
+/// To try the example without PostgreSQL, run `harpd --ephemeral`, which
+/// stores actions in an in-memory SQLite database and prints each one.
 use std::{
     net::{IpAddr, Ipv4Addr},
     time::Duration,
@@ -34,6 +36,9 @@ pub enum ActionKind {
 // we implement the `key()` method, which will return a string representation of
 // the action. This string will be stored in the database, so think about how
 // you'd like to have your action kinds represented.
+//
+// Since our keys are string literals, we also return them from `static_key()`,
+// which lets actions borrow the key rather than allocate a copy of it.
 impl Kind for ActionKind {
     fn key(&self) -> &'static str {
         match self {
@@ -41,6 +46,10 @@ impl Kind for ActionKind {
             ActionKind::PlayerLeave => "player_leave",
         }
     }
+
+    fn static_key(&self) -> Option<&'static str> {
+        Some(self.key())
+    }
 }
 
 // We'll define a simple struct to represent a player.
@@ -1,9 +1,10 @@
-pub const CREATE_HARP_TABLE: &str = "
-CREATE TABLE IF NOT EXISTS harp.actions (
+CREATE SCHEMA IF NOT EXISTS {schema};
+
+CREATE TABLE IF NOT EXISTS {schema}.{table} (
     id             serial primary key,
     unique_id      bigint                       not null,
     ip_address     inet                         not null,
     kind           varchar(255)                 not null,
     detail         jsonb,
     created        timestamptz default now()    not null
-)";
+);
@@ -0,0 +1,4 @@
+ALTER TABLE {schema}.{table} ADD COLUMN IF NOT EXISTS idempotency_key bigint;
+
+CREATE UNIQUE INDEX IF NOT EXISTS {table}_idempotency_key_idx
+    ON {schema}.{table} (unique_id, idempotency_key);
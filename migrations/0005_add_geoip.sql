@@ -0,0 +1,2 @@
+ALTER TABLE {schema}.{table} ADD COLUMN IF NOT EXISTS country char(2);
+ALTER TABLE {schema}.{table} ADD COLUMN IF NOT EXISTS asn bigint;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS {schema}.{table}_hourly (
+    kind           varchar(255)                 not null,
+    hour           timestamp                    not null,
+    count          bigint                       not null,
+    primary key (kind, hour)
+);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS {schema}.{table}_alerts (
+    id             serial primary key,
+    rule           varchar(255)                 not null,
+    group_key      varchar(255)                 not null,
+    count          bigint                       not null,
+    triggered      timestamptz default now()    not null
+);
@@ -0,0 +1 @@
+ALTER TABLE {schema}.{table} ADD COLUMN IF NOT EXISTS rate_exceeded boolean default false not null;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS {schema}.kinds (
+    id             integer generated by default as identity primary key,
+    kind           varchar(255)                 not null unique
+);
+
+ALTER TABLE {schema}.{table} ADD COLUMN IF NOT EXISTS kind_id integer
+    REFERENCES {schema}.kinds (id);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS {schema}.{table}_aggregates (
+    kind           varchar(255)                 not null,
+    unique_id      bigint                       not null,
+    bucket         timestamptz                  not null,
+    count          bigint                       not null,
+    primary key (kind, unique_id, bucket)
+);
@@ -0,0 +1 @@
+ALTER TABLE {schema}.{table} ADD COLUMN IF NOT EXISTS ordinal bigint;
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS {schema}.{table}_sessions (
+    session_id     bigint primary key,
+    unique_id      bigint                       not null,
+    ip_address     inet                         not null,
+    started        timestamptz                  not null,
+    last_seen      timestamptz                  not null,
+    ended          timestamptz,
+    actions        bigint                       not null
+);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS {schema}.{table}_aggregate_keys (
+    unique_id       bigint                       not null,
+    idempotency_key bigint                       not null,
+    bucket          timestamptz                  not null,
+    primary key (unique_id, idempotency_key)
+);
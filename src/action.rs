@@ -1,11 +1,46 @@
-use std::fmt::Display;
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fmt::Display,
+    io::{self, Write},
+    net::IpAddr,
+    sync::{OnceLock, RwLock},
+};
 
 use bufferfish::Bufferfish;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::types::ipnetwork::IpNetwork;
 use time::{macros::format_description, OffsetDateTime};
 
-use crate::Loggable;
+use crate::{
+    protocol::{read_optional_f32, read_optional_u64, write_optional_f32, write_optional_u64},
+    Loggable,
+};
+
+/// Maximum size, in bytes, of a serialized action detail. Frames are limited
+/// to the `max_frame_size` negotiated with `harpd` (see
+/// `HarpBuilder::max_frame_size`), so the detail must also leave room for the
+/// rest of the action under it.
+pub const MAX_DETAIL_SIZE: usize = 32 * 1024;
+
+/// The kind of the action `Sender::start_session` sends as a session opens.
+pub const SESSION_START: &str = "session_start";
+
+/// The kind of the action `Sender::end_session` sends as a session closes.
+pub const SESSION_END: &str = "session_end";
+
+/// The most distinct kinds `Action::decode` keeps a single shared copy of.
+/// Kinds beyond this are copied into every action, so a peer sending endless
+/// distinct kinds can't grow memory without bound.
+const MAX_INTERNED_KINDS: usize = 1024;
+
+/// The longest kind, in bytes, `Action::decode` interns. Longer kinds are
+/// copied into every action, so the interned set stays small however it fills.
+const MAX_INTERNED_KIND_LEN: usize = 64;
+
+/// Kinds seen by `Action::decode`. Each is leaked once, so that decoded
+/// actions can borrow it for `'static`.
+static INTERNED_KINDS: OnceLock<RwLock<HashSet<&'static str>>> = OnceLock::new();
 
 /// Represents a "kind" of action. Implementing this trait requires the `key()`
 /// method, which should return a string representation of the action kind. This
@@ -13,21 +48,117 @@ use crate::Loggable;
 ///
 /// The return value will be stored in the database, so consider that when
 /// deciding on a key.
+///
+/// Kinds whose keys are string literals, which is most of them, should also
+/// return them from `static_key`, so that creating an action of that kind
+/// doesn't allocate a copy of its key.
 pub trait Kind {
     fn key(&self) -> &str;
+
+    /// Returns the key if it lives for the whole program. Defaults to `None`,
+    /// in which case the key is copied into every action.
+    fn static_key(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+impl Kind for &'static str {
+    fn key(&self) -> &str {
+        self
+    }
+
+    fn static_key(&self) -> Option<&'static str> {
+        Some(*self)
+    }
 }
 
 /// Represents a "complete" action to be logged into the database at a later
 /// time. Actions are primarily defined by their kind, which is a string
 /// representation of the action that occurred. They can include optional
 /// details.
-#[derive(Debug)]
+///
+/// Actions serialize with their creation time as an RFC 3339 string, so they
+/// can be captured to JSON and re-sent later.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Action {
     pub id: u32,
-    pub addr: IpNetwork,
-    pub kind: String,
+    pub addr: IpAddr,
+    /// Borrowed for kinds with a `static_key`, and owned otherwise, such as
+    /// for actions decoded by `harpd`.
+    pub kind: Cow<'static, str>,
     pub detail: Option<Value>,
+    #[serde(with = "time::serde::rfc3339")]
     pub created: time::OffsetDateTime,
+    /// The name of the service which produced this action. This is not sent
+    /// over the wire; `harpd` fills it in from the connection handshake.
+    pub source: Option<String>,
+    /// A key which uniquely identifies this action for its ID. `harpd` will
+    /// only store one action per ID and key, so retransmitted actions are not
+    /// duplicated. See `HarpBuilder::idempotency_keys`.
+    pub idempotency_key: Option<u64>,
+    /// A monotonically increasing number stamped by the service as the action
+    /// is sent, which `harpd` uses to detect actions lost in transit.
+    pub sequence: Option<u64>,
+    /// The rate this action's kind was sampled at, if sampling is configured
+    /// for it. Each stored action stands in for `1 / sample_rate` actions.
+    pub sample_rate: Option<f32>,
+    /// The ISO country code of `addr`. This is not sent over the wire; `harpd`
+    /// fills it in when GeoIP lookups are configured.
+    pub country: Option<String>,
+    /// The autonomous system number of `addr`. This is not sent over the wire;
+    /// `harpd` fills it in when GeoIP lookups are configured.
+    pub asn: Option<u32>,
+    /// Whether this action's identifier had sent more actions than the
+    /// configured rate threshold when it arrived. This is not sent over the
+    /// wire; `harpd` sets it when rate counters are configured.
+    #[serde(default)]
+    pub rate_exceeded: bool,
+    /// Which lane this action is sent and stored through. High priority
+    /// actions, such as bans or purchases, skip ahead of normal ones on both
+    /// the service and `harpd`.
+    #[serde(default)]
+    pub priority: Priority,
+    /// The position of this action among those sent for its (ip, id) pair,
+    /// stamped by the service when `HarpBuilder::ordered_identifiers` is
+    /// enabled. Unlike `created` and `sequence`, it survives retries,
+    /// requeues, and reconnects, so `harpd` stores it to put a player's
+    /// actions back in the order they were sent.
+    pub ordinal: Option<u64>,
+    /// The login session this action belongs to, stamped by the service while
+    /// a session is open for its (ip, id) pair. See `Sender::start_session`.
+    pub session_id: Option<u64>,
+    /// The trace this action was sent from, such as the 32 hex digit ID of an
+    /// OpenTelemetry trace, so actions can be joined to backend API traces.
+    /// With the `trace-context` feature, `Sender` fills it in from the
+    /// current span.
+    pub trace_id: Option<String>,
+}
+
+/// The lanes actions travel through. See `Action::with_priority`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+impl From<Priority> for u8 {
+    fn from(value: Priority) -> Self {
+        match value {
+            Priority::Normal => 0,
+            Priority::High => 1,
+        }
+    }
+}
+
+impl From<u8> for Priority {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Priority::Normal,
+            _ => Priority::High,
+        }
+    }
 }
 
 impl Action {
@@ -37,23 +168,160 @@ impl Action {
 
         Self {
             id,
-            addr: IpNetwork::from(ip),
-            kind: kind.key().to_string(),
+            addr: ip,
+            kind: match kind.static_key() {
+                Some(key) => Cow::Borrowed(key),
+                None => Cow::Owned(kind.key().to_string()),
+            },
             detail: None,
             created: time::OffsetDateTime::now_utc(),
+            source: None,
+            idempotency_key: None,
+            sequence: None,
+            sample_rate: None,
+            country: None,
+            asn: None,
+            rate_exceeded: false,
+            priority: Priority::Normal,
+            ordinal: None,
+            session_id: None,
+            trace_id: None,
         }
     }
 
     /// Create an action with a detail string.
     pub fn with_detail(kind: impl Kind, detail: Value, target: &impl Loggable) -> Self {
-        let (ip, id) = target.identifier();
+        Self { detail: Some(detail), ..Self::new(kind, target) }
+    }
 
-        Self {
+    /// Sets the priority this action is sent and stored with.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Groups this action into the login session `session_id`, for actions
+    /// sent without going through `Sender`, which stamps them itself.
+    pub fn with_session(mut self, session_id: u64) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    /// Ties this action to the trace `trace_id`, overriding any taken from the
+    /// current span.
+    pub fn with_trace_id(mut self, trace_id: impl Into<String>) -> Self {
+        self.trace_id = Some(trace_id.into());
+        self
+    }
+
+    /// Create an action with a detail serialized from any `Serialize` type.
+    ///
+    /// Types whose in-memory size already exceeds `MAX_DETAIL_SIZE`, such as
+    /// large arrays, are rejected at compile time. The serialized size can
+    /// only be known at runtime, so details which serialize to more than
+    /// `MAX_DETAIL_SIZE` bytes return an error.
+    pub fn with_serialized_detail<T: Serialize>(
+        kind: impl Kind,
+        detail: &T,
+        target: &impl Loggable,
+    ) -> Result<Self, ActionError> {
+        const {
+            assert!(
+                std::mem::size_of::<T>() <= MAX_DETAIL_SIZE,
+                "detail type is larger than `MAX_DETAIL_SIZE`"
+            )
+        };
+
+        let detail = serde_json::to_value(detail).map_err(ActionError::Serialize)?;
+
+        // Measured as it would be written, without allocating the output.
+        let mut size = ByteCounter(0);
+        serde_json::to_writer(&mut size, &detail).map_err(ActionError::Serialize)?;
+        if size.0 > MAX_DETAIL_SIZE {
+            return Err(ActionError::DetailTooLarge(size.0));
+        }
+
+        Ok(Self::with_detail(kind, detail, target))
+    }
+
+    /// Decodes an action straight from an encoded frame, as produced by
+    /// `Bufferfish::try_from(&action)`. Strings are parsed where they lie in
+    /// the frame rather than copied out first, and kinds are interned, so
+    /// the detail is usually the only allocation. Accepts the frames
+    /// `Action::try_from(Bufferfish)` does, except those with bytes left over
+    /// after the last field.
+    pub fn decode(frame: &[u8]) -> Result<Self, ActionError> {
+        let mut reader = FrameReader(frame);
+
+        let id = reader.read_u32()?;
+        let addr = parse_addr(reader.read_str()?)?;
+        let kind = intern_kind(reader.read_str()?);
+        let detail = parse_detail(reader.read_str()?)?;
+        let created = parse_created(reader.read_str()?)?;
+
+        let idempotency_key = reader.read_optional_u64()?;
+        let sequence = reader.read_optional_u64()?;
+        let sample_rate = reader.read_optional_f32()?;
+        let priority = Priority::from(reader.read_u8()?);
+        let ordinal = reader.read_optional_u64()?;
+        let session_id = reader.read_optional_u64()?;
+        let trace_id = parse_optional(reader.read_str()?);
+
+        if !reader.0.is_empty() {
+            let message = format!("{} trailing bytes after the action", reader.0.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+        }
+
+        Ok(Self {
             id,
-            addr: IpNetwork::from(ip),
-            kind: kind.key().to_string(),
-            detail: Some(detail),
-            created: time::OffsetDateTime::now_utc(),
+            addr,
+            kind,
+            detail,
+            created,
+            source: None,
+            idempotency_key,
+            sequence,
+            sample_rate,
+            country: None,
+            asn: None,
+            rate_exceeded: false,
+            priority,
+            ordinal,
+            session_id,
+            trace_id,
+        })
+    }
+
+    /// Returns an approximation of the memory used by this action, in bytes.
+    /// This counts the struct itself plus its string data, and is intended for
+    /// enforcing byte-based limits rather than exact accounting.
+    pub fn approximate_size(&self) -> usize {
+        // Static kinds are shared, so only owned ones count.
+        let kind = match &self.kind {
+            Cow::Owned(kind) => kind.len(),
+            Cow::Borrowed(_) => 0,
+        };
+
+        std::mem::size_of::<Self>()
+            + kind
+            + self.source.as_ref().map_or(0, String::len)
+            + self.country.as_ref().map_or(0, String::len)
+            + self.trace_id.as_ref().map_or(0, String::len)
+            + self.detail.as_ref().map_or(0, approximate_value_size)
+    }
+}
+
+/// Approximates the memory used by a JSON value's contents, in bytes.
+fn approximate_value_size(value: &Value) -> usize {
+    match value {
+        Value::Null | Value::Bool(_) | Value::Number(_) => std::mem::size_of::<Value>(),
+        Value::String(s) => std::mem::size_of::<Value>() + s.len(),
+        Value::Array(values) => {
+            std::mem::size_of::<Value>() + values.iter().map(approximate_value_size).sum::<usize>()
+        }
+        Value::Object(map) => {
+            std::mem::size_of::<Value>()
+                + map.iter().map(|(k, v)| k.len() + approximate_value_size(v)).sum::<usize>()
         }
     }
 }
@@ -63,33 +331,37 @@ impl TryFrom<Bufferfish> for Action {
 
     fn try_from(mut value: Bufferfish) -> Result<Self, Self::Error> {
         let id = value.read_u32()?;
-
-        let addr = value.read_string()?;
-        let addr = addr
-            .parse::<IpNetwork>()
-            .map_err(|_| ActionError::Parse { from: addr, to: "ipnetwork::IpNetwork".into() })?;
-
+        let addr = parse_addr(&value.read_string()?)?;
         let kind = value.read_string()?;
-
-        let detail = value.read_string()?;
-        let detail =
-            if detail.is_empty() {
-                None
-            } else {
-                Some(serde_json::from_str(&detail).map_err(|_| ActionError::Parse {
-                    from: detail,
-                    to: "serde_json::Value".into(),
-                })?)
-            };
-
-        let created = value.read_string()?;
-
-        // 2023-02-24 13:01:12.558038011 +00:00:00
-        let format = format_description!("[year]-[month]-[day] [hour padding:none repr:24]:[minute]:[second].[subsecond] [offset_hour]:[offset_minute]:[offset_second]");
-        let created = OffsetDateTime::parse(&created, format)
-            .map_err(|_| ActionError::Parse { from: created, to: "time::OffsetDateTime".into() })?;
-
-        Ok(Self { id, addr, kind, detail, created })
+        let detail = parse_detail(&value.read_string()?)?;
+        let created = parse_created(&value.read_string()?)?;
+
+        let idempotency_key = read_optional_u64(&mut value)?;
+        let sequence = read_optional_u64(&mut value)?;
+        let sample_rate = read_optional_f32(&mut value)?;
+        let priority = Priority::from(value.read_u8()?);
+        let ordinal = read_optional_u64(&mut value)?;
+        let session_id = read_optional_u64(&mut value)?;
+        let trace_id = parse_optional(&value.read_string()?);
+
+        Ok(Self {
+            id,
+            addr,
+            kind: Cow::Owned(kind),
+            detail,
+            created,
+            source: None,
+            idempotency_key,
+            sequence,
+            sample_rate,
+            country: None,
+            asn: None,
+            rate_exceeded: false,
+            priority,
+            ordinal,
+            session_id,
+            trace_id,
+        })
     }
 }
 
@@ -97,35 +369,188 @@ impl TryFrom<Action> for Bufferfish {
     type Error = ActionError;
 
     fn try_from(value: Action) -> Result<Self, Self::Error> {
+        Bufferfish::try_from(&value)
+    }
+}
+
+impl TryFrom<&Action> for Bufferfish {
+    type Error = ActionError;
+
+    fn try_from(value: &Action) -> Result<Self, Self::Error> {
+        // Both fit in this many bytes, so neither is allocated as a string.
+        let mut scratch = [0; 64];
+
         let mut bf = Bufferfish::new();
         bf.write_u32(value.id)?;
-        bf.write_string(&value.addr.to_string())?;
+        bf.write_string(format_into(&mut scratch, &value.addr)?)?;
         bf.write_string(&value.kind)?;
 
-        match value.detail {
-            Some(detail) => bf.write_string(&serde_json::to_string(&detail).map_err(|_| {
+        match &value.detail {
+            Some(detail) => bf.write_string(&serde_json::to_string(detail).map_err(|_| {
                 ActionError::Parse { from: "serde_json::Value".into(), to: "String".into() }
             })?)?,
             None => bf.write_string("")?,
         }
 
-        bf.write_string(&value.created.to_string())?;
+        bf.write_string(format_into(&mut scratch, &value.created)?)?;
+        write_optional_u64(&mut bf, value.idempotency_key)?;
+        write_optional_u64(&mut bf, value.sequence)?;
+        write_optional_f32(&mut bf, value.sample_rate)?;
+        bf.write_u8(value.priority.into())?;
+        write_optional_u64(&mut bf, value.ordinal)?;
+        write_optional_u64(&mut bf, value.session_id)?;
+        bf.write_string(value.trace_id.as_deref().unwrap_or_default())?;
 
         Ok(bf)
     }
 }
 
+fn parse_addr(addr: &str) -> Result<IpAddr, ActionError> {
+    // Game servers listening on dual-stack sockets see IPv4 players as
+    // IPv4-mapped IPv6 addresses; store those as plain IPv4.
+    addr.parse::<IpAddr>()
+        .map(|addr| addr.to_canonical())
+        .map_err(|_| ActionError::Parse { from: addr.into(), to: "std::net::IpAddr".into() })
+}
+
+fn parse_detail(detail: &str) -> Result<Option<Value>, ActionError> {
+    if detail.is_empty() {
+        return Ok(None);
+    }
+
+    serde_json::from_str(detail)
+        .map(Some)
+        .map_err(|_| ActionError::Parse { from: detail.into(), to: "serde_json::Value".into() })
+}
+
+/// Reads an optional string field, which is written as an empty string when
+/// unset.
+fn parse_optional(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+fn parse_created(created: &str) -> Result<OffsetDateTime, ActionError> {
+    // 2023-02-24 13:01:12.558038011 +00:00:00
+    let format = format_description!(
+        "[year]-[month]-[day] [hour padding:none repr:24]:[minute]:[second].[subsecond] \
+         [offset_hour]:[offset_minute]:[offset_second]"
+    );
+    OffsetDateTime::parse(created, format)
+        .map_err(|_| ActionError::Parse { from: created.into(), to: "time::OffsetDateTime".into() })
+}
+
+/// Returns a shared copy of `kind`, leaking one the first time each kind is
+/// seen, up to `MAX_INTERNED_KINDS` of no more than `MAX_INTERNED_KIND_LEN`
+/// bytes.
+fn intern_kind(kind: &str) -> Cow<'static, str> {
+    if kind.len() > MAX_INTERNED_KIND_LEN {
+        return Cow::Owned(kind.to_string());
+    }
+
+    let kinds = INTERNED_KINDS.get_or_init(Default::default);
+
+    if let Some(&interned) = kinds.read().unwrap_or_else(|e| e.into_inner()).get(kind) {
+        return Cow::Borrowed(interned);
+    }
+
+    let mut kinds = kinds.write().unwrap_or_else(|e| e.into_inner());
+    if let Some(&interned) = kinds.get(kind) {
+        return Cow::Borrowed(interned);
+    }
+
+    if kinds.len() >= MAX_INTERNED_KINDS {
+        return Cow::Owned(kind.to_string());
+    }
+
+    let interned: &'static str = Box::leak(kind.into());
+    kinds.insert(interned);
+
+    Cow::Borrowed(interned)
+}
+
+/// Reads the fields of an encoded action in place, in the same layout
+/// Bufferfish writes them: big-endian integers, and strings prefixed with a
+/// `u16` length.
+struct FrameReader<'a>(&'a [u8]);
+
+impl<'a> FrameReader<'a> {
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], ActionError> {
+        let (head, tail) = self
+            .0
+            .split_first_chunk::<N>()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        self.0 = tail;
+
+        Ok(*head)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, ActionError> {
+        Ok(u8::from_be_bytes(self.read_array()?))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, ActionError> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    fn read_str(&mut self) -> Result<&'a str, ActionError> {
+        let len = usize::from(u16::from_be_bytes(self.read_array()?));
+        if self.0.len() < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+
+        std::str::from_utf8(head).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+    }
+
+    /// Reads a `u64` written by `write_optional_u64`.
+    fn read_optional_u64(&mut self) -> Result<Option<u64>, ActionError> {
+        match self.read_u8()? {
+            0 => Ok(None),
+            _ => {
+                let high = u64::from(self.read_u32()?);
+                let low = u64::from(self.read_u32()?);
+                Ok(Some((high << 32) | low))
+            }
+        }
+    }
+
+    /// Reads an `f32` written by `write_optional_f32`.
+    fn read_optional_f32(&mut self) -> Result<Option<f32>, ActionError> {
+        match self.read_u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(f32::from_bits(self.read_u32()?))),
+        }
+    }
+}
+
+/// Formats `value` into `buf` rather than a new `String`, for the fields
+/// written to every frame.
+fn format_into<'a>(buf: &'a mut [u8], value: &impl Display) -> Result<&'a str, ActionError> {
+    let remaining = {
+        let mut cursor = &mut *buf;
+        write!(cursor, "{value}")?;
+        cursor.len()
+    };
+    let len = buf.len() - remaining;
+
+    std::str::from_utf8(&buf[..len])
+        .map_err(|_| ActionError::Parse { from: "formatted value".into(), to: "&str".into() })
+}
+
 impl Display for Action {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let detail = match &self.detail {
             Some(d) => d.to_string(),
             None => "None".to_string(),
         };
+        let source = self.source.as_deref().unwrap_or("None");
 
         write!(
             f,
-            "Action {{ id: {}, addr: {}, kind: {}, detail: {}, created: {} }}",
-            self.id, self.addr, self.kind, detail, self.created
+            "Action {{ id: {}, addr: {}, kind: {}, detail: {}, created: {}, source: {} }}",
+            self.id, self.addr, self.kind, detail, self.created, source
         )
     }
 }
@@ -136,6 +561,10 @@ pub enum ActionError {
     BufferRead(std::io::Error),
     /// General conversion error from a buffer string result to an action type.
     Parse { from: String, to: String },
+    /// A serialized detail exceeded `MAX_DETAIL_SIZE` bytes.
+    DetailTooLarge(usize),
+    /// A detail could not be serialized to JSON.
+    Serialize(serde_json::Error),
 }
 
 impl std::error::Error for ActionError {}
@@ -145,6 +574,10 @@ impl Display for ActionError {
         match self {
             ActionError::BufferRead(e) => write!(f, "Error reading from buffer: {e}"),
             ActionError::Parse { from, to } => write!(f, "Unable to parse {from} into `{to}`"),
+            ActionError::DetailTooLarge(size) => {
+                write!(f, "Detail is {size} bytes; the maximum is {MAX_DETAIL_SIZE}")
+            }
+            ActionError::Serialize(e) => write!(f, "Unable to serialize detail: {e}"),
         }
     }
 }
@@ -155,9 +588,56 @@ impl From<std::io::Error> for ActionError {
     }
 }
 
+/// Counts the bytes written to it, discarding them.
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::net::IpAddr;
+
+    use tokio_util::bytes::Bytes;
+
     use super::*;
+    use crate::test_fixtures::{Target, TestKind};
+
+    #[test]
+    fn serialized_detail() {
+        #[derive(Serialize)]
+        struct Detail {
+            reason: &'static str,
+        }
+
+        let action = Action::with_serialized_detail(
+            TestKind("my_kind"),
+            &Detail { reason: "lost connection" },
+            &Target,
+        )
+        .unwrap();
+        assert_eq!(action.detail, Some(serde_json::json!({ "reason": "lost connection" })));
+
+        let oversized = "a".repeat(MAX_DETAIL_SIZE);
+        assert!(matches!(
+            Action::with_serialized_detail(TestKind("my_kind"), &oversized, &Target),
+            Err(ActionError::DetailTooLarge(_))
+        ));
+
+        // JSON objects need string keys, and serde's reason is kept.
+        let unkeyed = std::collections::HashMap::from([((1, 2), 3)]);
+        let e = Action::with_serialized_detail(TestKind("my_kind"), &unkeyed, &Target).unwrap_err();
+        assert!(matches!(e, ActionError::Serialize(_)));
+        assert!(e.to_string().contains("key must be a string"));
+    }
 
     #[test]
     fn invalid_bufferfish_to_action() {
@@ -190,7 +670,119 @@ mod tests {
         bf.write_string("my_kind").unwrap();
         bf.write_string("").unwrap();
         bf.write_string("2023-02-24 13:01:12.558038011 +00:00:00").unwrap();
+        bf.write_u8(0).unwrap();
+        bf.write_u8(0).unwrap();
+        bf.write_u8(0).unwrap();
+        bf.write_u8(0).unwrap();
+        bf.write_u8(0).unwrap();
+        bf.write_u8(0).unwrap();
+        bf.write_string("").unwrap();
 
         assert!(Action::try_from(bf).is_ok());
     }
+
+    #[test]
+    fn ipv4_mapped_addr_is_canonical() {
+        let mut action = Action::new(TestKind("my_kind"), &Target);
+        action.addr = "::ffff:10.0.0.1".parse().unwrap();
+
+        let bf = Bufferfish::try_from(action).unwrap();
+        assert_eq!(Action::try_from(bf).unwrap().addr, IpAddr::from([10, 0, 0, 1]));
+
+        let mut action = Action::new(TestKind("my_kind"), &Target);
+        action.addr = "2001:db8::1".parse().unwrap();
+
+        let bf = Bufferfish::try_from(action).unwrap();
+        assert_eq!(Action::try_from(bf).unwrap().addr, "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn idempotency_key_round_trip() {
+        let mut bf = Bufferfish::new();
+        bf.write_u32(1).unwrap();
+        bf.write_string("127.0.0.1").unwrap();
+        bf.write_string("my_kind").unwrap();
+        bf.write_string("").unwrap();
+        bf.write_string("2023-02-24 13:01:12.558038011 +00:00:00").unwrap();
+        write_optional_u64(&mut bf, Some(u64::MAX - 1)).unwrap();
+        write_optional_u64(&mut bf, None).unwrap();
+        write_optional_f32(&mut bf, None).unwrap();
+        bf.write_u8(0).unwrap();
+        write_optional_u64(&mut bf, None).unwrap();
+        write_optional_u64(&mut bf, None).unwrap();
+        bf.write_string("").unwrap();
+
+        let action = Action::try_from(bf).unwrap();
+        assert_eq!(action.idempotency_key, Some(u64::MAX - 1));
+
+        let bf = Bufferfish::try_from(action).unwrap();
+        assert_eq!(Action::try_from(bf).unwrap().idempotency_key, Some(u64::MAX - 1));
+    }
+
+    #[test]
+    fn priority_round_trip() {
+        let action = Action::new(TestKind("my_kind"), &Target).with_priority(Priority::High);
+
+        let bf = Bufferfish::try_from(action).unwrap();
+        assert_eq!(Action::try_from(bf).unwrap().priority, Priority::High);
+
+        let bf = Bufferfish::try_from(Action::new(TestKind("my_kind"), &Target)).unwrap();
+        assert_eq!(Action::try_from(bf).unwrap().priority, Priority::Normal);
+    }
+
+    #[test]
+    fn decode_matches_bufferfish() {
+        let mut action = Action::with_detail(
+            TestKind("my_kind"),
+            serde_json::json!({ "reason": "afk" }),
+            &Target,
+        )
+        .with_priority(Priority::High);
+        action.idempotency_key = Some(u64::MAX - 1);
+        action.sequence = Some(42);
+        action.sample_rate = Some(0.25);
+        action.ordinal = Some(1_700_000_000_000_000);
+        action.session_id = Some(7);
+        action.trace_id = Some("4bf92f3577b34da6a3ce929d0e0e4736".into());
+
+        let frame: Bytes = Bufferfish::try_from(&action).unwrap().into();
+        let decoded = Action::decode(&frame).unwrap();
+        assert_eq!(decoded, Action::try_from(Bufferfish::from(frame.clone())).unwrap());
+        assert!(matches!(decoded.kind, Cow::Borrowed("my_kind")));
+
+        // Truncated frames are rejected rather than read past, and frames
+        // with anything after the last field are rejected too.
+        assert!(Action::decode(&frame[..frame.len() - 1]).is_err());
+        assert!(Action::decode(&[]).is_err());
+        assert!(Action::decode(&[&frame[..], b"extra"].concat()).is_err());
+    }
+
+    #[test]
+    fn long_kinds_are_not_interned() {
+        let kind = "k".repeat(MAX_INTERNED_KIND_LEN + 1);
+        assert!(matches!(intern_kind(&kind), Cow::Owned(owned) if owned == kind));
+        assert!(matches!(intern_kind("short_kind"), Cow::Borrowed("short_kind")));
+    }
+
+    #[test]
+    fn static_kinds_are_borrowed() {
+        assert!(matches!(Action::new("login", &Target).kind, Cow::Borrowed("login")));
+        assert!(matches!(Action::new(TestKind("my_kind"), &Target).kind, Cow::Owned(_)));
+
+        let bf = Bufferfish::try_from(Action::new("login", &Target)).unwrap();
+        assert_eq!(Action::try_from(bf).unwrap().kind, "login");
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let action = Action::with_detail(
+            TestKind("my_kind"),
+            serde_json::json!({ "reason": "afk" }),
+            &Target,
+        )
+        .with_priority(Priority::High);
+
+        let json = serde_json::to_string(&action).unwrap();
+        assert_eq!(serde_json::from_str::<Action>(&json).unwrap(), action);
+    }
 }
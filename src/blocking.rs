@@ -0,0 +1,233 @@
+//! A synchronous client for tools which don't run an async runtime, such as
+//! small admin scripts. Actions are encoded exactly as they are by `Harp`, but
+//! are written over a plain `std::net::TcpStream`.
+use std::{
+    io::{self, BufWriter, Write},
+    net::{SocketAddr, TcpStream},
+};
+
+use bufferfish::Bufferfish;
+use tokio_util::bytes::Bytes;
+
+use crate::{
+    action::Action,
+    builder::{FrameKey, HarpBuilder},
+    interceptor::Interceptors,
+    ordinals::Ordinals,
+    protocol::{append_checksum, append_signature, Handshake, LengthField},
+    sampling::Sampler,
+    Harp, Result,
+};
+
+/// A synchronous connection to a Harp server. Actions are buffered by `send`
+/// and written to the server by `flush`, or when the connection is dropped.
+///
+/// Unlike `Harp`, this client does not reconnect, and actions returned by the
+/// server because its queue is full are not retried.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use harp::{action::{Action, Kind}, blocking::BlockingHarp, HarpId, Loggable};
+/// # use std::net::{IpAddr, Ipv4Addr};
+/// #
+/// # pub struct Admin {}
+/// #
+/// # impl Loggable for Admin {
+/// #     fn identifier(&self) -> HarpId {
+/// #         (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)}
+/// # }
+/// #
+/// # pub struct ResetPassword;
+/// #
+/// # impl Kind for ResetPassword {
+/// #     fn key(&self) -> &'static str {
+/// #         "reset_password"
+/// #     }
+/// # }
+/// #
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut harp = BlockingHarp::connect()?;
+///
+/// harp.send(Action::new(ResetPassword, &Admin {}))?;
+/// harp.flush()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BlockingHarp {
+    stream: BufWriter<TcpStream>,
+    idempotency_keys: bool,
+    /// Whether a checksum is appended to every action frame.
+    checksums: bool,
+    /// The key every action frame is signed with, if any.
+    frame_key: Option<FrameKey>,
+    /// The number of action frames signed so far, which is the index the next
+    /// one is signed with.
+    signed_frames: u64,
+    /// The framing announced in the handshake, used for every action frame.
+    length_field: LengthField,
+    max_frame_size: usize,
+    sampler: Sampler,
+    interceptors: Interceptors,
+    /// The sequence number stamped on the next action sent.
+    next_sequence: u64,
+    /// Stamps per-identifier ordinals, if `ordered_identifiers` is set.
+    ordinals: Option<Ordinals>,
+}
+
+impl BlockingHarp {
+    /// Connects to the default Harp server on "127.0.0.1:7777".
+    pub fn connect() -> Result<Self> {
+        Harp::builder().connect_blocking()
+    }
+
+    /// Connects to the designated Harp server.
+    pub fn connect_with_options(hostname: &str, port: u16) -> Result<Self> {
+        Harp::builder().hostname(hostname).port(port).connect_blocking()
+    }
+
+    pub(crate) fn raw_connect(addr: SocketAddr, builder: HarpBuilder) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        let mut harp = Self {
+            stream: BufWriter::new(stream),
+            idempotency_keys: builder.idempotency_keys,
+            checksums: builder.checksums,
+            frame_key: builder.frame_key,
+            signed_frames: 0,
+            length_field: builder.length_field,
+            max_frame_size: builder.get_max_frame_size(),
+            sampler: builder.sampler,
+            interceptors: builder.interceptors,
+            next_sequence: 1,
+            ordinals: builder.ordered_identifiers.then(Ordinals::default),
+        };
+
+        let credentials =
+            builder.auth.as_ref().map(|auth| auth.credentials(builder.service_name.as_deref()));
+        let handshake = Handshake::new(builder.service_name)
+            .with_checksums(builder.checksums)
+            .with_signed_frames(harp.frame_key.is_some())
+            .with_framing(harp.length_field, harp.max_frame_size)
+            .with_credentials(credentials);
+
+        // The handshake is always framed with a u16 length.
+        harp.write_frame(
+            Bufferfish::try_from(handshake)?.into(),
+            LengthField::U16,
+            u16::MAX.into(),
+        )?;
+        harp.flush()?;
+
+        tracing::info!("Connected to Harp on {addr}");
+
+        Ok(harp)
+    }
+
+    /// Buffers an action to be sent to the Harp server, unless it is dropped by
+    /// sampling or an interceptor. Call `flush` to make sure it has been
+    /// written.
+    pub fn send(&mut self, mut action: Action) -> Result<()> {
+        if !self.sampler.sample(&mut action) {
+            return Ok(());
+        }
+
+        let Some(mut action) = self.interceptors.apply(action) else {
+            return Ok(());
+        };
+
+        if self.idempotency_keys && action.idempotency_key.is_none() {
+            action.idempotency_key = Some(fastrand::u64(..));
+        }
+
+        action.sequence = Some(self.next_sequence);
+        self.next_sequence += 1;
+
+        if let Some(ordinals) = &self.ordinals {
+            ordinals.stamp(&mut action);
+        }
+        #[cfg(feature = "trace-context")]
+        crate::trace_context::stamp(&mut action);
+
+        let frame: Bytes = Bufferfish::try_from(action)?.into();
+        let frame = match &self.frame_key {
+            Some(key) => {
+                self.signed_frames += 1;
+                append_signature(&key.0, self.signed_frames - 1, &frame)
+            }
+            None => frame,
+        };
+        let frame = if self.checksums { append_checksum(&frame) } else { frame };
+
+        self.write_frame(frame, self.length_field, self.max_frame_size)
+    }
+
+    /// Writes all buffered actions to the Harp server.
+    pub fn flush(&mut self) -> Result<()> {
+        self.stream.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes a single frame into the send buffer, prefixed with its length as
+    /// a big-endian integer of the given width to match the framing `harpd`
+    /// expects.
+    fn write_frame(
+        &mut self,
+        frame: Bytes,
+        length_field: LengthField,
+        max_frame_size: usize,
+    ) -> Result<()> {
+        if frame.len() > max_frame_size {
+            let message = format!("frame exceeds {max_frame_size} bytes");
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+        }
+
+        match length_field {
+            LengthField::U16 => self.stream.write_all(&(frame.len() as u16).to_be_bytes())?,
+            LengthField::U32 => self.stream.write_all(&(frame.len() as u32).to_be_bytes())?,
+        }
+        self.stream.write_all(&frame)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener};
+
+    use super::*;
+    use crate::test_fixtures::{Target, TestKind};
+
+    fn read_frame(stream: &mut TcpStream) -> Bufferfish {
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+
+        let mut frame = vec![0; usize::from(u16::from_be_bytes(len))];
+        stream.read_exact(&mut frame).unwrap();
+
+        Bufferfish::from(Bytes::from(frame))
+    }
+
+    #[test]
+    fn sends_handshake_and_actions() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut harp =
+            BlockingHarp::raw_connect(addr, Harp::builder().service_name("script")).unwrap();
+        harp.send(Action::new(TestKind("my_kind"), &Target)).unwrap();
+        harp.flush().unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let handshake = Handshake::try_from(read_frame(&mut stream)).unwrap();
+        assert_eq!(handshake.service.as_deref(), Some("script"));
+
+        let action = Action::try_from(read_frame(&mut stream)).unwrap();
+        assert_eq!(action.kind, "my_kind");
+        assert_eq!(action.sequence, Some(1));
+    }
+}
@@ -0,0 +1,624 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::time::MissedTickBehavior;
+
+#[cfg(feature = "encryption")]
+use crate::encryption::DetailCipher;
+#[cfg(feature = "proxy")]
+use crate::proxy::{Proxy, ProxyTarget};
+#[cfg(feature = "tls")]
+use crate::tls::TlsOptions;
+use crate::{
+    action::{Action, Kind},
+    blocking::BlockingHarp,
+    collector::Collector,
+    connection::{ConnectionStatus, Status},
+    expiry::Expiry,
+    interceptor::{Interceptor, Interceptors},
+    protocol::{sign_handshake, Credentials, LengthField, Nack, DEFAULT_MAX_FRAME_SIZE},
+    sampling::Sampler,
+    sender::Sender,
+    transport::Endpoint,
+    Channels, Harp, Result, RETRY_CONNECT_LIMIT,
+};
+
+/// The number of actions a lazy service holds while it first connects, if not
+/// configured.
+const DEFAULT_STARTUP_BUFFER: usize = 10_000;
+
+/// The most frames read from the server in a row before waiting actions are
+/// sent, if not configured.
+const DEFAULT_MAX_CONSECUTIVE_READS: usize = 64;
+
+/// Configures a connection to a Harp server. Created with `Harp::builder()`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use harp::Harp;
+/// #
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let harp = Harp::builder()
+///     .hostname("127.0.0.1")
+///     .port(7777)
+///     .service_name("shard-1")
+///     .create_service()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct HarpBuilder {
+    hostname: Option<String>,
+    port: Option<u16>,
+    pub(crate) service_name: Option<String>,
+    pub(crate) batching: Option<Batching>,
+    pub(crate) retry: ReserveRetry,
+    pub(crate) idempotency_keys: bool,
+    pub(crate) ordered_identifiers: bool,
+    pub(crate) checksums: bool,
+    pub(crate) frame_key: Option<FrameKey>,
+    pub(crate) length_field: LengthField,
+    pub(crate) max_frame_size: Option<usize>,
+    max_consecutive_reads: Option<usize>,
+    pub(crate) on_nack: Option<NackCallback>,
+    pub(crate) sampler: Sampler,
+    pub(crate) interceptors: Interceptors,
+    pub(crate) reserve_capacity: Option<usize>,
+    pub(crate) reserve_max_bytes: Option<usize>,
+    pub(crate) reserve_file: Option<PathBuf>,
+    pub(crate) expiry: Expiry,
+    pub(crate) reconnect: Reconnect,
+    pub(crate) auth: Option<Auth>,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<TlsOptions>,
+    #[cfg(feature = "proxy")]
+    proxy: Option<Proxy>,
+    #[cfg(all(windows, feature = "named-pipe"))]
+    named_pipe: Option<String>,
+    startup_buffer: Option<usize>,
+}
+
+/// Controls how actions are coalesced before being written to the socket.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Batching {
+    /// The longest an action may wait before the batch is flushed.
+    pub delay: Duration,
+    /// The number of pending actions which flushes the batch immediately.
+    pub max_pending: usize,
+}
+
+/// Controls how actions in the reserve queue are resent.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReserveRetry {
+    /// The time between attempts to resend actions.
+    pub interval: Duration,
+    /// The most actions resent on each attempt.
+    pub batch_size: usize,
+    /// What happens when attempts are missed, such as while the service is
+    /// blocked or disconnected.
+    pub missed_tick_behavior: MissedTickBehavior,
+}
+
+impl Default for ReserveRetry {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(3),
+            batch_size: 10,
+            missed_tick_behavior: MissedTickBehavior::Delay,
+        }
+    }
+}
+
+/// Receives every `Nack` sent by the Harp server.
+pub(crate) struct NackCallback(Box<dyn Fn(&Nack) + Send + Sync>);
+
+impl NackCallback {
+    pub(crate) fn call(&self, nack: &Nack) {
+        (self.0)(nack);
+    }
+}
+
+impl std::fmt::Debug for NackCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("NackCallback")
+    }
+}
+
+/// How the service proves its identity to the Harp server.
+#[derive(Clone)]
+pub(crate) enum Auth {
+    /// Sends a token shared with the server.
+    Token(String),
+    /// Signs the handshake with a key shared with the server.
+    Key(Vec<u8>),
+}
+
+impl Auth {
+    /// Returns the credentials to send in the handshake for `service`. Keys
+    /// sign a fresh timestamp on every connection.
+    pub(crate) fn credentials(&self, service: Option<&str>) -> Credentials {
+        match self {
+            Auth::Token(token) => Credentials::Token(token.clone()),
+            Auth::Key(key) => {
+                let timestamp =
+                    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                let signature = sign_handshake(key, service, timestamp);
+                Credentials::Signature { timestamp, signature }
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Auth::Token(_) => f.write_str("Token(..)"),
+            Auth::Key(_) => f.write_str("Key(..)"),
+        }
+    }
+}
+
+/// The key every frame after the handshake is signed with.
+#[derive(Clone)]
+pub(crate) struct FrameKey(pub Arc<[u8]>);
+
+impl std::fmt::Debug for FrameKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FrameKey(..)")
+    }
+}
+
+/// Controls how the service connects, and reconnects, to the Harp server.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Reconnect {
+    /// The most attempts made before giving up, or `None` to retry forever.
+    pub max_attempts: Option<u32>,
+    /// Whether a failed first connection is retried, rather than returned as
+    /// an error.
+    pub retry_initial: bool,
+    /// Whether `create_service` returns before the first connection is made.
+    pub background: bool,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self { max_attempts: Some(RETRY_CONNECT_LIMIT), retry_initial: true, background: false }
+    }
+}
+
+impl HarpBuilder {
+    /// Sets the hostname of the Harp server. Defaults to "127.0.0.1".
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Sets the port of the Harp server. Defaults to 7777.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Authenticates with a token shared with the Harp server, for servers
+    /// which check tokens. Replaces any key set with `auth_key`.
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth = Some(Auth::Token(token.into()));
+        self
+    }
+
+    /// Authenticates by signing the service name with a key shared with the
+    /// Harp server, for servers which check HMAC signatures. The key itself is
+    /// never sent. Replaces any token set with `auth_token`.
+    pub fn auth_key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.auth = Some(Auth::Key(key.into()));
+        self
+    }
+
+    /// Returns the hostname of the Harp server.
+    #[cfg(feature = "tls")]
+    pub(crate) fn get_hostname(&self) -> &str {
+        self.hostname.as_deref().unwrap_or("127.0.0.1")
+    }
+
+    /// Connects to the Harp server over TLS, verifying its certificate and
+    /// presenting a client certificate if one is set. Only supported by async
+    /// services. See `TlsOptions` for more information.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, options: TlsOptions) -> Self {
+        self.tls = Some(options);
+        self
+    }
+
+    /// Tunnels connections to the Harp server through a SOCKS5 or HTTP proxy,
+    /// including TLS connections. Only supported by async services. See
+    /// `Proxy` for more information.
+    #[cfg(feature = "proxy")]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Connects to a Harp server on the same machine over the named pipe
+    /// `name`, such as `\\.\pipe\harp`, rather than over TCP. The hostname,
+    /// port, and any TLS options are ignored. Only supported by async
+    /// services on Windows.
+    #[cfg(all(windows, feature = "named-pipe"))]
+    pub fn named_pipe(mut self, name: impl Into<String>) -> Self {
+        self.named_pipe = Some(name.into());
+        self
+    }
+
+    /// Returns where the service connects: the named pipe if one is set, then
+    /// `addr` over TLS if configured, and otherwise `addr` over TCP. TLS and
+    /// TCP connections go through the proxy, if one is set.
+    pub(crate) fn endpoint(&self, addr: SocketAddr) -> Result<Endpoint> {
+        #[cfg(all(windows, feature = "named-pipe"))]
+        if let Some(name) = &self.named_pipe {
+            return Ok(Endpoint::Pipe(name.clone()));
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            let target = tls.target(addr, self.get_hostname())?;
+            #[cfg(feature = "proxy")]
+            let target = target.through(self.proxy.clone());
+
+            return Ok(Endpoint::Tls(target));
+        }
+
+        #[cfg(feature = "proxy")]
+        if let Some(proxy) = &self.proxy {
+            return Ok(Endpoint::Proxy(ProxyTarget { proxy: proxy.clone(), addr }));
+        }
+
+        Ok(Endpoint::Tcp(addr))
+    }
+
+    /// Sets the name this service announces to the Harp server when it
+    /// connects. The server stores it alongside every action sent on the
+    /// connection, so it should identify this process, such as a shard name.
+    pub fn service_name(mut self, service_name: impl Into<String>) -> Self {
+        self.service_name = Some(service_name.into());
+        self
+    }
+
+    /// Enables coalescing of actions before they are sent. Rather than flushing
+    /// the socket for every action, actions are buffered and written together
+    /// once `delay` has passed since the first buffered action, or once
+    /// `max_pending` actions are buffered, whichever comes first.
+    ///
+    /// This reduces syscall overhead for services which log many actions at
+    /// the cost of up to `delay` of extra latency. Disabled by default.
+    pub fn batching(mut self, delay: Duration, max_pending: usize) -> Self {
+        self.batching = Some(Batching { delay, max_pending: max_pending.max(1) });
+        self
+    }
+
+    /// Assigns a random idempotency key to every action which doesn't already
+    /// have one before it is sent. `harpd` ignores any action whose ID and key
+    /// it has already stored, so actions which are retransmitted after a
+    /// reconnect are not duplicated. Disabled by default.
+    pub fn idempotency_keys(mut self, enabled: bool) -> Self {
+        self.idempotency_keys = enabled;
+        self
+    }
+
+    /// Stamps every action with an ordinal which increases for each (ip, id)
+    /// pair in the order actions are sent. Retries, requeues, and reconnects
+    /// can deliver a player's actions out of order; `harpd` stores the
+    /// ordinal and inserts each batch by it, so `ORDER BY ordinal` gives a
+    /// player's actions back in the order they happened. Costs 9 bytes per
+    /// action. Disabled by default.
+    pub fn ordered_identifiers(mut self, enabled: bool) -> Self {
+        self.ordered_identifiers = enabled;
+        self
+    }
+
+    /// Appends a CRC32 checksum to every frame sent, which `harpd` verifies
+    /// before parsing. Frames corrupted in transit, such as by a faulty proxy,
+    /// are discarded rather than stored as nonsense rows. Costs 4 bytes per
+    /// action. Disabled by default.
+    pub fn checksums(mut self, enabled: bool) -> Self {
+        self.checksums = enabled;
+        self
+    }
+
+    /// Appends an HMAC-SHA256 of every frame sent, keyed with a secret shared
+    /// with `harpd`, which verifies it before parsing. Frames modified in
+    /// transit, such as by a middlebox, are rejected and counted rather than
+    /// stored. Costs 32 bytes per action. Disabled by default.
+    pub fn sign_frames(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.frame_key = Some(FrameKey(key.into().into()));
+        self
+    }
+
+    /// Sets the width of the length prefix on every frame after the handshake.
+    /// `LengthField::U16`, the default, caps frames at 64KB; `U32` allows
+    /// larger frames, up to `max_frame_size`. `harpd` uses whichever framing
+    /// the service announces.
+    pub fn length_field(mut self, length_field: LengthField) -> Self {
+        self.length_field = length_field;
+        self
+    }
+
+    /// Sets the largest frame, in bytes, sent or accepted after the handshake.
+    /// Actions which encode to anything larger are dropped rather than sent.
+    /// `harpd` may ask for a smaller limit when the service connects. Defaults
+    /// to 1MB, capped at what the length field can hold.
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = Some(max_frame_size);
+        self
+    }
+
+    /// Returns the largest frame allowed after the handshake.
+    pub(crate) fn get_max_frame_size(&self) -> usize {
+        self.length_field.clamp(self.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE))
+    }
+
+    /// Sets the most frames read from the server in a row before the service
+    /// stops to send waiting actions. The run loop always reads frames first,
+    /// so that returned actions and NACKs are seen promptly, but a flood of
+    /// returned actions would otherwise hold back every send, and the reserve
+    /// retry timer, until it ends. Defaults to 64.
+    pub fn max_consecutive_reads(mut self, max: usize) -> Self {
+        self.max_consecutive_reads = Some(max.max(1));
+        self
+    }
+
+    /// Returns the most frames read from the server in a row.
+    pub(crate) fn get_max_consecutive_reads(&self) -> usize {
+        self.max_consecutive_reads.unwrap_or(DEFAULT_MAX_CONSECUTIVE_READS)
+    }
+
+    /// Samples actions of the given kind, keeping roughly `rate` of them (for
+    /// example, 0.01 keeps 1%). Sampling happens in `Sender::send`, before
+    /// actions enter the channel, and kept actions record their sample rate.
+    /// Kinds without a rate are always kept.
+    pub fn sample_rate(mut self, kind: impl Kind, rate: f64) -> Self {
+        self.sampler.set_rate(kind.key().to_string(), rate);
+        self
+    }
+
+    /// Registers an interceptor which can modify or drop every action before
+    /// it is sent. Interceptors run in the order they are registered, after
+    /// sampling. See `Interceptor` for more information.
+    pub fn interceptor(mut self, interceptor: impl Interceptor) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Encrypts the detail of every action before it is sent, after every
+    /// interceptor has run. `harpd` stores encrypted details as they are, so
+    /// its detail schemas, size limits, and indexes only see the encrypted
+    /// form. Requires the `encryption` feature. See `harp::encryption`.
+    #[cfg(feature = "encryption")]
+    pub fn encrypt_detail(mut self, cipher: DetailCipher) -> Self {
+        self.interceptors.encrypt_detail(cipher);
+        self
+    }
+
+    /// Sets the maximum number of actions held for retrying after they fail
+    /// to send or are returned by the server. Once full, the oldest actions
+    /// are dropped. Defaults to 10,000.
+    pub fn reserve_capacity(mut self, capacity: usize) -> Self {
+        self.reserve_capacity = Some(capacity);
+        self
+    }
+
+    /// Sets the most bytes of encoded actions held for retrying, on top of the
+    /// reserve capacity. Once reached, the oldest actions are dropped. Only
+    /// the capacity is used by default.
+    pub fn reserve_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.reserve_max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Saves the reserve queue to `path` when the service stops, and loads it
+    /// back the next time a service is created with the same path, so
+    /// actions waiting to be retried survive a restart. Actions still in the
+    /// channel are not saved. Disabled by default.
+    pub fn reserve_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.reserve_file = Some(path.into());
+        self
+    }
+
+    /// Drops actions which are older than `max_age` when they would be sent,
+    /// rather than sending them late. This applies to actions retried from
+    /// the reserve queue and to actions which waited out a disconnect. Ages
+    /// are measured from when the action was created. Actions never expire by
+    /// default.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.expiry.set_default(max_age);
+        self
+    }
+
+    /// Sets the maximum age for actions of the given kind, overriding
+    /// `max_age`. See `max_age` for more information.
+    pub fn max_age_for(mut self, kind: impl Kind, max_age: Duration) -> Self {
+        self.expiry.set_max_age(kind.key().to_string(), max_age);
+        self
+    }
+
+    /// Registers a callback which receives every action dropped for being
+    /// older than its maximum age, such as to write it to a local file.
+    pub fn on_expired(mut self, callback: impl Fn(Action) + Send + Sync + 'static) -> Self {
+        self.expiry.set_callback(callback);
+        self
+    }
+
+    /// Registers a callback which receives every `Nack` the Harp server sends
+    /// when it rejects an action, such as to count rejections by code or log
+    /// the offending kinds. NACKs are logged either way, and rejected actions
+    /// are never retried.
+    pub fn on_nack(mut self, callback: impl Fn(&Nack) + Send + Sync + 'static) -> Self {
+        self.on_nack = Some(NackCallback(Box::new(callback)));
+        self
+    }
+
+    /// Sets how often actions in the reserve queue are resent, and the most
+    /// actions resent each time. Resending is drip fed, as the reserve queue
+    /// usually fills while the server is struggling. Defaults to 10 actions
+    /// every 3 seconds.
+    pub fn reserve_retry(mut self, interval: Duration, batch_size: usize) -> Self {
+        self.retry.interval = interval.max(Duration::from_millis(1));
+        self.retry.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Sets what happens when reserve retries are missed, such as after the
+    /// service was disconnected for a while. `Burst` catches up on every
+    /// missed retry at once, which can flood a recovering server, so the
+    /// default is `Delay`.
+    pub fn missed_tick_behavior(mut self, behavior: MissedTickBehavior) -> Self {
+        self.retry.missed_tick_behavior = behavior;
+        self
+    }
+
+    /// Sets the most attempts made to connect, or reconnect, to the Harp
+    /// server before the service gives up, or `None` to retry forever. The
+    /// delay between attempts grows by 3 seconds each time, up to 42 seconds.
+    /// Defaults to 15 attempts.
+    pub fn max_connect_attempts(mut self, attempts: Option<u32>) -> Self {
+        self.reconnect.max_attempts = attempts.map(|attempts| attempts.max(1));
+        self
+    }
+
+    /// Sets whether a failed first connection is retried like a reconnect.
+    /// When disabled, connecting returns an error as soon as the first
+    /// attempt fails, such as when `harpd` isn't running. Enabled by default.
+    pub fn retry_initial_connect(mut self, enabled: bool) -> Self {
+        self.reconnect.retry_initial = enabled;
+        self
+    }
+
+    /// Makes `create_service` return immediately, connecting in the
+    /// background instead. Actions sent before the connection is made wait in
+    /// the channel, and are dropped if every attempt fails. See
+    /// `Sender::status` to check on the connection. Disabled by default.
+    pub fn connect_in_background(mut self, enabled: bool) -> Self {
+        self.reconnect.background = enabled;
+        self
+    }
+
+    /// Sets the most actions a lazy service holds while it makes its first
+    /// connection. Actions sent once the buffer is full are dropped, and
+    /// counted by `ReserveMetrics::startup_dropped`. Defaults to 10,000. See
+    /// `create_service_lazy`.
+    pub fn startup_buffer(mut self, capacity: usize) -> Self {
+        self.startup_buffer = Some(capacity);
+        self
+    }
+
+    /// Attempts to connect to the configured Harp server. See `Harp::connect`
+    /// for more information.
+    pub async fn connect(self) -> Result<Harp> {
+        let addr = Harp::create_addr(self.hostname.as_deref(), self.port);
+        Harp::raw_connect(addr, self).await
+    }
+
+    /// Connects to the configured Harp server without an async runtime. Any
+    /// batching configured on the builder is ignored, as the returned client
+    /// only writes actions when flushed. See `BlockingHarp` for more
+    /// information.
+    pub fn connect_blocking(self) -> Result<BlockingHarp> {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return Err("TLS is not supported by the blocking client".into());
+        }
+
+        #[cfg(all(windows, feature = "named-pipe"))]
+        if self.named_pipe.is_some() {
+            return Err("Named pipes are not supported by the blocking client".into());
+        }
+
+        #[cfg(feature = "proxy")]
+        if self.proxy.is_some() {
+            return Err("Proxies are not supported by the blocking client".into());
+        }
+
+        let addr = Harp::create_addr(self.hostname.as_deref(), self.port);
+        BlockingHarp::raw_connect(addr, self)
+    }
+
+    /// Connects to the configured Harp server and spawns a new task to run the
+    /// service. See `Harp::create_service` for more information.
+    pub async fn create_service(mut self) -> Result<Sender> {
+        let addr = Harp::create_addr(self.hostname.as_deref(), self.port);
+        let sampler = Arc::new(std::mem::take(&mut self.sampler));
+        let channels = Channels::new(&mut self);
+        let sender = channels.sender(sampler);
+
+        if self.reconnect.background {
+            self.spawn_background(addr, channels);
+        } else {
+            let mut harp = Harp::connect_channels(addr, self, channels).await?;
+            tokio::spawn(async move {
+                let _ = harp.run().await;
+            });
+        }
+
+        Ok(sender)
+    }
+
+    /// Returns a `Sender` immediately and connects to the configured Harp
+    /// server in the background. See `Harp::create_service_lazy` for more
+    /// information.
+    pub fn create_service_lazy(mut self) -> Sender {
+        let addr = Harp::create_addr(self.hostname.as_deref(), self.port);
+        let sampler = Arc::new(std::mem::take(&mut self.sampler));
+        let channels = Channels::new(&mut self);
+        let sender = channels
+            .sender(sampler)
+            .with_startup_buffer(self.startup_buffer.unwrap_or(DEFAULT_STARTUP_BUFFER));
+
+        self.spawn_background(addr, channels);
+
+        sender
+    }
+
+    /// Spawns a task which connects to the Harp server, then runs the service.
+    fn spawn_background(self, addr: SocketAddr, channels: Channels) {
+        tokio::spawn(async move {
+            match Harp::connect_channels(addr, self, channels).await {
+                Ok(mut harp) => {
+                    let _ = harp.run().await;
+                }
+                Err(e) => tracing::error!("Failed to connect to Harp on {addr}: {e}"),
+            }
+        });
+    }
+
+    /// Returns a `Sender` which is not connected to any Harp server, and which
+    /// silently drops every action after sampling. See
+    /// `Harp::create_null_service` for more information.
+    pub fn create_null_service(self) -> Sender {
+        Sender::null(Arc::new(self.sampler))
+    }
+
+    /// Returns a `Sender` which is not connected to any Harp server, along with
+    /// a `Collector` which stores every action sent through it. Sampling and
+    /// interceptors are applied as they would be for a connected service.
+    pub fn create_collector_service(self) -> (Sender, Collector) {
+        let (tx, rx) = flume::unbounded();
+
+        // Both lanes feed the same collector, so actions are kept in the order
+        // they were sent regardless of priority.
+        let sender = Sender::new(
+            tx.clone(),
+            tx,
+            None,
+            Arc::new(self.sampler),
+            Arc::default(),
+            ConnectionStatus::fixed(Status::Connected),
+        )
+        .with_ordinals(self.ordered_identifiers.then(Arc::default));
+        (sender, Collector::new(rx, self.interceptors))
+    }
+}
@@ -0,0 +1,50 @@
+//! An in-memory store for the actions sent by a service, for unit tests and
+//! development builds which run without a Harp server.
+use crate::{action::Action, interceptor::Interceptors};
+
+/// Collects every action sent through a `Sender` created by
+/// `HarpBuilder::create_collector_service`, so tests can inspect what would
+/// have been logged. Sampling and interceptors are applied just as they would
+/// be for a connected service.
+///
+/// # Examples
+///
+/// ```
+/// # use harp::{action::{Action, Kind}, Harp, HarpId, Loggable};
+/// # use std::net::IpAddr;
+/// # struct Player;
+/// # impl Loggable for Player {
+/// #     fn identifier(&self) -> HarpId {
+/// #         (IpAddr::from([127, 0, 0, 1]), 1)
+/// #     }
+/// # }
+/// # struct Login;
+/// # impl Kind for Login {
+/// #     fn key(&self) -> &str {
+/// #         "login"
+/// #     }
+/// # }
+/// let (harp, collector) = Harp::builder().create_collector_service();
+///
+/// harp.send(Action::new(Login, &Player)).unwrap();
+///
+/// let actions = collector.actions();
+/// assert_eq!(actions[0].kind, "login");
+/// ```
+#[derive(Debug)]
+pub struct Collector {
+    rx: flume::Receiver<Action>,
+    interceptors: Interceptors,
+}
+
+impl Collector {
+    pub(crate) fn new(rx: flume::Receiver<Action>, interceptors: Interceptors) -> Self {
+        Self { rx, interceptors }
+    }
+
+    /// Removes and returns every action sent since the last call, in the
+    /// order they were sent. Actions dropped by an interceptor are skipped.
+    pub fn actions(&self) -> Vec<Action> {
+        self.rx.try_iter().filter_map(|action| self.interceptors.apply(action)).collect()
+    }
+}
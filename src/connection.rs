@@ -0,0 +1,185 @@
+//! Tracks whether a service's connection to the Harp server is up, as reported
+//! by the reconnecting stream's callbacks.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use time::OffsetDateTime;
+use tokio::sync::{watch, Notify};
+
+/// The state of a service's connection to the Harp server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The first connection has not been established yet. Actions wait in the
+    /// channel until it is.
+    Connecting,
+    /// Actions are being sent.
+    Connected,
+    /// The connection dropped and is being retried. Actions wait in the
+    /// channel until it is back.
+    Reconnecting,
+    /// Every connection attempt failed, and the service has given up. Actions
+    /// will not be sent again.
+    Failed,
+}
+
+/// A handle for checking, or waiting on, a service's connection to the Harp
+/// server. Cheap to clone; every clone observes the same service.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use harp::{connection::Status, Harp};
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let harp = Harp::create_service().await?;
+/// let status = harp.status();
+///
+/// if status.status() != Status::Connected {
+///     println!("Harp has been {:?} since {}", status.status(), status.since());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConnectionStatus {
+    rx: watch::Receiver<(Status, OffsetDateTime)>,
+}
+
+impl ConnectionStatus {
+    /// Creates a handle which always reports `status`, for senders which have
+    /// no connection.
+    pub(crate) fn fixed(status: Status) -> Self {
+        let (_, rx) = watch::channel((status, OffsetDateTime::now_utc()));
+        Self { rx }
+    }
+
+    /// Returns the current state of the connection.
+    pub fn status(&self) -> Status {
+        self.rx.borrow().0
+    }
+
+    /// Returns when the connection entered its current state.
+    pub fn since(&self) -> OffsetDateTime {
+        self.rx.borrow().1
+    }
+
+    /// Returns true if actions are currently being sent.
+    pub fn is_connected(&self) -> bool {
+        self.status() == Status::Connected
+    }
+
+    /// Waits until the connection changes state, returning the new state, or
+    /// `None` if the service has stopped.
+    pub async fn changed(&mut self) -> Option<Status> {
+        self.rx.changed().await.ok()?;
+        Some(self.status())
+    }
+}
+
+/// Shared between a `Harp` service and the callbacks of its reconnecting
+/// stream, which run whenever the stream connects or drops.
+#[derive(Debug)]
+pub(crate) struct ConnectionState {
+    connected: AtomicBool,
+    /// Set whenever the stream reconnects, as the handshake must be sent again
+    /// before any other frames.
+    reconnected: AtomicBool,
+    /// Woken whenever the connection goes up or down.
+    changed: Notify,
+    /// Published to `ConnectionStatus` handles.
+    status: watch::Sender<(Status, OffsetDateTime)>,
+}
+
+impl ConnectionState {
+    /// Creates the state for a stream which has not connected yet.
+    pub(crate) fn new() -> Self {
+        Self {
+            connected: AtomicBool::new(false),
+            reconnected: AtomicBool::new(false),
+            changed: Notify::new(),
+            status: watch::Sender::new((Status::Connecting, OffsetDateTime::now_utc())),
+        }
+    }
+
+    pub(crate) fn on_connect(&self) {
+        self.reconnected.store(true, Ordering::Release);
+        self.connected.store(true, Ordering::Release);
+        self.set_status(Status::Connected);
+        self.changed.notify_one();
+    }
+
+    pub(crate) fn on_disconnect(&self) {
+        self.connected.store(false, Ordering::Release);
+        self.set_status(Status::Reconnecting);
+        self.changed.notify_one();
+    }
+
+    /// Called once every connection attempt has failed.
+    pub(crate) fn on_connect_fail(&self) {
+        self.connected.store(false, Ordering::Release);
+        self.set_status(Status::Failed);
+        self.changed.notify_one();
+    }
+
+    fn set_status(&self, status: Status) {
+        self.status.send_if_modified(|current| {
+            if current.0 == status {
+                return false;
+            }
+
+            *current = (status, OffsetDateTime::now_utc());
+            true
+        });
+    }
+
+    /// Returns a handle which observes this connection.
+    pub(crate) fn subscribe(&self) -> ConnectionStatus {
+        ConnectionStatus { rx: self.status.subscribe() }
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the stream has reconnected since the handshake was
+    /// last sent.
+    pub(crate) fn reconnected(&self) -> bool {
+        self.reconnected.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn clear_reconnected(&self) {
+        self.reconnected.store(false, Ordering::Release);
+    }
+
+    /// Waits until the connection goes up or down.
+    pub(crate) async fn changed(&self) {
+        self.changed.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_follows_callbacks() {
+        let state = ConnectionState::new();
+        let status = state.subscribe();
+        assert_eq!(status.status(), Status::Connecting);
+
+        state.on_connect();
+        assert!(status.is_connected());
+
+        state.on_disconnect();
+        assert_eq!(status.status(), Status::Reconnecting);
+
+        let since = status.since();
+        state.on_disconnect();
+        assert_eq!(status.since(), since);
+
+        state.on_connect_fail();
+        assert_eq!(status.status(), Status::Failed);
+
+        state.on_connect();
+        assert!(status.is_connected());
+    }
+}
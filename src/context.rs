@@ -0,0 +1,142 @@
+//! Task-local context for logging actions without passing the acting player
+//! through every function. Wrap the code which handles a player in
+//! `with_actor`, install a `Sender` once with `set_sender`, and anything called
+//! from inside can use `harp::log`.
+//!
+//! Task-locals are not inherited by tasks spawned inside the scope; wrap those
+//! in `with_actor` again.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use harp::{action::Kind, context, Harp, HarpId, Loggable};
+//! # use std::net::IpAddr;
+//! # struct Player;
+//! # impl Loggable for Player {
+//! #     fn identifier(&self) -> HarpId {
+//! #         (IpAddr::from([127, 0, 0, 1]), 1)
+//! #     }
+//! # }
+//! # struct Trade;
+//! # impl Kind for Trade {
+//! #     fn key(&self) -> &str {
+//! #         "trade"
+//! #     }
+//! # }
+//! async fn complete_trade() -> Result<(), context::ContextError> {
+//!     // No player reference needed down here.
+//!     harp::log(Trade)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let _ = context::set_sender(Harp::create_service().await?);
+//!
+//! context::with_actor(&Player, complete_trade()).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{fmt::Display, future::Future, sync::OnceLock};
+
+use serde_json::Value;
+
+use crate::{
+    action::{Action, Kind},
+    sender::Sender,
+    HarpId, Loggable,
+};
+
+tokio::task_local! {
+    static ACTOR: HarpId;
+}
+
+static SENDER: OnceLock<Sender> = OnceLock::new();
+
+/// The actor stored in the task-local context.
+struct Current(HarpId);
+
+impl Loggable for Current {
+    fn identifier(&self) -> HarpId {
+        self.0
+    }
+}
+
+/// Runs `future` with `actor` as the current actor, so actions logged with
+/// `harp::log` inside it are attributed to them. Scopes can be nested; the
+/// innermost actor wins.
+pub async fn with_actor<F: Future>(actor: &impl Loggable, future: F) -> F::Output {
+    ACTOR.scope(actor.identifier(), future).await
+}
+
+/// Returns the identifier of the current actor, if called inside `with_actor`.
+pub fn current_actor() -> Option<HarpId> {
+    ACTOR.try_with(|actor| *actor).ok()
+}
+
+/// Installs the `Sender` used by `harp::log`. It can only be set once; if one
+/// is already installed, `sender` is handed back.
+pub fn set_sender(sender: Sender) -> Result<(), Sender> {
+    SENDER.set(sender)
+}
+
+/// Sends an action with no detail for the current actor. See `with_actor`.
+pub fn log(kind: impl Kind) -> Result<(), ContextError> {
+    send(|actor| Action::new(kind, actor))
+}
+
+/// Sends an action with a detail for the current actor. See `with_actor`.
+pub fn log_with(kind: impl Kind, detail: Value) -> Result<(), ContextError> {
+    send(|actor| Action::with_detail(kind, detail, actor))
+}
+
+fn send(build: impl FnOnce(&Current) -> Action) -> Result<(), ContextError> {
+    let actor = current_actor().ok_or(ContextError::NoActor)?;
+    let sender = SENDER.get().ok_or(ContextError::NoSender)?;
+
+    sender.send(build(&Current(actor))).map_err(|_| ContextError::Closed)
+}
+
+#[derive(Debug)]
+pub enum ContextError {
+    /// Called outside of `with_actor`.
+    NoActor,
+    /// No sender has been installed with `set_sender`.
+    NoSender,
+    /// The Harp service is no longer running.
+    Closed,
+}
+
+impl std::error::Error for ContextError {}
+
+impl Display for ContextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContextError::NoActor => write!(f, "No actor is set for the current task"),
+            ContextError::NoSender => write!(f, "No Harp sender has been installed"),
+            ContextError::Closed => write!(f, "The Harp service is no longer running"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::Player;
+
+    #[tokio::test]
+    async fn innermost_actor_wins() {
+        assert_eq!(current_actor(), None);
+
+        with_actor(&Player(1), async {
+            assert_eq!(current_actor().map(|(_, id)| id), Some(1));
+
+            with_actor(&Player(2), async {
+                assert_eq!(current_actor().map(|(_, id)| id), Some(2));
+            })
+            .await;
+
+            assert_eq!(current_actor().map(|(_, id)| id), Some(1));
+        })
+        .await;
+    }
+}
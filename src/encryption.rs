@@ -0,0 +1,202 @@
+//! Encryption of action details at rest, enabled with the `encryption`
+//! feature. Details are encrypted with AES-256-GCM and stored as a JSON
+//! object in place of the original, so they can still be written to the
+//! `detail` column:
+//!
+//! ```json
+//! {"encrypted": "aes-256-gcm", "nonce": "...", "ciphertext": "..."}
+//! ```
+//!
+//! Services can encrypt details before they are sent with
+//! `HarpBuilder::encrypt_detail`, or `harpd` can encrypt them before they are
+//! stored with `detail.encryption_key_file`. Reading them back needs the same
+//! key; see `DetailCipher::decrypt`.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use harp::{encryption::DetailCipher, Harp};
+//! #
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let key = std::fs::read_to_string("/etc/harp/detail.key")?;
+//! let cipher = DetailCipher::from_base64(&key)?;
+//!
+//! let harp = Harp::builder().encrypt_detail(cipher).create_service().await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::fmt::Display;
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use zeroize::Zeroizing;
+
+use crate::action::Action;
+
+/// The algorithm named in every encrypted detail.
+const ALGORITHM: &str = "aes-256-gcm";
+
+/// Length in bytes of a detail encryption key.
+pub const KEY_LEN: usize = 32;
+
+/// Length in bytes of the nonce stored with each encrypted detail.
+const NONCE_LEN: usize = 12;
+
+/// Encrypts and decrypts action details with a 256-bit key. Every detail is
+/// encrypted with a fresh random nonce, so the same detail never encrypts to
+/// the same value twice.
+#[derive(Clone)]
+pub struct DetailCipher(Aes256Gcm);
+
+impl DetailCipher {
+    /// Creates a cipher from a 32-byte key.
+    pub fn new(key: &[u8]) -> Result<Self, EncryptionError> {
+        Aes256Gcm::new_from_slice(key).map(Self).map_err(|_| EncryptionError::InvalidKey)
+    }
+
+    /// Creates a cipher from a base64-encoded 32-byte key, such as one written
+    /// by `openssl rand -base64 32`. Surrounding whitespace is ignored.
+    pub fn from_base64(key: &str) -> Result<Self, EncryptionError> {
+        let key =
+            Zeroizing::new(STANDARD.decode(key.trim()).map_err(|_| EncryptionError::InvalidKey)?);
+
+        Self::new(&key)
+    }
+
+    /// Returns `detail` encrypted. Details which are already encrypted are
+    /// returned unchanged, so encrypting on both the service and `harpd` is
+    /// harmless.
+    pub fn encrypt(&self, detail: &Value) -> Result<Value, EncryptionError> {
+        if is_encrypted(detail) {
+            return Ok(detail.clone());
+        }
+
+        let plaintext = Zeroizing::new(detail.to_string().into_bytes());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext =
+            self.0.encrypt(&nonce, plaintext.as_slice()).map_err(|_| EncryptionError::Failed)?;
+
+        Ok(json!({
+            "encrypted": ALGORITHM,
+            "nonce": STANDARD.encode(nonce.as_slice()),
+            "ciphertext": STANDARD.encode(ciphertext),
+        }))
+    }
+
+    /// Returns `detail` decrypted. Details which aren't encrypted are returned
+    /// unchanged, so tables written before encryption was enabled can be read
+    /// in the same way.
+    pub fn decrypt(&self, detail: &Value) -> Result<Value, EncryptionError> {
+        if !is_encrypted(detail) {
+            return Ok(detail.clone());
+        }
+
+        let field = |name| {
+            detail
+                .get(name)
+                .and_then(Value::as_str)
+                .and_then(|value| STANDARD.decode(value).ok())
+                .ok_or(EncryptionError::Malformed)
+        };
+
+        let nonce = field("nonce")?;
+        if nonce.len() != NONCE_LEN {
+            return Err(EncryptionError::Malformed);
+        }
+
+        let plaintext = Zeroizing::new(
+            self.0
+                .decrypt(Nonce::from_slice(&nonce), field("ciphertext")?.as_slice())
+                .map_err(|_| EncryptionError::Invalid)?,
+        );
+
+        serde_json::from_slice(&plaintext).map_err(|_| EncryptionError::Malformed)
+    }
+
+    /// Encrypts the detail of `action` in place, if it has one.
+    pub(crate) fn encrypt_action(&self, action: &mut Action) -> Result<(), EncryptionError> {
+        if let Some(detail) = &mut action.detail {
+            *detail = self.encrypt(detail)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for DetailCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DetailCipher(..)")
+    }
+}
+
+/// Returns true if `detail` was encrypted by a `DetailCipher`.
+pub fn is_encrypted(detail: &Value) -> bool {
+    detail.as_object().is_some_and(|object| {
+        object.len() == 3
+            && object.get("encrypted").and_then(Value::as_str) == Some(ALGORITHM)
+            && object.contains_key("nonce")
+            && object.contains_key("ciphertext")
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncryptionError {
+    /// The key is not 32 bytes, or not valid base64.
+    InvalidKey,
+    /// The detail could not be encrypted.
+    Failed,
+    /// The encrypted detail is missing its nonce or ciphertext, or did not
+    /// decrypt to JSON.
+    Malformed,
+    /// The detail was encrypted with a different key, or has been modified.
+    Invalid,
+}
+
+impl std::error::Error for EncryptionError {}
+
+impl Display for EncryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncryptionError::InvalidKey => {
+                write!(f, "Detail encryption keys must be {KEY_LEN} bytes, encoded as base64")
+            }
+            EncryptionError::Failed => write!(f, "Failed to encrypt detail"),
+            EncryptionError::Malformed => write!(f, "Malformed encrypted detail"),
+            EncryptionError::Invalid => {
+                write!(f, "Failed to decrypt detail; wrong key or modified ciphertext")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_round_trip() {
+        let cipher = DetailCipher::new(&[7; KEY_LEN]).unwrap();
+        let detail = json!({ "email": "player@example.com", "level": 12 });
+
+        let encrypted = cipher.encrypt(&detail).unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.to_string().contains("player@example.com"));
+
+        // Encrypting twice doesn't wrap the detail again.
+        assert_eq!(cipher.encrypt(&encrypted).unwrap(), encrypted);
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), detail);
+
+        // Plain details pass through.
+        assert_eq!(cipher.decrypt(&detail).unwrap(), detail);
+
+        let other = DetailCipher::new(&[8; KEY_LEN]).unwrap();
+        assert_eq!(other.decrypt(&encrypted), Err(EncryptionError::Invalid));
+
+        assert_eq!(DetailCipher::new(&[7; 16]).unwrap_err(), EncryptionError::InvalidKey);
+    }
+}
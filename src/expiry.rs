@@ -0,0 +1,87 @@
+use std::{collections::HashMap, fmt::Debug, time::Duration};
+
+use time::OffsetDateTime;
+
+use crate::action::Action;
+
+/// Maximum ages for actions waiting to be sent, after which they are dropped
+/// rather than sent late. Ages are measured from when the action was created.
+/// Kinds without a configured age fall back to the default, and actions never
+/// expire if neither is set.
+#[derive(Default)]
+pub(crate) struct Expiry {
+    default: Option<Duration>,
+    kinds: HashMap<String, Duration>,
+    on_expired: Option<Box<dyn Fn(Action) + Send + Sync>>,
+}
+
+impl Expiry {
+    pub(crate) fn set_default(&mut self, max_age: Duration) {
+        self.default = Some(max_age);
+    }
+
+    pub(crate) fn set_max_age(&mut self, kind: String, max_age: Duration) {
+        self.kinds.insert(kind, max_age);
+    }
+
+    pub(crate) fn set_callback(&mut self, callback: impl Fn(Action) + Send + Sync + 'static) {
+        self.on_expired = Some(Box::new(callback));
+    }
+
+    /// Returns true if any action can expire.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.default.is_some() || !self.kinds.is_empty()
+    }
+
+    /// Returns true if the action is older than the maximum age for its kind.
+    pub(crate) fn is_expired(&self, action: &Action) -> bool {
+        let Some(max_age) = self.kinds.get(action.kind.as_ref()).or(self.default.as_ref()) else {
+            return false;
+        };
+
+        OffsetDateTime::now_utc() - action.created > *max_age
+    }
+
+    /// Hands an expired action to the callback, if one is registered.
+    pub(crate) fn expire(&self, action: Action) {
+        tracing::debug!("Dropping expired {} action", action.kind);
+
+        if let Some(on_expired) = &self.on_expired {
+            on_expired(action);
+        }
+    }
+}
+
+impl Debug for Expiry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Expiry")
+            .field("default", &self.default)
+            .field("kinds", &self.kinds)
+            .field("on_expired", &self.on_expired.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{Target, TestKind};
+
+    #[test]
+    fn per_kind_age_overrides_default() {
+        let mut expiry = Expiry::default();
+        expiry.set_default(Duration::from_secs(3600));
+        expiry.set_max_age("position".into(), Duration::from_secs(10));
+
+        let mut position = Action::new(TestKind("position"), &Target);
+        let mut purchase = Action::new(TestKind("purchase"), &Target);
+        position.created -= Duration::from_secs(60);
+        purchase.created -= Duration::from_secs(60);
+
+        assert!(expiry.is_expired(&position));
+        assert!(!expiry.is_expired(&purchase));
+
+        purchase.created -= Duration::from_secs(7200);
+        assert!(expiry.is_expired(&purchase));
+    }
+}
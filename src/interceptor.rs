@@ -0,0 +1,115 @@
+//! Hooks for mutating or vetoing actions before they are sent.
+use std::fmt::Debug;
+
+use crate::action::Action;
+#[cfg(feature = "encryption")]
+use crate::encryption::DetailCipher;
+
+/// Inspects every action before it is encoded and sent to the Harp server.
+/// Interceptors can modify an action, such as stripping personal information
+/// from its detail or adding a build hash, or veto it by returning `None`.
+///
+/// Closures with the signature `Fn(Action) -> Option<Action>` implement this
+/// trait.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use harp::Harp;
+/// #
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let harp = Harp::builder()
+///     .interceptor(|mut action: harp::action::Action| {
+///         if let Some(detail) = action.detail.as_mut().and_then(|d| d.as_object_mut()) {
+///             detail.remove("email");
+///         }
+///
+///         Some(action)
+///     })
+///     .create_service()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait Interceptor: Send + Sync + 'static {
+    /// Returns the action to send, or `None` to drop it.
+    fn on_action(&self, action: Action) -> Option<Action>;
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(Action) -> Option<Action> + Send + Sync + 'static,
+{
+    fn on_action(&self, action: Action) -> Option<Action> {
+        self(action)
+    }
+}
+
+/// The interceptors registered on a `HarpBuilder`, applied in the order they
+/// were added.
+#[derive(Default)]
+pub(crate) struct Interceptors {
+    interceptors: Vec<Box<dyn Interceptor>>,
+    // Applied after every interceptor, so that they see the plain detail.
+    #[cfg(feature = "encryption")]
+    cipher: Option<DetailCipher>,
+}
+
+impl Interceptors {
+    pub(crate) fn push(&mut self, interceptor: impl Interceptor) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn encrypt_detail(&mut self, cipher: DetailCipher) {
+        self.cipher = Some(cipher);
+    }
+
+    /// Runs an action through every interceptor, stopping early if one of them
+    /// drops it.
+    pub(crate) fn apply(&self, action: Action) -> Option<Action> {
+        #[allow(unused_mut)]
+        let mut action = self
+            .interceptors
+            .iter()
+            .try_fold(action, |action, interceptor| interceptor.on_action(action))?;
+
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            if let Err(e) = cipher.encrypt_action(&mut action) {
+                tracing::error!("Dropped {} action: {e}", action.kind);
+                return None;
+            }
+        }
+
+        Some(action)
+    }
+}
+
+impl Debug for Interceptors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Interceptors({})", self.interceptors.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{Target, TestKind};
+
+    #[test]
+    fn interceptors_apply_in_order() {
+        let mut interceptors = Interceptors::default();
+        interceptors.push(|action: Action| (action.kind != "secret").then_some(action));
+        interceptors.push(|mut action: Action| {
+            action.kind.to_mut().push_str("_seen");
+            Some(action)
+        });
+
+        let action = interceptors.apply(Action::new(TestKind("login"), &Target)).unwrap();
+        assert_eq!(action.kind, "login_seen");
+
+        assert!(interceptors.apply(Action::new(TestKind("secret"), &Target)).is_none());
+    }
+}
@@ -0,0 +1,136 @@
+//! A compile-time registry of the kinds a game logs, declared once with
+//! `harp::kinds!` and shared between the service, which sends them, and
+//! `harpd`, which can reject any kind not in it.
+use std::fmt::Display;
+
+/// Declares an enum of kinds, each with the key its actions are stored under.
+/// The enum implements `Kind`, `FromStr`, and `Display`, and lists its keys in
+/// `KEYS`, which an embedded server can pass to `ServerBuilder::known_kinds`,
+/// or `harpd` can be given as `validation.known_kinds`.
+///
+/// Without an enum declaration, the enum is named `Kinds`.
+///
+/// # Examples
+///
+/// ```
+/// # use harp::{Harp, HarpId, Loggable};
+/// # use std::net::IpAddr;
+/// # struct Player;
+/// # impl Loggable for Player {
+/// #     fn identifier(&self) -> HarpId {
+/// #         (IpAddr::from([127, 0, 0, 1]), 1)
+/// #     }
+/// # }
+/// harp::kinds! {
+///     /// Everything players do that the game logs.
+///     pub enum GameKind {
+///         PlayerJoin = "player_join",
+///         PlayerLeave = "player_leave",
+///     }
+/// }
+///
+/// let (harp, collector) = Harp::builder().create_collector_service();
+/// harp.log(GameKind::PlayerJoin, &Player).unwrap();
+///
+/// let kind = collector.actions()[0].kind.parse::<GameKind>().unwrap();
+/// assert_eq!(kind, GameKind::PlayerJoin);
+/// assert_eq!(GameKind::KEYS, ["player_join", "player_leave"]);
+/// ```
+#[macro_export]
+macro_rules! kinds {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $($(#[$variant_meta:meta])* $variant:ident = $key:literal),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        $vis enum $name {
+            $($(#[$variant_meta])* $variant),*
+        }
+
+        impl $name {
+            /// Every kind, in the order they were declared.
+            pub const ALL: &'static [$name] = &[$($name::$variant),*];
+
+            /// The key of every kind, in the order they were declared.
+            pub const KEYS: &'static [&'static str] = &[$($key),*];
+
+            /// Returns the key actions of this kind are stored under.
+            pub const fn as_str(self) -> &'static str {
+                match self {
+                    $($name::$variant => $key),*
+                }
+            }
+        }
+
+        impl $crate::action::Kind for $name {
+            fn key(&self) -> &str {
+                self.as_str()
+            }
+
+            fn static_key(&self) -> ::std::option::Option<&'static str> {
+                ::std::option::Option::Some(self.as_str())
+            }
+        }
+
+        impl ::std::str::FromStr for $name {
+            type Err = $crate::kinds::UnknownKind;
+
+            fn from_str(key: &str) -> ::std::result::Result<Self, Self::Err> {
+                match key {
+                    $($key => ::std::result::Result::Ok($name::$variant),)*
+                    _ => ::std::result::Result::Err($crate::kinds::UnknownKind(key.to_string())),
+                }
+            }
+        }
+
+        impl ::std::fmt::Display for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+    };
+    ($($(#[$variant_meta:meta])* $variant:ident = $key:literal),* $(,)?) => {
+        $crate::kinds! {
+            pub enum Kinds {
+                $($(#[$variant_meta])* $variant = $key),*
+            }
+        }
+    };
+}
+
+/// Returned when parsing a key which isn't in a `harp::kinds!` registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownKind(pub String);
+
+impl std::error::Error for UnknownKind {}
+
+impl Display for UnknownKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown kind \"{}\"", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UnknownKind;
+    use crate::action::Kind;
+
+    crate::kinds! {
+        Login = "login",
+        Chat = "chat",
+    }
+
+    #[test]
+    fn registry_round_trip() {
+        for kind in Kinds::ALL {
+            assert_eq!(kind.to_string().parse::<Kinds>(), Ok(*kind));
+            assert_eq!(kind.static_key(), Some(kind.key()));
+        }
+
+        assert_eq!(Kinds::KEYS, ["login", "chat"]);
+        assert_eq!("trade".parse::<Kinds>(), Err(UnknownKind("trade".into())));
+    }
+}
@@ -0,0 +1,195 @@
+//! A `tracing_subscriber::Layer` which turns tracing events into actions, so
+//! that services which are already instrumented can log actions without new
+//! call sites.
+use std::{fmt::Debug, net::IpAddr};
+
+use serde_json::{Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{
+    action::{Action, Kind},
+    sender::Sender,
+    HarpId, Loggable,
+};
+
+/// The field holding the action kind.
+const KIND_FIELD: &str = "harp.kind";
+/// The field holding the action ID.
+const ID_FIELD: &str = "harp.id";
+/// The field holding the action IP address.
+const IP_FIELD: &str = "harp.ip";
+
+/// Sends an action for every tracing event which carries the `harp.kind`,
+/// `harp.id`, and `harp.ip` fields. All other fields on the event, including
+/// its message, are stored as the action's detail. Events without all three
+/// fields are ignored.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use harp::{layer::HarpLayer, Harp};
+/// # use std::net::{IpAddr, Ipv4Addr};
+/// # use tracing_subscriber::prelude::*;
+/// #
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let harp = Harp::create_service().await?;
+///
+/// tracing_subscriber::registry()
+///     .with(tracing_subscriber::fmt::layer())
+///     .with(HarpLayer::new(harp))
+///     .init();
+///
+/// let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+/// tracing::info!(harp.kind = "player_join", harp.id = 1, harp.ip = %ip, "Player joined");
+/// # Ok(())
+/// # }
+/// ```
+pub struct HarpLayer {
+    sender: Sender,
+}
+
+impl HarpLayer {
+    /// Creates a layer which sends actions through the given `Sender`.
+    pub fn new(sender: Sender) -> Self {
+        Self { sender }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for HarpLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+
+        // Errors are not logged here, as doing so would emit another event
+        // from inside the subscriber.
+        if let Some(action) = visitor.into_action() {
+            let _ = self.sender.send(action);
+        }
+    }
+}
+
+/// Collects the Harp fields and detail of a single event.
+#[derive(Default)]
+struct EventVisitor {
+    kind: Option<String>,
+    id: Option<u32>,
+    ip: Option<IpAddr>,
+    detail: Map<String, Value>,
+}
+
+impl EventVisitor {
+    fn into_action(self) -> Option<Action> {
+        let target = EventTarget((self.ip?, self.id?));
+        let kind = EventKind(self.kind?);
+
+        if self.detail.is_empty() {
+            Some(Action::new(kind, &target))
+        } else {
+            Some(Action::with_detail(kind, Value::Object(self.detail), &target))
+        }
+    }
+}
+
+impl Visit for EventVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            KIND_FIELD => self.kind = Some(value.to_string()),
+            IP_FIELD => self.ip = value.parse().ok(),
+            name => {
+                self.detail.insert(name.to_string(), value.into());
+            }
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            ID_FIELD => self.id = u32::try_from(value).ok(),
+            name => {
+                self.detail.insert(name.to_string(), value.into());
+            }
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        match field.name() {
+            ID_FIELD => self.id = u32::try_from(value).ok(),
+            name => {
+                self.detail.insert(name.to_string(), value.into());
+            }
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.detail.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.detail.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+struct EventKind(String);
+
+impl Kind for EventKind {
+    fn key(&self) -> &str {
+        &self.0
+    }
+}
+
+struct EventTarget(HarpId);
+
+impl Loggable for EventTarget {
+    fn identifier(&self) -> HarpId {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::{
+        connection::{ConnectionStatus, Status},
+        sampling::Sampler,
+    };
+
+    #[test]
+    fn events_become_actions() {
+        let (tx, rx) = flume::unbounded();
+        let layer = HarpLayer::new(Sender::new(
+            tx.clone(),
+            tx,
+            None,
+            Arc::new(Sampler::default()),
+            Arc::default(),
+            ConnectionStatus::fixed(Status::Connected),
+        ));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let ip = IpAddr::from([127, 0, 0, 1]);
+            tracing::info!(harp.kind = "player_join", harp.id = 7, harp.ip = %ip, "Player joined");
+            tracing::info!(harp.kind = "player_join", "Missing an identifier");
+        });
+
+        let action = rx.try_recv().unwrap();
+        assert_eq!(action.kind, "player_join");
+        assert_eq!(action.id, 7);
+        assert_eq!(action.addr, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(action.detail, Some(serde_json::json!({ "message": "Player joined" })));
+
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -2,38 +2,92 @@
 #![forbid(unsafe_code)]
 
 pub mod action;
+pub mod blocking;
+pub mod builder;
+pub mod collector;
+pub mod connection;
+pub mod context;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+mod expiry;
+pub mod interceptor;
+pub mod kinds;
+pub mod layer;
+#[cfg(feature = "tower")]
+pub mod middleware;
+mod ordinals;
+mod pending;
+#[cfg(all(windows, feature = "named-pipe"))]
+mod pipe;
+#[cfg(feature = "bevy")]
+pub mod plugin;
+pub mod protocol;
+#[cfg(feature = "proxy")]
+pub mod proxy;
+pub mod reserve;
+mod sampling;
 pub mod sender;
+#[cfg(feature = "server")]
+pub mod server;
+mod sessions;
+pub mod subscriber;
+#[cfg(test)]
+mod test_fixtures;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "trace-context")]
+mod trace_context;
+mod transport;
 
 use std::{
     net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
-use action::Action;
+use action::{Action, Priority};
 use bufferfish::Bufferfish;
+use builder::{Auth, Batching, FrameKey, HarpBuilder, NackCallback, ReserveRetry};
+use connection::{ConnectionState, ConnectionStatus};
+pub use context::{log, log_with};
 use futures_util::{SinkExt, StreamExt};
-use sender::Sender;
-use stubborn_io::{tokio::StubbornIo, ReconnectOptions, StubbornTcpStream};
-use tokio::{
-    net::TcpStream,
-    time::{interval, MissedTickBehavior},
+use interceptor::Interceptors;
+use ordinals::Ordinals;
+use pending::Pending;
+use protocol::{
+    append_checksum, append_signature, FrameCodec, Handshake, LengthField, ProtocolError, Response,
+    CHECKSUM_LEN, SIGNATURE_LEN,
+};
+use reserve::{ReserveMetrics, ReserveQueue, DEFAULT_RESERVE_CAPACITY};
+use sampling::Sampler;
+use sender::{FlushRequest, Sender};
+use stubborn_io::ReconnectOptions;
+use tokio::time::{interval_at, sleep_until, Instant};
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::Framed,
 };
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use transport::Transport;
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 pub type HarpId = (IpAddr, u32);
 
-/// The maximum amount of times this service will attempt to reconnect to the
-/// Harp server.
+/// The default maximum amount of times this service will attempt to connect or
+/// reconnect to the Harp server. Services which retry forever stop increasing
+/// their delay once they reach this many attempts.
 const RETRY_CONNECT_LIMIT: u32 = 15;
 /// The amount of time in seconds, multiplied by the retry count, to wait before
 /// attempting to reconnect to the Harp server.
 const RETRY_CONNECT_INTERVAL_SECS: u32 = 3;
-/// The amount of time in seconds to wait before attempting to resend actions in
-/// the reserve queue.
-const RETRY_RESERVE_INTERVAL_SECS: u64 = 3;
-/// The maximum amount of actions to send from the reserve queue each tick.
-const RETRY_RESERVE_BATCH_SIZE: usize = 10;
+/// The most actions taken from a channel in one go when several are waiting.
+/// Their frames are fed into the send buffer together and written with a
+/// single flush, rather than a flush each.
+const MAX_BURST: usize = 64;
 
 /// Structs which implement the `Loggable` trait are able to be identified by a
 /// pair of IP and ID - generally a specific player / account or an unidentified
@@ -63,13 +117,141 @@ pub trait Loggable {
 pub struct HarpError {}
 
 pub struct Harp {
-    stream: Framed<StubbornIo<TcpStream, SocketAddr>, LengthDelimitedCodec>,
+    stream: Framed<Transport, FrameCodec>,
     rx: flume::Receiver<Action>,
     tx: flume::Sender<Action>,
-    reserve_queue: Vec<Bufferfish>,
+    /// A separate lane for high priority actions, which is always drained
+    /// before `rx`.
+    priority_rx: flume::Receiver<Action>,
+    priority_tx: flume::Sender<Action>,
+    /// Flush requests from `Sender::flush`, each answered once the actions
+    /// waiting when it arrived have been written to the socket.
+    flush_rx: flume::Receiver<FlushRequest>,
+    flush_tx: flume::Sender<FlushRequest>,
+    /// Frames from `Sender::send_raw`, which were encoded by the caller.
+    raw_rx: flume::Receiver<Bytes>,
+    reserve_queue: ReserveQueue,
+    /// Frames fed into the send buffer which haven't been flushed yet.
+    pending: Pending,
+    service_name: Option<String>,
+    auth: Option<Auth>,
+    batching: Option<Batching>,
+    retry: ReserveRetry,
+    idempotency_keys: bool,
+    /// Whether a checksum is appended to every frame after the handshake.
+    checksums: bool,
+    /// The key every frame after the handshake is signed with, if any.
+    frame_key: Option<FrameKey>,
+    /// The number of frames signed since the last handshake, which is the
+    /// index the next one is signed with.
+    signed_frames: u64,
+    /// The framing announced in the handshake, used for every later frame.
+    length_field: LengthField,
+    max_frame_size: usize,
+    /// The largest frame allowed on the current connection, which starts at
+    /// `max_frame_size` on every handshake and may be lowered by the server.
+    frame_limit: usize,
+    /// The largest frame `Sender::send_raw` accepts, kept in step with
+    /// `frame_limit` less any checksum or signature.
+    max_raw_frame_size: Arc<AtomicUsize>,
+    /// The most frames read from the server in a row before the other
+    /// branches of the run loop get a turn.
+    max_consecutive_reads: usize,
+    on_nack: Option<NackCallback>,
+    interceptors: Interceptors,
+    /// The sequence number stamped on the next action sent.
+    next_sequence: u64,
+    /// Stamps actions which reached the channels without going through a
+    /// `Sender`, if ordered identifiers are enabled.
+    ordinals: Option<Arc<Ordinals>>,
+    /// Whether the underlying stream is connected, updated by its reconnect
+    /// callbacks.
+    connection: Arc<ConnectionState>,
+}
+
+/// The parts of a service which exist before it connects, so that a `Sender`
+/// can be handed out while the connection is still being established.
+pub(crate) struct Channels {
+    rx: flume::Receiver<Action>,
+    tx: flume::Sender<Action>,
+    priority_rx: flume::Receiver<Action>,
+    priority_tx: flume::Sender<Action>,
+    flush_rx: flume::Receiver<FlushRequest>,
+    flush_tx: flume::Sender<FlushRequest>,
+    raw_rx: flume::Receiver<Bytes>,
+    raw_tx: flume::Sender<Bytes>,
+    /// The largest raw frame which fits in a frame once any checksum or
+    /// signature is added, lowered by the run loop if the server asks for a
+    /// smaller frame limit.
+    max_raw_frame_size: Arc<AtomicUsize>,
+    reserve_queue: ReserveQueue,
+    ordinals: Option<Arc<Ordinals>>,
+    connection: Arc<ConnectionState>,
+}
+
+impl Channels {
+    /// Creates the channels for a service, taking its expiry settings from
+    /// the builder.
+    pub(crate) fn new(builder: &mut HarpBuilder) -> Self {
+        let (tx, rx) = flume::unbounded::<Action>();
+        let (priority_tx, priority_rx) = flume::unbounded::<Action>();
+        let (flush_tx, flush_rx) = flume::unbounded::<FlushRequest>();
+        let (raw_tx, raw_rx) = flume::unbounded::<Bytes>();
+
+        let overhead = if builder.checksums { CHECKSUM_LEN } else { 0 }
+            + if builder.frame_key.is_some() { SIGNATURE_LEN } else { 0 };
+
+        let mut reserve_queue = ReserveQueue::new(
+            builder.reserve_capacity.unwrap_or(DEFAULT_RESERVE_CAPACITY),
+            std::mem::take(&mut builder.expiry),
+        );
+        if let Some(max_bytes) = builder.reserve_max_bytes {
+            reserve_queue = reserve_queue.with_max_bytes(max_bytes);
+        }
+        if let Some(file) = builder.reserve_file.clone() {
+            reserve_queue = reserve_queue.with_file(file);
+        }
+
+        Self {
+            rx,
+            tx,
+            priority_rx,
+            priority_tx,
+            flush_rx,
+            flush_tx,
+            raw_rx,
+            raw_tx,
+            max_raw_frame_size: Arc::new(AtomicUsize::new(
+                builder.get_max_frame_size().saturating_sub(overhead),
+            )),
+            reserve_queue,
+            ordinals: builder.ordered_identifiers.then(Arc::default),
+            connection: Arc::new(ConnectionState::new()),
+        }
+    }
+
+    /// Returns a `Sender` for the service these channels belong to.
+    pub(crate) fn sender(&self, sampler: Arc<Sampler>) -> Sender {
+        Sender::new(
+            self.tx.clone(),
+            self.priority_tx.clone(),
+            Some(self.flush_tx.clone()),
+            sampler,
+            self.reserve_queue.metrics(),
+            self.connection.subscribe(),
+        )
+        .with_ordinals(self.ordinals.clone())
+        .with_raw_frames(self.raw_tx.clone(), Arc::clone(&self.max_raw_frame_size))
+    }
 }
 
 impl Harp {
+    /// Returns a builder for configuring a connection to a Harp server, such
+    /// as the hostname, port, and the name this service identifies itself by.
+    pub fn builder() -> HarpBuilder {
+        HarpBuilder::default()
+    }
+
     /// This is a helper function to simplify the initial setup of a Harp
     /// service. It will attempt to connect to the Harp server and, if
     /// successful, will spawn a new task via Tokio to run the service.
@@ -123,14 +305,7 @@ impl Harp {
     /// custom hostname and port.
     #[inline(always)]
     pub async fn create_service() -> Result<Sender> {
-        let mut harp = Harp::connect().await?;
-        let tx = harp.get_sender();
-
-        tokio::spawn(async move {
-            let _ = harp.run().await;
-        });
-
-        Ok(Sender(tx))
+        Harp::builder().create_service().await
     }
 
     /// This is a helper function to simplify the initial setup of a Harp
@@ -158,14 +333,32 @@ impl Harp {
     /// # }
     #[inline(always)]
     pub async fn create_service_with_options(hostname: &str, port: u16) -> Result<Sender> {
-        let mut harp = Harp::connect_with_options(hostname, port).await?;
-        let tx = harp.get_sender();
+        Harp::builder().hostname(hostname).port(port).create_service().await
+    }
 
-        tokio::spawn(async move {
-            let _ = harp.run().await;
-        });
+    /// Returns a `Sender` straight away, without waiting for the Harp server,
+    /// and connects to the default Harp server in the background. Game servers
+    /// can start up whether or not `harpd` is available.
+    ///
+    /// Until the first connection is made, up to 10,000 actions are held and
+    /// any more are dropped; see `HarpBuilder::startup_buffer`. Once
+    /// connected, the held actions are sent in order. The connection is
+    /// retried with the usual backoff, and `Sender::status` reports its
+    /// progress.
+    ///
+    /// This must be called from within a Tokio runtime.
+    pub fn create_service_lazy() -> Sender {
+        Harp::builder().create_service_lazy()
+    }
 
-        Ok(Sender(tx))
+    /// Returns a `Sender` which is not connected to any Harp server, and which
+    /// silently drops every action sent to it. This lets the same code run in
+    /// unit tests and development builds as in production.
+    ///
+    /// See `HarpBuilder::create_collector_service` to keep the actions for
+    /// inspection instead.
+    pub fn create_null_service() -> Sender {
+        Harp::builder().create_null_service()
     }
 
     /// Attempts to connect to the default Harp server. If the connection fails,
@@ -177,8 +370,7 @@ impl Harp {
     /// Prefer to use `create_service` or `create_service_with_options` instead,
     /// which handles all of this for you.
     pub async fn connect() -> Result<Self> {
-        let addr = Harp::create_addr(None, None);
-        Self::raw_connect(addr).await
+        Harp::builder().connect().await
     }
 
     /// Attempts to connect to the designated Harp server. If the connection
@@ -190,41 +382,191 @@ impl Harp {
     /// Prefer to use `create_service` or `create_service_with_options` instead,
     /// which handles all of this for you.
     pub async fn connect_with_options(hostname: &str, port: u16) -> Result<Self> {
-        let addr = Harp::create_addr(Some(hostname), Some(port));
-        Self::raw_connect(addr).await
+        Harp::builder().hostname(hostname).port(port).connect().await
     }
 
-    async fn raw_connect(addr: SocketAddr) -> Result<Self> {
-        let mut interval = interval(Duration::from_millis(1000));
-        // TODO: This could result in massive bursts of actions if the server is
-        // disconnected for a long time. This should be configurable, but also
-        // probably have a different default.
-        interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    pub(crate) async fn raw_connect(addr: SocketAddr, mut builder: HarpBuilder) -> Result<Self> {
+        let channels = Channels::new(&mut builder);
+        Self::connect_channels(addr, builder, channels).await
+    }
 
+    /// Connects to the Harp server and runs the service on channels which may
+    /// already have senders, such as when the service connects in the
+    /// background. Failed attempts, including the first, are retried with
+    /// backoff unless the builder disables it.
+    pub(crate) async fn connect_channels(
+        addr: SocketAddr,
+        builder: HarpBuilder,
+        channels: Channels,
+    ) -> Result<Self> {
         // TODO: Should accept custom backoff generators.
-        let options = ReconnectOptions::new().with_retries_generator(backoff_generator);
+        let connection = channels.connection;
+        let on_connect = Arc::clone(&connection);
+        let on_disconnect = Arc::clone(&connection);
+        let on_connect_fail = Arc::clone(&connection);
+        let max_attempts = builder.reconnect.max_attempts;
+        let options = ReconnectOptions::new()
+            .with_retries_generator(move || backoff_generator(max_attempts))
+            .with_exit_if_first_connect_fails(!builder.reconnect.retry_initial)
+            .with_on_connect_callback(move || on_connect.on_connect())
+            .with_on_disconnect_callback(move || on_disconnect.on_disconnect())
+            .with_on_connect_fail_callback(move || on_connect_fail.on_connect_fail());
+
+        let endpoint = builder.endpoint(addr)?;
+        let peer = endpoint.to_string();
+
+        let stream = match Transport::connect(endpoint, options).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                // The first attempt may fail without any retries, so make sure
+                // the status reflects it either way.
+                connection.on_connect_fail();
+                return Err(e.into());
+            }
+        };
+        connection.on_connect();
+
+        let stream = Framed::new(stream, FrameCodec::new());
+
+        let mut harp = Self {
+            stream,
+            rx: channels.rx,
+            tx: channels.tx,
+            priority_rx: channels.priority_rx,
+            priority_tx: channels.priority_tx,
+            flush_rx: channels.flush_rx,
+            flush_tx: channels.flush_tx,
+            raw_rx: channels.raw_rx,
+            reserve_queue: channels.reserve_queue,
+            pending: Pending::default(),
+            service_name: builder.service_name,
+            auth: builder.auth,
+            batching: builder.batching,
+            retry: builder.retry,
+            idempotency_keys: builder.idempotency_keys,
+            checksums: builder.checksums,
+            frame_key: builder.frame_key,
+            signed_frames: 0,
+            length_field: builder.length_field,
+            max_frame_size: builder.get_max_frame_size(),
+            frame_limit: builder.get_max_frame_size(),
+            max_raw_frame_size: channels.max_raw_frame_size,
+            max_consecutive_reads: builder.get_max_consecutive_reads(),
+            on_nack: builder.on_nack,
+            interceptors: builder.interceptors,
+            next_sequence: 1,
+            ordinals: channels.ordinals,
+            connection,
+        };
+        harp.send_handshake().await?;
+
+        tracing::info!("Service connected to Harp on {peer}");
+
+        Ok(harp)
+    }
 
-        // TODO: Expand retries to include fresh connections. Currently, if a
-        //service fails to connect to the server (received a ConnectionRefused
-        // error), it just closes out. Ideally, we attempt to reconnect to the
-        // server.
-        let stream = StubbornTcpStream::connect_with_options(addr, options).await?;
-        stream.set_nodelay(true)?;
+    /// Announces this service to the Harp server. This must be the first frame
+    /// sent on every connection.
+    async fn send_handshake(&mut self) -> Result<()> {
+        // Clear the flag before sending so that a reconnect which happens
+        // during the send is not missed.
+        self.connection.clear_reconnected();
+
+        let credentials =
+            self.auth.as_ref().map(|auth| auth.credentials(self.service_name.as_deref()));
+        let handshake = Handshake::new(self.service_name.clone())
+            .with_checksums(self.checksums)
+            .with_signed_frames(self.frame_key.is_some())
+            .with_responses(true)
+            .with_framing(self.length_field, self.max_frame_size)
+            .with_credentials(credentials);
+
+        // A reconnected stream starts over with handshake framing, and signs
+        // frames from index 0 again.
+        self.signed_frames = 0;
+        self.stream.codec_mut().reset();
+        self.stream.send(Bufferfish::try_from(handshake)?.into()).await?;
+        self.stream.codec_mut().negotiate(self.length_field, self.max_frame_size);
+
+        // Each server sets its own limit, so a lower one asked for by the last
+        // doesn't outlive its connection.
+        self.set_frame_limit(self.max_frame_size);
+
+        Ok(())
+    }
 
-        let stream = LengthDelimitedCodec::builder().length_field_type::<u16>().new_framed(stream);
+    /// Sets the largest frame allowed on the current connection, along with
+    /// the largest raw frame `Sender::send_raw` accepts.
+    fn set_frame_limit(&mut self, frame_limit: usize) {
+        self.frame_limit = frame_limit;
+        self.stream.codec_mut().set_max_frame_size(frame_limit);
 
-        let (tx, rx) = flume::unbounded::<Action>();
+        let overhead = self.sent_length(&[]);
+        self.max_raw_frame_size.store(frame_limit.saturating_sub(overhead), Ordering::Relaxed);
+    }
 
-        tracing::info!("Service connected to Harp on {addr}");
+    /// Writes a single frame into the send buffer without flushing the socket,
+    /// re-sending the handshake first if the stream has reconnected since the
+    /// last frame. Frames are kept without their checksum, which is added
+    /// here, so that those returned by the server can be resent as-is.
+    ///
+    /// Fed frames are gathered in the stream's write buffer, so that a burst
+    /// of them goes out in as few writes as possible once flushed.
+    async fn feed_frame(&mut self, frame: Bytes) -> Result<()> {
+        if self.connection.reconnected() {
+            tracing::debug!("Reconnected to Harp; resending handshake");
+            self.resume().await?;
+        }
 
-        Ok(Self { stream, rx, tx, reserve_queue: Vec::with_capacity(10) })
+        self.write_frame(frame).await
+    }
+
+    /// Starts over on a reconnected stream. Frames fed for the old connection
+    /// are cleared from the write buffer, as the new one must see the
+    /// handshake first, and those still pending are fed again behind it with
+    /// fresh signatures.
+    async fn resume(&mut self) -> Result<()> {
+        self.stream.write_buffer_mut().clear();
+        self.send_handshake().await?;
+
+        for frame in self.pending.frames().to_vec() {
+            self.write_frame(frame).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Signs and checksums a frame, if configured, and writes it into the send
+    /// buffer.
+    async fn write_frame(&mut self, frame: Bytes) -> Result<()> {
+        let frame = match &self.frame_key {
+            Some(key) => {
+                self.signed_frames += 1;
+                append_signature(&key.0, self.signed_frames - 1, &frame)
+            }
+            None => frame,
+        };
+        let frame = if self.checksums { append_checksum(&frame) } else { frame };
+        self.stream.feed(frame).await?;
+
+        Ok(())
+    }
+
+    /// Returns the counters for this service's reserve queue, which holds
+    /// actions that failed to send or were returned by the server.
+    pub fn reserve_metrics(&self) -> Arc<ReserveMetrics> {
+        self.reserve_queue.metrics()
     }
 
     /// Convert a provided host and port into a `SocketAddr`. If no host or port
-    /// are provided, defaults to "127.0.0.1:7777".
-    fn create_addr(host: Option<&str>, port: Option<u16>) -> SocketAddr {
-        let host =
-            host.unwrap_or("127.0.0.1").parse::<IpAddr>().unwrap_or_else(|_| [127, 0, 0, 1].into());
+    /// are provided, defaults to "127.0.0.1:7777". IPv6 hosts may be written
+    /// with or without brackets, such as "::1" or "[::1]".
+    pub(crate) fn create_addr(host: Option<&str>, port: Option<u16>) -> SocketAddr {
+        let host = host
+            .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+            .unwrap_or("127.0.0.1")
+            .parse::<IpAddr>()
+            .unwrap_or_else(|_| [127, 0, 0, 1].into());
         let port = port.unwrap_or(7777);
 
         SocketAddr::new(host, port)
@@ -236,56 +578,341 @@ impl Harp {
         self.tx.clone()
     }
 
+    /// Returns a reference to the write half of the high priority channel.
+    /// Actions sent here are always sent before those waiting in the normal
+    /// channel, and are never held back for batching.
+    pub fn get_priority_sender(&self) -> flume::Sender<Action> {
+        self.priority_tx.clone()
+    }
+
+    /// Returns a handle for checking whether this service is connected to the
+    /// Harp server, and since when.
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.connection.subscribe()
+    }
+
+    /// Returns the channel `Sender::flush` uses to ask the run loop to flush.
+    pub(crate) fn get_flush_sender(&self) -> flume::Sender<FlushRequest> {
+        self.flush_tx.clone()
+    }
+
     /// Starts a new Harp service. This will listen for incoming `Action`s on
     /// the channel, convert them into `Bufferfish` packets, and send them to
     /// the Harp server.
     pub async fn run(&mut self) -> Result<()> {
-        let mut interval = interval(Duration::from_secs(RETRY_RESERVE_INTERVAL_SECS));
+        // Start the retry timer at a random point in its first period, so that
+        // services which started together don't all resend at once.
+        let offset = self.retry.interval.mul_f64(fastrand::f64());
+        let mut interval = interval_at(Instant::now() + offset, self.retry.interval);
+        interval.set_missed_tick_behavior(self.retry.missed_tick_behavior);
+
+        // Frames from the server are read before anything else is done, so a
+        // steady stream of them is cut off after a while to let sends through.
+        let mut reads = 0;
 
         loop {
+            // While the stream is reconnecting, actions are left in the channel
+            // rather than sent into a dead socket, and picked up again once the
+            // connection is back.
+            let connected = self.connection.is_connected();
+
             tokio::select! {
-                Some(Ok(bytes)) = self.stream.next() => {
-                    // If we ever receive a message from the Harp server, it is
-                    // because an action was not able to be processed and has
-                    // been returned. The Bufferfish will be stored in the
-                    // reserve queue and retried later.
-                    let bf = Bufferfish::from(bytes);
-                    self.reserve_queue.push(bf);
-                },
-                Ok(action) = self.rx.recv_async() => {
-                    let bf: Bufferfish = action.try_into()?;
-                    if let Err(e) = self.stream.send(bf.into()).await {
-                        tracing::error!("Failed to send action: {e}");
+                biased;
+
+                _ = self.connection.changed() => {
+                    match (connected, self.connection.is_connected()) {
+                        (false, true) => tracing::info!("Reconnected to Harp; resuming sends"),
+                        (true, false) => tracing::warn!("Disconnected from Harp; pausing sends"),
+                        _ => {}
                     }
                 }
+                // When batching, actions are fed into the send buffer and
+                // flushed together once the batch is full or its deadline
+                // passes.
+                _ = sleep_until(self.pending.deadline()), if self.pending.batched() > 0 => {
+                    self.flush_frames().await;
+                }
+                Some(Ok(bytes)) = self.stream.next(), if reads < self.max_consecutive_reads => {
+                    reads += 1;
+                    self.handle_response(bytes);
+                    continue;
+                }
                 _ = interval.tick() => {
+                    interval.reset_after(jitter(self.retry.interval));
+
                     // If we have any actions in the reserve queue, we should
                     // attempt to send them again.
-                    if !self.reserve_queue.is_empty() {
-                        tracing::debug!("Attempting to resend {} actions", self.reserve_queue.len());
+                    if connected && !self.reserve_queue.is_empty() {
+                        let waiting = self.reserve_queue.len();
+                        tracing::debug!("Attempting to resend {waiting} actions");
 
                         // As the reserve queue is only used due to a serious
                         // server error, we will drip feed the actions back in
                         // case the server is still suffering from backpressure.
-                        for bf in self.reserve_queue.drain(..RETRY_RESERVE_BATCH_SIZE) {
-                            if let Err(e) = self.stream.send(bf.into()).await {
-                                tracing::error!("Failed to send action: {e}");
+                        for frame in self.reserve_queue.take(self.retry.batch_size) {
+                            match self.feed_frame(frame.clone()).await {
+                                Ok(()) => self.pending.push(frame),
+                                Err(e) => {
+                                    tracing::error!("Failed to resend action: {e}");
+                                    self.reserve_queue.push(frame);
+                                }
                             }
                         }
+                        self.flush_frames().await;
                     }
                 }
+                Ok(request) = self.flush_rx.recv_async(), if connected => {
+                    let flushed = self.flush_waiting().await;
+                    let _ = request.send(flushed);
+                }
+                // High priority actions are always taken first.
+                Ok(action) = self.priority_rx.recv_async(), if connected => {
+                    let rx = self.priority_rx.clone();
+                    self.send_burst(action, &rx).await;
+                }
+                Ok(action) = self.rx.recv_async(), if connected => {
+                    let rx = self.rx.clone();
+                    self.send_burst(action, &rx).await;
+                }
+                Ok(frame) = self.raw_rx.recv_async(), if connected => {
+                    self.send_raw_burst(frame).await;
+                }
+                // Every other branch had its turn and nothing was waiting, so
+                // go back to reading.
+                _ = std::future::ready(()), if reads >= self.max_consecutive_reads => {}
             }
+
+            reads = 0;
         }
     }
-}
 
-fn backoff_generator() -> impl Iterator<Item = std::time::Duration> {
-    let mut v = Vec::with_capacity(15);
-    for i in 0..RETRY_CONNECT_LIMIT {
-        v.push(std::time::Duration::from_secs(u64::from(RETRY_CONNECT_INTERVAL_SECS * i)));
+    /// Handles a frame from the Harp server. Actions which the server couldn't
+    /// process yet are returned, and kept in the reserve queue to be retried
+    /// later. Rejected actions are reported with a NACK instead, and dropped.
+    /// Control frames never enter the reserve queue.
+    fn handle_response(&mut self, bytes: BytesMut) {
+        match Response::decode(bytes) {
+            Ok(Response::Requeue(frame)) => self.reserve_queue.push(frame),
+            Ok(Response::Ping) => tracing::trace!("Received ping from Harp"),
+            Ok(Response::Config(settings)) => {
+                for (key, value) in settings {
+                    match (key.as_str(), value.parse::<usize>()) {
+                        ("max_frame_size", Ok(max_frame_size)) => {
+                            tracing::debug!("Harp limits frames to {max_frame_size} bytes");
+                            self.set_frame_limit(self.max_frame_size.min(max_frame_size));
+                        }
+                        _ => tracing::debug!(
+                            "Ignoring unsupported setting from Harp: {key} = {value}"
+                        ),
+                    }
+                }
+            }
+            Ok(Response::Nack(nack)) => {
+                tracing::warn!(
+                    code = %nack.code,
+                    sequence = ?nack.sequence,
+                    "Harp rejected an action: {}",
+                    nack.reason
+                );
+
+                if let Some(on_nack) = &self.on_nack {
+                    on_nack.call(&nack);
+                }
+            }
+            // Newer servers may send control frames this build doesn't know.
+            Err(ProtocolError::UnknownResponse(kind)) => {
+                tracing::debug!("Ignoring unknown frame type {kind} from Harp");
+            }
+            Err(e) => tracing::error!("Received an invalid frame from Harp: {e}"),
+        }
+    }
+
+    /// Sends every action waiting in either channel, then flushes the socket,
+    /// returning whether the flush succeeded. Only the actions queued when the
+    /// flush began are sent, so a busy service can't hold a flush open
+    /// forever.
+    async fn flush_waiting(&mut self) -> bool {
+        for rx in [self.priority_rx.clone(), self.rx.clone()] {
+            for action in rx.drain() {
+                self.send_action(action).await;
+            }
+        }
+        for frame in self.raw_rx.clone().drain() {
+            self.send_raw_frame(frame).await;
+        }
+
+        self.flush_frames().await
+    }
+
+    /// Sends `first` along with any actions already waiting behind it in
+    /// `rx`, up to `MAX_BURST`, then flushes the socket once for all of them.
+    async fn send_burst(&mut self, first: Action, rx: &flume::Receiver<Action>) {
+        for action in std::iter::once(first).chain(rx.try_iter().take(MAX_BURST - 1)) {
+            self.send_action(action).await;
+        }
+
+        if self.pending.has_unbatched() {
+            self.flush_frames().await;
+        }
+    }
+
+    /// Like `send_burst`, for frames from `Sender::send_raw`.
+    async fn send_raw_burst(&mut self, first: Bytes) {
+        let rx = self.raw_rx.clone();
+        for frame in std::iter::once(first).chain(rx.try_iter().take(MAX_BURST - 1)) {
+            self.send_raw_frame(frame).await;
+        }
+
+        if self.pending.has_unbatched() {
+            self.flush_frames().await;
+        }
+    }
+
+    /// Flushes the socket, returning whether it succeeded. Every frame fed
+    /// since the last flush, batched or not, is kept in `pending` until then;
+    /// if the flush fails they are all moved to the reserve queue to be
+    /// resent, and cleared from the write buffer so that the next flush
+    /// doesn't send them as well.
+    async fn flush_frames(&mut self) -> bool {
+        match self.stream.flush().await {
+            Ok(()) => {
+                self.pending.flushed();
+                true
+            }
+            Err(e) => {
+                tracing::error!("Failed to flush actions; keeping them in reserve: {e}");
+                self.pending.failed(&mut self.reserve_queue);
+                self.stream.write_buffer_mut().clear();
+                false
+            }
+        }
+    }
+
+    /// Encodes and feeds a single action into the send buffer, keeping it in
+    /// the reserve queue if it fails to send. When batching, normal priority
+    /// actions are flushed once the batch is full or its deadline passes;
+    /// every other frame is left for the caller to flush. Either way, the
+    /// frame is held in `pending` until it has been flushed.
+    async fn send_action(&mut self, action: Action) {
+        let Some(action) = self.interceptors.apply(action) else {
+            return;
+        };
+
+        let Some(mut action) = self.reserve_queue.unexpired(action) else {
+            return;
+        };
+
+        if self.idempotency_keys && action.idempotency_key.is_none() {
+            action.idempotency_key = Some(fastrand::u64(..));
+        }
+
+        // The sequence is part of the frame, so it is stamped before encoding
+        // and handed back if the action is dropped below, so that harpd
+        // doesn't report a deliberate drop as a lost frame.
+        action.sequence = Some(self.next_sequence);
+        self.next_sequence += 1;
+
+        if let Some(ordinals) = &self.ordinals {
+            ordinals.stamp(&mut action);
+        }
+
+        // Encode before sending, so that an action which fails to send can be
+        // kept in the reserve queue as-is.
+        let frame: Bytes = match Bufferfish::try_from(&action) {
+            Ok(bf) => bf.into(),
+            Err(e) => {
+                tracing::error!("Failed to encode action: {e}");
+                self.next_sequence -= 1;
+                return;
+            }
+        };
+
+        // Frames over the limit would never send, so they would sit in the
+        // reserve queue forever.
+        let length = self.sent_length(&frame);
+        if length > self.frame_limit {
+            tracing::error!(
+                "Dropped {} action: {length} bytes exceeds the {} byte frame limit",
+                action.kind,
+                self.frame_limit
+            );
+            self.next_sequence -= 1;
+            return;
+        }
+
+        self.feed_action_frame(frame, action.priority).await;
+    }
+
+    /// Feeds a frame from `Sender::send_raw` into the send buffer as it is,
+    /// batched like a normal priority action. The frame was checked against
+    /// the frame limit when it was sent, but the server may have lowered it
+    /// since.
+    async fn send_raw_frame(&mut self, frame: Bytes) {
+        let length = self.sent_length(&frame);
+        if length > self.frame_limit {
+            tracing::error!(
+                "Dropped raw frame: {length} bytes exceeds the {} byte frame limit",
+                self.frame_limit
+            );
+            return;
+        }
+
+        if !self.reserve_queue.is_frame_unexpired(&frame) {
+            return;
+        }
+
+        self.feed_action_frame(frame, Priority::Normal).await;
+    }
+
+    /// Returns the length of `frame` once any checksum or signature is added.
+    fn sent_length(&self, frame: &[u8]) -> usize {
+        frame.len()
+            + if self.checksums { CHECKSUM_LEN } else { 0 }
+            + if self.frame_key.is_some() { SIGNATURE_LEN } else { 0 }
+    }
+
+    /// Feeds an encoded action into the send buffer, keeping it in the
+    /// reserve queue if it fails to send. Every frame fed, batched or not, is
+    /// added to `pending`, and moved to the reserve queue if the flush which
+    /// should have sent it fails. See `send_action` for how frames are
+    /// batched.
+    async fn feed_action_frame(&mut self, frame: Bytes, priority: Priority) {
+        let batching = self.batching.filter(|_| priority == Priority::Normal);
+
+        if let Err(e) = self.feed_frame(frame.clone()).await {
+            tracing::error!("Failed to send action; keeping it in reserve: {e}");
+            self.reserve_queue.push(frame);
+            return;
+        }
+
+        let Some(batching) = batching else {
+            self.pending.push(frame);
+            return;
+        };
+
+        self.pending.push_batched(frame, batching.delay);
+        if self.pending.batched() >= batching.max_pending {
+            self.flush_frames().await;
+        }
     }
+}
+
+/// Returns the delays between connection attempts, retrying forever if
+/// `max_attempts` is `None`.
+fn backoff_generator(max_attempts: Option<u32>) -> impl Iterator<Item = Duration> {
+    let limit = max_attempts.map_or(usize::MAX, |attempts| attempts as usize);
 
-    v.into_iter()
+    (0..RETRY_CONNECT_LIMIT)
+        .chain(std::iter::repeat(RETRY_CONNECT_LIMIT - 1))
+        .take(limit)
+        .map(|i| jitter(Duration::from_secs(u64::from(RETRY_CONNECT_INTERVAL_SECS * i))))
+}
+
+/// Randomly scales a delay by up to 25% either way. When many services lose
+/// the server at the same moment, this spreads their reconnects and retries
+/// out rather than having them all arrive together.
+fn jitter(duration: Duration) -> Duration {
+    duration.mul_f64(0.75 + fastrand::f64() * 0.5)
 }
 
 #[cfg(test)]
@@ -305,5 +932,30 @@ mod tests {
         // Valid, custom host and port
         let addr = super::Harp::create_addr(Some("255.255.255.255"), Some(7000));
         assert_eq!(addr, SocketAddr::new([255, 255, 255, 255].into(), 7000));
+
+        // IPv6 host, with and without brackets
+        let addr = super::Harp::create_addr(Some("::1"), None);
+        assert_eq!(addr, SocketAddr::new(std::net::Ipv6Addr::LOCALHOST.into(), 7777));
+        let addr = super::Harp::create_addr(Some("[::1]"), None);
+        assert_eq!(addr, SocketAddr::new(std::net::Ipv6Addr::LOCALHOST.into(), 7777));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(backoff_generator(Some(5)).count(), 5);
+
+        // Retrying forever keeps the last delay rather than growing it.
+        let max = Duration::from_secs(u64::from(RETRY_CONNECT_INTERVAL_SECS * RETRY_CONNECT_LIMIT));
+        assert!(backoff_generator(None).take(100).skip(50).all(|delay| delay < max));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let base = Duration::from_secs(4);
+
+        for _ in 0..1000 {
+            let delay = jitter(base);
+            assert!(delay >= Duration::from_secs(3) && delay <= Duration::from_secs(5));
+        }
     }
 }
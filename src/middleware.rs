@@ -0,0 +1,222 @@
+//! A `tower` middleware which logs HTTP requests as actions, for services such
+//! as an axum matchmaking API. Enabled with the `tower` feature.
+//!
+//! Requests are attributed to the `RequestActor` in their extensions, which is
+//! usually inserted by the service's authentication middleware once it knows
+//! who is making the request. Requests without one are not logged.
+use std::{
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use http::{Method, Request, Response, Uri};
+use serde_json::json;
+use tower::{Layer, Service};
+
+use crate::{
+    action::{Action, Kind},
+    sender::Sender,
+    HarpId, Loggable,
+};
+
+/// The kind used for requests if none is configured.
+const DEFAULT_KIND: &str = "http_request";
+
+/// Identifies who made a request. Insert it into the request's extensions to
+/// have the request logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestActor {
+    pub ip: IpAddr,
+    pub id: u32,
+}
+
+impl Loggable for RequestActor {
+    fn identifier(&self) -> HarpId {
+        (self.ip, self.id)
+    }
+}
+
+type KindFn = dyn Fn(&Method, &Uri) -> Option<String> + Send + Sync;
+
+/// Wraps a service so that every request with a `RequestActor` is logged as an
+/// action once its response is ready. The detail holds the method, path,
+/// response status, and how long the request took in milliseconds.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use harp::{middleware::ActionLayer, Harp};
+/// # use http::Method;
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let harp = Harp::create_service().await?;
+///
+/// // Log joins and leaves under their own kinds, and skip everything else.
+/// let layer = ActionLayer::new(harp).kind_fn(|method, uri| match (method, uri.path()) {
+///     (&Method::POST, "/queue/join") => Some("queue_join".into()),
+///     (&Method::POST, "/queue/leave") => Some("queue_leave".into()),
+///     _ => None,
+/// });
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ActionLayer {
+    sender: Sender,
+    kind: Arc<KindFn>,
+}
+
+impl ActionLayer {
+    /// Creates a layer which logs every request with a `RequestActor` as an
+    /// `http_request` action.
+    pub fn new(sender: Sender) -> Self {
+        Self { sender, kind: Arc::new(|_, _| Some(DEFAULT_KIND.to_string())) }
+    }
+
+    /// Logs every request with a `RequestActor` under the given kind.
+    pub fn kind(mut self, kind: impl Into<String>) -> Self {
+        let kind = kind.into();
+        self.kind = Arc::new(move |_, _| Some(kind.clone()));
+        self
+    }
+
+    /// Chooses the kind for each request from its method and URI. Requests
+    /// for which `kind` returns `None` are not logged.
+    pub fn kind_fn(
+        mut self,
+        kind: impl Fn(&Method, &Uri) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.kind = Arc::new(kind);
+        self
+    }
+}
+
+impl<S> Layer<S> for ActionLayer {
+    type Service = ActionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ActionService { inner, sender: self.sender.clone(), kind: Arc::clone(&self.kind) }
+    }
+}
+
+/// The service created by `ActionLayer`.
+#[derive(Clone)]
+pub struct ActionService<S> {
+    inner: S,
+    sender: Sender,
+    kind: Arc<KindFn>,
+}
+
+/// A request which will be logged once its response is ready.
+struct Pending {
+    actor: RequestActor,
+    kind: String,
+    method: Method,
+    path: String,
+    started: Instant,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ActionService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let pending = request.extensions().get::<RequestActor>().and_then(|actor| {
+            Some(Pending {
+                actor: *actor,
+                kind: (self.kind)(request.method(), request.uri())?,
+                method: request.method().clone(),
+                path: request.uri().path().to_string(),
+                started: Instant::now(),
+            })
+        });
+
+        let sender = self.sender.clone();
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = response.await?;
+
+            if let Some(pending) = pending {
+                let detail = json!({
+                    "method": pending.method.as_str(),
+                    "path": pending.path,
+                    "status": response.status().as_u16(),
+                    "duration_ms": pending.started.elapsed().as_secs_f64() * 1000.0,
+                });
+                let action = Action::with_detail(RequestKind(pending.kind), detail, &pending.actor);
+
+                // A stopped Harp service shouldn't fail the request.
+                let _ = sender.send(action);
+            }
+
+            Ok(response)
+        })
+    }
+}
+
+struct RequestKind(String);
+
+impl Kind for RequestKind {
+    fn key(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, future::Ready};
+
+    use super::*;
+    use crate::Harp;
+
+    /// Responds to every request with an empty 200.
+    struct Ok200;
+
+    impl Service<Request<()>> for Ok200 {
+        type Response = Response<()>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: Request<()>) -> Self::Future {
+            std::future::ready(Ok(Response::new(())))
+        }
+    }
+
+    #[tokio::test]
+    async fn log_requests_with_an_actor() {
+        let (harp, collector) = Harp::builder().create_collector_service();
+        let mut service = ActionLayer::new(harp).kind("api_request").layer(Ok200);
+
+        let mut request = Request::post("/queue/join").body(()).unwrap();
+        request.extensions_mut().insert(RequestActor { ip: IpAddr::from([127, 0, 0, 1]), id: 7 });
+        service.call(request).await.unwrap();
+
+        // Anonymous requests are not logged.
+        service.call(Request::get("/health").body(()).unwrap()).await.unwrap();
+
+        let actions = collector.actions();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].kind, "api_request");
+        assert_eq!(actions[0].id, 7);
+        assert_eq!(actions[0].detail.as_ref().unwrap()["path"], "/queue/join");
+        assert_eq!(actions[0].detail.as_ref().unwrap()["status"], 200);
+    }
+}
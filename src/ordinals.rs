@@ -0,0 +1,110 @@
+//! Numbers each identifier's actions in the order they were sent. See
+//! `HarpBuilder::ordered_identifiers`.
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{action::Action, HarpId};
+
+/// The most identifiers whose last ordinal is remembered. Past this, every
+/// one is forgotten at once, leaving only the highest behind as a floor for
+/// their next ordinals; see `Ordinals::stamp`.
+const MAX_TRACKED_IDENTIFIERS: usize = 100_000;
+
+/// Stamps actions with an ordinal which strictly increases for each (ip, id)
+/// pair, so that `harpd` can store a player's actions in the order they were
+/// sent even when retries and requeues deliver them out of it.
+///
+/// Ordinals are microseconds since the Unix epoch, bumped past the
+/// identifier's previous ordinal when two actions land in the same
+/// microsecond. That keeps them increasing across restarts of the service,
+/// and roughly comparable between services, without storing anything.
+#[derive(Debug, Default)]
+pub(crate) struct Ordinals {
+    tracked: Mutex<Tracked>,
+}
+
+#[derive(Debug, Default)]
+struct Tracked {
+    /// The last ordinal of each identifier.
+    last: HashMap<HarpId, u64>,
+    /// The highest ordinal of any forgotten identifier, which an identifier
+    /// starts from when it isn't in `last`.
+    floor: u64,
+}
+
+impl Ordinals {
+    /// Stamps `action` with the next ordinal for its identifier, unless it
+    /// already has one, such as an action being resent.
+    pub(crate) fn stamp(&self, action: &mut Action) {
+        if action.ordinal.is_some() {
+            return;
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let now = u64::try_from(now.as_micros()).unwrap_or(u64::MAX);
+
+        let mut tracked = self.tracked.lock().unwrap_or_else(|e| e.into_inner());
+        let Tracked { last, floor } = &mut *tracked;
+
+        // A forgotten identifier's next ordinal is past the floor, and so past
+        // its last one, however quickly it was sending.
+        if last.len() >= MAX_TRACKED_IDENTIFIERS {
+            *floor = last.drain().map(|(_, ordinal)| ordinal).fold(*floor, u64::max);
+        }
+
+        let ordinal = last.entry((action.addr, action.id)).or_insert(*floor);
+        *ordinal = now.max(*ordinal + 1);
+        action.ordinal = Some(*ordinal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_fixtures::Player, Loggable};
+
+    #[test]
+    fn ordinals_increase_per_identifier() {
+        let ordinals = Ordinals::default();
+        let stamp = |id| {
+            let mut action = Action::new("move", &Player(id));
+            ordinals.stamp(&mut action);
+            action.ordinal.unwrap()
+        };
+
+        // Faster than the clock ticks, so most share a microsecond.
+        let first = (0..1000).map(|_| stamp(1)).collect::<Vec<_>>();
+        assert!(first.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(stamp(2) > 0);
+
+        // Actions being resent keep their ordinal.
+        let mut resent = Action::new("move", &Player(1));
+        resent.ordinal = Some(7);
+        ordinals.stamp(&mut resent);
+        assert_eq!(resent.ordinal, Some(7));
+    }
+
+    #[test]
+    fn forgotten_identifiers_keep_increasing() {
+        let ordinals = Ordinals::default();
+        let stamp = |id| {
+            let mut action = Action::new("move", &Player(id));
+            ordinals.stamp(&mut action);
+            action.ordinal.unwrap()
+        };
+
+        // An identifier sending faster than the clock, far ahead of it.
+        let ahead = u64::MAX / 2;
+        ordinals.tracked.lock().unwrap().last.insert(Player(0).identifier(), ahead);
+
+        for id in 1..=MAX_TRACKED_IDENTIFIERS as u32 {
+            stamp(id);
+        }
+
+        assert!(ordinals.tracked.lock().unwrap().last.len() < MAX_TRACKED_IDENTIFIERS);
+        assert!(stamp(0) > ahead);
+    }
+}
@@ -0,0 +1,110 @@
+//! Frames fed into a service's send buffer which haven't been flushed yet.
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tokio_util::bytes::Bytes;
+
+use crate::reserve::ReserveQueue;
+
+/// Every frame fed into the send buffer since it was last flushed, kept until
+/// a flush succeeds so that none are lost if it fails. Batched frames are
+/// counted separately, as they wait for their batch to fill or its deadline
+/// to pass rather than being flushed straight away.
+#[derive(Debug)]
+pub(crate) struct Pending {
+    frames: Vec<Bytes>,
+    batched: usize,
+    deadline: Instant,
+}
+
+impl Default for Pending {
+    fn default() -> Self {
+        Self { frames: Vec::new(), batched: 0, deadline: Instant::now() }
+    }
+}
+
+impl Pending {
+    /// Adds a frame which the caller flushes once it has fed everything in
+    /// hand.
+    pub(crate) fn push(&mut self, frame: Bytes) {
+        self.frames.push(frame);
+    }
+
+    /// Adds a batched frame, starting a new batch which must be flushed
+    /// within `delay` if none is open.
+    pub(crate) fn push_batched(&mut self, frame: Bytes, delay: Duration) {
+        if self.batched == 0 {
+            self.deadline = Instant::now() + delay;
+        }
+
+        self.frames.push(frame);
+        self.batched += 1;
+    }
+
+    /// Returns every frame waiting to be flushed, in the order they were fed.
+    pub(crate) fn frames(&self) -> &[Bytes] {
+        &self.frames
+    }
+
+    /// Returns the number of batched frames waiting to be flushed.
+    pub(crate) fn batched(&self) -> usize {
+        self.batched
+    }
+
+    /// Returns when the open batch must be flushed by.
+    pub(crate) fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Returns true if any frame other than a batched one is waiting to be
+    /// flushed.
+    pub(crate) fn has_unbatched(&self) -> bool {
+        self.frames.len() > self.batched
+    }
+
+    /// Forgets every frame after a successful flush.
+    pub(crate) fn flushed(&mut self) {
+        self.frames.clear();
+        self.batched = 0;
+    }
+
+    /// Moves every frame into the reserve queue after a failed flush, to be
+    /// resent later.
+    pub(crate) fn failed(&mut self, reserve_queue: &mut ReserveQueue) {
+        for frame in self.frames.drain(..) {
+            reserve_queue.push(frame);
+        }
+        self.batched = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expiry::Expiry;
+
+    #[test]
+    fn failed_flush_reserves_batched_frames() {
+        let mut reserve_queue = ReserveQueue::new(10, Expiry::default());
+        let mut pending = Pending::default();
+
+        // A batched frame, still waiting for its batch to fill, and one which
+        // was due to be flushed with the burst it arrived in.
+        pending.push_batched(Bytes::from_static(b"batched"), Duration::from_secs(1));
+        pending.push(Bytes::from_static(b"priority"));
+        assert!(pending.has_unbatched());
+
+        pending.failed(&mut reserve_queue);
+        assert_eq!(pending.batched(), 0);
+        assert!(!pending.has_unbatched());
+
+        let reserved = reserve_queue.take(10);
+        assert_eq!(reserved, [Bytes::from_static(b"batched"), Bytes::from_static(b"priority")]);
+
+        // Once flushed, frames are forgotten rather than resent.
+        pending.push_batched(Bytes::from_static(b"sent"), Duration::from_secs(1));
+        pending.flushed();
+        pending.failed(&mut reserve_queue);
+        assert!(reserve_queue.is_empty());
+    }
+}
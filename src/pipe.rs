@@ -0,0 +1,72 @@
+//! Named pipe connections to Harp servers on Windows, enabled with the
+//! `named-pipe` feature. See `HarpBuilder::named_pipe`.
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use stubborn_io::tokio::UnderlyingIo;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::windows::named_pipe::{ClientOptions, NamedPipeClient},
+    time::sleep,
+};
+
+/// Returned when every instance of the pipe is serving another client.
+const ERROR_PIPE_BUSY: i32 = 231;
+
+/// How long to wait for a busy pipe to free up an instance before trying
+/// again.
+const BUSY_DELAY: Duration = Duration::from_millis(50);
+
+/// A connection to a Harp server over a named pipe, which `StubbornIo`
+/// reconnects in the same way as a TCP stream. Connects to the pipe's name,
+/// such as `\\.\pipe\harp`.
+pub(crate) struct PipeConnection(NamedPipeClient);
+
+impl UnderlyingIo<String> for PipeConnection {
+    fn establish(name: String) -> Pin<Box<dyn Future<Output = io::Result<Self>> + Send>> {
+        Box::pin(async move {
+            // The server creates a new instance of the pipe as each client
+            // connects, so a busy pipe only needs a moment.
+            loop {
+                match ClientOptions::new().open(&name) {
+                    Ok(client) => return Ok(Self(client)),
+                    Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => sleep(BUSY_DELAY).await,
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+    }
+}
+
+impl AsyncRead for PipeConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PipeConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,203 @@
+//! A Bevy plugin for games which log to Harp. Enabled with the `bevy` feature.
+//!
+//! The plugin runs the Harp service on its own small Tokio runtime, so games do
+//! not need one. Systems log actions by writing `ActionEvent`s, which are sent
+//! at the end of every frame, and can read `ConnectionChanged` events or the
+//! `HarpSender` resource to react to the connection dropping.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use bevy_app::prelude::*;
+//! # use bevy_ecs::prelude::*;
+//! # use harp::{action::{Action, Kind}, plugin::{ActionEvent, HarpPlugin}, Harp, HarpId, Loggable};
+//! # use std::net::IpAddr;
+//! # #[derive(Component)]
+//! # struct Player;
+//! # impl Loggable for Player {
+//! #     fn identifier(&self) -> HarpId {
+//! #         (IpAddr::from([127, 0, 0, 1]), 1)
+//! #     }
+//! # }
+//! # struct Login;
+//! # impl Kind for Login {
+//! #     fn key(&self) -> &str {
+//! #         "login"
+//! #     }
+//! # }
+//! fn log_logins(players: Query<&Player, Added<Player>>, mut actions: EventWriter<ActionEvent>) {
+//!     for player in &players {
+//!         actions.send(ActionEvent(Action::new(Login, player)));
+//!     }
+//! }
+//!
+//! App::new()
+//!     .add_plugins(HarpPlugin::new(Harp::builder().service_name("world-1")))
+//!     .add_systems(Update, log_logins)
+//!     .run();
+//! ```
+use std::{sync::Mutex, time::Duration};
+
+use bevy_app::{App, AppExit, Last, Plugin};
+use bevy_ecs::prelude::*;
+
+use crate::{action::Action, builder::HarpBuilder, connection::Status, sender::Sender};
+
+/// How long to wait for queued actions to be sent when the app exits.
+const EXIT_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Creates the Harp service when added to an app. See the module
+/// documentation for more information.
+pub struct HarpPlugin {
+    source: Mutex<Option<Source>>,
+}
+
+enum Source {
+    Builder(HarpBuilder),
+    Sender(Sender),
+}
+
+impl HarpPlugin {
+    /// Connects to Harp in the background with the given options. The app
+    /// starts immediately; actions logged before the connection is made are
+    /// held, as with `HarpBuilder::create_service_lazy`.
+    pub fn new(builder: HarpBuilder) -> Self {
+        Self { source: Mutex::new(Some(Source::Builder(builder))) }
+    }
+
+    /// Uses an existing `Sender`, such as a null or collector service in
+    /// tests.
+    pub fn with_sender(sender: Sender) -> Self {
+        Self { source: Mutex::new(Some(Source::Sender(sender))) }
+    }
+}
+
+impl Default for HarpPlugin {
+    /// Connects to Harp with the default options.
+    fn default() -> Self {
+        Self::new(HarpBuilder::default())
+    }
+}
+
+impl Plugin for HarpPlugin {
+    fn build(&self, app: &mut App) {
+        let sender = match self.source.lock().unwrap().take() {
+            Some(Source::Builder(builder)) => {
+                let runtime = tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(1)
+                    .thread_name("harp")
+                    .enable_all()
+                    .build()
+                    .expect("failed to start the Harp runtime");
+
+                let sender = {
+                    let _guard = runtime.enter();
+                    builder.create_service_lazy()
+                };
+
+                app.insert_resource(HarpRuntime(runtime));
+                sender
+            }
+            Some(Source::Sender(sender)) => sender,
+            None => panic!("HarpPlugin can only be added to one app"),
+        };
+
+        app.insert_resource(LastStatus(sender.status().status()))
+            .insert_resource(HarpSender(sender))
+            .add_event::<ActionEvent>()
+            .add_event::<ConnectionChanged>()
+            .add_systems(Last, (send_actions, watch_connection, flush_on_exit).chain());
+    }
+}
+
+/// The `Sender` for the app's Harp service, for systems which would rather
+/// send actions directly than write events.
+#[derive(Resource, Clone)]
+pub struct HarpSender(pub Sender);
+
+impl std::ops::Deref for HarpSender {
+    type Target = Sender;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// An action to send to Harp at the end of the frame.
+#[derive(Event, Debug)]
+pub struct ActionEvent(pub Action);
+
+/// Sent when the connection to Harp changes state, such as when it drops and
+/// is being retried.
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionChanged(pub Status);
+
+/// Keeps the runtime the Harp service runs on alive for as long as the app.
+#[derive(Resource)]
+struct HarpRuntime(tokio::runtime::Runtime);
+
+#[derive(Resource)]
+struct LastStatus(Status);
+
+fn send_actions(sender: Res<HarpSender>, mut events: ResMut<Events<ActionEvent>>) {
+    for ActionEvent(action) in events.drain() {
+        if sender.send(action).is_err() {
+            tracing::warn!("Dropped an action because the Harp service has stopped");
+        }
+    }
+}
+
+fn watch_connection(
+    sender: Res<HarpSender>,
+    mut last: ResMut<LastStatus>,
+    mut changed: EventWriter<ConnectionChanged>,
+) {
+    let status = sender.status().status();
+    if status != last.0 {
+        last.0 = status;
+        changed.send(ConnectionChanged(status));
+    }
+}
+
+/// Gives actions sent during the last frame a chance to reach Harp before the
+/// runtime is dropped.
+fn flush_on_exit(
+    mut exit: EventReader<AppExit>,
+    sender: Res<HarpSender>,
+    runtime: Option<Res<HarpRuntime>>,
+) {
+    if exit.read().last().is_none() {
+        return;
+    }
+
+    if let Some(runtime) = runtime {
+        if let Err(e) = runtime.0.block_on(sender.flush(EXIT_FLUSH_TIMEOUT)) {
+            tracing::warn!("Failed to flush Harp actions on exit: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_fixtures::{Target, TestKind},
+        Harp,
+    };
+
+    #[test]
+    fn send_events_each_frame() {
+        let (harp, collector) = Harp::builder().create_collector_service();
+
+        let mut app = App::new();
+        app.add_plugins(HarpPlugin::with_sender(harp));
+
+        app.world_mut().send_event(ActionEvent(Action::new(TestKind("login"), &Target)));
+        app.update();
+        assert_eq!(collector.actions().len(), 1);
+
+        // Drained, so not sent again on the next frame.
+        app.update();
+        assert!(collector.actions().is_empty());
+    }
+}
@@ -0,0 +1,1003 @@
+//! Wire-level messages exchanged between services and `harpd`, other than the
+//! actions themselves.
+use std::fmt::Display;
+
+use bufferfish::Bufferfish;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio_util::{
+    bytes::{Buf, BufMut, Bytes, BytesMut},
+    codec::{Decoder, Encoder, LengthDelimitedCodec},
+};
+
+/// The version of the wire protocol spoken by this library, bumped whenever
+/// the layout of a frame changes. `harpd` refuses connections which announce
+/// a different version with a [NackCode::UnsupportedVersion].
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// The maximum length, in bytes, of a service name.
+pub const MAX_SERVICE_NAME_LEN: usize = 255;
+
+/// The largest frame sent after the handshake, in bytes, if not configured.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// The length, in bytes, of the checksum appended to frames on connections
+/// which negotiated checksums.
+pub const CHECKSUM_LEN: usize = 4;
+
+/// The length, in bytes, of the HMAC-SHA256 appended to frames on connections
+/// which negotiated signed frames.
+pub const SIGNATURE_LEN: usize = 32;
+
+/// Handshake flag asking `harpd` to verify a checksum on every later frame.
+const FLAG_CHECKSUMS: u8 = 1;
+/// Handshake flag announcing that the service understands [Response] frames.
+const FLAG_RESPONSES: u8 = 1 << 1;
+/// Handshake flag announcing that [Credentials] follow the fixed fields.
+const FLAG_CREDENTIALS: u8 = 1 << 2;
+/// Handshake flag announcing that every later frame is signed.
+const FLAG_SIGNED: u8 = 1 << 3;
+
+/// [Credentials] type for a token.
+const CREDENTIALS_TOKEN: u8 = 0;
+/// [Credentials] type for a signature.
+const CREDENTIALS_SIGNATURE: u8 = 1;
+
+/// [Response] type for an action returned to be retried later.
+const RESPONSE_REQUEUE: u8 = 0;
+/// [Response] type for a [Nack].
+const RESPONSE_NACK: u8 = 1;
+// Type 2 is reserved. It once acknowledged actions, which `harpd` never sent,
+// as actions from one service can be stored out of order.
+/// [Response] type checking that the service is alive.
+const RESPONSE_PING: u8 = 3;
+/// [Response] type carrying settings for the service.
+const RESPONSE_CONFIG: u8 = 4;
+
+/// The first frame sent by a service on every new connection, including
+/// reconnects. `harpd` will not accept actions until it has received one.
+///
+/// # Wire Format
+///
+/// | Field          | Type     | Notes                                |
+/// |----------------|----------|--------------------------------------|
+/// | version        | `u16`    | Must equal [PROTOCOL_VERSION].       |
+/// | service        | `String` | Service name; empty if unidentified. |
+/// | flags          | `u8`     | See below.                           |
+/// | length_field   | `u8`     | Length prefix width; 2 or 4 bytes.   |
+/// | max_frame_size | `u32`    | Largest frame after the handshake.   |
+/// | credentials    | varies   | Only present if flag bit 2 is set.   |
+///
+/// Flag bit 0 announces that frames carry checksums, bit 1 that the service
+/// understands [Response] frames, bit 2 that [Credentials] follow, and bit 3
+/// that frames are signed.
+///
+/// The handshake itself is always framed with a `u16` length. Every frame
+/// after it, in both directions, uses the length field and maximum frame size
+/// it announces; see [FrameCodec].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub version: u16,
+    /// An optional name identifying the service, such as a game shard. `harpd`
+    /// attaches it to every action received on the connection.
+    pub service: Option<String>,
+    /// Whether every frame after the handshake ends with a CRC32 checksum.
+    /// See [append_checksum].
+    pub checksums: bool,
+    /// Whether every frame after the handshake is signed, along with its
+    /// index on the connection, with a key shared with `harpd`. See
+    /// [append_signature].
+    pub signed: bool,
+    /// Whether `harpd` should wrap the frames it sends in a [Response], which
+    /// lets it send a [Nack] when an action is rejected. Otherwise, returned
+    /// actions are sent bare, and rejected actions are dropped silently.
+    pub responses: bool,
+    /// The width of the length prefix on every frame after the handshake.
+    pub length_field: LengthField,
+    /// The largest frame, in bytes, either side may send after the handshake.
+    /// `harpd` may lower this, and tells services which understand responses
+    /// with a [Response::Config] frame.
+    pub max_frame_size: u32,
+    /// Proof of the service's identity, for servers which authenticate
+    /// services.
+    pub credentials: Option<Credentials>,
+}
+
+impl Handshake {
+    /// Create a handshake for the current protocol version.
+    pub fn new(service: Option<String>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            service,
+            checksums: false,
+            signed: false,
+            responses: false,
+            length_field: LengthField::U16,
+            max_frame_size: u32::from(u16::MAX),
+            credentials: None,
+        }
+    }
+
+    /// Sets whether the frames which follow the handshake carry checksums.
+    pub fn with_checksums(mut self, enabled: bool) -> Self {
+        self.checksums = enabled;
+        self
+    }
+
+    /// Sets whether the frames which follow the handshake are signed.
+    pub fn with_signed_frames(mut self, enabled: bool) -> Self {
+        self.signed = enabled;
+        self
+    }
+
+    /// Sets whether the service understands [Response] frames.
+    pub fn with_responses(mut self, enabled: bool) -> Self {
+        self.responses = enabled;
+        self
+    }
+
+    /// Sets the framing used after the handshake. The maximum frame size is
+    /// capped at the largest length the length field can hold.
+    pub fn with_framing(mut self, length_field: LengthField, max_frame_size: usize) -> Self {
+        self.length_field = length_field;
+        self.max_frame_size = u32::try_from(length_field.clamp(max_frame_size)).unwrap_or(u32::MAX);
+        self
+    }
+
+    /// Sets the credentials the service authenticates with.
+    pub fn with_credentials(mut self, credentials: Option<Credentials>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+}
+
+impl TryFrom<Bufferfish> for Handshake {
+    type Error = ProtocolError;
+
+    fn try_from(mut value: Bufferfish) -> Result<Self, Self::Error> {
+        let version = value.read_u16()?;
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+
+        let service = value.read_string()?;
+        if service.len() > MAX_SERVICE_NAME_LEN {
+            return Err(ProtocolError::InvalidHandshake(format!(
+                "service name exceeds {MAX_SERVICE_NAME_LEN} bytes"
+            )));
+        }
+        let service = if service.is_empty() { None } else { Some(service) };
+        let flags = value.read_u8()?;
+        let length_field = match value.read_u8()? {
+            2 => LengthField::U16,
+            4 => LengthField::U32,
+            width => {
+                return Err(ProtocolError::InvalidHandshake(format!(
+                    "unsupported length field of {width} bytes"
+                )));
+            }
+        };
+
+        let max_frame_size = value.read_u32()?;
+        if max_frame_size as usize > length_field.clamp(max_frame_size as usize) {
+            return Err(ProtocolError::InvalidHandshake(format!(
+                "maximum frame size of {max_frame_size} bytes exceeds the length field"
+            )));
+        }
+
+        let credentials = if flags & FLAG_CREDENTIALS != 0 {
+            Some(match value.read_u8()? {
+                CREDENTIALS_TOKEN => Credentials::Token(value.read_string()?),
+                CREDENTIALS_SIGNATURE => Credentials::Signature {
+                    timestamp: read_u64(&mut value)?,
+                    signature: value.read_string()?,
+                },
+                kind => {
+                    return Err(ProtocolError::InvalidHandshake(format!(
+                        "unknown credentials type {kind}"
+                    )));
+                }
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            version,
+            service,
+            checksums: flags & FLAG_CHECKSUMS != 0,
+            signed: flags & FLAG_SIGNED != 0,
+            responses: flags & FLAG_RESPONSES != 0,
+            length_field,
+            max_frame_size,
+            credentials,
+        })
+    }
+}
+
+impl TryFrom<Handshake> for Bufferfish {
+    type Error = ProtocolError;
+
+    fn try_from(value: Handshake) -> Result<Self, Self::Error> {
+        let mut bf = Bufferfish::new();
+        bf.write_u16(value.version)?;
+        bf.write_string(value.service.as_deref().unwrap_or(""))?;
+        let mut flags = 0;
+        if value.checksums {
+            flags |= FLAG_CHECKSUMS;
+        }
+        if value.responses {
+            flags |= FLAG_RESPONSES;
+        }
+        if value.credentials.is_some() {
+            flags |= FLAG_CREDENTIALS;
+        }
+        if value.signed {
+            flags |= FLAG_SIGNED;
+        }
+        bf.write_u8(flags)?;
+        bf.write_u8(value.length_field.len() as u8)?;
+        bf.write_u32(value.max_frame_size)?;
+
+        match &value.credentials {
+            Some(Credentials::Token(token)) => {
+                bf.write_u8(CREDENTIALS_TOKEN)?;
+                bf.write_string(token)?;
+            }
+            Some(Credentials::Signature { timestamp, signature }) => {
+                bf.write_u8(CREDENTIALS_SIGNATURE)?;
+                write_u64(&mut bf, *timestamp)?;
+                bf.write_string(signature)?;
+            }
+            None => {}
+        }
+
+        Ok(bf)
+    }
+}
+
+/// How a service proves its identity in its [Handshake]. Which kind a server
+/// expects depends on how it is configured.
+#[derive(Clone, PartialEq)]
+pub enum Credentials {
+    /// A token shared with the server.
+    Token(String),
+    /// A hex-encoded HMAC-SHA256 of the service name and `timestamp`, keyed
+    /// with a secret shared with the server. See [sign_handshake].
+    Signature { timestamp: u64, signature: String },
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Tokens are secrets, so they're kept out of logs.
+        match self {
+            Credentials::Token(_) => f.write_str("Token(..)"),
+            Credentials::Signature { timestamp, .. } => {
+                f.debug_struct("Signature").field("timestamp", timestamp).finish_non_exhaustive()
+            }
+        }
+    }
+}
+
+/// The first frame sent by a subscriber, which receives actions from `harpd`
+/// as they arrive rather than sending them. Subscribers connect to the
+/// subscription port rather than the port services use.
+///
+/// # Wire Format
+///
+/// | Field   | Type       | Notes                                        |
+/// |---------|------------|----------------------------------------------|
+/// | version | `u16`      | Must equal [PROTOCOL_VERSION].               |
+/// | count   | `u16`      | The number of kinds which follow.            |
+/// | kinds   | `String`[] | Kinds to receive; every kind if none follow. |
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscribe {
+    pub version: u16,
+    /// The kinds of action to receive. An empty list receives every kind.
+    pub kinds: Vec<String>,
+}
+
+impl Subscribe {
+    /// Create a subscription to the given kinds for the current protocol
+    /// version.
+    pub fn new(kinds: Vec<String>) -> Self {
+        Self { version: PROTOCOL_VERSION, kinds }
+    }
+}
+
+impl TryFrom<Bufferfish> for Subscribe {
+    type Error = ProtocolError;
+
+    fn try_from(mut value: Bufferfish) -> Result<Self, Self::Error> {
+        let version = value.read_u16()?;
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+
+        let count = value.read_u16()?;
+        let kinds = (0..count).map(|_| value.read_string()).collect::<Result<_, _>>()?;
+
+        Ok(Self { version, kinds })
+    }
+}
+
+impl TryFrom<Subscribe> for Bufferfish {
+    type Error = ProtocolError;
+
+    fn try_from(value: Subscribe) -> Result<Self, Self::Error> {
+        let count = u16::try_from(value.kinds.len()).map_err(|_| {
+            ProtocolError::InvalidHandshake(format!(
+                "cannot subscribe to more than {} kinds",
+                u16::MAX
+            ))
+        })?;
+
+        let mut bf = Bufferfish::new();
+        bf.write_u16(value.version)?;
+        bf.write_u16(count)?;
+        for kind in &value.kinds {
+            bf.write_string(kind)?;
+        }
+
+        Ok(bf)
+    }
+}
+
+/// The width of the length prefix on each frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LengthField {
+    /// Frames of up to 64KB.
+    #[default]
+    U16,
+    /// Frames of up to 4GB, for services which send large or batched frames.
+    U32,
+}
+
+impl LengthField {
+    /// Returns the width of the prefix in bytes.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(self) -> usize {
+        match self {
+            LengthField::U16 => 2,
+            LengthField::U32 => 4,
+        }
+    }
+
+    /// Caps a frame size at the largest length this prefix can hold.
+    pub fn clamp(self, max_frame_size: usize) -> usize {
+        match self {
+            LengthField::U16 => max_frame_size.min(usize::from(u16::MAX)),
+            LengthField::U32 => max_frame_size.min(u32::MAX as usize),
+        }
+    }
+}
+
+/// Frames messages on a service connection with a length prefix. The
+/// handshake is always framed with a `u16` length; once it has been sent or
+/// received, `negotiate` switches to the framing it announced.
+#[derive(Debug)]
+pub struct FrameCodec {
+    handshake: LengthDelimitedCodec,
+    frames: LengthDelimitedCodec,
+    negotiated: bool,
+}
+
+impl FrameCodec {
+    pub fn new() -> Self {
+        Self {
+            handshake: frame_codec(LengthField::U16, usize::from(u16::MAX)),
+            frames: frame_codec(LengthField::U16, usize::from(u16::MAX)),
+            negotiated: false,
+        }
+    }
+
+    /// Switches to the given framing for every later frame.
+    pub fn negotiate(&mut self, length_field: LengthField, max_frame_size: usize) {
+        self.frames = frame_codec(length_field, max_frame_size);
+        self.negotiated = true;
+    }
+
+    /// Lowers the largest frame accepted or sent, such as when `harpd` asks
+    /// for a smaller limit than the service announced.
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.frames.set_max_frame_length(max_frame_size);
+    }
+
+    /// Returns the largest frame accepted or sent after the handshake.
+    pub fn max_frame_size(&self) -> usize {
+        self.frames.max_frame_length()
+    }
+
+    /// Returns to handshake framing, such as before announcing the service
+    /// again after a reconnect.
+    pub fn reset(&mut self) {
+        self.negotiated = false;
+    }
+
+    fn current(&mut self) -> &mut LengthDelimitedCodec {
+        if self.negotiated {
+            &mut self.frames
+        } else {
+            &mut self.handshake
+        }
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.current().decode(src)
+    }
+}
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.current().encode(item, dst)
+    }
+}
+
+fn frame_codec(length_field: LengthField, max_frame_size: usize) -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .length_field_length(length_field.len())
+        .max_frame_length(length_field.clamp(max_frame_size))
+        .new_codec()
+}
+
+/// A frame sent by `harpd` to a service which set [Handshake::responses],
+/// prefixed by a `u8` type. Only `Requeue` frames hold actions; the others are
+/// control frames, and clients should skip types they don't recognize, which
+/// [Response::decode] reports as [ProtocolError::UnknownResponse].
+///
+/// | Type | Variant   | Body                                          |
+/// |------|-----------|-----------------------------------------------|
+/// | 0    | `Requeue` | An encoded action.                            |
+/// | 1    | `Nack`    | See [Nack].                                   |
+/// | 2    | Reserved  |                                               |
+/// | 3    | `Ping`    | Empty.                                        |
+/// | 4    | `Config`  | `u16` count, then `String` key / value pairs. |
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// An encoded action which `harpd` couldn't accept yet, such as while its
+    /// queue is full, and which should be sent again later.
+    Requeue(Bytes),
+    /// An action was rejected and should not be sent again as-is.
+    Nack(Nack),
+    /// Checks that the connection is alive. No reply is needed.
+    Ping,
+    /// Settings `harpd` asks the service to use, as key / value pairs.
+    /// Settings the service doesn't recognize are ignored.
+    Config(Vec<(String, String)>),
+}
+
+impl Response {
+    pub fn encode(self) -> Result<Bytes, ProtocolError> {
+        match self {
+            Response::Requeue(frame) => {
+                let mut buf = BytesMut::with_capacity(frame.len() + 1);
+                buf.put_u8(RESPONSE_REQUEUE);
+                buf.extend_from_slice(&frame);
+
+                Ok(buf.freeze())
+            }
+            Response::Nack(nack) => {
+                let mut bf = Bufferfish::new();
+                bf.write_u8(RESPONSE_NACK)?;
+                bf.write_u8(nack.code as u8)?;
+                write_optional_u64(&mut bf, nack.sequence)?;
+                bf.write_string(&nack.reason)?;
+
+                Ok(bf.into())
+            }
+            Response::Ping => Ok(Bytes::from_static(&[RESPONSE_PING])),
+            Response::Config(settings) => {
+                let count = u16::try_from(settings.len()).map_err(|_| {
+                    ProtocolError::InvalidResponse(format!("more than {} settings", u16::MAX))
+                })?;
+
+                let mut bf = Bufferfish::new();
+                bf.write_u8(RESPONSE_CONFIG)?;
+                bf.write_u16(count)?;
+                for (key, value) in &settings {
+                    bf.write_string(key)?;
+                    bf.write_string(value)?;
+                }
+
+                Ok(bf.into())
+            }
+        }
+    }
+
+    pub fn decode(mut frame: BytesMut) -> Result<Self, ProtocolError> {
+        if frame.is_empty() {
+            return Err(ProtocolError::InvalidResponse("empty frame".into()));
+        }
+
+        match frame.get_u8() {
+            RESPONSE_REQUEUE => Ok(Response::Requeue(frame.freeze())),
+            RESPONSE_NACK => {
+                let mut bf = Bufferfish::from(frame);
+                let code = NackCode::try_from(bf.read_u8()?)?;
+                let sequence = read_optional_u64(&mut bf)?;
+                let reason = bf.read_string()?;
+
+                Ok(Response::Nack(Nack { code, sequence, reason }))
+            }
+            RESPONSE_PING => Ok(Response::Ping),
+            RESPONSE_CONFIG => {
+                let mut bf = Bufferfish::from(frame);
+                let count = bf.read_u16()?;
+                let settings = (0..count)
+                    .map(|_| Ok((bf.read_string()?, bf.read_string()?)))
+                    .collect::<Result<_, ProtocolError>>()?;
+
+                Ok(Response::Config(settings))
+            }
+            kind => Err(ProtocolError::UnknownResponse(kind)),
+        }
+    }
+}
+
+/// Tells a service that one of its frames was rejected, and why. Rejected
+/// actions are not stored, and resending them unchanged will fail again.
+///
+/// # Wire Format
+///
+/// | Field    | Type            | Notes                                     |
+/// |----------|-----------------|-------------------------------------------|
+/// | code     | `u8`            | See [NackCode].                           |
+/// | sequence | `Option<u64>`   | The action's sequence number, if decoded. |
+/// | reason   | `String`        | A description for logs.                   |
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nack {
+    pub code: NackCode,
+    /// The sequence number of the rejected action, or `None` if the frame
+    /// couldn't be decoded far enough to read it.
+    pub sequence: Option<u64>,
+    pub reason: String,
+}
+
+/// Why `harpd` rejected a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum NackCode {
+    /// The frame could not be decoded as an action.
+    ParseError = 1,
+    /// The frame was larger than `harpd` accepts. The connection is closed
+    /// after this is sent.
+    TooLarge = 2,
+    /// The service sent more actions than it is allowed to.
+    RateLimited = 3,
+    /// The action's detail did not match the schema for its kind.
+    SchemaInvalid = 4,
+    /// The frame's checksum did not match its contents.
+    Corrupt = 5,
+    /// The handshake's credentials were missing or not accepted. The
+    /// connection is closed after this is sent.
+    Unauthorized = 6,
+    /// The frame's signature did not match its contents.
+    BadSignature = 7,
+    /// The handshake announced a protocol version `harpd` doesn't speak. The
+    /// connection is closed after this is sent.
+    UnsupportedVersion = 8,
+    /// The handshake announced signed frames, but `harpd` has no key to
+    /// verify them with. The connection is closed after this is sent.
+    SigningUnavailable = 9,
+    /// Another connection is already open for the handshake's service name,
+    /// and `harpd` refuses duplicates. The connection is closed after this is
+    /// sent.
+    DuplicateService = 10,
+    /// The action's kind is not one of the kinds `harpd` was told to accept.
+    UnknownKind = 11,
+    /// The connection would exceed `harpd`'s total or per-IP connection
+    /// limit. Sent before the handshake is read, and the connection is closed
+    /// after this is sent.
+    TooManyConnections = 12,
+}
+
+impl TryFrom<u8> for NackCode {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(NackCode::ParseError),
+            2 => Ok(NackCode::TooLarge),
+            3 => Ok(NackCode::RateLimited),
+            4 => Ok(NackCode::SchemaInvalid),
+            5 => Ok(NackCode::Corrupt),
+            6 => Ok(NackCode::Unauthorized),
+            7 => Ok(NackCode::BadSignature),
+            8 => Ok(NackCode::UnsupportedVersion),
+            9 => Ok(NackCode::SigningUnavailable),
+            10 => Ok(NackCode::DuplicateService),
+            11 => Ok(NackCode::UnknownKind),
+            12 => Ok(NackCode::TooManyConnections),
+            _ => Err(ProtocolError::InvalidResponse(format!("unknown NACK code {value}"))),
+        }
+    }
+}
+
+impl Display for NackCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NackCode::ParseError => write!(f, "parse error"),
+            NackCode::TooLarge => write!(f, "too large"),
+            NackCode::RateLimited => write!(f, "rate limited"),
+            NackCode::SchemaInvalid => write!(f, "schema invalid"),
+            NackCode::Corrupt => write!(f, "corrupt"),
+            NackCode::Unauthorized => write!(f, "unauthorized"),
+            NackCode::BadSignature => write!(f, "bad signature"),
+            NackCode::UnsupportedVersion => write!(f, "unsupported version"),
+            NackCode::SigningUnavailable => write!(f, "signing unavailable"),
+            NackCode::DuplicateService => write!(f, "duplicate service"),
+            NackCode::UnknownKind => write!(f, "unknown kind"),
+            NackCode::TooManyConnections => write!(f, "too many connections"),
+        }
+    }
+}
+
+/// Returns a copy of `frame` with a big-endian CRC32 of its contents appended,
+/// for connections which negotiated checksums in their handshake. Only frames
+/// sent by services carry checksums.
+pub fn append_checksum(frame: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(frame.len() + CHECKSUM_LEN);
+    buf.extend_from_slice(frame);
+    buf.put_u32(crc32fast::hash(frame));
+
+    buf.freeze()
+}
+
+/// Verifies and removes the checksum added by [append_checksum], returning the
+/// original frame.
+pub fn strip_checksum(mut frame: BytesMut) -> Result<BytesMut, ProtocolError> {
+    if frame.len() < CHECKSUM_LEN {
+        return Err(ProtocolError::ChecksumMismatch);
+    }
+
+    let expected = frame.split_off(frame.len() - CHECKSUM_LEN).get_u32();
+    if crc32fast::hash(&frame) != expected {
+        return Err(ProtocolError::ChecksumMismatch);
+    }
+
+    Ok(frame)
+}
+
+/// Returns a copy of `frame` with an HMAC-SHA256 of its contents, keyed with
+/// `key`, appended, for connections which negotiated signed frames. Unlike a
+/// checksum, a signature can't be recomputed by anyone without the key, so
+/// `harpd` can tell frames which were modified in transit. Signatures are
+/// added before checksums.
+///
+/// `index` is the frame's position on the connection, counting from 0 for the
+/// first frame after the handshake. It is signed along with the frame, so a
+/// frame which is replayed, reordered, or follows a dropped one fails to
+/// verify.
+pub fn append_signature(key: &[u8], index: u64, frame: &[u8]) -> Bytes {
+    let mac = frame_mac(key, index, frame);
+
+    let mut buf = BytesMut::with_capacity(frame.len() + SIGNATURE_LEN);
+    buf.extend_from_slice(frame);
+    buf.extend_from_slice(&mac.finalize().into_bytes());
+
+    buf.freeze()
+}
+
+/// Verifies, in constant time, and removes the signature added by
+/// [append_signature] for the frame at `index`, returning the original frame.
+pub fn strip_signature(
+    key: &[u8],
+    index: u64,
+    mut frame: BytesMut,
+) -> Result<BytesMut, ProtocolError> {
+    if frame.len() < SIGNATURE_LEN {
+        return Err(ProtocolError::BadSignature);
+    }
+
+    let signature = frame.split_off(frame.len() - SIGNATURE_LEN);
+    frame_mac(key, index, &frame)
+        .verify_slice(&signature)
+        .map_err(|_| ProtocolError::BadSignature)?;
+
+    Ok(frame)
+}
+
+fn frame_mac(key: &[u8], index: u64, frame: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&index.to_be_bytes());
+    mac.update(frame);
+    mac
+}
+
+/// Returns the HMAC-SHA256 of a handshake's service name and timestamp, keyed
+/// with `key`, as sent in [Credentials::Signature]. The timestamp is in
+/// seconds since the Unix epoch, so that servers can refuse old signatures.
+pub fn sign_handshake(key: &[u8], service: Option<&str>, timestamp: u64) -> String {
+    handshake_mac(key, service, timestamp)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Checks a signature made by [sign_handshake] in constant time.
+pub fn verify_handshake(
+    key: &[u8],
+    service: Option<&str>,
+    timestamp: u64,
+    signature: &str,
+) -> bool {
+    let Some(signature) = decode_hex(signature) else {
+        return false;
+    };
+
+    handshake_mac(key, service, timestamp).verify_slice(&signature).is_ok()
+}
+
+fn handshake_mac(key: &[u8], service: Option<&str>, timestamp: u64) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length, so this can't fail.
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(service.unwrap_or("").as_bytes());
+    mac.update(b"\n");
+    mac.update(timestamp.to_string().as_bytes());
+
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Writes a `u64` as two big-endian `u32` halves, as Bufferfish has no native
+/// 64-bit integer type.
+pub(crate) fn write_u64(bf: &mut Bufferfish, value: u64) -> std::io::Result<()> {
+    bf.write_u32((value >> 32) as u32)?;
+    bf.write_u32(value as u32)?;
+
+    Ok(())
+}
+
+/// Reads a `u64` written by [write_u64].
+pub(crate) fn read_u64(bf: &mut Bufferfish) -> std::io::Result<u64> {
+    let high = u64::from(bf.read_u32()?);
+    let low = u64::from(bf.read_u32()?);
+
+    Ok((high << 32) | low)
+}
+
+/// Writes an optional `u64`, prefixed by a `u8` presence flag.
+pub(crate) fn write_optional_u64(bf: &mut Bufferfish, value: Option<u64>) -> std::io::Result<()> {
+    match value {
+        Some(value) => {
+            bf.write_u8(1)?;
+            write_u64(bf, value)
+        }
+        None => bf.write_u8(0),
+    }
+}
+
+/// Reads an optional `u64` written by [write_optional_u64].
+pub(crate) fn read_optional_u64(bf: &mut Bufferfish) -> std::io::Result<Option<u64>> {
+    match bf.read_u8()? {
+        0 => Ok(None),
+        _ => Ok(Some(read_u64(bf)?)),
+    }
+}
+
+/// Writes an optional `f32` as its raw bits, prefixed by a `u8` presence flag.
+pub(crate) fn write_optional_f32(bf: &mut Bufferfish, value: Option<f32>) -> std::io::Result<()> {
+    match value {
+        Some(value) => {
+            bf.write_u8(1)?;
+            bf.write_u32(value.to_bits())
+        }
+        None => bf.write_u8(0),
+    }
+}
+
+/// Reads an optional `f32` written by [write_optional_f32].
+pub(crate) fn read_optional_f32(bf: &mut Bufferfish) -> std::io::Result<Option<f32>> {
+    match bf.read_u8()? {
+        0 => Ok(None),
+        _ => Ok(Some(f32::from_bits(bf.read_u32()?))),
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// Invalid read from or write to a `Bufferfish` buffer.
+    BufferRead(std::io::Error),
+    /// The peer announced a protocol version this build does not speak.
+    UnsupportedVersion(u16),
+    /// The handshake or subscription was well-formed but contained invalid
+    /// values.
+    InvalidHandshake(String),
+    /// A frame's checksum did not match its contents, so it was corrupted in
+    /// transit.
+    ChecksumMismatch,
+    /// A frame's signature did not match its contents, so it was modified in
+    /// transit or signed with a different key.
+    BadSignature,
+    /// A frame sent by `harpd` was malformed.
+    InvalidResponse(String),
+    /// A frame sent by `harpd` had a type this build doesn't know, such as a
+    /// control frame added by a newer version.
+    UnknownResponse(u8),
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::BufferRead(e) => write!(f, "Error reading from buffer: {e}"),
+            ProtocolError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported protocol version {v} (expected {PROTOCOL_VERSION})")
+            }
+            ProtocolError::InvalidHandshake(reason) => write!(f, "Invalid handshake: {reason}"),
+            ProtocolError::ChecksumMismatch => write!(f, "Frame checksum does not match"),
+            ProtocolError::BadSignature => write!(f, "Frame signature does not match"),
+            ProtocolError::InvalidResponse(reason) => write!(f, "Invalid response: {reason}"),
+            ProtocolError::UnknownResponse(kind) => write!(f, "Unknown response type {kind}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(value: std::io::Error) -> Self {
+        Self::BufferRead(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_round_trip() {
+        let handshake = Handshake::new(Some("shard-1".into()));
+        let bf = Bufferfish::try_from(handshake.clone()).unwrap();
+        assert_eq!(Handshake::try_from(bf).unwrap(), handshake);
+
+        let handshake = Handshake::new(None).with_checksums(true);
+        let bf = Bufferfish::try_from(handshake.clone()).unwrap();
+        assert_eq!(Handshake::try_from(bf).unwrap(), handshake);
+    }
+
+    #[test]
+    fn handshake_credentials() {
+        let token = Credentials::Token("secret".into());
+        let handshake = Handshake::new(Some("shard-1".into())).with_credentials(Some(token));
+        let bf = Bufferfish::try_from(handshake.clone()).unwrap();
+        assert_eq!(Handshake::try_from(bf).unwrap(), handshake);
+
+        let signature = sign_handshake(b"key", Some("shard-1"), 1_700_000_000);
+        assert!(verify_handshake(b"key", Some("shard-1"), 1_700_000_000, &signature));
+        assert!(!verify_handshake(b"key", Some("shard-2"), 1_700_000_000, &signature));
+        assert!(!verify_handshake(b"other", Some("shard-1"), 1_700_000_000, &signature));
+        assert!(!verify_handshake(b"key", Some("shard-1"), 1_700_000_001, &signature));
+        assert!(!verify_handshake(b"key", Some("shard-1"), 1_700_000_000, "not hex"));
+
+        let credentials = Credentials::Signature { timestamp: 1_700_000_000, signature };
+        let handshake = Handshake::new(Some("shard-1".into())).with_credentials(Some(credentials));
+        let bf = Bufferfish::try_from(handshake.clone()).unwrap();
+        assert_eq!(Handshake::try_from(bf).unwrap(), handshake);
+    }
+
+    #[test]
+    fn negotiate_framing() {
+        let handshake = Handshake::new(None).with_framing(LengthField::U32, 1 << 20);
+        let bf = Bufferfish::try_from(handshake.clone()).unwrap();
+        assert_eq!(Handshake::try_from(bf).unwrap(), handshake);
+
+        // A u16 length can't describe anything larger than 64KB.
+        let handshake = Handshake::new(None).with_framing(LengthField::U16, 1 << 20);
+        assert_eq!(handshake.max_frame_size, u32::from(u16::MAX));
+
+        let mut codec = FrameCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Bytes::from_static(b"hello"), &mut buf).unwrap();
+        assert_eq!(buf.len(), 2 + 5);
+
+        codec.negotiate(LengthField::U32, 1 << 20);
+        codec.encode(Bytes::from_static(b"hello"), &mut buf).unwrap();
+        assert_eq!(buf.len(), 2 + 5 + 4 + 5);
+
+        codec.reset();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), &b"hello"[..]);
+        codec.negotiate(LengthField::U32, 1 << 20);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), &b"hello"[..]);
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let nack = Response::Nack(Nack {
+            code: NackCode::SchemaInvalid,
+            sequence: Some(42),
+            reason: "missing \"name\"".into(),
+        });
+        let frame = BytesMut::from(&nack.clone().encode().unwrap()[..]);
+        assert_eq!(Response::decode(frame).unwrap(), nack);
+
+        let requeue = Response::Requeue(Bytes::from_static(b"action"));
+        let frame = BytesMut::from(&requeue.clone().encode().unwrap()[..]);
+        assert_eq!(Response::decode(frame).unwrap(), requeue);
+
+        for response in
+            [Response::Ping, Response::Config(vec![("max_frame_size".into(), "65535".into())])]
+        {
+            let frame = BytesMut::from(&response.clone().encode().unwrap()[..]);
+            assert_eq!(Response::decode(frame).unwrap(), response);
+        }
+
+        assert!(matches!(
+            Response::decode(BytesMut::from(&[9u8][..])),
+            Err(ProtocolError::UnknownResponse(9))
+        ));
+    }
+
+    #[test]
+    fn verify_checksums() {
+        let frame = append_checksum(b"login");
+        assert_eq!(strip_checksum(BytesMut::from(&frame[..])).unwrap(), &b"login"[..]);
+
+        let mut corrupt = BytesMut::from(&frame[..]);
+        corrupt[0] ^= 0xff;
+        assert!(strip_checksum(corrupt).is_err());
+        assert!(strip_checksum(BytesMut::from(&b"abc"[..])).is_err());
+    }
+
+    #[test]
+    fn verify_signatures() {
+        let frame = append_signature(b"key", 3, b"login");
+        assert_eq!(frame.len(), 5 + SIGNATURE_LEN);
+        assert_eq!(strip_signature(b"key", 3, BytesMut::from(&frame[..])).unwrap(), &b"login"[..]);
+        assert!(strip_signature(b"other", 3, BytesMut::from(&frame[..])).is_err());
+
+        // A frame replayed at any other position on the connection fails.
+        assert!(strip_signature(b"key", 4, BytesMut::from(&frame[..])).is_err());
+
+        let mut tampered = BytesMut::from(&frame[..]);
+        tampered[0] ^= 0xff;
+        assert!(strip_signature(b"key", 3, tampered).is_err());
+        assert!(strip_signature(b"key", 0, BytesMut::from(&b"abc"[..])).is_err());
+
+        let handshake = Handshake::new(None).with_signed_frames(true);
+        let bf = Bufferfish::try_from(handshake.clone()).unwrap();
+        assert_eq!(Handshake::try_from(bf).unwrap(), handshake);
+    }
+
+    #[test]
+    fn subscribe_round_trip() {
+        let subscribe = Subscribe::new(vec!["login_failed".into(), "trade".into()]);
+        let bf = Bufferfish::try_from(subscribe.clone()).unwrap();
+        assert_eq!(Subscribe::try_from(bf).unwrap(), subscribe);
+    }
+
+    #[test]
+    fn reject_invalid_handshake() {
+        let mut bf = Bufferfish::new();
+        bf.write_u16(PROTOCOL_VERSION + 1).unwrap();
+        bf.write_string("shard-1").unwrap();
+        assert!(Handshake::try_from(bf).is_err());
+
+        let mut bf = Bufferfish::new();
+        bf.write_u16(PROTOCOL_VERSION).unwrap();
+        bf.write_string(&"a".repeat(MAX_SERVICE_NAME_LEN + 1)).unwrap();
+        bf.write_u8(0).unwrap();
+        assert!(Handshake::try_from(bf).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! Tunneling connections to Harp servers through a SOCKS5 or HTTP proxy,
+//! enabled with the `proxy` feature. Pass a `Proxy` to `HarpBuilder::proxy`,
+//! and every connection, including reconnects and TLS connections, is opened
+//! through it before the first frame is sent.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use harp::{proxy::Proxy, Harp};
+//! #
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let proxy = Proxy::socks5("proxy.internal:1080").credentials("shard-1", "s3cret");
+//!
+//! let harp = Harp::builder().hostname("10.20.0.5").proxy(proxy).create_service().await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use stubborn_io::tokio::UnderlyingIo;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+
+/// The longest response accepted from an HTTP proxy to a CONNECT request.
+const MAX_HTTP_RESPONSE: usize = 8 * 1024;
+
+/// A proxy that connections to the Harp server are tunneled through.
+#[derive(Clone)]
+pub struct Proxy {
+    protocol: ProxyProtocol,
+    addr: String,
+    credentials: Option<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProxyProtocol {
+    Socks5,
+    HttpConnect,
+}
+
+impl Proxy {
+    /// Tunnels through the SOCKS5 proxy at `addr`, such as
+    /// "proxy.internal:1080".
+    pub fn socks5(addr: impl Into<String>) -> Self {
+        Self { protocol: ProxyProtocol::Socks5, addr: addr.into(), credentials: None }
+    }
+
+    /// Tunnels through the HTTP proxy at `addr` with a CONNECT request.
+    pub fn http(addr: impl Into<String>) -> Self {
+        Self { protocol: ProxyProtocol::HttpConnect, addr: addr.into(), credentials: None }
+    }
+
+    /// Authenticates with the proxy as `username`, using username/password
+    /// authentication with SOCKS5 proxies and basic authentication with HTTP
+    /// proxies. Neither is encrypted on the way to the proxy.
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Returns the address of the proxy.
+    pub(crate) fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Connects to the proxy and asks it to open a tunnel to `target`. The
+    /// returned stream carries bytes to and from `target` as if it were
+    /// connected directly.
+    pub(crate) async fn connect(&self, target: SocketAddr) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.set_nodelay(true)?;
+
+        let credentials = self.credentials.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+        match self.protocol {
+            ProxyProtocol::Socks5 => socks5_connect(&mut stream, target, credentials).await?,
+            ProxyProtocol::HttpConnect => http_connect(&mut stream, target, credentials).await?,
+        }
+
+        Ok(stream)
+    }
+}
+
+impl std::fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Proxy")
+            .field("protocol", &self.protocol)
+            .field("addr", &self.addr)
+            .field("username", &self.credentials.as_ref().map(|(username, _)| username))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Everything needed to open, or reopen, a tunnel to a Harp server.
+#[derive(Debug, Clone)]
+pub(crate) struct ProxyTarget {
+    pub proxy: Proxy,
+    pub addr: SocketAddr,
+}
+
+/// A connection to a Harp server through a proxy, which `StubbornIo`
+/// reconnects in the same way as a plain TCP stream.
+pub(crate) struct ProxyConnection(TcpStream);
+
+impl UnderlyingIo<ProxyTarget> for ProxyConnection {
+    fn establish(target: ProxyTarget) -> Pin<Box<dyn Future<Output = io::Result<Self>> + Send>> {
+        Box::pin(async move { Ok(Self(target.proxy.connect(target.addr).await?)) })
+    }
+}
+
+impl AsyncRead for ProxyConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxyConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Opens a tunnel to `target` through a SOCKS5 proxy (RFC 1928), logging in
+/// with username/password authentication (RFC 1929) if credentials are set.
+async fn socks5_connect<S>(
+    stream: &mut S,
+    target: SocketAddr,
+    credentials: Option<(&str, &str)>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Offer no authentication, and username/password if we have them.
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[5, 2, 0, 2],
+        None => &[5, 1, 0],
+    };
+    stream.write_all(greeting).await?;
+
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    match (choice, credentials) {
+        ([5, 0], _) => {}
+        ([5, 2], Some((username, password))) => {
+            let (Ok(username_len), Ok(password_len)) =
+                (u8::try_from(username.len()), u8::try_from(password.len()))
+            else {
+                return Err(io::Error::other("SOCKS5 credentials are limited to 255 bytes"));
+            };
+
+            let mut login = vec![1, username_len];
+            login.extend(username.as_bytes());
+            login.push(password_len);
+            login.extend(password.as_bytes());
+            stream.write_all(&login).await?;
+
+            let mut status = [0; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(io::Error::other("SOCKS5 proxy refused the credentials"));
+            }
+        }
+        _ => return Err(io::Error::other("SOCKS5 proxy requires an unsupported login method")),
+    }
+
+    let mut request = vec![5, 1, 0];
+    match target {
+        SocketAddr::V4(addr) => {
+            request.push(1);
+            request.extend(addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            request.push(4);
+            request.extend(addr.ip().octets());
+        }
+    }
+    request.extend(target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(io::Error::other(format!(
+            "SOCKS5 proxy failed to connect to {target} (reply {})",
+            reply[1]
+        )));
+    }
+
+    // The reply ends with the address the proxy connected from, which isn't
+    // needed, but must be read past.
+    let bound_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => usize::from(stream.read_u8().await?),
+        _ => return Err(io::Error::other("SOCKS5 proxy sent an invalid reply")),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(())
+}
+
+/// Opens a tunnel to `target` through an HTTP proxy with a CONNECT request.
+async fn http_connect<S>(
+    stream: &mut S,
+    target: SocketAddr,
+    credentials: Option<(&str, &str)>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some((username, password)) = credentials {
+        let token = STANDARD.encode(format!("{username}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read a byte at a time, so that nothing after the headers is consumed.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_HTTP_RESPONSE {
+            return Err(io::Error::other("HTTP proxy sent an oversized response"));
+        }
+
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("HTTP proxy failed to connect to {target}: {status}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn tunnel_handshakes() {
+        let target = SocketAddr::from(([10, 20, 0, 5], 7777));
+
+        // A SOCKS5 proxy which asks for a login, then connects.
+        let (mut client, mut proxy) = duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut greeting = [0; 4];
+            proxy.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, 0, 2]);
+            proxy.write_all(&[5, 2]).await.unwrap();
+
+            let mut login = [0; 10];
+            proxy.read_exact(&mut login).await.unwrap();
+            assert_eq!(&login, b"\x01\x03bob\x04pass");
+            proxy.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0; 10];
+            proxy.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [5, 1, 0, 1, 10, 20, 0, 5, 0x1E, 0x61]);
+            proxy.write_all(&[5, 0, 0, 1, 10, 0, 0, 1, 0x9C, 0x40]).await.unwrap();
+        });
+        socks5_connect(&mut client, target, Some(("bob", "pass"))).await.unwrap();
+        server.await.unwrap();
+
+        // An HTTP proxy which refuses the tunnel.
+        let (mut client, mut proxy) = duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut request = vec![0; 64];
+            let read = proxy.read(&mut request).await.unwrap();
+            assert!(request[..read].starts_with(b"CONNECT 10.20.0.5:7777 HTTP/1.1\r\n"));
+            proxy.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await.unwrap();
+        });
+        let result = http_connect(&mut client, target, None).await;
+        assert!(result.unwrap_err().to_string().contains("403 Forbidden"));
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,385 @@
+//! Holds encoded actions which could not be delivered, either because sending
+//! them failed or because the Harp server returned them, until they can be
+//! retried.
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio_util::bytes::Bytes;
+
+use crate::{action::Action, expiry::Expiry};
+
+/// The number of actions held in the reserve queue if not configured.
+pub(crate) const DEFAULT_RESERVE_CAPACITY: usize = 10_000;
+
+/// Counters describing a service's reserve queue. Shared with every `Sender`
+/// for the service; see `Sender::reserve_metrics`.
+#[derive(Debug, Default)]
+pub struct ReserveMetrics {
+    len: AtomicUsize,
+    bytes: AtomicUsize,
+    queued: AtomicU64,
+    resent: AtomicU64,
+    dropped: AtomicU64,
+    expired: AtomicU64,
+    startup_dropped: AtomicU64,
+}
+
+impl ReserveMetrics {
+    /// Returns the number of actions currently waiting to be retried.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Returns the approximate number of bytes held by the actions waiting to
+    /// be retried, counting their encoded frames.
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if no actions are waiting to be retried.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the total number of actions which have entered the queue.
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of actions taken from the queue to be resent.
+    pub fn resent(&self) -> u64 {
+        self.resent.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of actions discarded because the queue was
+    /// full, by count or by bytes.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of actions discarded because they were older
+    /// than their maximum age before they could be sent. See
+    /// `HarpBuilder::max_age`.
+    pub fn expired(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of actions discarded because a lazy service's
+    /// startup buffer was full. See `HarpBuilder::create_service_lazy`.
+    pub fn startup_dropped(&self) -> u64 {
+        self.startup_dropped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_startup_dropped(&self) {
+        self.startup_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A bounded queue of encoded frames. Once full, by count or optionally by
+/// bytes, the oldest frames are discarded to make room for new ones. Frames
+/// older than their maximum age are discarded rather than resent.
+///
+/// If given a file, the queue is written to it when dropped and read back when
+/// the next queue is created with the same file, so returned actions survive
+/// a restart. Each frame is stored as a big-endian `u32` length followed by
+/// its bytes.
+#[derive(Debug)]
+pub(crate) struct ReserveQueue {
+    frames: VecDeque<Bytes>,
+    capacity: usize,
+    /// The total length of `frames`.
+    bytes: usize,
+    max_bytes: Option<usize>,
+    expiry: Expiry,
+    metrics: Arc<ReserveMetrics>,
+    file: Option<PathBuf>,
+}
+
+impl ReserveQueue {
+    pub(crate) fn new(capacity: usize, expiry: Expiry) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            capacity: capacity.max(1),
+            bytes: 0,
+            max_bytes: None,
+            expiry,
+            metrics: Arc::default(),
+            file: None,
+        }
+    }
+
+    /// Limits the queue to roughly `max_bytes` of frames, as well as its
+    /// capacity. The newest frame is always kept, even if it is larger.
+    pub(crate) fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Loads any frames saved to `file` by a previous queue, and saves this
+    /// queue there when it is dropped.
+    pub(crate) fn with_file(mut self, file: PathBuf) -> Self {
+        match load(&file) {
+            Ok(frames) if !frames.is_empty() => {
+                tracing::info!("Loaded {} actions into the reserve queue", frames.len());
+                for frame in frames {
+                    self.push(frame);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::error!("Failed to load the reserve queue from {}: {e}", file.display())
+            }
+        }
+
+        self.file = Some(file);
+        self
+    }
+
+    /// Returns the action if it is still fresh enough to send, or records it
+    /// as expired. Used for actions which waited in the channel, such as while
+    /// the service was disconnected.
+    pub(crate) fn unexpired(&self, action: Action) -> Option<Action> {
+        if !self.expiry.is_expired(&action) {
+            return Some(action);
+        }
+
+        self.metrics.expired.fetch_add(1, Ordering::Relaxed);
+        self.expiry.expire(action);
+        None
+    }
+
+    /// Like `unexpired`, for an encoded action. Frames are only decoded when
+    /// expiry is configured. One which can't be decoded is kept, and left for
+    /// the server to judge.
+    pub(crate) fn is_frame_unexpired(&self, frame: &[u8]) -> bool {
+        if !self.expiry.is_enabled() {
+            return true;
+        }
+
+        match Action::decode(frame) {
+            Ok(action) => self.unexpired(action).is_some(),
+            Err(_) => true,
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> Arc<ReserveMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, frame: Bytes) {
+        self.bytes += frame.len();
+        self.frames.push_back(frame);
+        self.metrics.queued.fetch_add(1, Ordering::Relaxed);
+
+        while self.frames.len() > 1 && self.is_over_limit() {
+            if let Some(oldest) = self.frames.pop_front() {
+                self.bytes -= oldest.len();
+            }
+            self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("Reserve queue is full; dropped the oldest action");
+        }
+
+        self.record_size();
+    }
+
+    fn is_over_limit(&self) -> bool {
+        self.frames.len() > self.capacity || self.max_bytes.is_some_and(|max| self.bytes > max)
+    }
+
+    fn record_size(&self) {
+        self.metrics.len.store(self.frames.len(), Ordering::Relaxed);
+        self.metrics.bytes.store(self.bytes, Ordering::Relaxed);
+    }
+
+    /// Removes up to `count` of the oldest frames to be resent, discarding any
+    /// which have expired along the way.
+    pub(crate) fn take(&mut self, count: usize) -> Vec<Bytes> {
+        let mut frames = Vec::with_capacity(count.min(self.frames.len()));
+
+        while frames.len() < count {
+            let Some(frame) = self.frames.pop_front() else {
+                break;
+            };
+            self.bytes -= frame.len();
+
+            if self.is_frame_unexpired(&frame) {
+                frames.push(frame);
+            }
+        }
+
+        self.metrics.resent.fetch_add(frames.len() as u64, Ordering::Relaxed);
+        self.record_size();
+
+        frames
+    }
+}
+
+impl Drop for ReserveQueue {
+    fn drop(&mut self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        match save(file, &self.frames) {
+            Ok(()) if !self.frames.is_empty() => {
+                tracing::info!(
+                    "Saved {} reserved actions to {}",
+                    self.frames.len(),
+                    file.display()
+                );
+            }
+            Ok(()) => {}
+            Err(e) => {
+                tracing::error!("Failed to save the reserve queue to {}: {e}", file.display())
+            }
+        }
+    }
+}
+
+/// Reads the frames saved by `save`. A missing file holds no frames.
+fn load(file: &Path) -> io::Result<Vec<Bytes>> {
+    let mut bytes = match fs::read(file) {
+        Ok(bytes) => Bytes::from(bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut frames = Vec::new();
+    while bytes.len() >= 4 {
+        let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        if bytes.len() < 4 + len {
+            break;
+        }
+
+        frames.push(bytes.slice(4..4 + len));
+        bytes = bytes.slice(4 + len..);
+    }
+
+    if !bytes.is_empty() {
+        tracing::warn!("Ignored a truncated frame at the end of {}", file.display());
+    }
+
+    Ok(frames)
+}
+
+/// Writes every frame to `file`, replacing it whole so that a crash part way
+/// through leaves the previous contents. An empty queue removes the file.
+fn save(file: &Path, frames: &VecDeque<Bytes>) -> io::Result<()> {
+    if frames.is_empty() {
+        return match fs::remove_file(file) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+
+    let temp = file.with_extension("tmp");
+    let mut writer = io::BufWriter::new(fs::File::create(&temp)?);
+    for frame in frames {
+        let len = u32::try_from(frame.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "frame exceeds u32::MAX"))?;
+        writer.write_all(&len.to_be_bytes())?;
+        writer.write_all(frame)?;
+    }
+    writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+
+    fs::rename(temp, file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bufferfish::Bufferfish;
+
+    use super::*;
+    use crate::test_fixtures::Target;
+
+    #[test]
+    fn drop_oldest_when_full() {
+        let mut reserve = ReserveQueue::new(2, Expiry::default());
+        let metrics = reserve.metrics();
+
+        reserve.push(Bytes::from_static(b"a"));
+        reserve.push(Bytes::from_static(b"b"));
+        reserve.push(Bytes::from_static(b"c"));
+
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics.queued(), 3);
+        assert_eq!(metrics.dropped(), 1);
+
+        let frames = reserve.take(10);
+        assert_eq!(frames, vec![Bytes::from_static(b"b"), Bytes::from_static(b"c")]);
+        assert_eq!(metrics.resent(), 2);
+        assert!(metrics.is_empty());
+    }
+
+    #[test]
+    fn drop_oldest_over_max_bytes() {
+        let mut reserve = ReserveQueue::new(10, Expiry::default()).with_max_bytes(4);
+        let metrics = reserve.metrics();
+
+        reserve.push(Bytes::from_static(b"ab"));
+        reserve.push(Bytes::from_static(b"cd"));
+        assert_eq!(metrics.bytes(), 4);
+
+        reserve.push(Bytes::from_static(b"e"));
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics.bytes(), 3);
+        assert_eq!(metrics.dropped(), 1);
+
+        // A frame over the limit on its own is still kept.
+        reserve.push(Bytes::from_static(b"fghij"));
+        assert_eq!(reserve.take(10), vec![Bytes::from_static(b"fghij")]);
+        assert_eq!(metrics.bytes(), 0);
+    }
+
+    #[test]
+    fn persist_across_queues() {
+        let file = std::env::temp_dir().join(format!("harp-reserve-{}", fastrand::u64(..)));
+
+        let mut reserve = ReserveQueue::new(10, Expiry::default()).with_file(file.clone());
+        reserve.push(Bytes::from_static(b"a"));
+        reserve.push(Bytes::from_static(b"bc"));
+        drop(reserve);
+
+        let mut reserve = ReserveQueue::new(10, Expiry::default()).with_file(file.clone());
+        assert_eq!(reserve.take(10), vec![Bytes::from_static(b"a"), Bytes::from_static(b"bc")]);
+
+        // Dropping an empty queue removes the file.
+        drop(reserve);
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn expire_encoded_frames() {
+        let mut expiry = Expiry::default();
+        expiry.set_default(Duration::from_secs(10));
+        let reserve = ReserveQueue::new(10, expiry);
+        let metrics = reserve.metrics();
+
+        let encode = |action: &Action| Bytes::from(Bufferfish::try_from(action).unwrap());
+        let mut stale = Action::new("position", &Target);
+        stale.created -= Duration::from_secs(60);
+
+        assert!(reserve.is_frame_unexpired(&encode(&Action::new("position", &Target))));
+        assert!(!reserve.is_frame_unexpired(&encode(&stale)));
+        assert!(reserve.is_frame_unexpired(b"not an action"));
+        assert_eq!(metrics.expired(), 1);
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+use crate::action::Action;
+
+/// Per-kind sample rates applied to actions before they are queued for
+/// sending. Kinds without a configured rate are always kept.
+#[derive(Debug, Default)]
+pub(crate) struct Sampler {
+    rates: HashMap<String, f64>,
+}
+
+impl Sampler {
+    /// Sets the sample rate for a kind. The rate is clamped between 0.0 (drop
+    /// everything) and 1.0 (keep everything).
+    pub(crate) fn set_rate(&mut self, kind: String, rate: f64) {
+        self.rates.insert(kind, rate.clamp(0.0, 1.0));
+    }
+
+    /// Decides whether an action should be kept. Kept actions of a sampled
+    /// kind have their sample rate recorded, so that counts can be scaled back
+    /// up when querying.
+    pub(crate) fn sample(&self, action: &mut Action) -> bool {
+        let Some(&rate) = self.rates.get(action.kind.as_ref()) else {
+            return true;
+        };
+
+        if rate >= 1.0 {
+            return true;
+        }
+
+        if fastrand::f64() < rate {
+            action.sample_rate = Some(rate as f32);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{Target, TestKind};
+
+    #[test]
+    fn sample_by_kind() {
+        let mut sampler = Sampler::default();
+        sampler.set_rate("never".into(), 0.0);
+        sampler.set_rate("always".into(), 1.0);
+
+        let mut action = Action::new(TestKind("never"), &Target);
+        assert!(!sampler.sample(&mut action));
+
+        let mut action = Action::new(TestKind("always"), &Target);
+        assert!(sampler.sample(&mut action));
+        assert_eq!(action.sample_rate, None);
+
+        let mut action = Action::new(TestKind("unsampled"), &Target);
+        assert!(sampler.sample(&mut action));
+        assert_eq!(action.sample_rate, None);
+    }
+}
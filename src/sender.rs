@@ -1,22 +1,493 @@
 //! This is a wrapper around Sender<Action> for ensuring the returned inner
-//! value of the `create_service` functions (Result<T, E) are used.
-use std::ops::{Deref, DerefMut};
+//! value of the `create_service` functions (Result<T, E) are used. It also
+//! applies any sampling configured on the `HarpBuilder` before actions enter
+//! the channel.
+use std::{
+    fmt::Display,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use crate::action::Action;
+use bufferfish::Bufferfish;
+use serde_json::Value;
+use tokio_util::bytes::Bytes;
 
+use crate::{
+    action::{Action, ActionError, Kind, Priority, SESSION_END, SESSION_START},
+    connection::{ConnectionStatus, Status},
+    ordinals::Ordinals,
+    reserve::ReserveMetrics,
+    sampling::Sampler,
+    sessions::Sessions,
+    Loggable,
+};
+
+/// Cloning a `Sender` is cheap, and every clone sends to the same service.
+/// Actions can only be sent through its own methods, so that every one is
+/// sampled and stamped on the way.
 #[must_use = "The returned send channel hasn't been used anywhere. This means a socket is open to the Harp server on a seperate task, but never utilized."]
-pub struct Sender(pub flume::Sender<Action>);
+#[derive(Clone, Debug)]
+pub struct Sender {
+    tx: flume::Sender<Action>,
+    priority_tx: flume::Sender<Action>,
+    /// Unset for senders with no run loop behind them, which have nothing to
+    /// flush.
+    flush_tx: Option<flume::Sender<FlushRequest>>,
+    /// Carries frames from `send_raw` to the run loop, along with the largest
+    /// frame it can send on its current connection. Unset for senders with no
+    /// run loop behind them, which take the decoded action instead.
+    raw: Option<(flume::Sender<Bytes>, Arc<AtomicUsize>)>,
+    sampler: Arc<Sampler>,
+    reserve: Arc<ReserveMetrics>,
+    status: ConnectionStatus,
+    /// The most actions held while a lazy service makes its first connection.
+    startup_buffer: Option<usize>,
+    /// Stamps every action sent, if ordered identifiers are enabled.
+    ordinals: Option<Arc<Ordinals>>,
+    /// The login session open for each player. See `start_session`.
+    sessions: Arc<Sessions>,
+    /// Set for senders created by `create_null_service`, which have no
+    /// receiver and drop every action.
+    null: bool,
+}
+
+impl Sender {
+    pub(crate) fn new(
+        tx: flume::Sender<Action>,
+        priority_tx: flume::Sender<Action>,
+        flush_tx: Option<flume::Sender<FlushRequest>>,
+        sampler: Arc<Sampler>,
+        reserve: Arc<ReserveMetrics>,
+        status: ConnectionStatus,
+    ) -> Self {
+        Self {
+            tx,
+            priority_tx,
+            flush_tx,
+            raw: None,
+            sampler,
+            reserve,
+            status,
+            startup_buffer: None,
+            ordinals: None,
+            sessions: Arc::default(),
+            null: false,
+        }
+    }
+
+    /// Creates a sender which drops every action without error.
+    pub(crate) fn null(sampler: Arc<Sampler>) -> Self {
+        let (tx, _) = flume::unbounded();
+        Self {
+            priority_tx: tx.clone(),
+            tx,
+            flush_tx: None,
+            raw: None,
+            sampler,
+            reserve: Arc::default(),
+            status: ConnectionStatus::fixed(Status::Connected),
+            startup_buffer: None,
+            ordinals: None,
+            sessions: Arc::default(),
+            null: true,
+        }
+    }
+
+    /// Limits the actions held until the service first connects, dropping any
+    /// beyond `capacity`.
+    pub(crate) fn with_startup_buffer(mut self, capacity: usize) -> Self {
+        self.startup_buffer = Some(capacity);
+        self
+    }
+
+    /// Sends frames from `send_raw` to the run loop on `raw_tx`, rejecting any
+    /// over `max_frame_size` bytes, which the run loop keeps up to date.
+    pub(crate) fn with_raw_frames(
+        mut self,
+        raw_tx: flume::Sender<Bytes>,
+        max_frame_size: Arc<AtomicUsize>,
+    ) -> Self {
+        self.raw = Some((raw_tx, max_frame_size));
+        self
+    }
+
+    /// Stamps every action sent with an ordinal from `ordinals`, shared with
+    /// the service's run loop.
+    pub(crate) fn with_ordinals(mut self, ordinals: Option<Arc<Ordinals>>) -> Self {
+        self.ordinals = ordinals;
+        self
+    }
+
+    /// Sends an action to the Harp service, unless it is dropped by sampling.
+    /// Dropped actions are not an error. High priority actions are sent on a
+    /// separate channel, which the service always drains first.
+    pub fn send(&self, mut action: Action) -> Result<(), flume::SendError<Action>> {
+        if !self.admit(&mut action) {
+            return Ok(());
+        }
+
+        self.lane(&action).send(action)
+    }
+
+    /// Like `send`, but never blocks. The service's channels are unbounded, so
+    /// this only fails if the service has stopped running.
+    pub fn try_send(&self, mut action: Action) -> Result<(), flume::TrySendError<Action>> {
+        if !self.admit(&mut action) {
+            return Ok(());
+        }
+
+        self.lane(&action).try_send(action)
+    }
+
+    /// Sends a frame which was encoded elsewhere, such as by a service written
+    /// in another language and relayed through this one, without re-encoding
+    /// it. The frame must hold a single action laid out as
+    /// `Bufferfish::try_from(&action)` writes it, without a checksum or
+    /// signature; those are added as it is sent, if configured.
+    ///
+    /// The frame is decoded to check its shape, and rejected if it holds
+    /// anything after the action or sets a sequence number, as `harpd` tracks
+    /// one sequence per connection and would otherwise see gaps between the
+    /// frame's and this service's. It is then sent as it is, skipping
+    /// sampling, interceptors, and every field this service would otherwise
+    /// stamp, such as idempotency keys, but expires like any other action
+    /// while it waits to be sent. Raw frames are batched with normal priority
+    /// actions, whatever their own priority.
+    ///
+    /// Senders without a connection, such as a collector's, hand over the
+    /// decoded action instead. It is still neither sampled nor stamped, but
+    /// passes through the collector's interceptors like any other action.
+    pub fn send_raw(&self, frame: Bufferfish) -> Result<(), RawFrameError> {
+        let frame: Bytes = frame.into();
+        if let Some((_, max_frame_size)) = &self.raw {
+            if frame.len() > max_frame_size.load(Ordering::Relaxed) {
+                return Err(RawFrameError::TooLarge(frame.len()));
+            }
+        }
+
+        let action = Action::decode(&frame).map_err(RawFrameError::Invalid)?;
+        if action.sequence.is_some() {
+            let e =
+                io::Error::new(io::ErrorKind::InvalidData, "raw frames must not set a sequence");
+            return Err(RawFrameError::Invalid(e.into()));
+        }
+
+        if self.null {
+            return Ok(());
+        }
+
+        match &self.raw {
+            Some((raw_tx, _)) => raw_tx.send(frame).map_err(|_| RawFrameError::Closed),
+            None => self.lane(&action).send(action).map_err(|_| RawFrameError::Closed),
+        }
+    }
+
+    /// Sends an action with no detail. Shorthand for
+    /// `sender.send(Action::new(kind, target))`.
+    pub fn log(
+        &self,
+        kind: impl Kind,
+        target: &impl Loggable,
+    ) -> Result<(), flume::SendError<Action>> {
+        self.send(Action::new(kind, target))
+    }
+
+    /// Sends an action with a detail. Shorthand for
+    /// `sender.send(Action::with_detail(kind, detail, target))`.
+    pub fn log_with(
+        &self,
+        kind: impl Kind,
+        detail: Value,
+        target: &impl Loggable,
+    ) -> Result<(), flume::SendError<Action>> {
+        self.send(Action::with_detail(kind, detail, target))
+    }
+
+    /// Like `log`, but never blocks. See `try_send`.
+    pub fn try_log(
+        &self,
+        kind: impl Kind,
+        target: &impl Loggable,
+    ) -> Result<(), flume::TrySendError<Action>> {
+        self.try_send(Action::new(kind, target))
+    }
+
+    /// Like `log_with`, but never blocks. See `try_send`.
+    pub fn try_log_with(
+        &self,
+        kind: impl Kind,
+        detail: Value,
+        target: &impl Loggable,
+    ) -> Result<(), flume::TrySendError<Action>> {
+        self.try_send(Action::with_detail(kind, detail, target))
+    }
+
+    /// Opens a login session for `target`, sending a `session_start` action,
+    /// and returns the session's id. Every action sent for `target` through
+    /// this sender or its clones is tagged with the id until `end_session`,
+    /// so queries can group a player's actions by session. Starting a session
+    /// while one is open replaces it. A session which goes 12 hours without an
+    /// action is closed, as is the longest idle once 100,000 are open.
+    pub fn start_session(&self, target: &impl Loggable) -> Result<u64, flume::SendError<Action>> {
+        let session_id = self.sessions.start(target.identifier());
+        self.send(Action::new(SESSION_START, target))?;
+
+        Ok(session_id)
+    }
+
+    /// Closes the login session open for `target`, sending a `session_end`
+    /// action as its last, and returns the session's id. Returns `None`
+    /// without sending anything if no session is open.
+    pub fn end_session(
+        &self,
+        target: &impl Loggable,
+    ) -> Result<Option<u64>, flume::SendError<Action>> {
+        let Some(session_id) = self.sessions.end(target.identifier()) else {
+            return Ok(None);
+        };
+        self.send(Action::new(SESSION_END, target).with_session(session_id))?;
+
+        Ok(Some(session_id))
+    }
+
+    /// Applies sampling, returning false if the action should be dropped
+    /// instead of sent. Actions are also dropped while a lazy service's startup
+    /// buffer is full.
+    fn admit(&self, action: &mut Action) -> bool {
+        if !self.sampler.sample(action) || self.null {
+            return false;
+        }
+
+        if self.startup_buffer_full() {
+            self.reserve.record_startup_dropped();
+            return false;
+        }
+
+        // Stamped here rather than in the run loop, so that high priority
+        // actions skipping ahead don't take earlier ordinals.
+        if let Some(ordinals) = &self.ordinals {
+            ordinals.stamp(action);
+        }
+        self.sessions.stamp(action);
+        #[cfg(feature = "trace-context")]
+        crate::trace_context::stamp(action);
+
+        true
+    }
+
+    fn startup_buffer_full(&self) -> bool {
+        self.startup_buffer.is_some_and(|capacity| {
+            self.status.status() == Status::Connecting && self.len() >= capacity
+        })
+    }
 
-impl Deref for Sender {
-    type Target = flume::Sender<Action>;
+    /// Returns the channel an action is sent on, based on its priority.
+    fn lane(&self, action: &Action) -> &flume::Sender<Action> {
+        match action.priority {
+            Priority::High => &self.priority_tx,
+            Priority::Normal => &self.tx,
+        }
+    }
+
+    /// Sends every action waiting in the channel and flushes the socket,
+    /// waiting up to `timeout` for the service to finish. Useful before
+    /// shutting down, or at the end of a match, to make sure nothing sent so
+    /// far is still sitting in memory.
+    ///
+    /// Actions sent after the flush begins are not waited for. While the
+    /// service is disconnected, the flush waits for it to reconnect. A
+    /// successful flush only means the actions were written to the socket:
+    /// `harpd` never confirms that it stored them, and any it returns or
+    /// rejects arrive later as requeues or NACKs. Actions which fail to send
+    /// are kept in the reserve queue as usual.
+    pub async fn flush(&self, timeout: Duration) -> Result<(), FlushError> {
+        let Some(flush_tx) = &self.flush_tx else {
+            return Ok(());
+        };
+
+        let (reply_tx, reply_rx) = flume::bounded(1);
+        flush_tx.send(reply_tx).map_err(|_| FlushError::Closed)?;
+
+        match tokio::time::timeout(timeout, reply_rx.recv_async()).await {
+            Ok(Ok(true)) => Ok(()),
+            Ok(Ok(false)) => Err(FlushError::Failed),
+            Ok(Err(_)) => Err(FlushError::Closed),
+            Err(_) => Err(FlushError::Timeout),
+        }
+    }
+
+    /// Returns the counters for the service's reserve queue, which holds
+    /// actions that failed to send or were returned by the server until they
+    /// can be retried.
+    pub fn reserve_metrics(&self) -> &ReserveMetrics {
+        &self.reserve
+    }
+
+    /// Returns a handle for checking whether the service is connected to the
+    /// Harp server, or waiting for that to change. Senders which are not
+    /// backed by a connection always report `Status::Connected`.
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.clone()
+    }
+
+    /// Returns the number of actions sent through either lane which the
+    /// service hasn't picked up yet. Null senders always report zero.
+    pub fn len(&self) -> usize {
+        if self.null {
+            return 0;
+        }
+
+        self.tx.len() + self.priority_tx.len()
+    }
+
+    /// Returns true if the service has picked up every action sent so far.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A request from `Sender::flush`, answered with whether the socket was
+/// flushed.
+pub(crate) type FlushRequest = flume::Sender<bool>;
+
+#[derive(Debug)]
+pub enum FlushError {
+    /// The service stopped running before the flush finished.
+    Closed,
+    /// The socket could not be flushed.
+    Failed,
+    /// The flush did not finish within the timeout.
+    Timeout,
+}
+
+impl std::error::Error for FlushError {}
+
+impl Display for FlushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlushError::Closed => write!(f, "The Harp service is no longer running"),
+            FlushError::Failed => write!(f, "Failed to flush actions to the Harp server"),
+            FlushError::Timeout => write!(f, "Timed out waiting for actions to flush"),
+        }
+    }
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+#[derive(Debug)]
+pub enum RawFrameError {
+    /// The frame does not decode as an action.
+    Invalid(ActionError),
+    /// The frame is larger than the service can send, in bytes.
+    TooLarge(usize),
+    /// The service stopped running.
+    Closed,
+}
+
+impl std::error::Error for RawFrameError {}
+
+impl Display for RawFrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RawFrameError::Invalid(e) => write!(f, "Invalid raw frame: {e}"),
+            RawFrameError::TooLarge(len) => write!(f, "Raw frame is too large: {len} bytes"),
+            RawFrameError::Closed => write!(f, "The Harp service is no longer running"),
+        }
     }
 }
 
-impl DerefMut for Sender {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+#[cfg(test)]
+mod tests {
+    use bufferfish::Bufferfish;
+    use serde_json::json;
+
+    use super::RawFrameError;
+    use crate::{
+        action::{Action, SESSION_END, SESSION_START},
+        connection::Status,
+        test_fixtures::{Target, TestKind},
+        Harp,
+    };
+
+    #[test]
+    fn log_helpers_build_actions() {
+        let (harp, collector) = Harp::builder().create_collector_service();
+
+        harp.log(TestKind("login"), &Target).unwrap();
+        harp.try_log_with(TestKind("chat"), json!({ "message": "hi" }), &Target).unwrap();
+
+        let actions = collector.actions();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].kind, "login");
+        assert_eq!(actions[0].detail, None);
+        assert_eq!(actions[1].kind, "chat");
+        assert_eq!(actions[1].detail, Some(json!({ "message": "hi" })));
+    }
+
+    #[test]
+    fn sessions_tag_actions() {
+        let (harp, collector) = Harp::builder().create_collector_service();
+
+        harp.log(TestKind("connect"), &Target).unwrap();
+        let session_id = harp.start_session(&Target).unwrap();
+        harp.clone().log(TestKind("chat"), &Target).unwrap();
+        assert_eq!(harp.end_session(&Target).unwrap(), Some(session_id));
+        assert_eq!(harp.end_session(&Target).unwrap(), None);
+        harp.log(TestKind("disconnect"), &Target).unwrap();
+
+        let sessions = collector.actions().iter().map(|a| (a.kind.to_string(), a.session_id));
+        assert_eq!(
+            sessions.collect::<Vec<_>>(),
+            [
+                ("connect".into(), None),
+                (SESSION_START.into(), Some(session_id)),
+                ("chat".into(), Some(session_id)),
+                (SESSION_END.into(), Some(session_id)),
+                ("disconnect".into(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn raw_frames_are_validated() {
+        let frame = || Bufferfish::try_from(Action::new(TestKind("relayed"), &Target)).unwrap();
+
+        // Senders without a run loop take the decoded action instead.
+        let (harp, collector) = Harp::builder().create_collector_service();
+        harp.send_raw(frame()).unwrap();
+        assert_eq!(collector.actions()[0].kind, "relayed");
+
+        let mut truncated = Bufferfish::new();
+        truncated.write_u32(1).unwrap();
+        assert!(matches!(harp.send_raw(truncated), Err(RawFrameError::Invalid(_))));
+
+        let mut trailing = frame();
+        trailing.write_u32(1).unwrap();
+        assert!(matches!(harp.send_raw(trailing), Err(RawFrameError::Invalid(_))));
+
+        let mut sequenced = Action::new(TestKind("relayed"), &Target);
+        sequenced.sequence = Some(1);
+        let sequenced = Bufferfish::try_from(sequenced).unwrap();
+        assert!(matches!(harp.send_raw(sequenced), Err(RawFrameError::Invalid(_))));
+
+        // Nothing listens on this port, so frames wait in the channel.
+        let harp = Harp::builder().port(1).max_frame_size(16).create_service_lazy();
+        assert!(matches!(harp.send_raw(frame()), Err(RawFrameError::TooLarge(_))));
+    }
+
+    #[tokio::test]
+    async fn lazy_service_bounds_startup_buffer() {
+        // Nothing listens on this port, so the service stays connecting.
+        let harp = Harp::builder().port(1).startup_buffer(2).create_service_lazy();
+
+        for _ in 0..3 {
+            harp.log(TestKind("login"), &Target).unwrap();
+        }
+
+        assert_eq!(harp.status().status(), Status::Connecting);
+        assert_eq!(harp.len(), 2);
+        assert_eq!(harp.reserve_metrics().startup_dropped(), 1);
     }
 }
@@ -0,0 +1,487 @@
+//! The `harpd` server, available as a library with the `server` feature so
+//! that it can be embedded in other binaries.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use harp::{action::Action, server::Server};
+//! #
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! Server::builder()
+//!     .config_path("/etc/harp/config.toml")
+//!     .transform(|mut action: Action| {
+//!         action.kind.to_mut().make_ascii_lowercase();
+//!         Some(action)
+//!     })
+//!     .listen()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+mod admin;
+mod alerts;
+pub mod auth;
+pub mod config;
+mod counters;
+mod ephemeral;
+mod export;
+mod geoip;
+mod import;
+mod kinds;
+mod limits;
+mod listener;
+pub mod logging;
+mod metrics;
+#[cfg(feature = "otel")]
+mod otel;
+mod proxy;
+mod queue;
+pub mod reload;
+mod sequence;
+mod spill;
+mod sql;
+mod subscriptions;
+mod systemd;
+mod tls;
+pub mod transform;
+mod validation;
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use sqlx::PgPool;
+use tokio::sync::{watch, RwLock};
+
+pub use self::{
+    auth::Authenticator,
+    config::{read_secret, Config},
+    export::{ExportFilter, ExportFormat},
+    transform::Transform,
+};
+use self::{
+    config::SharedConfig,
+    ephemeral::EphemeralStore,
+    geoip::GeoIp,
+    queue::Store,
+    reload::{build_env_filter, LogHandle},
+    transform::Transforms,
+};
+use crate::{encryption::DetailCipher, Result};
+
+/// The config file loaded if no other path is given.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/harp/config.toml";
+
+/// A commented config file with the settings needed to get started, as
+/// written by `ServerBuilder::init`.
+pub const DEFAULT_CONFIG: &str = include_str!("server/default_config.toml");
+
+/// The longest wait between attempts to prepare the database when connecting
+/// in the background.
+const MAX_PREPARE_DELAY: Duration = Duration::from_secs(60);
+
+/// Entry point for running a Harp server. See `Server::builder`.
+pub struct Server;
+
+impl Server {
+    /// Returns a builder for configuring and starting a Harp server.
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+/// Configures a Harp server. Created with `Server::builder()`.
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Option<Config>,
+    config_path: Option<PathBuf>,
+    transforms: Transforms,
+    authenticator: Option<Box<dyn Authenticator>>,
+    export_cipher: Option<DetailCipher>,
+    skip_migrations: bool,
+    ephemeral: bool,
+    ephemeral_database: Option<PathBuf>,
+    log_handle: Option<LogHandle>,
+    known_kinds: Option<Vec<String>>,
+}
+
+impl ServerBuilder {
+    /// Sets the config file to load, which is also reloaded on SIGHUP.
+    /// Defaults to `/etc/harp/config.toml`.
+    pub fn config_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// Uses an already loaded config rather than reading one from a file. The
+    /// config is not reloaded on SIGHUP.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Registers a transform which is applied to every action after it is
+    /// decoded and validated. Transforms run in the order they are registered.
+    /// See `Transform` for more information.
+    pub fn transform(mut self, transform: impl Transform) -> Self {
+        self.transforms.push(transform);
+        self
+    }
+
+    /// Decides which services may connect, and what they are known as,
+    /// replacing the `[auth]` method in the config. See `Authenticator` for
+    /// more information.
+    pub fn authenticator(mut self, authenticator: impl Authenticator) -> Self {
+        self.authenticator = Some(Box::new(authenticator));
+        self
+    }
+
+    /// Decrypts details encrypted with `cipher`, whether by `harpd` or by
+    /// their service, when exporting. Encrypted details are exported as they
+    /// are stored otherwise.
+    pub fn decrypt_exports(mut self, cipher: DetailCipher) -> Self {
+        self.export_cipher = Some(cipher);
+        self
+    }
+
+    /// Skips running database migrations on startup, for databases where the
+    /// server's user cannot alter the schema. Migrations are run by default.
+    pub fn skip_migrations(mut self, skip: bool) -> Self {
+        self.skip_migrations = skip;
+        self
+    }
+
+    /// Runs without a database server, for demos and CI. Each flush stores
+    /// the queued actions in an in-process SQLite database, held in memory
+    /// unless `ephemeral_database` is set, and prints them to stdout as JSON
+    /// lines. The `[database]` settings are ignored.
+    pub fn ephemeral(mut self, ephemeral: bool) -> Self {
+        self.ephemeral = ephemeral;
+        self
+    }
+
+    /// Sets the SQLite file an ephemeral server stores actions in, so that
+    /// they can be inspected after it exits. It is created if missing.
+    pub fn ephemeral_database(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ephemeral_database = Some(path.into());
+        self
+    }
+
+    /// Rejects actions of any kind not in `kinds`, such as the `KEYS` of a
+    /// `harp::kinds!` registry, replacing `validation.known_kinds` in the
+    /// config.
+    pub fn known_kinds(mut self, kinds: &[&str]) -> Self {
+        self.known_kinds = Some(kinds.iter().map(|kind| kind.to_string()).collect());
+        self
+    }
+
+    /// Sets a handle to the tracing filter, which is updated with the config's
+    /// `log_level` on startup and whenever the config is reloaded.
+    pub fn log_handle(mut self, log_handle: LogHandle) -> Self {
+        self.log_handle = Some(log_handle);
+        self
+    }
+
+    /// Runs any pending database migrations, then returns without listening.
+    /// Fails if a column mapping is configured, as the table is not Harp's.
+    pub async fn migrate(mut self) -> Result<()> {
+        let config = self.load_config()?;
+        if config.has_column_mapping() {
+            return Err("Migrations can't be run against a table with a column mapping".into());
+        }
+
+        let pg = connect_database(&config).await?;
+
+        migrate(&pg, &config).await
+    }
+
+    /// Sets up a new install in one step: writes `DEFAULT_CONFIG` to the
+    /// config path if no file exists there, then connects to the database and
+    /// runs the migrations, creating the schema and tables. An existing config
+    /// file is never overwritten. Returns true if the config file was written.
+    pub async fn init(self) -> Result<bool> {
+        let written = match &self.config {
+            Some(_) => false,
+            None => {
+                let path = self.config_path.as_deref().unwrap_or(Path::new(DEFAULT_CONFIG_PATH));
+                write_default_config(path)?
+            }
+        };
+
+        self.migrate().await?;
+
+        Ok(written)
+    }
+
+    /// Recomputes the hourly rollup table from every stored action, including
+    /// those in routed tables, then returns without listening. Returns the
+    /// number of rollup rows written.
+    pub async fn backfill_rollups(mut self) -> Result<u64> {
+        let config = self.load_config()?;
+        let pg = connect_database(&config).await?;
+
+        let tables = config.get_qualified_tables();
+        let kinds_table = config.get_kinds_table();
+        sql::backfill_rollups(&pg, &tables, &config.get_rollup_table(), kinds_table.as_deref())
+            .await
+    }
+
+    /// Writes every stored action matching `filter` to the file at `out`, then
+    /// returns without listening. Rows are streamed from the database rather
+    /// than loaded up front. Returns the number of actions exported.
+    ///
+    /// Filtering by a routed kind reads from its table; otherwise only the
+    /// actions table is read.
+    pub async fn export(
+        mut self,
+        filter: &ExportFilter,
+        format: ExportFormat,
+        out: impl AsRef<Path>,
+    ) -> Result<u64> {
+        let config = self.load_config()?;
+        let pg = connect_database(&config).await?;
+
+        let table = match &filter.kind {
+            Some(kind) => config.get_table_for(kind),
+            None => config.get_qualified_table(),
+        };
+
+        let kinds_table = config.get_kinds_table();
+        let cipher = self.export_cipher.as_ref();
+        export::export(&pg, &table, kinds_table.as_deref(), filter, format, out.as_ref(), cipher)
+            .await
+    }
+
+    /// Inserts the archived actions in the JSON-lines file at `path`, such as
+    /// one written by `export`, then returns without listening. Actions whose
+    /// idempotency key is already stored are skipped. Returns the number of
+    /// actions read.
+    pub async fn import(mut self, path: impl AsRef<Path>) -> Result<u64> {
+        let config = self.load_config()?;
+        let pg = connect_database(&config).await?;
+
+        import::import(&pg, &config.get_insert_statements()?, path.as_ref()).await
+    }
+
+    /// Connects to the database, runs any pending migrations, and then accepts
+    /// services until an error occurs, or an operator sends `drain-and-exit` to
+    /// the admin socket.
+    ///
+    /// If `database.connect_in_background` is set, services are accepted
+    /// straight away instead. Their actions wait in the queue while the
+    /// database is connected and migrated in the background, and are flushed
+    /// once it is ready. Ephemeral servers never connect at all, and open
+    /// their SQLite database instead.
+    pub async fn listen(mut self) -> Result<()> {
+        let reloadable = self.config.is_none();
+        let config = self.load_config()?;
+        let migrate = self.should_migrate(&config);
+
+        let (store, ready) = if self.ephemeral {
+            let path = self.ephemeral_database.as_deref();
+            match path {
+                Some(path) => {
+                    tracing::warn!("Running ephemerally; storing actions in {}", path.display())
+                }
+                None => tracing::warn!("Running ephemerally; stored actions are lost on exit"),
+            }
+
+            (Store::Ephemeral(EphemeralStore::open(path).await?), None)
+        } else if config.connects_in_background() {
+            let (ready_tx, ready) = watch::channel(false);
+            (Store::Postgres(connect_database_lazy(&config)?), Some((ready_tx, ready)))
+        } else {
+            let pg = connect_database(&config).await?;
+            if migrate {
+                self::migrate(&pg, &config).await?;
+            }
+
+            (Store::Postgres(pg), None)
+        };
+        let pg = store.postgres();
+
+        let geoip = Arc::new(GeoIp::default());
+        geoip.load(&config.geoip)?;
+
+        let authenticator: Arc<dyn Authenticator> = match self.authenticator.take() {
+            Some(authenticator) => Arc::from(authenticator),
+            None => Arc::from(config.get_authenticator()?),
+        };
+
+        let config = Arc::new(RwLock::new(config));
+
+        let ready = match (ready, &pg) {
+            (Some((ready_tx, ready)), Some(pg)) => {
+                tracing::info!("Connecting to the database in the background");
+                tokio::spawn(prepare_database(pg.clone(), Arc::clone(&config), migrate, ready_tx));
+                ready
+            }
+            // Already connected and migrated, or there's no database; the
+            // sender is dropped, but the value stays readable.
+            _ => watch::channel(true).1,
+        };
+
+        // Configs given directly to the builder have no file to reload from.
+        #[cfg(unix)]
+        {
+            if reloadable {
+                let config = Arc::clone(&config);
+                let geoip = Arc::clone(&geoip);
+                let path = self.config_path.take();
+                let log_handle = self.log_handle.clone();
+                let pg = pg.clone();
+                tokio::spawn(async move {
+                    let result = reload::watch_sighup(path, config, pg, geoip, log_handle).await;
+                    if let Err(e) = result {
+                        tracing::error!("Error watching for SIGHUP: {e}");
+                    }
+                });
+            }
+        }
+
+        listener::listen(
+            config,
+            store,
+            ready,
+            self.transforms,
+            geoip,
+            authenticator,
+            self.log_handle,
+        )
+        .await
+    }
+
+    /// Returns true if migrations should be run before actions are inserted,
+    /// logging why if not.
+    fn should_migrate(&self, config: &Config) -> bool {
+        if self.skip_migrations {
+            tracing::info!("Skipping database migrations");
+            false
+        } else if config.has_column_mapping() {
+            tracing::info!("Skipping database migrations for a table with a column mapping");
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Takes the config given to the builder, or loads it from the config
+    /// file, and applies its log level.
+    fn load_config(&mut self) -> Result<Config> {
+        let mut config = match self.config.take() {
+            Some(config) => config,
+            None => Config::load_from_file(self.config_path.as_ref())?,
+        };
+
+        if let Some(kinds) = self.known_kinds.take() {
+            config.validation.known_kinds = Some(kinds);
+        }
+
+        if let (Some(log_handle), Some(log_level)) = (&self.log_handle, &config.log_level) {
+            log_handle.reload(build_env_filter(Some(log_level)))?;
+        }
+
+        Ok(config)
+    }
+}
+
+/// Writes `DEFAULT_CONFIG` to `path`, creating its directory if needed, unless
+/// a file is already there. Returns true if the file was written.
+fn write_default_config(path: &Path) -> Result<bool> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(mut file) => {
+            file.write_all(DEFAULT_CONFIG.as_bytes())?;
+            Ok(true)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn migrate(pg: &PgPool, config: &Config) -> Result<()> {
+    let (schema, table) = (config.get_schema(), config.get_table());
+    let routed_tables = config.get_routed_tables();
+    let detail_keys = &config.detail.indexed_keys;
+
+    let (manage_indexes, normalize_kinds) =
+        (config.manages_indexes(), config.get_kinds_table().is_some());
+
+    sql::migrate(pg, schema, table, &routed_tables, detail_keys, manage_indexes, normalize_kinds)
+        .await
+}
+
+/// Connects to and migrates the database for a server which is already
+/// listening, retrying with backoff until it succeeds, then marks it ready so
+/// that queued actions are flushed.
+async fn prepare_database(
+    pg: PgPool,
+    config: SharedConfig,
+    migrate: bool,
+    ready: watch::Sender<bool>,
+) {
+    let mut delay = Duration::from_secs(1);
+
+    loop {
+        let result = {
+            let config = config.read().await;
+            if migrate {
+                self::migrate(&pg, &config).await
+            } else {
+                pg.acquire().await.map(drop).map_err(Into::into)
+            }
+        };
+
+        match result {
+            Ok(()) => break,
+            Err(e) => tracing::warn!("Database not ready: {e}; retrying in {delay:?}"),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_PREPARE_DELAY);
+    }
+
+    tracing::info!("Database ready; flushing queued actions");
+    let _ = ready.send(true);
+}
+
+async fn connect_database(config: &Config) -> Result<PgPool> {
+    let pg = config.get_pool_options().connect_with(config.get_connect_options()?).await?;
+
+    Ok(pg)
+}
+
+/// Creates a pool which connects on first use, for starting without the
+/// database.
+fn connect_database_lazy(config: &Config) -> Result<PgPool> {
+    let pg = config.get_pool_options().connect_lazy_with(config.get_connect_options()?);
+
+    Ok(pg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_loadable_default_config() {
+        let dir = std::env::temp_dir().join(format!("harp-init-{}", std::process::id()));
+        let path = dir.join("config.toml");
+
+        assert!(write_default_config(&path).unwrap());
+        Config::load_from_file(Some(&path)).unwrap();
+
+        // An existing file is left alone.
+        assert!(!write_default_config(&path).unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+//! A local control interface for operating harpd without restarts. Operators
+//! connect over a Unix socket or a localhost TCP port and send one command per
+//! line, receiving a single line in reply:
+//!
+//! | Command                     | Reply                                          |
+//! |-----------------------------|------------------------------------------------|
+//! | `stats`                     | A JSON object of counters and queue state.     |
+//! | `flush`                     | `ok` once the whole queue has been written.    |
+//! | `pause-ingest`              | `ok`; actions are returned to their services.  |
+//! | `resume`                    | `ok`; actions are accepted again.              |
+//! | `drain-and-exit`            | `ok` once the queue is written, then exits.    |
+//! | `set-log-level <DIRECTIVE>` | `ok`, or an error if the directive is invalid. |
+//!
+//! Failed commands reply with a line starting with `error:`.
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{oneshot, Notify},
+};
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    server::{
+        counters::RateCounters,
+        metrics::Metrics,
+        queue::{FlushSender, QueueSender},
+        reload::LogHandle,
+    },
+    Result,
+};
+
+/// The longest command line accepted, in bytes.
+const MAX_COMMAND_LENGTH: usize = 1024;
+
+/// Runtime switches flipped by admin commands and read by the rest of harpd.
+#[derive(Default)]
+pub(crate) struct Controls {
+    paused: AtomicBool,
+    shutdown: Notify,
+    log_handle: Option<LogHandle>,
+}
+
+impl Controls {
+    pub(crate) fn new(log_handle: Option<LogHandle>) -> Self {
+        Self { log_handle, ..Self::default() }
+    }
+
+    /// Returns true if ingest is paused, in which case services should have
+    /// their actions returned rather than queued.
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Waits until an operator asks harpd to exit.
+    pub(crate) async fn shutdown(&self) {
+        self.shutdown.notified().await;
+    }
+}
+
+/// Handles shared by every admin connection.
+#[derive(Clone)]
+pub(crate) struct Admin {
+    pub(crate) controls: Arc<Controls>,
+    pub(crate) flush: FlushSender,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) queue: QueueSender,
+    /// The rate counters and how many of their busiest identifiers `stats`
+    /// reports, if counting is enabled.
+    pub(crate) top_talkers: Option<(Arc<RateCounters>, usize)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Stats,
+    Flush,
+    PauseIngest,
+    Resume,
+    DrainAndExit,
+    SetLogLevel(String),
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (command, argument) = match s.trim().split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, Some(argument.trim())),
+            None => (s.trim(), None),
+        };
+
+        match (command, argument) {
+            ("stats", None) => Ok(Self::Stats),
+            ("flush", None) => Ok(Self::Flush),
+            ("pause-ingest", None) => Ok(Self::PauseIngest),
+            ("resume", None) => Ok(Self::Resume),
+            ("drain-and-exit", None) => Ok(Self::DrainAndExit),
+            ("set-log-level", Some(directive)) => Ok(Self::SetLogLevel(directive.to_string())),
+            ("set-log-level", None) => Err("set-log-level needs a directive".into()),
+            _ => Err(format!("unknown command '{}'", s.trim())),
+        }
+    }
+}
+
+/// Accepts admin connections on a TCP listener until it fails.
+pub(crate) async fn accept_tcp(listener: TcpListener, admin: Admin) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => spawn_connection(stream, addr.to_string(), admin.clone()),
+            Err(e) => tracing::error!("Error accepting admin connection: {e}"),
+        }
+    }
+}
+
+/// Accepts admin connections on a Unix socket at `path` until it fails. Any
+/// stale socket file left by a previous run is replaced, and the socket is
+/// only accessible to its owner and group.
+#[cfg(unix)]
+pub(crate) async fn accept_unix(path: std::path::PathBuf, admin: Admin) -> Result<()> {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let listener = tokio::net::UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, Permissions::from_mode(0o660))?;
+    tracing::info!("harpd accepting admin commands on {}", path.display());
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => spawn_connection(stream, path.display().to_string(), admin.clone()),
+            Err(e) => tracing::error!("Error accepting admin connection: {e}"),
+        }
+    }
+}
+
+/// Warns if an admin listener is reachable from other machines.
+pub(crate) fn check_addr(addr: SocketAddr) {
+    if !addr.ip().is_loopback() {
+        tracing::warn!("Admin commands are accepted on {addr}, which is not a loopback address");
+    }
+}
+
+fn spawn_connection<S>(stream: S, peer: String, admin: Admin)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = handle_connection(stream, &peer, admin).await {
+            tracing::error!("Error handling admin connection: {e}");
+        }
+    });
+}
+
+/// Runs commands from a single admin connection until it disconnects.
+async fn handle_connection<S>(stream: S, peer: &str, admin: Admin) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut frame = Framed::new(stream, LinesCodec::new_with_max_length(MAX_COMMAND_LENGTH));
+
+    while let Some(line) = frame.next().await {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let command = match line.parse::<Command>() {
+            Ok(command) => command,
+            Err(e) => {
+                frame.send(format!("error: {e}")).await?;
+                continue;
+            }
+        };
+
+        tracing::info!("Admin command from {peer}: {}", line.trim());
+
+        // harpd only exits once the drain has succeeded, so that nothing is
+        // left behind in memory.
+        let mut exit = command == Command::DrainAndExit;
+        let reply = match run(command, &admin).await {
+            Ok(reply) => reply,
+            Err(e) => {
+                exit = false;
+                format!("error: {e}")
+            }
+        };
+        frame.send(reply).await?;
+
+        if exit {
+            admin.controls.shutdown.notify_one();
+        }
+    }
+
+    Ok(())
+}
+
+async fn run(command: Command, admin: &Admin) -> Result<String> {
+    match command {
+        Command::Stats => {
+            let mut stats = admin.metrics.snapshot();
+            stats["queue_waiting"] = json!(admin.queue.max_capacity() - admin.queue.capacity());
+            stats["paused"] = json!(admin.controls.is_paused());
+            if let Some((counters, top)) = &admin.top_talkers {
+                stats["top_talkers"] = counters
+                    .top_talkers(*top)
+                    .into_iter()
+                    .map(|((ip, id), count)| json!({ "ip": ip, "id": id, "count": count }))
+                    .collect();
+            }
+
+            Ok(stats.to_string())
+        }
+        Command::Flush => {
+            flush(admin).await?;
+            Ok("ok".into())
+        }
+        Command::PauseIngest => {
+            admin.controls.set_paused(true);
+            tracing::warn!("Ingest paused; actions will be returned to services");
+            Ok("ok".into())
+        }
+        Command::Resume => {
+            admin.controls.set_paused(false);
+            tracing::info!("Ingest resumed");
+            Ok("ok".into())
+        }
+        Command::DrainAndExit => {
+            // Stop taking new actions first, so the flush leaves nothing
+            // behind.
+            admin.controls.set_paused(true);
+            tracing::info!("Draining the queue before exiting");
+
+            flush(admin)
+                .await
+                .map_err(|e| format!("{e}; still paused, not exiting. Run it again to retry"))?;
+            Ok("ok".into())
+        }
+        Command::SetLogLevel(directive) => {
+            let Some(log_handle) = &admin.controls.log_handle else {
+                return Err("the log level can't be changed in this process".into());
+            };
+
+            let filter = EnvFilter::try_new(&directive)?;
+            log_handle.reload(filter)?;
+            tracing::info!("Log level set to \"{directive}\"");
+
+            Ok("ok".into())
+        }
+    }
+}
+
+/// Asks the queue processor to write the whole queue, waiting until it has,
+/// and failing if it couldn't.
+async fn flush(admin: &Admin) -> Result<()> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    admin.flush.send(reply_tx).await.map_err(|_| "queue processor has stopped")?;
+    reply_rx.await.map_err(|_| "queue processor has stopped")??;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("stats".parse(), Ok(Command::Stats));
+        assert_eq!(" drain-and-exit\r".parse(), Ok(Command::DrainAndExit));
+        assert_eq!(
+            "set-log-level harpd=debug,info".parse(),
+            Ok(Command::SetLogLevel("harpd=debug,info".into()))
+        );
+
+        assert!("set-log-level".parse::<Command>().is_err());
+        assert!("flush now".parse::<Command>().is_err());
+        assert!("reboot".parse::<Command>().is_err());
+    }
+}
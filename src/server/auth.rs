@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::protocol::{verify_handshake, Credentials};
+
+/// What an `Authenticator` knows about a service when it connects.
+#[derive(Debug)]
+pub struct AuthRequest<'a> {
+    /// The address the service connected from.
+    pub addr: SocketAddr,
+    /// The name the service announced in its handshake, if any.
+    pub service: Option<&'a str>,
+    /// The credentials sent in the handshake, if any.
+    pub credentials: Option<&'a Credentials>,
+    /// The common name of the service's client certificate, if it presented
+    /// one. Certificates have already been verified against `tls.client_ca`.
+    pub certificate: Option<&'a str>,
+}
+
+/// Decides whether a service may connect once it has sent its handshake, and
+/// what it is known as. The configured `[auth]` method is used unless the
+/// server is embedded with its own authenticator; see
+/// `ServerBuilder::authenticator`.
+///
+/// Closures with the signature
+/// `Fn(&AuthRequest) -> Result<Option<String>, AuthError>` implement this
+/// trait.
+pub trait Authenticator: Send + Sync + 'static {
+    /// Returns the name the service is known by, which is stored in the
+    /// `source` column and used to detect duplicate services, or why it was
+    /// refused. `Ok(None)` accepts the service without a name.
+    fn authenticate(&self, request: &AuthRequest<'_>) -> Result<Option<String>, AuthError>;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&AuthRequest<'_>) -> Result<Option<String>, AuthError> + Send + Sync + 'static,
+{
+    fn authenticate(&self, request: &AuthRequest<'_>) -> Result<Option<String>, AuthError> {
+        self(request)
+    }
+}
+
+/// Accepts every service, known by its client certificate's name if it
+/// presented one, and otherwise by the name it announced. Used when no
+/// authentication is configured.
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, request: &AuthRequest<'_>) -> Result<Option<String>, AuthError> {
+        Ok(request.certificate.or(request.service).map(str::to_string))
+    }
+}
+
+/// Accepts services which send one of a fixed set of tokens, each known by the
+/// name its token was issued for. See `HarpBuilder::auth_token`.
+pub struct StaticTokens {
+    // Tokens are looked up by their digest, so a lookup takes the same time
+    // however much of a token matches.
+    tokens: HashMap<[u8; 32], String>,
+}
+
+impl StaticTokens {
+    /// Creates an authenticator from pairs of service names and their tokens.
+    pub fn new<N, T>(tokens: impl IntoIterator<Item = (N, T)>) -> Self
+    where
+        N: Into<String>,
+        T: AsRef<str>,
+    {
+        let tokens =
+            tokens.into_iter().map(|(name, token)| (digest(token.as_ref()), name.into())).collect();
+
+        Self { tokens }
+    }
+}
+
+impl Authenticator for StaticTokens {
+    fn authenticate(&self, request: &AuthRequest<'_>) -> Result<Option<String>, AuthError> {
+        let Some(Credentials::Token(token)) = request.credentials else {
+            return Err(AuthError::Missing);
+        };
+
+        match self.tokens.get(&digest(token)) {
+            Some(name) => Ok(Some(name.clone())),
+            None => Err(AuthError::Invalid),
+        }
+    }
+}
+
+/// Accepts services which present a client certificate, known by the
+/// certificate's common name. Requires `tls.client_ca`.
+pub struct ClientCertificate;
+
+impl Authenticator for ClientCertificate {
+    fn authenticate(&self, request: &AuthRequest<'_>) -> Result<Option<String>, AuthError> {
+        match request.certificate {
+            Some(name) => Ok(Some(name.to_string())),
+            None => Err(AuthError::Missing),
+        }
+    }
+}
+
+/// Accepts services whose handshake is signed with a shared key, known by the
+/// name they announced. See `HarpBuilder::auth_key`.
+///
+/// A captured handshake can be replayed until its signature expires, so
+/// connections should also use TLS where they cross untrusted networks.
+pub struct HmacHandshake {
+    key: Zeroizing<Vec<u8>>,
+    max_skew: Duration,
+}
+
+impl HmacHandshake {
+    /// Creates an authenticator for signatures made with `key`, which are
+    /// accepted for `max_skew` either side of the server's clock.
+    pub fn new(key: impl Into<Vec<u8>>, max_skew: Duration) -> Self {
+        Self { key: Zeroizing::new(key.into()), max_skew }
+    }
+}
+
+impl Authenticator for HmacHandshake {
+    fn authenticate(&self, request: &AuthRequest<'_>) -> Result<Option<String>, AuthError> {
+        let Some(Credentials::Signature { timestamp, signature }) = request.credentials else {
+            return Err(AuthError::Missing);
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if now.abs_diff(*timestamp) > self.max_skew.as_secs() {
+            return Err(AuthError::Expired);
+        }
+
+        if !verify_handshake(&self.key, request.service, *timestamp, signature) {
+            return Err(AuthError::Invalid);
+        }
+
+        Ok(request.service.map(str::to_string))
+    }
+}
+
+fn digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// The service sent no credentials, or not the kind expected.
+    Missing,
+    /// The credentials were not accepted.
+    Invalid,
+    /// The handshake was signed too long ago, or too far in the future.
+    Expired,
+    /// Refused by a custom authenticator, for the given reason.
+    Refused(String),
+}
+
+impl std::error::Error for AuthError {}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "Missing credentials"),
+            AuthError::Invalid => write!(f, "Invalid credentials"),
+            AuthError::Expired => write!(f, "Handshake signature has expired"),
+            AuthError::Refused(reason) => write!(f, "Refused: {reason}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::sign_handshake;
+
+    fn request<'a>(
+        service: Option<&'a str>,
+        credentials: Option<&'a Credentials>,
+    ) -> AuthRequest<'a> {
+        AuthRequest {
+            addr: SocketAddr::from(([127, 0, 0, 1], 5000)),
+            service,
+            credentials,
+            certificate: None,
+        }
+    }
+
+    #[test]
+    fn check_tokens() {
+        let tokens = StaticTokens::new([("shard-1", "s3cret")]);
+
+        // The token decides the name, whatever the service announced.
+        let token = Credentials::Token("s3cret".into());
+        let name = tokens.authenticate(&request(Some("other"), Some(&token)));
+        assert_eq!(name, Ok(Some("shard-1".into())));
+
+        let wrong = Credentials::Token("guess".into());
+        assert_eq!(tokens.authenticate(&request(None, Some(&wrong))), Err(AuthError::Invalid));
+        assert_eq!(tokens.authenticate(&request(None, None)), Err(AuthError::Missing));
+    }
+
+    #[test]
+    fn check_signatures() {
+        let hmac = HmacHandshake::new(b"key".to_vec(), Duration::from_secs(300));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        let sign = |service, timestamp| Credentials::Signature {
+            timestamp,
+            signature: sign_handshake(b"key", Some(service), timestamp),
+        };
+
+        let signed = sign("shard-1", now);
+        let name = hmac.authenticate(&request(Some("shard-1"), Some(&signed)));
+        assert_eq!(name, Ok(Some("shard-1".into())));
+
+        // Signed for a different name.
+        let result = hmac.authenticate(&request(Some("shard-2"), Some(&signed)));
+        assert_eq!(result, Err(AuthError::Invalid));
+
+        let stale = sign("shard-1", now - 600);
+        let result = hmac.authenticate(&request(Some("shard-1"), Some(&stale)));
+        assert_eq!(result, Err(AuthError::Expired));
+    }
+}
@@ -0,0 +1,1650 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions, PgSslMode},
+    types::ipnetwork::IpNetwork,
+};
+use tokio::sync::RwLock;
+use zeroize::Zeroizing;
+
+use crate::{
+    encryption::DetailCipher,
+    server::{
+        auth::{AllowAll, Authenticator, ClientCertificate, HmacHandshake, StaticTokens},
+        queue::{BatchSizing, FlushThresholds, QueueLimits, DEFAULT_MIN_BATCH_SIZE, LIMIT},
+        spill::SpillOptions,
+        sql::{is_valid_identifier, ColumnMapping, Field, InsertStatements},
+    },
+    Result,
+};
+
+/// The daemon configuration, shared between tasks so that it can be reloaded
+/// at runtime.
+pub(crate) type SharedConfig = Arc<RwLock<Config>>;
+
+/// The smallest maximum packet size harpd will accept, in bytes.
+const MIN_PACKET_SIZE: usize = 128;
+
+/// The number of actions buffered for each subscriber if not configured.
+const DEFAULT_SUBSCRIBER_BUFFER: usize = 1024;
+
+/// The number of top talkers logged each window if not configured.
+const DEFAULT_TOP_TALKERS: usize = 10;
+
+/// The number of caught up actions written on each flush if not configured.
+const DEFAULT_CATCH_UP_PER_FLUSH: usize = 1000;
+
+/// The most caught up actions held in the queue at once if not configured.
+const DEFAULT_CATCH_UP_MAX_QUEUED: usize = 100_000;
+
+/// How long the queue processor may stall before it is restarted, in seconds,
+/// if not configured.
+const DEFAULT_STALL_TIMEOUT_SECS: u64 = 300;
+
+/// How far a signed handshake's timestamp may be from the server's clock, in
+/// seconds, if not configured.
+const DEFAULT_MAX_SKEW_SECS: u64 = 300;
+
+/// The number of actions written to each spill segment if not configured.
+const DEFAULT_SPILL_SEGMENT_ACTIONS: usize = 10_000;
+
+/// The name reported to Postgres if neither the config nor the connection
+/// string sets one.
+const DEFAULT_APPLICATION_NAME: &str = "harpd";
+
+/// A struct representing the configuration for the harpd daemon.
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    host: IpAddr,
+    port: u16,
+    database: DatabaseConfig,
+
+    #[serde(default)]
+    pub listener: ListenerConfig,
+
+    // Additional addresses to listen on, alongside `host` and `port`, each
+    // with its own settings.
+    #[serde(default)]
+    pub listen: Vec<ListenAddr>,
+
+    #[serde(default)]
+    pub admin: AdminConfig,
+
+    #[serde(default)]
+    pub logging: LoggingConfig,
+
+    #[serde(default)]
+    pub validation: ValidationConfig,
+
+    #[serde(default)]
+    pub geoip: GeoIpConfig,
+
+    #[serde(default)]
+    pub subscriptions: SubscriptionConfig,
+
+    #[serde(default)]
+    pub rate_counters: RateCounterConfig,
+
+    #[serde(default)]
+    pub expiry: ExpiryConfig,
+
+    #[serde(default)]
+    pub catch_up: CatchUpConfig,
+
+    #[serde(default)]
+    pub detail: DetailConfig,
+
+    #[serde(default)]
+    pub watchdog: WatchdogConfig,
+
+    #[serde(default)]
+    pub batching: BatchingConfig,
+
+    #[serde(default)]
+    pub spill: SpillConfig,
+
+    #[serde(default)]
+    pub tls: TlsConfig,
+
+    #[serde(default)]
+    pub auth: AuthConfig,
+
+    #[serde(default)]
+    pub signing: SigningConfig,
+
+    // Threshold rules which raise an alert when too many actions of a kind
+    // arrive within a window.
+    #[serde(default)]
+    pub alerts: Vec<AlertRule>,
+
+    // Kinds stored only as a count for each player in every window, in
+    // `<table>_aggregates`, rather than as rows.
+    #[serde(default)]
+    pub aggregates: Vec<AggregateRule>,
+
+    // Duration in seconds between processing the queue.
+    #[serde(rename = "process_interval")]
+    pub process_interval_secs: NonZeroU64,
+
+    // Number of queued actions, and approximate bytes, which trigger a flush
+    // before the next interval.
+    #[serde(default)]
+    pub flush_threshold_actions: Option<NonZeroUsize>,
+    #[serde(default)]
+    pub flush_threshold_bytes: Option<NonZeroUsize>,
+
+    // Maximum time (in milliseconds) to spend draining the queue on each
+    // flush. The whole queue is drained if unset.
+    #[serde(default)]
+    pub flush_time_budget_ms: Option<NonZeroU64>,
+
+    // Number of batches inserted at once on each flush, each on its own
+    // pooled connection. Can't exceed `database.max_connections`.
+    #[serde(default = "default_flush_workers")]
+    pub flush_workers: NonZeroUsize,
+
+    // Maximum number of decoded actions waiting to be moved into the queue.
+    // Once full, actions are returned to their services.
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: NonZeroUsize,
+
+    // Maximum number of actions held in the queue waiting to be written.
+    // Unlimited if unset.
+    #[serde(default)]
+    pub max_queued_actions: Option<NonZeroUsize>,
+
+    // Maximum approximate memory (in bytes) used by the actions held in the
+    // queue. Unlimited if unset.
+    #[serde(default)]
+    pub max_queued_bytes: Option<NonZeroUsize>,
+
+    // Maintain per-kind hourly counts in `<table>_hourly` as actions are
+    // inserted.
+    #[serde(default)]
+    pub hourly_rollups: bool,
+
+    // Maintain a row for each login session in `<table>_sessions` as actions
+    // tagged with it are inserted.
+    #[serde(default)]
+    pub sessions: bool,
+
+    // Maximum size (in bytes) to accept for a single packet.
+    #[serde(default = "default_max_packet_size")]
+    pub max_packet_size: usize,
+
+    // Tracing filter directive, such as "info" or "harpd=debug". Falls back to
+    // `RUST_LOG` when unset.
+    #[serde(default)]
+    pub log_level: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ListenerConfig {
+    // Maximum number of simultaneous service connections. Unlimited if unset.
+    pub max_connections: Option<NonZeroUsize>,
+
+    // Maximum number of simultaneous service connections from a single IP
+    // address. Unlimited if unset.
+    pub max_connections_per_ip: Option<NonZeroUsize>,
+
+    // Duration in seconds after which a connection that has sent no frames is
+    // closed. Connections are never timed out if unset.
+    #[serde(rename = "idle_timeout")]
+    pub idle_timeout_secs: Option<NonZeroU64>,
+
+    // When `host` is an IPv6 address, only accept IPv6 clients. Otherwise the
+    // listener also accepts IPv4 clients where the platform supports it, so
+    // binding `::` listens on both.
+    #[serde(default)]
+    pub ipv6_only: bool,
+
+    // What happens when a service connects with the same name as one which is
+    // already connected, such as a shard which was started twice.
+    #[serde(default)]
+    pub duplicate_services: DuplicatePolicy,
+
+    // Expect a PROXY protocol v2 header, as sent by a load balancer, at the
+    // start of every service connection, and use the client address it holds
+    // in place of the load balancer's. Connections without one are refused,
+    // so only enable this when every service connects through the balancer.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+
+    // Addresses or CIDR ranges of the load balancers allowed to send a PROXY
+    // header, such as "10.0.0.0/24". Connections from anywhere else are
+    // refused when the PROXY protocol is enabled. Any peer is trusted if
+    // empty.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+
+    // Name of a named pipe to also accept services on, such as
+    // `\\.\pipe\harp`. Only supported on Windows, with the `named-pipe`
+    // feature.
+    pub named_pipe: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicatePolicy {
+    // Accept both connections, logging a warning.
+    #[default]
+    Allow,
+    // Close the new connection, keeping the existing one.
+    RejectNew,
+    // Close the existing connection in favor of the new one.
+    KickOld,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ListenAddr {
+    // Address and port to bind, such as "10.0.0.5:7777" or "[::1]:7780".
+    pub addr: SocketAddr,
+
+    // What is served on this address.
+    #[serde(default)]
+    pub protocol: ListenProtocol,
+
+    // Only accept IPv6 clients when `addr` is an IPv6 address. See
+    // `listener.ipv6_only`.
+    #[serde(default)]
+    pub ipv6_only: bool,
+
+    // Connection limits for this address, counted separately from other
+    // addresses. Falls back to the `[listener]` limits if unset.
+    pub max_connections: Option<NonZeroUsize>,
+    pub max_connections_per_ip: Option<NonZeroUsize>,
+
+    // Expect a PROXY protocol header on service connections to this address.
+    // Falls back to `listener.proxy_protocol` if unset.
+    pub proxy_protocol: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenProtocol {
+    // Services sending actions.
+    #[default]
+    Services,
+    // Subscribers receiving actions as they arrive.
+    Subscribers,
+    // Operators sending admin commands. Should only be bound to a loopback
+    // address.
+    Admin,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct AdminConfig {
+    // Path of a Unix socket accepting admin commands, such as `stats` and
+    // `drain-and-exit`. The admin socket is disabled if unset.
+    pub socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct LoggingConfig {
+    // Format of log lines, on stdout and in log files.
+    #[serde(default)]
+    pub format: LogFormat,
+
+    // Directory to write log files to, in addition to stdout. Log files are
+    // not written if unset.
+    pub directory: Option<PathBuf>,
+
+    // How often to start a new log file.
+    #[serde(default)]
+    pub rotation: LogRotation,
+
+    // Number of log files to keep; the oldest are deleted as new ones are
+    // started. Every file is kept if unset.
+    pub max_files: Option<NonZeroUsize>,
+
+    // OTLP collector to export traces and metrics to, such as
+    // "http://localhost:4317". Requires the `otel` feature; nothing is
+    // exported if unset.
+    pub otlp_endpoint: Option<String>,
+
+    // Service name reported to the collector. Defaults to "harpd".
+    pub otlp_service_name: Option<String>,
+}
+
+impl LoggingConfig {
+    /// Returns the service name reported to the OTLP collector.
+    pub(crate) fn get_otlp_service_name(&self) -> &str {
+        self.otlp_service_name.as_deref().unwrap_or("harpd")
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ValidationConfig {
+    // Directory of JSON Schemas named `<kind>.json`, used to validate the
+    // detail of actions of that kind. Details are not validated if unset.
+    pub schema_dir: Option<PathBuf>,
+
+    // Kinds services may send, such as the `KEYS` of a `harp::kinds!`
+    // registry. Actions of any other kind are rejected. Every kind is accepted
+    // if unset.
+    pub known_kinds: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GeoIpConfig {
+    // Paths to MaxMind GeoLite2 Country and ASN databases, used to look up the
+    // country and autonomous system of each action's IP address. Lookups are
+    // skipped for any database which is unset.
+    pub country_database: Option<PathBuf>,
+    pub asn_database: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SubscriptionConfig {
+    // Port to accept subscribers on, which receive matching actions as they
+    // arrive. Subscriptions are disabled if unset.
+    pub port: Option<u16>,
+
+    // Maximum number of actions buffered for each subscriber. Subscribers
+    // which fall further behind miss actions.
+    pub buffer: Option<NonZeroUsize>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct RateCounterConfig {
+    // Duration in seconds over which actions are counted for each (ip, id)
+    // pair. Counting is disabled if unset.
+    #[serde(rename = "window")]
+    pub window_secs: Option<NonZeroU64>,
+
+    // Number of actions from a single (ip, id) pair within the window above
+    // which stored actions are tagged with `rate_exceeded`. Actions are never
+    // tagged if unset.
+    pub threshold: Option<u64>,
+
+    // Number of top talkers logged at the end of each window.
+    pub top: Option<NonZeroUsize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExpiryConfig {
+    // Maximum age in seconds of an arriving action, measured from when it was
+    // created. Older actions, such as those retried long after a disconnect,
+    // are dropped. Actions never expire if unset.
+    #[serde(rename = "max_age")]
+    pub max_age_secs: Option<NonZeroU64>,
+
+    // Maximum ages for specific kinds, overriding `max_age`.
+    #[serde(default)]
+    pub kinds: HashMap<String, NonZeroU64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CatchUpConfig {
+    // Age in seconds past which an arriving action, such as one buffered by a
+    // service through a long disconnect, is caught up: queued in its own low
+    // priority lane, which is only written after live actions, a limited
+    // number per flush. Every action is live if unset.
+    #[serde(rename = "age")]
+    pub age_secs: Option<NonZeroU64>,
+
+    // Maximum number of caught up actions written on each flush.
+    pub per_flush: Option<NonZeroUsize>,
+
+    // Maximum number of caught up actions held in the queue at once, apart
+    // from its limits for live actions. Further caught up actions are
+    // returned to their services, to be resent later.
+    pub max_queued: Option<NonZeroUsize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DetailConfig {
+    // Maximum size in bytes of an action's detail, measured as JSON. Details
+    // are not limited if unset.
+    pub max_size: Option<NonZeroUsize>,
+
+    // What happens to actions whose detail is too large.
+    #[serde(default)]
+    pub oversize: OversizePolicy,
+
+    // Detail keys to index in every actions table, for queries such as
+    // `detail->>'match_id' = $1`. Must be lowercase letters, digits, and
+    // underscores.
+    #[serde(default)]
+    pub indexed_keys: Vec<String>,
+
+    // File holding a base64-encoded 256-bit key which details are encrypted
+    // with before they are stored. Details are stored in plain JSON if unset.
+    pub encryption_key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct WatchdogConfig {
+    // Duration in seconds the queue processor may go without making progress,
+    // on top of the process interval, before it is restarted. Defaults to 300.
+    #[serde(rename = "stall_timeout")]
+    pub stall_timeout_secs: Option<NonZeroU64>,
+
+    // Return actions to their services while the processor is being
+    // restarted, rather than accepting actions it may never write.
+    #[serde(default)]
+    pub refuse_ingest: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BatchingConfig {
+    // Most actions inserted in a single transaction. Capped at, and defaults
+    // to, the most which fit in one statement's bind parameters.
+    pub max_size: Option<NonZeroUsize>,
+
+    // Fewest actions inserted in a single transaction once batches have
+    // shrunk. Defaults to 100.
+    pub min_size: Option<NonZeroUsize>,
+
+    // Insert latency (in milliseconds) to aim for. Batches grow while inserts
+    // finish within it, and shrink when they take longer or fail. Every batch
+    // is `max_size` if unset.
+    pub target_latency_ms: Option<NonZeroU64>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct SpillConfig {
+    // Directory that actions are written to once the queue is full or can't
+    // grow, rather than returning them to their services. Spilled actions are
+    // read back oldest first as the queue drains, and kept across restarts.
+    // Disabled if unset.
+    pub dir: Option<PathBuf>,
+
+    // Maximum size (in bytes) of the actions held in `dir`. Once reached,
+    // actions are returned to their services. Unlimited if unset.
+    pub max_bytes: Option<NonZeroU64>,
+
+    // Number of actions written to each file in `dir`. Defaults to 10000.
+    pub segment_actions: Option<NonZeroUsize>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct TlsConfig {
+    // PEM files holding the certificate chain and private key presented to
+    // services. Service connections are plain TCP if unset.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+
+    // PEM file of CA certificates used to verify client certificates. A
+    // verified certificate's common name (CN) is used as the service's name,
+    // in place of the one it announces. Client certificates are not requested
+    // if unset.
+    pub client_ca: Option<PathBuf>,
+
+    // Refuse services which don't present a certificate signed by
+    // `client_ca`, rather than falling back to the name they announce.
+    #[serde(default = "default_true")]
+    pub require_client_cert: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self { cert: None, key: None, client_ca: None, require_client_cert: true }
+    }
+}
+
+impl TlsConfig {
+    /// Returns true if service connections use TLS.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.cert.is_some()
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct AuthConfig {
+    // How services prove who they are when they connect.
+    #[serde(default)]
+    pub method: AuthMethod,
+
+    // Tokens accepted by the "token" method, keyed by the name of the service
+    // each was issued to. Prefer `tokens_file`, which keeps them out of the
+    // config.
+    #[serde(default)]
+    pub tokens: HashMap<String, String>,
+
+    // File holding tokens accepted by the "token" method, one `name = token`
+    // pair per line. Blank lines and lines starting with `#` are skipped.
+    pub tokens_file: Option<PathBuf>,
+
+    // File holding the key shared with services by the "hmac" method.
+    pub key_file: Option<PathBuf>,
+
+    // Duration in seconds a signed handshake is accepted for, either side of
+    // the server's clock. Defaults to 300.
+    #[serde(rename = "max_skew")]
+    pub max_skew_secs: Option<NonZeroU64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    // Accept every service.
+    #[default]
+    None,
+    // Require one of `auth.tokens` or the tokens in `auth.tokens_file`.
+    Token,
+    // Require a client certificate signed by `tls.client_ca`.
+    Certificate,
+    // Require a handshake signed with the key in `auth.key_file`.
+    Hmac,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct SigningConfig {
+    // File holding the key services sign their frames with. Services which
+    // sign their frames are refused if unset.
+    pub key_file: Option<PathBuf>,
+
+    // Refuse services which don't sign their frames.
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizePolicy {
+    // Refuse the action, sending a NACK to services which understand them.
+    #[default]
+    Reject,
+    // Store the action with its detail replaced by
+    // `{"truncated": true, "size": <bytes>}`.
+    Truncate,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AlertRule {
+    // Name of the rule, included in logs, webhooks, and alert rows.
+    pub name: String,
+
+    // The kind of action counted by this rule.
+    pub kind: String,
+
+    // The rule triggers once more than this many actions arrive within the
+    // window for a single group.
+    pub count: usize,
+    #[serde(rename = "window")]
+    pub window_secs: NonZeroU64,
+
+    // What actions are grouped by when counting.
+    #[serde(default)]
+    pub group_by: GroupBy,
+
+    // What happens when the rule triggers. Webhooks require `webhook_url`.
+    #[serde(default)]
+    pub action: AlertAction,
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AggregateRule {
+    // The kind of action folded into counts.
+    pub kind: String,
+
+    // Length of each counting window in seconds, such as 60 for a count per
+    // minute. Windows are aligned to the Unix epoch.
+    #[serde(rename = "window")]
+    pub window_secs: NonZeroU64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    Ip,
+    Id,
+    Source,
+    #[default]
+    None,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertAction {
+    // Log a warning.
+    #[default]
+    Log,
+    // POST a JSON description of the alert to `webhook_url`.
+    Webhook,
+    // Write a row to the `<table>_alerts` table.
+    Row,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct DatabaseConfig {
+    // Connection fields, used unless `url` is set.
+    #[serde(default = "default_database_name")]
+    name: String,
+    #[serde(default = "default_database_user")]
+    user: String,
+    #[serde(default)]
+    pass: String,
+    #[serde(default = "default_database_host")]
+    host: IpAddr,
+    #[serde(default = "default_database_port")]
+    port: i16,
+
+    // File holding the password, such as a Docker or systemd secret, so that
+    // it needn't be written in this file. Read whenever the pool's connect
+    // options are built, including on reload, and used instead of `pass`.
+    pass_file: Option<PathBuf>,
+
+    // Full connection string, such as
+    // "postgres://harp@db.internal/harp?sslmode=require". Can express
+    // anything libpq understands, including Unix sockets, and takes
+    // precedence over the connection fields above. The pool and TLS settings
+    // below are applied on top.
+    url: Option<String>,
+
+    // Maximum number of connections to assign to the database connection pool.
+    max_connections: NonZeroU32,
+
+    // Number of connections the pool keeps open even when idle.
+    #[serde(default)]
+    min_connections: u32,
+
+    // Duration in seconds to wait for a free connection before an insert
+    // fails. Defaults to 30 seconds.
+    #[serde(rename = "acquire_timeout")]
+    acquire_timeout_secs: Option<NonZeroU64>,
+
+    // Maximum time (in milliseconds) a single statement may run before
+    // Postgres cancels it. Uses the server's setting if unset.
+    statement_timeout_ms: Option<NonZeroU64>,
+
+    // Name reported to Postgres, shown in `pg_stat_activity`. Defaults to
+    // "harpd", unless the connection string sets one.
+    application_name: Option<String>,
+
+    // Whether, and how strictly, to use TLS for the database connection.
+    ssl_mode: Option<SslMode>,
+
+    // CA certificate used to verify the server with the `verify-ca` and
+    // `verify-full` SSL modes.
+    ssl_root_cert: Option<PathBuf>,
+
+    // Schema and table that actions are written to.
+    #[serde(default = "default_schema")]
+    schema: String,
+    #[serde(default = "default_table")]
+    table: String,
+
+    // Tables in the same schema that actions of specific kinds are written to
+    // instead of `table`, such as `chat = "chat_actions"`. Routed tables are
+    // created by migrations alongside `table`.
+    #[serde(default)]
+    routes: HashMap<String, String>,
+
+    // Start accepting services before the database is reachable, queueing
+    // their actions in memory while it is connected and migrated in the
+    // background.
+    #[serde(default)]
+    connect_in_background: bool,
+
+    // Create indexes on (kind, created), (ip_address), and (unique_id,
+    // created) in every actions table on startup. Turn off to manage indexes
+    // yourself.
+    #[serde(default = "default_true")]
+    manage_indexes: bool,
+
+    // Store an integer `kind_id`, looked up in the `kinds` table of the same
+    // schema, on each action rather than its kind. Kinds are mapped to ids as
+    // actions are inserted, and back again by exports and rollups. The `kind`
+    // column is made nullable when this is turned on.
+    #[serde(default)]
+    normalize_kinds: bool,
+
+    // Insert each batch ordered by created time and kind rather than arrival
+    // order, so that rows land in the indexes in order.
+    #[serde(default)]
+    sort_inserts: bool,
+
+    // Column names for writing into an existing table with its own schema.
+    #[serde(default)]
+    columns: ColumnConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    Disable,
+    Allow,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl FromStr for SslMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "disable" => Ok(Self::Disable),
+            "allow" => Ok(Self::Allow),
+            "prefer" => Ok(Self::Prefer),
+            "require" => Ok(Self::Require),
+            "verify-ca" => Ok(Self::VerifyCa),
+            "verify-full" => Ok(Self::VerifyFull),
+            _ => Err(format!("unknown SSL mode '{s}'")),
+        }
+    }
+}
+
+impl From<SslMode> for PgSslMode {
+    fn from(mode: SslMode) -> Self {
+        match mode {
+            SslMode::Disable => PgSslMode::Disable,
+            SslMode::Allow => PgSslMode::Allow,
+            SslMode::Prefer => PgSslMode::Prefer,
+            SslMode::Require => PgSslMode::Require,
+            SslMode::VerifyCa => PgSslMode::VerifyCa,
+            SslMode::VerifyFull => PgSslMode::VerifyFull,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+struct ColumnConfig {
+    // Column each action field is written to, such as `kind = "event"`.
+    // Fields not listed keep their usual column names.
+    #[serde(default)]
+    rename: HashMap<Field, String>,
+
+    // Fields which are not written, for tables with no column for them.
+    #[serde(default)]
+    omit: Vec<Field>,
+
+    // Opt-in fields to write to their usual columns, such as `ordinal`. Opt-in
+    // fields are otherwise only written if they are renamed.
+    #[serde(default)]
+    include: Vec<Field>,
+
+    // Columns given the same value in every row, such as
+    // `application = "harp"`. Values are cast from text by Postgres.
+    #[serde(default)]
+    constants: HashMap<String, String>,
+}
+
+impl ColumnConfig {
+    /// Returns true if any column differs from Harp's own.
+    fn is_mapped(&self) -> bool {
+        self != &Self::default()
+    }
+}
+
+impl Config {
+    /// Attempts to read a given config file. If no file is given, it will
+    /// attempt to read the default config file at `/etc/harp/config.toml`.
+    ///
+    /// Once the file has been parsed, any `HARP_*` environment variables that
+    /// are set will override the matching values from the file. See
+    /// [Config::apply_env] for the full list.
+    ///
+    /// # Example
+    ///
+    /// ```toml
+    /// # config.toml
+    /// host = "127.0.0.1"
+    /// port = 7777
+    /// process_interval = 10
+    ///
+    /// [database]
+    /// name = "harp"
+    /// user = "harp"
+    /// pass = "harp"
+    /// host = "127.0.0.1"
+    /// port = 5432
+    /// ```
+    ///
+    /// See [Config] for more information.
+    pub fn load_from_file<P: AsRef<Path>>(path: Option<P>) -> Result<Self> {
+        let config_path = match path {
+            Some(path) => path.as_ref().to_path_buf(),
+            None => Path::new(super::DEFAULT_CONFIG_PATH).to_path_buf(),
+        };
+
+        let config_file = std::fs::read_to_string(config_path)?;
+
+        Self::load_from_str(&config_file)
+    }
+
+    /// Parses a config from TOML, such as `harp::server::DEFAULT_CONFIG`, then
+    /// applies any `HARP_*` environment variables as `load_from_file` does.
+    pub fn load_from_str(config: &str) -> Result<Self> {
+        let mut config: Config = toml::from_str(config)?;
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Overrides values read from the config file with any matching `HARP_*`
+    /// environment variables. Environment variables always take precedence
+    /// over the file.
+    ///
+    /// | Variable                        | Overrides                          |
+    /// |---------------------------------|------------------------------------|
+    /// | `HARP_HOST`                     | `host`                             |
+    /// | `HARP_PORT`                     | `port`                             |
+    /// | `HARP_PROCESS_INTERVAL`         | `process_interval`                 |
+    /// | `HARP_MAX_PACKET_SIZE`          | `max_packet_size`                  |
+    /// | `HARP_QUEUE_CAPACITY`           | `queue_capacity`                   |
+    /// | `HARP_HOURLY_ROLLUPS`           | `hourly_rollups`                   |
+    /// | `HARP_FLUSH_THRESHOLD_ACTIONS`  | `flush_threshold_actions`          |
+    /// | `HARP_FLUSH_THRESHOLD_BYTES`    | `flush_threshold_bytes`            |
+    /// | `HARP_FLUSH_TIME_BUDGET_MS`     | `flush_time_budget_ms`             |
+    /// | `HARP_LOG_LEVEL`                | `log_level`                        |
+    /// | `HARP_DATABASE_URL`             | `database.url`                     |
+    /// | `DATABASE_URL`                  | `database.url`, as a fallback      |
+    /// | `HARP_DATABASE_NAME`            | `database.name`                    |
+    /// | `HARP_DATABASE_USER`            | `database.user`                    |
+    /// | `HARP_DATABASE_PASS`            | `database.pass`                    |
+    /// | `HARP_DATABASE_PASS_FILE`       | `database.pass_file`               |
+    /// | `HARP_DATABASE_HOST`            | `database.host`                    |
+    /// | `HARP_DATABASE_PORT`            | `database.port`                    |
+    /// | `HARP_DATABASE_MAX_CONNECTIONS` | `database.max_connections`         |
+    /// | `HARP_DATABASE_MIN_CONNECTIONS` | `database.min_connections`         |
+    /// | `HARP_DATABASE_SSL_MODE`        | `database.ssl_mode`                |
+    /// | `HARP_DATABASE_SCHEMA`          | `database.schema`                  |
+    /// | `HARP_DATABASE_TABLE`           | `database.table`                   |
+    fn apply_env(&mut self) -> Result<()> {
+        override_from_env("HARP_HOST", &mut self.host)?;
+        override_from_env("HARP_PORT", &mut self.port)?;
+        override_from_env("HARP_PROCESS_INTERVAL", &mut self.process_interval_secs)?;
+        override_from_env("HARP_MAX_PACKET_SIZE", &mut self.max_packet_size)?;
+        override_from_env("HARP_QUEUE_CAPACITY", &mut self.queue_capacity)?;
+        override_from_env("HARP_HOURLY_ROLLUPS", &mut self.hourly_rollups)?;
+        if let Some(actions) = env_var("HARP_FLUSH_THRESHOLD_ACTIONS")? {
+            self.flush_threshold_actions = Some(actions);
+        }
+        if let Some(bytes) = env_var("HARP_FLUSH_THRESHOLD_BYTES")? {
+            self.flush_threshold_bytes = Some(bytes);
+        }
+        if let Some(ms) = env_var("HARP_FLUSH_TIME_BUDGET_MS")? {
+            self.flush_time_budget_ms = Some(ms);
+        }
+        if let Some(level) = env_var("HARP_LOG_LEVEL")? {
+            self.log_level = Some(level);
+        }
+
+        let database = &mut self.database;
+        if let Some(url) = env_var("HARP_DATABASE_URL")?.or(env_var("DATABASE_URL")?) {
+            database.url = Some(url);
+        }
+        override_from_env("HARP_DATABASE_NAME", &mut database.name)?;
+        override_from_env("HARP_DATABASE_USER", &mut database.user)?;
+        override_from_env("HARP_DATABASE_PASS", &mut database.pass)?;
+        if let Some(path) = env_var("HARP_DATABASE_PASS_FILE")? {
+            database.pass_file = Some(path);
+        }
+        override_from_env("HARP_DATABASE_HOST", &mut database.host)?;
+        override_from_env("HARP_DATABASE_PORT", &mut database.port)?;
+        override_from_env("HARP_DATABASE_MAX_CONNECTIONS", &mut database.max_connections)?;
+        override_from_env("HARP_DATABASE_MIN_CONNECTIONS", &mut database.min_connections)?;
+        if let Some(ssl_mode) = env_var("HARP_DATABASE_SSL_MODE")? {
+            database.ssl_mode = Some(ssl_mode);
+        }
+        override_from_env("HARP_DATABASE_SCHEMA", &mut database.schema)?;
+        override_from_env("HARP_DATABASE_TABLE", &mut database.table)?;
+
+        Ok(())
+    }
+
+    /// Checks values which can't be validated by their types alone.
+    fn validate(&self) -> Result<()> {
+        // Schema and table names are interpolated directly into queries, so
+        // they must be plain identifiers.
+        let rollup_table = format!("{}_hourly", self.database.table);
+        let alert_table = format!("{}_alerts", self.database.table);
+        let aggregate_table = format!("{}_aggregates", self.database.table);
+        let aggregate_keys_table = format!("{}_aggregate_keys", self.database.table);
+        let session_table = format!("{}_sessions", self.database.table);
+        let columns = &self.database.columns;
+        let names = [
+            &self.database.schema,
+            &self.database.table,
+            &rollup_table,
+            &alert_table,
+            &aggregate_table,
+            &aggregate_keys_table,
+            &session_table,
+        ];
+        for name in names
+            .into_iter()
+            .chain(self.database.routes.values())
+            .chain(columns.rename.values())
+            .chain(columns.constants.keys())
+            .chain(&self.detail.indexed_keys)
+        {
+            if !is_valid_identifier(name) {
+                return Err(format!(
+                    "Invalid database identifier \"{name}\": must be lowercase letters, digits, and underscores"
+                )
+                .into());
+            }
+        }
+
+        // Workers beyond the pool's size would only wait for a connection,
+        // and time out as if the database were down.
+        if self.flush_workers.get() > self.database.max_connections.get() as usize {
+            return Err("flush_workers can't exceed database.max_connections".into());
+        }
+
+        if let (Some(min), Some(max)) = (self.batching.min_size, self.batching.max_size) {
+            if min > max {
+                return Err("batching.min_size can't exceed batching.max_size".into());
+            }
+        }
+
+        for proxy in &self.listener.trusted_proxies {
+            if proxy.parse::<IpNetwork>().is_err() {
+                return Err(format!("Invalid listener.trusted_proxies entry \"{proxy}\"").into());
+            }
+        }
+
+        if self.database.pass_file.is_some() && !self.database.pass.is_empty() {
+            return Err("Set either database.pass or database.pass_file, not both".into());
+        }
+
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            return Err("Set both tls.cert and tls.key, or neither".into());
+        }
+
+        if self.tls.client_ca.is_some() && !self.tls.is_enabled() {
+            return Err("tls.client_ca needs tls.cert and tls.key".into());
+        }
+
+        match self.auth.method {
+            AuthMethod::Token if self.auth.tokens.is_empty() && self.auth.tokens_file.is_none() => {
+                return Err("The token auth method needs auth.tokens_file or auth.tokens".into());
+            }
+            AuthMethod::Certificate if self.tls.client_ca.is_none() => {
+                return Err("The certificate auth method needs tls.client_ca".into());
+            }
+            AuthMethod::Hmac if self.auth.key_file.is_none() => {
+                return Err("The hmac auth method needs auth.key_file".into());
+            }
+            _ => {}
+        }
+
+        if self.database.normalize_kinds && self.has_column_mapping() {
+            return Err("database.normalize_kinds can't be used with database.columns".into());
+        }
+
+        if self.signing.required && self.signing.key_file.is_none() {
+            return Err("signing.required needs signing.key_file".into());
+        }
+
+        // Encrypted details have none of their original keys to index.
+        if self.detail.encryption_key_file.is_some() && !self.detail.indexed_keys.is_empty() {
+            return Err("detail.indexed_keys can't be used with detail.encryption_key_file".into());
+        }
+
+        if self.hourly_rollups
+            && [Field::Kind, Field::Created].iter().any(|field| columns.omit.contains(field))
+        {
+            return Err("Hourly rollups need the kind and created columns".into());
+        }
+
+        let mut aggregated = HashSet::new();
+        for rule in &self.aggregates {
+            if !aggregated.insert(&rule.kind) {
+                return Err(
+                    format!("Kind \"{}\" has more than one aggregate rule", rule.kind).into()
+                );
+            }
+
+            if self.database.routes.contains_key(&rule.kind) {
+                return Err(
+                    format!("Kind \"{}\" can't be both routed and aggregated", rule.kind).into()
+                );
+            }
+        }
+
+        for rule in &self.alerts {
+            if rule.action == AlertAction::Webhook && rule.webhook_url.is_none() {
+                return Err(format!("Alert rule \"{}\" has no webhook_url", rule.name).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the settings from a freshly loaded config which are safe to
+    /// change while the daemon is running: the process interval, the flush
+    /// thresholds and time budget, the queue limit, the maximum packet size,
+    /// the connection limits, the GeoIP databases, the subscriber buffer size,
+    /// action expiry, catch-up throttling, the detail size limit, the watchdog,
+    /// and the log level.
+    /// Settings which require a restart are left untouched, and a warning is
+    /// logged if they differ.
+    pub(crate) fn reload_from(&mut self, new: Config) {
+        if new.host != self.host || new.port != self.port {
+            tracing::warn!("Listener address changes require a restart; ignoring");
+        }
+
+        if new.listener.ipv6_only != self.listener.ipv6_only {
+            tracing::warn!("Listener ipv6_only changes require a restart; ignoring");
+        }
+
+        if new.listen != self.listen {
+            tracing::warn!("Listen address changes require a restart; ignoring");
+        }
+
+        if new.logging != self.logging {
+            tracing::warn!("Logging changes require a restart; ignoring");
+        }
+
+        if new.admin != self.admin {
+            tracing::warn!("Admin socket changes require a restart; ignoring");
+        }
+
+        if new.subscriptions.port != self.subscriptions.port {
+            tracing::warn!("Subscription port changes require a restart; ignoring");
+        }
+
+        if new.validation.schema_dir != self.validation.schema_dir {
+            tracing::warn!("Schema directory changes require a restart; ignoring");
+        }
+
+        // Kinds given to `ServerBuilder::known_kinds` aren't in the file, so
+        // only files which list their own are compared.
+        if new.validation.known_kinds.is_some()
+            && new.validation.known_kinds != self.validation.known_kinds
+        {
+            tracing::warn!("Known kind changes require a restart; ignoring");
+        }
+
+        if new.database != self.database {
+            tracing::warn!("Database changes require a restart; ignoring");
+        }
+
+        if new.rate_counters != self.rate_counters {
+            tracing::warn!("Rate counter changes require a restart; ignoring");
+        }
+
+        if new.alerts != self.alerts {
+            tracing::warn!("Alert rule changes require a restart; ignoring");
+        }
+
+        if new.aggregates != self.aggregates {
+            tracing::warn!("Aggregate rule changes require a restart; ignoring");
+        }
+
+        if new.hourly_rollups != self.hourly_rollups {
+            tracing::warn!("Hourly rollup changes require a restart; ignoring");
+        }
+
+        if new.sessions != self.sessions {
+            tracing::warn!("Session tracking changes require a restart; ignoring");
+        }
+
+        if new.detail.indexed_keys != self.detail.indexed_keys {
+            tracing::warn!("Detail index changes require a restart; ignoring");
+        }
+
+        if new.detail.encryption_key_file != self.detail.encryption_key_file {
+            tracing::warn!("Detail encryption changes require a restart; ignoring");
+        }
+
+        if new.spill != self.spill {
+            tracing::warn!("Spill changes require a restart; ignoring");
+        }
+
+        if new.tls != self.tls {
+            tracing::warn!("TLS changes require a restart; ignoring");
+        }
+
+        if new.auth != self.auth {
+            tracing::warn!("Auth changes require a restart; ignoring");
+        }
+
+        if new.signing != self.signing {
+            tracing::warn!("Frame signing changes require a restart; ignoring");
+        }
+
+        if new.listener.named_pipe != self.listener.named_pipe {
+            tracing::warn!("Named pipe changes require a restart; ignoring");
+        }
+
+        self.process_interval_secs = new.process_interval_secs;
+        self.flush_threshold_actions = new.flush_threshold_actions;
+        self.flush_threshold_bytes = new.flush_threshold_bytes;
+        self.flush_time_budget_ms = new.flush_time_budget_ms;
+        // The pool keeps its size until a restart, so the workers can't
+        // outgrow it.
+        if new.flush_workers.get() > self.database.max_connections.get() as usize {
+            tracing::warn!("flush_workers exceeds the running pool's max_connections; ignoring");
+        } else {
+            self.flush_workers = new.flush_workers;
+        }
+        self.max_queued_actions = new.max_queued_actions;
+        self.max_queued_bytes = new.max_queued_bytes;
+        self.max_packet_size = new.max_packet_size;
+        self.listener = ListenerConfig {
+            ipv6_only: self.listener.ipv6_only,
+            named_pipe: self.listener.named_pipe.take(),
+            ..new.listener
+        };
+        self.geoip = new.geoip;
+        self.subscriptions.buffer = new.subscriptions.buffer;
+        self.expiry = new.expiry;
+        self.catch_up = new.catch_up;
+        self.detail.max_size = new.detail.max_size;
+        self.detail.oversize = new.detail.oversize;
+        self.watchdog = new.watchdog;
+        self.batching = new.batching;
+        self.log_level = new.log_level;
+    }
+
+    /// Returns the options for connecting to the database. Settings in the
+    /// `[database]` section are applied on top of a connection string, if one
+    /// is given. The password file, if any, is read each time.
+    pub(crate) fn get_connect_options(&self) -> Result<PgConnectOptions> {
+        let database = &self.database;
+        let mut options = match &database.url {
+            Some(url) => url.parse::<PgConnectOptions>()?,
+            None => PgConnectOptions::new()
+                .host(&database.host.to_string())
+                .port(u16::try_from(database.port)?)
+                .username(&database.user)
+                .password(&database.pass)
+                .database(&database.name),
+        };
+
+        if let Some(name) = &database.application_name {
+            options = options.application_name(name);
+        } else if options.get_application_name().is_none() {
+            options = options.application_name(DEFAULT_APPLICATION_NAME);
+        }
+
+        if let Some(ssl_mode) = database.ssl_mode {
+            options = options.ssl_mode(ssl_mode.into());
+        }
+
+        if let Some(path) = &database.ssl_root_cert {
+            options = options.ssl_root_cert(path);
+        }
+
+        if let Some(path) = &database.pass_file {
+            options = options.password(&read_secret(path)?);
+        }
+
+        if let Some(ms) = database.statement_timeout_ms {
+            options = options.options([("statement_timeout", ms.to_string())]);
+        }
+
+        Ok(options)
+    }
+
+    /// Returns true if the database password is read from a file.
+    pub(crate) fn has_pass_file(&self) -> bool {
+        self.database.pass_file.is_some()
+    }
+
+    /// Returns the options for the database connection pool.
+    pub(crate) fn get_pool_options(&self) -> PgPoolOptions {
+        let database = &self.database;
+        let mut options = PgPoolOptions::new()
+            .max_connections(database.max_connections.get())
+            .min_connections(database.min_connections.min(database.max_connections.get()));
+
+        if let Some(secs) = database.acquire_timeout_secs {
+            options = options.acquire_timeout(Duration::from_secs(secs.get()));
+        }
+
+        options
+    }
+
+    /// Returns the schema that actions are written to.
+    pub(crate) fn get_schema(&self) -> &str {
+        &self.database.schema
+    }
+
+    /// Returns the table that actions are written to.
+    pub(crate) fn get_table(&self) -> &str {
+        &self.database.table
+    }
+
+    /// Returns the schema-qualified name of the actions table.
+    pub(crate) fn get_qualified_table(&self) -> String {
+        format!("{}.{}", self.database.schema, self.database.table)
+    }
+
+    /// Returns the schema-qualified name of the table actions of `kind` are
+    /// written to.
+    pub(crate) fn get_table_for(&self, kind: &str) -> String {
+        let table = self.database.routes.get(kind).unwrap_or(&self.database.table);
+        format!("{}.{}", self.database.schema, table)
+    }
+
+    /// Returns the tables that specific kinds are routed to, each once and
+    /// without the actions table.
+    pub(crate) fn get_routed_tables(&self) -> Vec<&str> {
+        let tables: BTreeSet<&str> = self.database.routes.values().map(String::as_str).collect();
+        tables.into_iter().filter(|&table| table != self.database.table).collect()
+    }
+
+    /// Returns the schema-qualified names of the actions table and every
+    /// routed table.
+    pub(crate) fn get_qualified_tables(&self) -> Vec<String> {
+        std::iter::once(self.get_table())
+            .chain(self.get_routed_tables())
+            .map(|table| format!("{}.{table}", self.database.schema))
+            .collect()
+    }
+
+    /// Returns true if actions are written to columns other than Harp's own,
+    /// in which case the table is not Harp's to migrate.
+    pub(crate) fn has_column_mapping(&self) -> bool {
+        self.database.columns.is_mapped()
+    }
+
+    /// Returns the columns actions are written to.
+    pub(crate) fn get_column_mapping(&self) -> ColumnMapping {
+        let columns = &self.database.columns;
+
+        // Harp's own tables have a column for every field, but an existing
+        // table only has the opt-in ones it asks for.
+        let mut mapping =
+            if columns.is_mapped() { ColumnMapping::default() } else { ColumnMapping::all() };
+        for &field in &columns.include {
+            mapping = mapping.include(field);
+        }
+        for (&field, column) in &columns.rename {
+            mapping = mapping.rename(field, column);
+        }
+        for &field in &columns.omit {
+            mapping = mapping.omit(field);
+        }
+
+        if let Some(kinds_table) = self.get_kinds_table() {
+            mapping = mapping.normalize_kinds(&kinds_table);
+        }
+
+        // Sorted, so the rendered statements are the same on every start.
+        let mut constants = columns.constants.iter().collect::<Vec<_>>();
+        constants.sort();
+        constants
+            .into_iter()
+            .fold(mapping, |mapping, (column, value)| mapping.constant(column, value))
+    }
+
+    /// Renders the insert statements for the actions table and every routed
+    /// table. The detail encryption key is read from its file here.
+    pub(crate) fn get_insert_statements(&self) -> Result<InsertStatements> {
+        let rollup_table = self.hourly_rollups.then(|| self.get_rollup_table());
+        let statements = InsertStatements::with_columns(
+            &self.get_qualified_table(),
+            rollup_table.as_deref(),
+            self.get_column_mapping(),
+        );
+
+        let statements =
+            self.database.routes.keys().fold(statements, |statements, kind| {
+                statements.route(kind, &self.get_table_for(kind))
+            });
+
+        let statements = self.aggregates.iter().fold(statements, |statements, rule| {
+            statements.aggregate(&rule.kind, Duration::from_secs(rule.window_secs.get()))
+        });
+
+        let statements = match self.get_detail_cipher()? {
+            Some(cipher) => statements.encrypt_detail(cipher),
+            None => statements,
+        };
+
+        let statements = if self.sessions { statements.track_sessions() } else { statements };
+
+        Ok(if self.database.sort_inserts { statements.sort_by_created() } else { statements })
+    }
+
+    /// Returns the schema-qualified name of the hourly rollup table.
+    pub(crate) fn get_rollup_table(&self) -> String {
+        format!("{}_hourly", self.get_qualified_table())
+    }
+
+    /// Returns the schema-qualified name of the kinds table, if kinds are
+    /// normalized.
+    pub(crate) fn get_kinds_table(&self) -> Option<String> {
+        self.database.normalize_kinds.then(|| format!("{}.kinds", self.database.schema))
+    }
+
+    /// Returns the schema-qualified name of the alerts table.
+    pub(crate) fn get_alert_table(&self) -> String {
+        format!("{}_alerts", self.get_qualified_table())
+    }
+
+    /// Returns a `SocketAddr` for the Harp server.
+    pub(crate) fn get_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+
+    /// Returns a `SocketAddr` for subscribers, if subscriptions are enabled.
+    pub(crate) fn get_subscription_addr(&self) -> Option<SocketAddr> {
+        self.subscriptions.port.map(|port| SocketAddr::new(self.host, port))
+    }
+
+    /// Returns the number of actions buffered for each subscriber.
+    pub(crate) fn get_subscriber_buffer(&self) -> usize {
+        self.subscriptions.buffer.map_or(DEFAULT_SUBSCRIBER_BUFFER, NonZeroUsize::get)
+    }
+
+    /// Returns the window over which actions are counted for each (ip, id)
+    /// pair, if rate counters are enabled.
+    pub(crate) fn get_rate_window(&self) -> Option<Duration> {
+        self.rate_counters.window_secs.map(|secs| Duration::from_secs(secs.get()))
+    }
+
+    /// Returns the number of top talkers logged at the end of each window.
+    pub(crate) fn get_top_talkers(&self) -> usize {
+        self.rate_counters.top.map_or(DEFAULT_TOP_TALKERS, NonZeroUsize::get)
+    }
+
+    /// Returns the maximum age of an arriving action of the given kind, if
+    /// actions of that kind expire.
+    pub(crate) fn get_max_age(&self, kind: &str) -> Option<Duration> {
+        self.expiry
+            .kinds
+            .get(kind)
+            .or(self.expiry.max_age_secs.as_ref())
+            .map(|secs| Duration::from_secs(secs.get()))
+    }
+
+    /// Returns the age past which an arriving action is caught up behind live
+    /// ones, if catch-up throttling is enabled.
+    pub(crate) fn get_catch_up_age(&self) -> Option<Duration> {
+        self.catch_up.age_secs.map(|secs| Duration::from_secs(secs.get()))
+    }
+
+    /// Returns the most caught up actions written on each flush.
+    pub(crate) fn get_catch_up_per_flush(&self) -> usize {
+        self.catch_up.per_flush.map_or(DEFAULT_CATCH_UP_PER_FLUSH, NonZeroUsize::get)
+    }
+
+    /// Returns the most caught up actions held in the queue at once.
+    pub(crate) fn get_catch_up_max_queued(&self) -> usize {
+        self.catch_up.max_queued.map_or(DEFAULT_CATCH_UP_MAX_QUEUED, NonZeroUsize::get)
+    }
+
+    /// Returns the maximum size of an action's detail in bytes, and what
+    /// happens to actions which exceed it, if details are limited.
+    pub(crate) fn get_detail_limit(&self) -> Option<(usize, OversizePolicy)> {
+        self.detail.max_size.map(|max_size| (max_size.get(), self.detail.oversize))
+    }
+
+    /// Returns the authenticator for the configured `[auth]` method. Token
+    /// and HMAC key files are read here.
+    pub(crate) fn get_authenticator(&self) -> Result<Box<dyn Authenticator>> {
+        let auth = &self.auth;
+        let authenticator: Box<dyn Authenticator> = match auth.method {
+            AuthMethod::None => Box::new(AllowAll),
+            AuthMethod::Token => match &auth.tokens_file {
+                Some(path) => {
+                    let file = read_secret(path)?;
+                    let tokens = parse_tokens(&file, path)?;
+                    let inline =
+                        auth.tokens.iter().map(|(name, token)| (name.as_str(), token.as_str()));
+                    Box::new(StaticTokens::new(inline.chain(tokens)))
+                }
+                None => Box::new(StaticTokens::new(&auth.tokens)),
+            },
+            AuthMethod::Certificate => Box::new(ClientCertificate),
+            AuthMethod::Hmac => {
+                let path = auth.key_file.as_deref().ok_or("auth.key_file is not set")?;
+                let secs = auth.max_skew_secs.map_or(DEFAULT_MAX_SKEW_SECS, u64::from);
+                let key = read_secret(path)?;
+                Box::new(HmacHandshake::new(key.as_bytes(), Duration::from_secs(secs)))
+            }
+        };
+
+        Ok(authenticator)
+    }
+
+    /// Returns the key services sign their frames with, read from its file, if
+    /// one is set.
+    pub(crate) fn get_frame_key(&self) -> Result<Option<Zeroizing<Vec<u8>>>> {
+        match &self.signing.key_file {
+            Some(path) => Ok(Some(Zeroizing::new(read_secret(path)?.as_bytes().to_vec()))),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the cipher details are encrypted with before they are stored,
+    /// read from its key file, if one is set.
+    pub(crate) fn get_detail_cipher(&self) -> Result<Option<DetailCipher>> {
+        match &self.detail.encryption_key_file {
+            Some(path) => Ok(Some(DetailCipher::from_base64(&read_secret(path)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns how long the queue processor may go without making progress,
+    /// on top of the process interval, before it is restarted.
+    pub(crate) fn get_stall_timeout(&self) -> Duration {
+        let secs = self.watchdog.stall_timeout_secs.map_or(DEFAULT_STALL_TIMEOUT_SECS, u64::from);
+        Duration::from_secs(secs)
+    }
+
+    /// Returns true if the server should listen before the database is
+    /// ready.
+    pub(crate) fn connects_in_background(&self) -> bool {
+        self.database.connect_in_background
+    }
+
+    /// Returns true if migrations should create the query indexes.
+    pub(crate) fn manages_indexes(&self) -> bool {
+        self.database.manage_indexes
+    }
+
+    /// Returns the interval in seconds between processing the queue.
+    pub(crate) fn get_process_interval_secs(&self) -> u64 {
+        self.process_interval_secs.into()
+    }
+
+    /// Returns the queue sizes which trigger an early flush.
+    pub(crate) fn get_flush_thresholds(&self) -> FlushThresholds {
+        FlushThresholds {
+            actions: self.flush_threshold_actions.map(NonZeroUsize::get),
+            bytes: self.flush_threshold_bytes.map(NonZeroUsize::get),
+        }
+    }
+
+    /// Returns the maximum time to spend draining the queue on each flush.
+    pub(crate) fn get_flush_time_budget(&self) -> Option<Duration> {
+        self.flush_time_budget_ms.map(|ms| Duration::from_millis(ms.into()))
+    }
+
+    /// Returns the number of batches inserted at once on each flush.
+    pub(crate) fn get_flush_workers(&self) -> usize {
+        self.flush_workers.into()
+    }
+
+    /// Returns the limits on how many actions are inserted per transaction.
+    pub(crate) fn get_batch_sizing(&self) -> BatchSizing {
+        let max = self.batching.max_size.map_or(LIMIT, NonZeroUsize::get).min(LIMIT);
+        let min = self.batching.min_size.map_or(DEFAULT_MIN_BATCH_SIZE, NonZeroUsize::get);
+
+        BatchSizing {
+            min: min.min(max),
+            max,
+            target_latency: self
+                .batching
+                .target_latency_ms
+                .map(|ms| Duration::from_millis(ms.into())),
+        }
+    }
+
+    /// Returns where the queue spills to disk, if it does.
+    pub(crate) fn get_spill_options(&self) -> Option<SpillOptions> {
+        Some(SpillOptions {
+            dir: self.spill.dir.clone()?,
+            max_bytes: self.spill.max_bytes.map(NonZeroU64::get),
+            segment_actions: self
+                .spill
+                .segment_actions
+                .map_or(DEFAULT_SPILL_SEGMENT_ACTIONS, NonZeroUsize::get),
+        })
+    }
+
+    /// Returns the capacity of the channel feeding the queue processor.
+    pub(crate) fn get_queue_capacity(&self) -> usize {
+        self.queue_capacity.into()
+    }
+
+    /// Returns the most actions, and approximate bytes, the queue may hold.
+    pub(crate) fn get_queue_limits(&self) -> QueueLimits {
+        QueueLimits {
+            actions: self.max_queued_actions.map(NonZeroUsize::get),
+            bytes: self.max_queued_bytes.map(NonZeroUsize::get),
+        }
+    }
+
+    /// Returns the duration after which an idle connection is closed, if any.
+    pub(crate) fn get_idle_timeout(&self) -> Option<Duration> {
+        self.listener.idle_timeout_secs.map(|secs| Duration::from_secs(secs.into()))
+    }
+
+    /// Returns true if a connection from `ip` may send a PROXY header, which
+    /// is any connection when no proxies are listed.
+    pub(crate) fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        let proxies = &self.listener.trusted_proxies;
+        proxies.is_empty()
+            || proxies
+                .iter()
+                .filter_map(|proxy| proxy.parse::<IpNetwork>().ok())
+                .any(|network| network.contains(ip))
+    }
+
+    /// Returns the maximum packet size in bytes. If the configured value is
+    /// smaller than the minimum packet size, the minimum is used instead.
+    pub(crate) fn get_max_packet_size(&self) -> usize {
+        self.max_packet_size.max(MIN_PACKET_SIZE)
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_packet_size() -> usize {
+    1024
+}
+
+fn default_flush_workers() -> NonZeroUsize {
+    NonZeroUsize::MIN
+}
+
+fn default_queue_capacity() -> NonZeroUsize {
+    NonZeroUsize::new(10_000).expect("queue capacity should be non-zero")
+}
+
+fn default_database_name() -> String {
+    "harp".to_string()
+}
+
+fn default_database_user() -> String {
+    "harp".to_string()
+}
+
+fn default_database_host() -> IpAddr {
+    IpAddr::from([127, 0, 0, 1])
+}
+
+fn default_database_port() -> i16 {
+    5432
+}
+
+fn default_schema() -> String {
+    "harp".to_string()
+}
+
+fn default_table() -> String {
+    "actions".to_string()
+}
+
+/// Reads and parses an environment variable. Returns `Ok(None)` if the variable
+/// is unset, and an error if it is set but cannot be parsed.
+fn env_var<T: FromStr>(key: &str) -> Result<Option<T>> {
+    match std::env::var(key) {
+        Ok(value) => match value.parse::<T>() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(format!("Invalid value for environment variable {key}: {value}").into()),
+        },
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(format!("Invalid value for environment variable {key}: {e}").into()),
+    }
+}
+
+/// Reads a secret from a file, dropping the trailing newline most tools write.
+/// The contents are zeroed once the returned value is dropped.
+pub fn read_secret(path: &Path) -> Result<Zeroizing<String>> {
+    let mut secret = Zeroizing::new(
+        std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read secret from {}: {e}", path.display()))?,
+    );
+    let len = secret.trim_end_matches(['\r', '\n']).len();
+    secret.truncate(len);
+
+    Ok(secret)
+}
+
+/// Parses the `name = token` pairs of a tokens file. Malformed lines are
+/// reported by number only, so that a token never ends up in a log.
+fn parse_tokens<'a>(file: &'a str, path: &Path) -> Result<Vec<(&'a str, &'a str)>> {
+    let mut tokens = Vec::new();
+    for (number, line) in file.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_once('=') {
+            Some((name, token)) if !name.trim().is_empty() && !token.trim().is_empty() => {
+                tokens.push((name.trim(), token.trim()));
+            }
+            _ => {
+                let path = path.display();
+                return Err(
+                    format!("Line {} of {path} is not a `name = token` pair", number + 1).into()
+                );
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Replaces `target` with the parsed value of an environment variable, if set.
+fn override_from_env<T: FromStr>(key: &str, target: &mut T) -> Result<()> {
+    if let Some(value) = env_var(key)? {
+        *target = value;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{action::Action, HarpId};
+
+/// Counts actions per identifier over a sliding window, to spot identifiers
+/// which send far more actions than normal. Each identifier only holds two
+/// counters, so tracking every player is cheap.
+pub(crate) struct RateCounters {
+    window: Duration,
+    /// The rate above which actions are tagged as `rate_exceeded`.
+    threshold: Option<u64>,
+    counters: Mutex<HashMap<HarpId, Counter>>,
+}
+
+/// A sliding window counter. The count for the previous window is weighted by
+/// how much of it still overlaps the sliding window.
+struct Counter {
+    window_start: Instant,
+    current: u64,
+    previous: u64,
+}
+
+impl Counter {
+    fn new(now: Instant) -> Self {
+        Self { window_start: now, current: 0, previous: 0 }
+    }
+
+    /// Moves the counter forward to the window containing `now`.
+    fn advance(&mut self, now: Instant, window: Duration) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < window {
+            return;
+        }
+
+        self.previous = if elapsed < window * 2 { self.current } else { 0 };
+        self.current = 0;
+        self.window_start =
+            now - Duration::from_nanos((elapsed.as_nanos() % window.as_nanos()) as u64);
+    }
+
+    /// Returns the estimated number of actions within the last window.
+    fn rate(&self, now: Instant, window: Duration) -> u64 {
+        let elapsed = now.duration_since(self.window_start).as_secs_f64() / window.as_secs_f64();
+        let overlap = (1.0 - elapsed).max(0.0);
+
+        self.current + (self.previous as f64 * overlap) as u64
+    }
+}
+
+impl RateCounters {
+    pub(crate) fn new(window: Duration, threshold: Option<u64>) -> Self {
+        Self { window, threshold, counters: Mutex::default() }
+    }
+
+    /// Counts an action against its identifier, tagging it if the identifier
+    /// has exceeded the threshold within the window.
+    pub(crate) fn observe(&self, action: &mut Action) {
+        let now = Instant::now();
+
+        let rate = {
+            let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+            let counter =
+                counters.entry((action.addr, action.id)).or_insert_with(|| Counter::new(now));
+            counter.advance(now, self.window);
+            counter.current += 1;
+            counter.rate(now, self.window)
+        };
+
+        if self.threshold.is_some_and(|threshold| rate > threshold) {
+            action.rate_exceeded = true;
+        }
+    }
+
+    /// Returns the `n` identifiers with the most actions within the window,
+    /// busiest first, and forgets identifiers which have gone quiet.
+    pub(crate) fn top_talkers(&self, n: usize) -> Vec<(HarpId, u64)> {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+
+        counters.retain(|_, counter| {
+            counter.advance(now, self.window);
+            counter.rate(now, self.window) > 0
+        });
+
+        let mut talkers: Vec<_> =
+            counters.iter().map(|(id, counter)| (*id, counter.rate(now, self.window))).collect();
+        talkers.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        talkers.truncate(n);
+
+        talkers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+    use crate::test_fixtures::{Player, TestKind};
+
+    #[test]
+    fn tag_and_rank_busy_identifiers() {
+        let counters = RateCounters::new(Duration::from_secs(60), Some(2));
+
+        for _ in 0..2 {
+            let mut action = Action::new(TestKind("position_update"), &Player(1));
+            counters.observe(&mut action);
+            assert!(!action.rate_exceeded);
+        }
+
+        let mut action = Action::new(TestKind("position_update"), &Player(1));
+        counters.observe(&mut action);
+        assert!(action.rate_exceeded);
+
+        counters.observe(&mut Action::new(TestKind("position_update"), &Player(2)));
+
+        let talkers = counters.top_talkers(1);
+        assert_eq!(talkers, vec![((IpAddr::from([127, 0, 0, 1]), 1), 3)]);
+    }
+}
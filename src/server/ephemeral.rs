@@ -0,0 +1,146 @@
+use std::{path::Path, str::FromStr};
+
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    SqlitePool,
+};
+
+use crate::{action::Action, server::sql::ACTION_COLUMNS, Result};
+
+/// The actions table, with the same columns as in Postgres. SQLite has no
+/// inet or timestamp types, so addresses and times are stored as text.
+const CREATE_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS actions (
+    id              integer primary key,
+    unique_id       integer not null,
+    ip_address      text    not null,
+    kind            text    not null,
+    detail          text,
+    created         text    not null,
+    source          text,
+    idempotency_key integer,
+    sample_rate     real,
+    country         text,
+    asn             integer,
+    rate_exceeded   boolean default false not null,
+    ordinal         integer,
+    session_id      integer,
+    trace_id        text
+)";
+
+const CREATE_IDEMPOTENCY_INDEX: &str = "
+CREATE UNIQUE INDEX IF NOT EXISTS actions_idempotency_key_idx
+    ON actions (unique_id, idempotency_key)";
+
+/// An in-process SQLite database which an ephemeral `harpd` stores actions in,
+/// so that demos and CI need no database server. It is held in memory, and
+/// lost on exit, unless it is given a file.
+///
+/// Every action goes to one `actions` table; the `[database]` table settings
+/// are ignored, as are rollups, aggregates, and sessions.
+#[derive(Debug, Clone)]
+pub(crate) struct EphemeralStore {
+    pool: SqlitePool,
+    insert: String,
+}
+
+impl EphemeralStore {
+    /// Opens the database at `path`, creating it if needed, or a new one in
+    /// memory if unset, and creates the actions table.
+    pub(crate) async fn open(path: Option<&Path>) -> Result<Self> {
+        let options = match path {
+            Some(path) => SqliteConnectOptions::new().filename(path).create_if_missing(true),
+            None => SqliteConnectOptions::from_str("sqlite::memory:")?,
+        };
+
+        // Every connection to `sqlite::memory:` opens a database of its own,
+        // so the pool keeps a single connection for as long as it runs.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await?;
+
+        sqlx::query(CREATE_TABLE).execute(&pool).await?;
+        sqlx::query(CREATE_IDEMPOTENCY_INDEX).execute(&pool).await?;
+
+        // Resent actions are ignored, as in Postgres.
+        let placeholders = vec!["?"; ACTION_COLUMNS.len()].join(", ");
+        let insert = format!(
+            "INSERT OR IGNORE INTO actions ({}) VALUES ({placeholders})",
+            ACTION_COLUMNS.join(", ")
+        );
+
+        Ok(Self { pool, insert })
+    }
+
+    /// Inserts actions in a single transaction, returning how many were
+    /// stored. Details are stored as given, even if encryption is configured.
+    pub(crate) async fn insert<'a>(
+        &self,
+        actions: impl IntoIterator<Item = &'a Action>,
+    ) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut stored = 0;
+
+        // Bound in the order of `ACTION_COLUMNS`.
+        for action in actions {
+            let result = sqlx::query(&self.insert)
+                .bind(i64::from(action.id))
+                .bind(action.addr.to_string())
+                .bind(action.kind.as_ref())
+                .bind(action.detail.as_ref().map(ToString::to_string))
+                .bind(action.created)
+                .bind(action.source.as_deref())
+                .bind(action.idempotency_key.map(|key| key as i64))
+                .bind(action.sample_rate)
+                .bind(action.country.as_deref())
+                .bind(action.asn.map(i64::from))
+                .bind(action.rate_exceeded)
+                .bind(action.ordinal.map(|ordinal| ordinal as i64))
+                .bind(action.session_id.map(|id| id as i64))
+                .bind(action.trace_id.as_deref())
+                .execute(&mut *tx)
+                .await?;
+            stored += result.rows_affected();
+        }
+
+        tx.commit().await?;
+
+        Ok(stored)
+    }
+
+    /// Returns the number of actions stored.
+    #[cfg(test)]
+    async fn count(&self) -> Result<i64> {
+        let count: i64 =
+            sqlx::query_scalar("SELECT count(*) FROM actions").fetch_one(&self.pool).await?;
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{Player, TestKind};
+
+    #[tokio::test]
+    async fn store_actions_in_memory() {
+        let store = EphemeralStore::open(None).await.unwrap();
+
+        let mut login = Action::new(TestKind("login"), &Player(1));
+        login.idempotency_key = Some(1);
+        let logout = Action::new(TestKind("logout"), &Player(2));
+        assert_eq!(store.insert([&login, &logout]).await.unwrap(), 2);
+
+        // Resent actions aren't stored twice.
+        assert_eq!(store.insert([&login]).await.unwrap(), 0);
+        assert_eq!(store.count().await.unwrap(), 2);
+
+        // Each store gets a database of its own.
+        let other = EphemeralStore::open(None).await.unwrap();
+        assert_eq!(other.count().await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,429 @@
+use std::{fmt::Display, io::Write, net::IpAddr, path::Path, str::FromStr, sync::Arc};
+
+use arrow_array::{
+    builder::{
+        BooleanBuilder, Float32Builder, Int32Builder, Int64Builder, StringBuilder,
+        TimestampMicrosecondBuilder,
+    },
+    ArrayRef, RecordBatch,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use futures_util::TryStreamExt;
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use sqlx::{types::ipnetwork::IpNetwork, FromRow, PgPool};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{encryption::DetailCipher, server::kinds::kind_expr, Result};
+
+/// Number of rows buffered into each Parquet row group.
+const PARQUET_BATCH_ROWS: usize = 8192;
+
+/// The file formats actions can be exported to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::Jsonl),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(format!("unknown export format '{s}'; expected csv, jsonl, or parquet")),
+        }
+    }
+}
+
+/// Restricts which stored actions are exported. Every action is exported if
+/// no fields are set.
+#[derive(Debug, Default, Clone)]
+pub struct ExportFilter {
+    /// Only export actions created at or after this time.
+    pub from: Option<OffsetDateTime>,
+    /// Only export actions created before this time.
+    pub to: Option<OffsetDateTime>,
+    /// Only export actions of this kind.
+    pub kind: Option<String>,
+}
+
+/// A stored action, as read back from the database.
+#[derive(Debug, FromRow)]
+struct ExportRow {
+    id: i32,
+    unique_id: i64,
+    ip_address: IpNetwork,
+    kind: String,
+    detail: Option<serde_json::Value>,
+    created: OffsetDateTime,
+    source: Option<String>,
+    idempotency_key: Option<i64>,
+    sample_rate: Option<f32>,
+    country: Option<String>,
+    asn: Option<i64>,
+    rate_exceeded: bool,
+    ordinal: Option<i64>,
+    session_id: Option<i64>,
+    trace_id: Option<String>,
+}
+
+impl ExportRow {
+    fn ip(&self) -> IpAddr {
+        self.ip_address.ip()
+    }
+
+    fn detail(&self) -> Option<String> {
+        self.detail.as_ref().map(ToString::to_string)
+    }
+}
+
+/// Column names, in the order every format writes them.
+const EXPORT_COLUMNS: &[&str] = &[
+    "id",
+    "unique_id",
+    "ip_address",
+    "kind",
+    "detail",
+    "created",
+    "source",
+    "idempotency_key",
+    "sample_rate",
+    "country",
+    "asn",
+    "rate_exceeded",
+    "ordinal",
+    "session_id",
+    "trace_id",
+];
+
+/// Streams the actions in `table` matching `filter` into `out`, returning the
+/// number of actions written. Rows are written as they are read, so exports
+/// of any size only hold a single row (or Parquet row group) in memory.
+///
+/// If kinds are normalized, each row's kind is looked up in `kinds_table`.
+/// Encrypted details are decrypted with `cipher` if one is given, and written
+/// as they are stored otherwise, or if they can't be decrypted with it.
+pub(crate) async fn export(
+    pg: &PgPool,
+    table: &str,
+    kinds_table: Option<&str>,
+    filter: &ExportFilter,
+    format: ExportFormat,
+    out: &Path,
+    cipher: Option<&DetailCipher>,
+) -> Result<u64> {
+    let file = std::io::BufWriter::new(std::fs::File::create(out)?);
+    let mut writer: Box<dyn RowWriter> = match format {
+        ExportFormat::Csv => Box::new(CsvWriter::new(file)?),
+        ExportFormat::Jsonl => Box::new(JsonlWriter(file)),
+        ExportFormat::Parquet => Box::new(ParquetWriter::new(file)?),
+    };
+
+    // Normalized kinds are filtered by id, so the kind index is still used.
+    let (columns, kind_filter) = match kinds_table {
+        Some(kinds_table) => (
+            EXPORT_COLUMNS
+                .iter()
+                .map(|&column| match column {
+                    "kind" => format!("{} AS kind", kind_expr(kinds_table)),
+                    column => column.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+            format!("(kind = $3 OR kind_id = (SELECT id FROM {kinds_table} WHERE kind = $3))"),
+        ),
+        None => (EXPORT_COLUMNS.join(", "), "kind = $3".to_string()),
+    };
+
+    let query = format!(
+        "SELECT {columns} FROM {table} AS actions \
+         WHERE ($1::timestamptz IS NULL OR created >= $1) \
+         AND ($2::timestamptz IS NULL OR created < $2) \
+         AND ($3::text IS NULL OR {kind_filter}) \
+         ORDER BY id"
+    );
+    let mut rows = sqlx::query_as::<_, ExportRow>(&query)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.kind.as_deref())
+        .fetch(pg);
+
+    let (mut count, mut undecrypted) = (0, 0);
+    while let Some(mut row) = rows.try_next().await? {
+        // A detail written under another key, or damaged, is kept as stored
+        // rather than failing the whole export.
+        if let (Some(cipher), Some(detail)) = (cipher, &row.detail) {
+            match cipher.decrypt(detail) {
+                Ok(detail) => row.detail = Some(detail),
+                Err(e) => {
+                    tracing::warn!(id = row.id, "Exporting a detail as stored: {e}");
+                    undecrypted += 1;
+                }
+            }
+        }
+
+        writer.write(&row)?;
+        count += 1;
+    }
+
+    writer.finish()?;
+
+    if undecrypted > 0 {
+        tracing::warn!("{undecrypted} details couldn't be decrypted, and were exported as stored");
+    }
+
+    Ok(count)
+}
+
+/// Writes rows in one of the export formats.
+trait RowWriter: Send {
+    fn write(&mut self, row: &ExportRow) -> Result<()>;
+
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+struct CsvWriter<W: Write>(W);
+
+impl<W: Write> CsvWriter<W> {
+    fn new(mut out: W) -> Result<Self> {
+        writeln!(out, "{}", EXPORT_COLUMNS.join(","))?;
+        Ok(Self(out))
+    }
+}
+
+impl<W: Write + Send> RowWriter for CsvWriter<W> {
+    fn write(&mut self, row: &ExportRow) -> Result<()> {
+        let fields = [
+            row.id.to_string(),
+            row.unique_id.to_string(),
+            row.ip().to_string(),
+            csv_field(&row.kind),
+            row.detail().as_deref().map(csv_field).unwrap_or_default(),
+            row.created.format(&Rfc3339)?,
+            row.source.as_deref().map(csv_field).unwrap_or_default(),
+            optional(row.idempotency_key),
+            optional(row.sample_rate),
+            row.country.as_deref().map(csv_field).unwrap_or_default(),
+            optional(row.asn),
+            row.rate_exceeded.to_string(),
+            optional(row.ordinal),
+            optional(row.session_id),
+            row.trace_id.as_deref().map(csv_field).unwrap_or_default(),
+        ];
+
+        writeln!(self.0, "{}", fields.join(","))?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+/// Quotes a CSV field if it contains a delimiter, quote, or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn optional<T: Display>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+struct JsonlWriter<W: Write>(W);
+
+impl<W: Write + Send> RowWriter for JsonlWriter<W> {
+    fn write(&mut self, row: &ExportRow) -> Result<()> {
+        let value = serde_json::json!({
+            "id": row.id,
+            "unique_id": row.unique_id,
+            "ip_address": row.ip(),
+            "kind": row.kind,
+            "detail": row.detail,
+            "created": row.created.format(&Rfc3339)?,
+            "source": row.source,
+            "idempotency_key": row.idempotency_key,
+            "sample_rate": row.sample_rate,
+            "country": row.country,
+            "asn": row.asn,
+            "rate_exceeded": row.rate_exceeded,
+            "ordinal": row.ordinal,
+            "session_id": row.session_id,
+            "trace_id": row.trace_id,
+        });
+
+        serde_json::to_writer(&mut self.0, &value)?;
+        self.0.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+/// Buffers rows into Arrow columns, writing a Parquet row group every
+/// `PARQUET_BATCH_ROWS` rows. Details are stored as JSON strings.
+struct ParquetWriter<W: Write + Send> {
+    writer: ArrowWriter<W>,
+    schema: SchemaRef,
+    rows: usize,
+    id: Int32Builder,
+    unique_id: Int64Builder,
+    ip_address: StringBuilder,
+    kind: StringBuilder,
+    detail: StringBuilder,
+    created: TimestampMicrosecondBuilder,
+    source: StringBuilder,
+    idempotency_key: Int64Builder,
+    sample_rate: Float32Builder,
+    country: StringBuilder,
+    asn: Int64Builder,
+    rate_exceeded: BooleanBuilder,
+    ordinal: Int64Builder,
+    session_id: Int64Builder,
+    trace_id: StringBuilder,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    fn new(out: W) -> Result<Self> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("unique_id", DataType::Int64, false),
+            Field::new("ip_address", DataType::Utf8, false),
+            Field::new("kind", DataType::Utf8, false),
+            Field::new("detail", DataType::Utf8, true),
+            Field::new(
+                "created",
+                DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+                false,
+            ),
+            Field::new("source", DataType::Utf8, true),
+            Field::new("idempotency_key", DataType::Int64, true),
+            Field::new("sample_rate", DataType::Float32, true),
+            Field::new("country", DataType::Utf8, true),
+            Field::new("asn", DataType::Int64, true),
+            Field::new("rate_exceeded", DataType::Boolean, false),
+            Field::new("ordinal", DataType::Int64, true),
+            Field::new("session_id", DataType::Int64, true),
+            Field::new("trace_id", DataType::Utf8, true),
+        ]));
+
+        let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+        let writer = ArrowWriter::try_new(out, Arc::clone(&schema), Some(properties))?;
+
+        Ok(Self {
+            writer,
+            schema,
+            rows: 0,
+            id: Int32Builder::new(),
+            unique_id: Int64Builder::new(),
+            ip_address: StringBuilder::new(),
+            kind: StringBuilder::new(),
+            detail: StringBuilder::new(),
+            created: TimestampMicrosecondBuilder::new().with_timezone("UTC"),
+            source: StringBuilder::new(),
+            idempotency_key: Int64Builder::new(),
+            sample_rate: Float32Builder::new(),
+            country: StringBuilder::new(),
+            asn: Int64Builder::new(),
+            rate_exceeded: BooleanBuilder::new(),
+            ordinal: Int64Builder::new(),
+            session_id: Int64Builder::new(),
+            trace_id: StringBuilder::new(),
+        })
+    }
+
+    /// Writes the buffered rows as a row group and resets the builders.
+    fn write_batch(&mut self) -> Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.id.finish()),
+            Arc::new(self.unique_id.finish()),
+            Arc::new(self.ip_address.finish()),
+            Arc::new(self.kind.finish()),
+            Arc::new(self.detail.finish()),
+            Arc::new(self.created.finish()),
+            Arc::new(self.source.finish()),
+            Arc::new(self.idempotency_key.finish()),
+            Arc::new(self.sample_rate.finish()),
+            Arc::new(self.country.finish()),
+            Arc::new(self.asn.finish()),
+            Arc::new(self.rate_exceeded.finish()),
+            Arc::new(self.ordinal.finish()),
+            Arc::new(self.session_id.finish()),
+            Arc::new(self.trace_id.finish()),
+        ];
+
+        let batch = RecordBatch::try_new(Arc::clone(&self.schema), columns)?;
+        self.writer.write(&batch)?;
+        self.writer.flush()?;
+        self.rows = 0;
+
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> RowWriter for ParquetWriter<W> {
+    fn write(&mut self, row: &ExportRow) -> Result<()> {
+        self.id.append_value(row.id);
+        self.unique_id.append_value(row.unique_id);
+        self.ip_address.append_value(row.ip().to_string());
+        self.kind.append_value(&row.kind);
+        self.detail.append_option(row.detail());
+        self.created.append_value((row.created.unix_timestamp_nanos() / 1000) as i64);
+        self.source.append_option(row.source.as_deref());
+        self.idempotency_key.append_option(row.idempotency_key);
+        self.sample_rate.append_option(row.sample_rate);
+        self.country.append_option(row.country.as_deref());
+        self.asn.append_option(row.asn);
+        self.rate_exceeded.append_value(row.rate_exceeded);
+        self.ordinal.append_option(row.ordinal);
+        self.session_id.append_option(row.session_id);
+        self.trace_id.append_option(row.trace_id.as_deref());
+
+        self.rows += 1;
+        if self.rows >= PARQUET_BATCH_ROWS {
+            self.write_batch()?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.write_batch()?;
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_csv_fields() {
+        assert_eq!(csv_field("login"), "login");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field(r#"{"name":"Rob"}"#), r#""{""name"":""Rob""}""#);
+    }
+
+    #[test]
+    fn parse_export_format() {
+        assert_eq!("parquet".parse(), Ok(ExportFormat::Parquet));
+        assert!("xlsx".parse::<ExportFormat>().is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::RwLock;
+
+use maxminddb::{geoip2, Reader};
+
+use crate::{action::Action, server::config::GeoIpConfig, Result};
+
+/// Looks up the country and autonomous system of each action's IP address in
+/// MaxMind GeoLite2 databases. The databases can be swapped out at runtime by
+/// calling `load` again.
+#[derive(Default)]
+pub(crate) struct GeoIp {
+    country: RwLock<Option<Reader<Vec<u8>>>>,
+    asn: RwLock<Option<Reader<Vec<u8>>>>,
+}
+
+impl GeoIp {
+    /// Opens the databases named in the config, replacing any which were
+    /// previously loaded. If either database fails to open, neither is
+    /// replaced.
+    pub(crate) fn load(&self, config: &GeoIpConfig) -> Result<()> {
+        let country = config.country_database.as_ref().map(Reader::open_readfile).transpose()?;
+        let asn = config.asn_database.as_ref().map(Reader::open_readfile).transpose()?;
+
+        *self.country.write().unwrap_or_else(|e| e.into_inner()) = country;
+        *self.asn.write().unwrap_or_else(|e| e.into_inner()) = asn;
+
+        Ok(())
+    }
+
+    /// Fills in the country and ASN of an action. Addresses which aren't found
+    /// in a database, such as private ranges, are left empty.
+    pub(crate) fn enrich(&self, action: &mut Action) {
+        if let Some(reader) = self.country.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            action.country = reader
+                .lookup::<geoip2::Country>(action.addr)
+                .ok()
+                .and_then(|country| country.country?.iso_code)
+                .map(str::to_string);
+        }
+
+        if let Some(reader) = self.asn.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            action.asn = reader
+                .lookup::<geoip2::Asn>(action.addr)
+                .ok()
+                .and_then(|asn| asn.autonomous_system_number);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::IpAddr, path::Path, sync::Arc};
+
+    use super::*;
+    use crate::test_fixtures::Target;
+
+    /// Builds a single-node IPv4 database in which every address in 0.0.0.0/1
+    /// maps to `record` and the rest aren't found.
+    fn database(database_type: &str, record: &[u8]) -> Vec<u8> {
+        let string = |s: &str| [&[0x40 | s.len() as u8][..], s.as_bytes()].concat();
+        let uint16 = |v: u16| [&[0xa2][..], &v.to_be_bytes()].concat();
+
+        // The left record points at the start of the data section, which
+        // follows the tree and its 16 byte separator, and the right record is
+        // the node count, meaning not found.
+        let mut db = vec![0, 0, 17, 0, 0, 1];
+        db.extend([0; 16]);
+        db.extend(record);
+
+        db.extend(b"\xab\xcd\xefMaxMind.com");
+        db.push(0xe0 | 9);
+        db.extend(string("node_count"));
+        db.extend([0xc4, 0, 0, 0, 1]);
+        db.extend(string("record_size"));
+        db.extend(uint16(24));
+        db.extend(string("ip_version"));
+        db.extend(uint16(4));
+        db.extend(string("database_type"));
+        db.extend(string(database_type));
+        db.extend(string("languages"));
+        db.extend([0x00, 0x04]);
+        db.extend(string("binary_format_major_version"));
+        db.extend(uint16(2));
+        db.extend(string("binary_format_minor_version"));
+        db.extend(uint16(0));
+        db.extend(string("build_epoch"));
+        db.extend([0x08, 0x02, 0, 0, 0, 0, 0, 0, 0, 0]);
+        db.extend(string("description"));
+        db.push(0xe0);
+
+        db
+    }
+
+    /// Writes a Country database mapping to Sweden and an ASN database mapping
+    /// to AS64512, returning a config naming both.
+    fn write_databases(dir: &Path) -> GeoIpConfig {
+        fs::create_dir_all(dir).unwrap();
+
+        let country = dir.join("country.mmdb");
+        let record = b"\xe1\x47country\xe1\x48iso_code\x42SE";
+        fs::write(&country, database("GeoLite2-Country", record)).unwrap();
+
+        let asn = dir.join("asn.mmdb");
+        let record = b"\xe1\x58autonomous_system_number\xc2\xfc\x00";
+        fs::write(&asn, database("GeoLite2-ASN", record)).unwrap();
+
+        GeoIpConfig { country_database: Some(country), asn_database: Some(asn) }
+    }
+
+    #[test]
+    fn enrich_actions() {
+        let dir = std::env::temp_dir().join(format!("harp-geoip-{}", fastrand::u64(..)));
+        let geoip = GeoIp::default();
+        geoip.load(&write_databases(&dir)).unwrap();
+
+        let mut action = Action::new("login", &Target);
+        geoip.enrich(&mut action);
+        assert_eq!(action.country.as_deref(), Some("SE"));
+        assert_eq!(action.asn, Some(64512));
+
+        // Addresses missing from the databases are left empty.
+        action.addr = IpAddr::from([203, 0, 113, 1]);
+        geoip.enrich(&mut action);
+        assert_eq!(action.country, None);
+        assert_eq!(action.asn, None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_reload_keeps_databases() {
+        let dir = std::env::temp_dir().join(format!("harp-geoip-{}", fastrand::u64(..)));
+        let geoip = Arc::new(GeoIp::default());
+        let config = write_databases(&dir);
+        geoip.load(&config).unwrap();
+
+        // A handler which panicked while holding a lock doesn't take lookups
+        // down with it.
+        let poisoner = Arc::clone(&geoip);
+        std::thread::spawn(move || {
+            let _guard = poisoner.country.write().unwrap();
+            panic!("poisoning the country lock");
+        })
+        .join()
+        .unwrap_err();
+
+        let missing = GeoIpConfig { asn_database: Some(dir.join("missing.mmdb")), ..config };
+        assert!(geoip.load(&missing).is_err());
+
+        let mut action = Action::new("login", &Target);
+        geoip.enrich(&mut action);
+        assert_eq!(action.country.as_deref(), Some("SE"));
+        assert_eq!(action.asn, Some(64512));
+
+        // Reloading without databases turns lookups off.
+        geoip.load(&GeoIpConfig::default()).unwrap();
+        let mut action = Action::new("login", &Target);
+        geoip.enrich(&mut action);
+        assert_eq!(action.country, None);
+        assert_eq!(action.asn, None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    net::IpAddr,
+    path::Path,
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::PgPool;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{
+    action::{Action, Priority},
+    server::{
+        metrics::Metrics,
+        queue::{insert_batch, LIMIT},
+        sql::InsertStatements,
+    },
+    Result,
+};
+
+/// An archived action, in the JSON-lines format written by `harpd export`.
+/// Other fields, such as the original row `id`, are ignored.
+#[derive(Debug, Deserialize)]
+struct ImportRow {
+    unique_id: i64,
+    ip_address: IpAddr,
+    kind: String,
+    #[serde(default)]
+    detail: Option<Value>,
+    created: String,
+    #[serde(default)]
+    source: Option<String>,
+    #[serde(default)]
+    idempotency_key: Option<i64>,
+    #[serde(default)]
+    sample_rate: Option<f32>,
+    #[serde(default)]
+    country: Option<String>,
+    #[serde(default)]
+    asn: Option<i64>,
+    #[serde(default)]
+    rate_exceeded: bool,
+    #[serde(default)]
+    ordinal: Option<i64>,
+    #[serde(default)]
+    session_id: Option<i64>,
+    #[serde(default)]
+    trace_id: Option<String>,
+}
+
+impl TryFrom<ImportRow> for Action {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(row: ImportRow) -> Result<Self> {
+        Ok(Action {
+            id: u32::try_from(row.unique_id)?,
+            addr: row.ip_address,
+            kind: row.kind.into(),
+            detail: row.detail,
+            created: OffsetDateTime::parse(&row.created, &Rfc3339)?,
+            source: row.source,
+            // Exports store the key with the same bits as a signed integer.
+            idempotency_key: row.idempotency_key.map(|key| key as u64),
+            sequence: None,
+            sample_rate: row.sample_rate,
+            country: row.country,
+            asn: row.asn.map(u32::try_from).transpose()?,
+            rate_exceeded: row.rate_exceeded,
+            priority: Priority::Normal,
+            ordinal: row.ordinal.map(|ordinal| ordinal as u64),
+            session_id: row.session_id.map(|id| id as u64),
+            trace_id: row.trace_id,
+        })
+    }
+}
+
+/// Reads archived actions from the JSON-lines file at `path` and inserts them
+/// through the same batched statements as the queue, returning the number of
+/// actions read. Actions whose idempotency key is already stored are skipped,
+/// so keyed actions are not duplicated if an interrupted import is run again.
+pub(crate) async fn import(pg: &PgPool, statements: &InsertStatements, path: &Path) -> Result<u64> {
+    let reader = BufReader::new(File::open(path)?);
+    let metrics = Metrics::default();
+
+    let mut batch = Vec::with_capacity(LIMIT);
+    let mut count = 0;
+
+    for (line, text) in reader.lines().enumerate() {
+        let text = text?;
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        let action = serde_json::from_str::<ImportRow>(&text)
+            .map_err(Into::into)
+            .and_then(Action::try_from)
+            .map_err(|e| format!("Invalid action on line {}: {e}", line + 1))?;
+
+        batch.push(action);
+        count += 1;
+
+        if batch.len() == LIMIT {
+            insert_batch(batch.iter(), pg, statements, &metrics, None).await?;
+            batch.clear();
+        }
+    }
+
+    if !batch.is_empty() {
+        insert_batch(batch.iter(), pg, statements, &metrics, None).await?;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_exported_line() {
+        let line = r#"{"id":7,"unique_id":1,"ip_address":"127.0.0.1","kind":"login","detail":{"name":"Rob"},"created":"2024-01-01T12:00:00Z","source":"world-1","idempotency_key":-1,"sample_rate":null,"country":"US","asn":15169,"rate_exceeded":false}"#;
+
+        let row = serde_json::from_str::<ImportRow>(line).unwrap();
+        let action = Action::try_from(row).unwrap();
+
+        assert_eq!(action.id, 1);
+        assert_eq!(action.kind, "login");
+        assert_eq!(action.idempotency_key, Some(u64::MAX));
+        assert_eq!(action.asn, Some(15169));
+        assert_eq!(action.created.unix_timestamp(), 1704110400);
+    }
+}
@@ -0,0 +1,78 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use sqlx::PgPool;
+
+use crate::Result;
+
+/// Maps kinds to the ids stored in place of them when kinds are normalized.
+/// Ids are assigned by the kinds table the first time a kind is inserted, and
+/// cached for the life of the process; ids are never reassigned, so the cache
+/// can't go stale.
+#[derive(Debug)]
+pub(crate) struct KindRegistry {
+    table: String,
+    ids: RwLock<HashMap<String, i32>>,
+}
+
+impl KindRegistry {
+    /// Creates a registry backed by `table`, which must be a validated,
+    /// schema-qualified table name.
+    pub(crate) fn new(table: &str) -> Self {
+        Self { table: table.to_string(), ids: RwLock::default() }
+    }
+
+    /// Makes sure every kind in `kinds` has an id, adding any the kinds table
+    /// hasn't seen. Runs outside of the insert's transaction, so ids stay
+    /// valid even if the insert is rolled back.
+    pub(crate) async fn register<'a>(
+        &self,
+        pg: &PgPool,
+        kinds: impl Iterator<Item = &'a str>,
+    ) -> Result<()> {
+        let missing = {
+            let ids = self.ids.read().unwrap_or_else(|e| e.into_inner());
+            kinds.filter(|kind| !ids.contains_key(*kind)).collect::<Vec<_>>()
+        };
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let table = &self.table;
+        sqlx::query(&format!(
+            "INSERT INTO {table} (kind) SELECT unnest($1::varchar[]) ON CONFLICT (kind) DO NOTHING"
+        ))
+        .bind(&missing)
+        .execute(pg)
+        .await?;
+
+        let rows: Vec<(i32, String)> =
+            sqlx::query_as(&format!("SELECT id, kind FROM {table} WHERE kind = ANY($1)"))
+                .bind(&missing)
+                .fetch_all(pg)
+                .await?;
+
+        tracing::debug!(count = rows.len(), "Registered kinds");
+        self.ids
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend(rows.into_iter().map(|(id, kind)| (kind, id)));
+
+        Ok(())
+    }
+
+    /// Returns the id of `kind`, if it has been registered.
+    pub(crate) fn id(&self, kind: &str) -> Option<i32> {
+        self.ids.read().unwrap_or_else(|e| e.into_inner()).get(kind).copied()
+    }
+}
+
+/// Returns an expression for the kind of each row of the actions table
+/// aliased as `actions`. Rows stored before kinds were normalized keep their
+/// kind, and every other row's is looked up in `kinds_table`.
+pub(crate) fn kind_expr(kinds_table: &str) -> String {
+    format!(
+        "COALESCE(actions.kind, \
+         (SELECT kinds.kind FROM {kinds_table} AS kinds WHERE kinds.id = actions.kind_id))"
+    )
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+use crate::server::{config::DuplicatePolicy, queue::ConnectionId};
+
+/// Tracks the number of open service connections, both in total and per IP
+/// address, so that harpd can refuse connections beyond the configured limits.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionTracker {
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionTracker {
+    /// Attempts to register a new connection from `ip`. On success, returns a
+    /// guard which releases the connection slot when dropped.
+    pub(crate) fn try_acquire(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        max_connections: Option<NonZeroUsize>,
+        max_connections_per_ip: Option<NonZeroUsize>,
+    ) -> Result<ConnectionGuard, LimitExceeded> {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(max) = max_connections {
+            if counts.total >= max.get() {
+                return Err(LimitExceeded::Total(max.get()));
+            }
+        }
+
+        let per_ip = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        if let Some(max) = max_connections_per_ip {
+            if per_ip >= max.get() {
+                return Err(LimitExceeded::PerIp(max.get()));
+            }
+        }
+
+        counts.total += 1;
+        counts.per_ip.insert(ip, per_ip + 1);
+
+        Ok(ConnectionGuard { tracker: Arc::clone(self), ip })
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        counts.total = counts.total.saturating_sub(1);
+
+        if let Some(count) = counts.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Holds a connection slot for as long as the connection is open.
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard {
+    tracker: Arc<ConnectionTracker>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.tracker.release(self.ip);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum LimitExceeded {
+    /// The total connection limit has been reached.
+    Total(usize),
+    /// The connection limit for a single IP has been reached.
+    PerIp(usize),
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitExceeded::Total(max) => write!(f, "connection limit of {max} reached"),
+            LimitExceeded::PerIp(max) => write!(f, "per-IP connection limit of {max} reached"),
+        }
+    }
+}
+
+/// Tracks the connection each named service is using, so that a service
+/// which connects twice, such as a shard started twice by mistake, can be
+/// detected and handled.
+#[derive(Debug, Default)]
+pub(crate) struct ServiceRegistry {
+    // Every open connection using each name, oldest first. More than one is
+    // only kept when duplicates are allowed, or while kicked ones close.
+    services: Mutex<HashMap<String, Vec<Registration>>>,
+}
+
+#[derive(Debug)]
+struct Registration {
+    connection: ConnectionId,
+    kick: Arc<Notify>,
+}
+
+impl ServiceRegistry {
+    /// Registers `connection` as the service `name`. If other connections are
+    /// already registered under the name, `policy` decides what happens: the
+    /// new connection is refused with the newest existing connection's ID, or
+    /// it joins them, and that ID is returned alongside the guard. With
+    /// `KickOld`, the existing connections are also told to close.
+    pub(crate) fn register(
+        self: &Arc<Self>,
+        name: &str,
+        connection: ConnectionId,
+        policy: DuplicatePolicy,
+    ) -> Result<(ServiceGuard, Option<ConnectionId>), ConnectionId> {
+        let mut services = self.services.lock().unwrap_or_else(|e| e.into_inner());
+
+        let registrations = services.entry(name.to_string()).or_default();
+        let existing = registrations.last().map(|existing| existing.connection);
+        if let Some(existing) = existing {
+            match policy {
+                DuplicatePolicy::Allow => {}
+                DuplicatePolicy::RejectNew => return Err(existing),
+                DuplicatePolicy::KickOld => {
+                    registrations.iter().for_each(|registration| registration.kick.notify_one())
+                }
+            }
+        }
+
+        let kick = Arc::new(Notify::new());
+        registrations.push(Registration { connection, kick: Arc::clone(&kick) });
+
+        let guard =
+            ServiceGuard { registry: Arc::clone(self), name: name.to_string(), connection, kick };
+
+        Ok((guard, existing))
+    }
+
+    fn release(&self, name: &str, connection: ConnectionId) {
+        let mut services = self.services.lock().unwrap_or_else(|e| e.into_inner());
+
+        // The name is only freed once every connection using it has closed.
+        if let Some(registrations) = services.get_mut(name) {
+            registrations.retain(|registration| registration.connection != connection);
+            if registrations.is_empty() {
+                services.remove(name);
+            }
+        }
+    }
+}
+
+/// Holds a service's name for as long as its connection is open.
+#[derive(Debug)]
+pub(crate) struct ServiceGuard {
+    registry: Arc<ServiceRegistry>,
+    name: String,
+    connection: ConnectionId,
+    kick: Arc<Notify>,
+}
+
+impl ServiceGuard {
+    /// Resolves once a newer connection for the same service has asked this
+    /// one to close.
+    pub(crate) async fn kicked(&self) {
+        self.kick.notified().await;
+    }
+}
+
+impl Drop for ServiceGuard {
+    fn drop(&mut self) {
+        self.registry.release(&self.name, self.connection);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enforce_connection_limits() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        let a: IpAddr = [10, 0, 0, 1].into();
+        let b: IpAddr = [10, 0, 0, 2].into();
+        let total = NonZeroUsize::new(3);
+        let per_ip = NonZeroUsize::new(2);
+
+        let first = tracker.try_acquire(a, total, per_ip).unwrap();
+        let _second = tracker.try_acquire(a, total, per_ip).unwrap();
+        assert_eq!(tracker.try_acquire(a, total, per_ip).unwrap_err(), LimitExceeded::PerIp(2));
+
+        let _third = tracker.try_acquire(b, total, per_ip).unwrap();
+        assert_eq!(tracker.try_acquire(b, total, per_ip).unwrap_err(), LimitExceeded::Total(3));
+
+        // Dropping a guard frees up its slot.
+        drop(first);
+        assert!(tracker.try_acquire(a, total, per_ip).is_ok());
+    }
+
+    #[tokio::test]
+    async fn handle_duplicate_services() {
+        let registry = Arc::new(ServiceRegistry::default());
+        let (first, second) = (ConnectionId::next(), ConnectionId::next());
+
+        let (guard, existing) =
+            registry.register("world-1", first, DuplicatePolicy::RejectNew).unwrap();
+        assert_eq!(existing, None);
+        assert_eq!(
+            registry.register("world-1", second, DuplicatePolicy::RejectNew).unwrap_err(),
+            first
+        );
+
+        // The first connection is told to close, and leaving doesn't free the
+        // name, which now belongs to the second.
+        let (_newer, existing) =
+            registry.register("world-1", second, DuplicatePolicy::KickOld).unwrap();
+        assert_eq!(existing, Some(first));
+        guard.kicked().await;
+        drop(guard);
+        assert!(registry.register("world-1", first, DuplicatePolicy::RejectNew).is_err());
+    }
+
+    #[test]
+    fn allowed_duplicates_hold_the_name() {
+        let registry = Arc::new(ServiceRegistry::default());
+        let (first, second, third) =
+            (ConnectionId::next(), ConnectionId::next(), ConnectionId::next());
+
+        let (older, _) = registry.register("world-1", first, DuplicatePolicy::Allow).unwrap();
+        let (newer, existing) =
+            registry.register("world-1", second, DuplicatePolicy::Allow).unwrap();
+        assert_eq!(existing, Some(first));
+
+        // The first connection is still open after the second closes, so the
+        // name is still taken.
+        drop(newer);
+        assert_eq!(
+            registry.register("world-1", third, DuplicatePolicy::RejectNew).unwrap_err(),
+            first
+        );
+
+        drop(older);
+        assert!(registry.register("world-1", third, DuplicatePolicy::RejectNew).is_ok());
+    }
+}
@@ -0,0 +1,1091 @@
+#[cfg(all(windows, feature = "named-pipe"))]
+use std::net::IpAddr;
+use std::{any::Any, future::Future, net::SocketAddr, sync::Arc, time::Duration};
+
+use bufferfish::Bufferfish;
+use futures_util::{SinkExt, StreamExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use time::OffsetDateTime;
+#[cfg(all(windows, feature = "named-pipe"))]
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::error::TrySendError, watch, Semaphore},
+    time::{interval, sleep_until, timeout, Instant},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{
+    bytes::{BufMut, Bytes, BytesMut},
+    codec::{Decoder, Framed, LengthDelimitedCodec},
+};
+use tracing::{field, Instrument, Span};
+use zeroize::Zeroizing;
+
+#[cfg(feature = "otel")]
+use crate::server::otel;
+use crate::{
+    action::{Action, Priority},
+    protocol::{
+        strip_checksum, strip_signature, FrameCodec, Handshake, Nack, NackCode, ProtocolError,
+        Response, Subscribe, PROTOCOL_VERSION,
+    },
+    server::{
+        admin::{self, Admin, Controls},
+        alerts::Alerts,
+        auth::{AuthRequest, Authenticator},
+        config::{DuplicatePolicy, ListenAddr, ListenProtocol, SharedConfig},
+        counters::RateCounters,
+        geoip::GeoIp,
+        limits::{ConnectionTracker, ServiceGuard, ServiceRegistry},
+        metrics::Metrics,
+        proxy,
+        queue::{self, ConnectionId, QueueSender, Queued, Store, Watchdog},
+        reload::LogHandle,
+        sequence::SequenceTracker,
+        subscriptions::Subscriptions,
+        systemd, tls,
+        transform::Transforms,
+        validation::{check_detail_size, DetailSize, SchemaRegistry},
+    },
+    Result,
+};
+
+/// Handles shared by every connection task.
+#[derive(Clone)]
+struct ServerState {
+    queue: QueueSender,
+    config: SharedConfig,
+    metrics: Arc<Metrics>,
+    schemas: Arc<SchemaRegistry>,
+    transforms: Arc<Transforms>,
+    geoip: Arc<GeoIp>,
+    subscriptions: Arc<Subscriptions>,
+    alerts: Arc<Alerts>,
+    counters: Option<Arc<RateCounters>>,
+    controls: Arc<Controls>,
+    watchdog: Arc<Watchdog>,
+    services: Arc<ServiceRegistry>,
+    tls: Option<TlsAcceptor>,
+    authenticator: Arc<dyn Authenticator>,
+    frame_key: Option<Arc<Zeroizing<Vec<u8>>>>,
+}
+
+impl ServerState {
+    /// Returns true if actions should be returned to their services rather
+    /// than queued: while an operator has paused ingest, or while the queue
+    /// processor is being restarted if `watchdog.refuse_ingest` is set.
+    async fn is_refusing_ingest(&self) -> bool {
+        self.controls.is_paused()
+            || (!self.watchdog.is_healthy() && self.config.read().await.watchdog.refuse_ingest)
+    }
+}
+
+pub(crate) async fn listen(
+    config: SharedConfig,
+    store: Store,
+    ready: watch::Receiver<bool>,
+    transforms: Transforms,
+    geoip: Arc<GeoIp>,
+    authenticator: Arc<dyn Authenticator>,
+    log_handle: Option<LogHandle>,
+) -> Result<()> {
+    let (addr, ipv6_only) = {
+        let config = config.read().await;
+        (config.get_addr(), config.listener.ipv6_only)
+    };
+
+    // Prefer a listener handed to us by systemd socket activation; otherwise
+    // bind the configured address ourselves.
+    let listener = match systemd::take_listener()? {
+        Some(listener) => {
+            tracing::info!("harpd listening on {} (socket activated)", listener.local_addr()?);
+            listener
+        }
+        None => {
+            let listener = bind(addr, ipv6_only)?;
+            tracing::info!("harpd listening on {addr}");
+            listener
+        }
+    };
+
+    // The database is connected and the listener is bound, so we're ready to
+    // accept services.
+    systemd::notify_ready();
+
+    // Each connection sends its actions to the queue processor over a bounded
+    // channel, rather than contending on a shared lock.
+    let metrics = Arc::new(Metrics::default());
+    let watchdog = Arc::new(Watchdog::new());
+    let pg = store.postgres();
+    let (shared_queue, flush) = queue::spawn_processor(
+        Arc::clone(&config),
+        store,
+        ready,
+        Arc::clone(&metrics),
+        Arc::clone(&watchdog),
+    )
+    .await?;
+    let controls = Arc::new(Controls::new(log_handle));
+
+    #[cfg(feature = "otel")]
+    {
+        let config = config.read().await;
+        if let Some(endpoint) = &config.logging.otlp_endpoint {
+            let service_name = config.logging.get_otlp_service_name();
+            otel::export_metrics(endpoint, service_name, Arc::clone(&metrics))?;
+        }
+    }
+
+    let alerts = {
+        let config = config.read().await;
+        Alerts::new(config.alerts.clone(), pg, config.get_alert_table())
+    };
+
+    let counters = {
+        let config = config.read().await;
+        config.get_rate_window().map(|window| {
+            let counters = Arc::new(RateCounters::new(window, config.rate_counters.threshold));
+            tokio::spawn(report_top_talkers(
+                Arc::clone(&counters),
+                window,
+                config.get_top_talkers(),
+            ));
+            counters
+        })
+    };
+
+    let schemas = {
+        let config = config.read().await;
+        let validation = &config.validation;
+        let schemas = match &validation.schema_dir {
+            Some(dir) => SchemaRegistry::load(dir)?,
+            None => SchemaRegistry::default(),
+        };
+
+        match &validation.known_kinds {
+            Some(kinds) => schemas.with_known_kinds(kinds),
+            None => schemas,
+        }
+    };
+
+    let tls = tls::acceptor(&config.read().await.tls)?;
+    if tls.is_some() {
+        tracing::info!("Service connections require TLS");
+    }
+
+    let frame_key = config.read().await.get_frame_key()?.map(Arc::new);
+
+    let state = ServerState {
+        queue: shared_queue,
+        config: Arc::clone(&config),
+        metrics,
+        schemas: Arc::new(schemas),
+        transforms: Arc::new(transforms),
+        geoip,
+        subscriptions: Arc::new(Subscriptions::default()),
+        alerts: Arc::new(alerts),
+        counters,
+        controls: Arc::clone(&controls),
+        watchdog,
+        services: Arc::new(ServiceRegistry::default()),
+        tls,
+        authenticator,
+        frame_key,
+    };
+
+    let top_talkers = config.read().await.get_top_talkers();
+    let admin = Admin {
+        controls: Arc::clone(&controls),
+        flush,
+        metrics: Arc::clone(&state.metrics),
+        queue: state.queue.clone(),
+        top_talkers: state.counters.clone().map(|counters| (counters, top_talkers)),
+    };
+
+    // Unix sockets aren't available on every platform.
+    #[cfg(unix)]
+    {
+        if let Some(path) = config.read().await.admin.socket.clone() {
+            let admin = admin.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin::accept_unix(path, admin).await {
+                    tracing::error!("Error accepting admin connections: {e}");
+                }
+            });
+        }
+    }
+
+    let subscription_addr = config.read().await.get_subscription_addr();
+    if let Some(addr) = subscription_addr {
+        let listener = bind(addr, ipv6_only)?;
+        tracing::info!("harpd accepting subscribers on {addr}");
+
+        tokio::spawn(accept_subscribers(listener, state.clone()));
+    }
+
+    for listen in config.read().await.listen.clone() {
+        let listener = bind(listen.addr, listen.ipv6_only)?;
+
+        match listen.protocol {
+            ListenProtocol::Services => {
+                tracing::info!("harpd listening on {}", listen.addr);
+                let connections = Arc::new(ConnectionTracker::default());
+                tokio::spawn(accept_services(listener, state.clone(), connections, Some(listen)));
+            }
+            ListenProtocol::Subscribers => {
+                tracing::info!("harpd accepting subscribers on {}", listen.addr);
+                tokio::spawn(accept_subscribers(listener, state.clone()));
+            }
+            ListenProtocol::Admin => {
+                admin::check_addr(listen.addr);
+                tracing::info!("harpd accepting admin commands on {}", listen.addr);
+                tokio::spawn(admin::accept_tcp(listener, admin.clone()));
+            }
+        }
+    }
+
+    // Pipe clients share the main listener's `max_connections`.
+    let connections = Arc::new(ConnectionTracker::default());
+
+    let named_pipe = config.read().await.listener.named_pipe.clone();
+    if let Some(name) = named_pipe {
+        // Named pipes are only available on Windows.
+        #[cfg(all(windows, feature = "named-pipe"))]
+        {
+            let server = ServerOptions::new().first_pipe_instance(true).create(&name)?;
+            tracing::info!("harpd listening on {name}");
+            let connections = Arc::clone(&connections);
+            tokio::spawn(accept_pipe_services(name, server, state.clone(), connections));
+        }
+        #[cfg(not(all(windows, feature = "named-pipe")))]
+        tracing::warn!("Named pipes need Windows and the named-pipe feature; ignoring {name}");
+    }
+
+    tokio::select! {
+        _ = accept_services(listener, state, connections, None) => {}
+        _ = controls.shutdown() => tracing::info!("Queue drained; shutting down"),
+    }
+
+    Ok(())
+}
+
+/// Accepts connections from external services on `listener`, counting them
+/// against its limits in `connections`. Each listener counts its own
+/// connections, besides the main one and the named pipe which share theirs;
+/// `listen` holds the overrides for an additional address, falling back to the
+/// `[listener]` limits.
+async fn accept_services(
+    listener: TcpListener,
+    state: ServerState,
+    connections: Arc<ConnectionTracker>,
+    listen: Option<ListenAddr>,
+) {
+    let pending_headers = Arc::new(Semaphore::new(proxy::MAX_PENDING_HEADERS));
+
+    {
+        let config = state.config.read().await;
+        let overrides = listen.as_ref().and_then(|l| l.proxy_protocol);
+        if overrides.unwrap_or(config.listener.proxy_protocol)
+            && config.listener.trusted_proxies.is_empty()
+        {
+            tracing::warn!(
+                "The PROXY protocol is enabled without listener.trusted_proxies, so any client \
+                 can set its own address"
+            );
+        }
+    }
+
+    // Each of these connections needs a handle to the queue and the rest of
+    // the shared state.
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("Error accepting connection: {e}");
+                continue;
+            }
+        };
+
+        let peer = canonical(peer);
+        let (proxy_protocol, trusted, header_timeout) = {
+            let config = state.config.read().await;
+            let overrides = listen.as_ref().and_then(|l| l.proxy_protocol);
+            let header_timeout = config
+                .get_idle_timeout()
+                .map_or(proxy::HEADER_TIMEOUT, |idle| idle.min(proxy::HEADER_TIMEOUT));
+            (
+                overrides.unwrap_or(config.listener.proxy_protocol),
+                config.is_trusted_proxy(peer.ip()),
+                header_timeout,
+            )
+        };
+
+        if !proxy_protocol {
+            admit(stream, peer, &state, &connections, listen.as_ref()).await;
+            continue;
+        }
+
+        // Only a trusted load balancer may say where a connection came from;
+        // anyone else could claim any address to dodge the per-IP limits.
+        if !trusted {
+            tracing::warn!("Rejected connection from {peer}: not a trusted proxy");
+            continue;
+        }
+
+        let Ok(permit) = Arc::clone(&pending_headers).try_acquire_owned() else {
+            tracing::warn!("Rejected connection from {peer}: too many pending PROXY headers");
+            continue;
+        };
+
+        // The header may take a while to arrive, so it is read off the accept
+        // loop, holding a permit until it does.
+        let (state, connections, listen) =
+            (state.clone(), Arc::clone(&connections), listen.clone());
+        tokio::spawn(async move {
+            let header = match timeout(header_timeout, proxy::read_header(&mut stream)).await {
+                Ok(header) => header,
+                Err(_) => {
+                    tracing::info!("Timed out waiting for a PROXY header from {peer}");
+                    return;
+                }
+            };
+            drop(permit);
+
+            match header {
+                Ok(addr) => {
+                    let addr = addr.map_or(peer, canonical);
+                    admit(stream, addr, &state, &connections, listen.as_ref()).await;
+                }
+                Err(e) => tracing::warn!("Rejected connection from {peer}: {e}"),
+            }
+        });
+    }
+}
+
+/// Starts serving a service connected from `addr`, unless it would exceed
+/// the listener's connection limits.
+async fn admit(
+    stream: TcpStream,
+    addr: SocketAddr,
+    state: &ServerState,
+    connections: &Arc<ConnectionTracker>,
+    listen: Option<&ListenAddr>,
+) {
+    let (max_connections, max_connections_per_ip) = {
+        let config = state.config.read().await;
+        (
+            listen.and_then(|l| l.max_connections).or(config.listener.max_connections),
+            listen
+                .and_then(|l| l.max_connections_per_ip)
+                .or(config.listener.max_connections_per_ip),
+        )
+    };
+
+    // Refuse the connection outright if it would exceed either limit;
+    // dropping the stream closes the socket.
+    let guard = match connections.try_acquire(addr.ip(), max_connections, max_connections_per_ip) {
+        Ok(guard) => guard,
+        Err(e) => {
+            tracing::warn!("Rejected connection from {addr}: {e}");
+            if state.tls.is_none() {
+                refuse(&stream, NackCode::TooManyConnections, e.to_string());
+            }
+            return;
+        }
+    };
+
+    // Every log from the connection carries its ID and, once it has
+    // identified itself, the service's name.
+    let id = ConnectionId::next();
+    let span = tracing::info_span!("connection", %id, %addr, service = field::Empty);
+    tracing::info!(parent: &span, "Service connected");
+
+    let metrics = Arc::clone(&state.metrics);
+    let state = state.clone();
+    let task = async move {
+        if let Err(e) = serve(id, addr, stream, state).await {
+            tracing::error!("Error handling connection: {e}");
+        }
+
+        drop(guard);
+    };
+    spawn_isolated(task, span, metrics);
+}
+
+/// Tells a service which is refused before its handshake is read why, in a
+/// NACK framed the way handshakes are. The write is never waited on, so a
+/// service which doesn't read can't hold up the accept loop; TLS listeners
+/// send nothing, as the service expects a TLS handshake first.
+fn refuse(stream: &TcpStream, code: NackCode, reason: String) {
+    let Ok(frame) = Response::Nack(Nack { code, sequence: None, reason }).encode() else {
+        return;
+    };
+
+    let mut buf = BytesMut::with_capacity(frame.len() + 2);
+    buf.put_u16(frame.len() as u16);
+    buf.extend_from_slice(&frame);
+    let _ = stream.try_write(&buf);
+}
+
+/// Accepts services on the named pipe `name`, whose first instance is
+/// `server`. Each client holds an instance of its own, so another is created as
+/// each one connects. Pipe clients have no address; they are known by the
+/// loopback address, and only count toward the main listener's
+/// `max_connections`, shared through `connections`. TLS is never used, as the
+/// pipe doesn't leave the machine.
+#[cfg(all(windows, feature = "named-pipe"))]
+async fn accept_pipe_services(
+    name: String,
+    mut server: NamedPipeServer,
+    state: ServerState,
+    connections: Arc<ConnectionTracker>,
+) {
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    // Tracked apart from TCP clients on the loopback address, so that pipe
+    // clients don't count toward their per-IP limit.
+    let tracked_ip = IpAddr::from([0, 0, 0, 0]);
+
+    loop {
+        let connected = server.connect().await;
+
+        // The next client needs an instance to connect to before this one is
+        // handed off.
+        let stream = match ServerOptions::new().create(&name) {
+            Ok(next) => std::mem::replace(&mut server, next),
+            Err(e) => {
+                tracing::error!("Error creating an instance of {name}: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        if let Err(e) = connected {
+            tracing::error!("Error accepting connection on {name}: {e}");
+            continue;
+        }
+
+        let max_connections = state.config.read().await.listener.max_connections;
+        let guard = match connections.try_acquire(tracked_ip, max_connections, None) {
+            Ok(guard) => guard,
+            Err(e) => {
+                tracing::warn!("Rejected connection on {name}: {e}");
+                continue;
+            }
+        };
+
+        let id = ConnectionId::next();
+        let span = tracing::info_span!("connection", %id, addr = %name, service = field::Empty);
+        tracing::info!(parent: &span, "Service connected");
+
+        let metrics = Arc::clone(&state.metrics);
+        let state = state.clone();
+        let task = async move {
+            if let Err(e) = handle_connection(id, addr, stream, None, state).await {
+                tracing::error!("Error handling connection: {e}");
+            }
+
+            drop(guard);
+        };
+        spawn_isolated(task, span, metrics);
+    }
+}
+
+/// Spawns a connection's task, watching it from a second task so that a panic
+/// is logged under the connection's span and counted, rather than only killing
+/// the task. Shared state is only updated with whole, validated actions, and
+/// its locks ignore poisoning, so other connections carry on unaffected.
+fn spawn_isolated(
+    task: impl Future<Output = ()> + Send + 'static,
+    span: Span,
+    metrics: Arc<Metrics>,
+) {
+    let handle = tokio::spawn(task.instrument(span.clone()));
+
+    tokio::spawn(
+        async move {
+            if let Err(e) = handle.await {
+                if e.is_panic() {
+                    metrics.record_connection_panic();
+                    tracing::error!("Connection task panicked: {}", panic_message(e.into_panic()));
+                }
+            }
+        }
+        .instrument(span),
+    );
+}
+
+/// Resolves once a newer connection for the same service has asked this one to
+/// close. Never resolves for unnamed services.
+async fn kicked(registration: Option<&ServiceGuard>) {
+    match registration {
+        Some(guard) => guard.kicked().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Returns the message a panic was raised with, if it has one.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => "no message".to_string(),
+        },
+    }
+}
+
+/// Binds a listener on `addr`. When `addr` is an IPv6 address and `ipv6_only`
+/// is off, the socket also accepts IPv4 clients on platforms which allow it,
+/// so binding `::` serves both address families.
+fn bind(addr: SocketAddr, ipv6_only: bool) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.is_ipv6() {
+        if let Err(e) = socket.set_only_v6(ipv6_only) {
+            tracing::warn!("Unable to set IPV6_V6ONLY on {addr}; using the platform default: {e}");
+        }
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6
+/// addresses, such as `::ffff:10.0.0.1`. Converting them back means they are
+/// logged, limited, and looked up the same as on an IPv4 listener.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Completes the TLS handshake, if enabled, then handles the connection. Like
+/// the handshake frame, the TLS handshake must finish within the idle timeout.
+async fn serve(
+    id: ConnectionId,
+    addr: SocketAddr,
+    stream: TcpStream,
+    state: ServerState,
+) -> Result<()> {
+    let Some(acceptor) = state.tls.clone() else {
+        return handle_connection(id, addr, stream, None, state).await;
+    };
+
+    let accept = acceptor.accept(stream);
+    let stream = match state.config.read().await.get_idle_timeout() {
+        Some(duration) => match timeout(duration, accept).await {
+            Ok(stream) => stream?,
+            Err(_) => {
+                tracing::info!(%addr, "Service timed out during TLS handshake");
+                return Ok(());
+            }
+        },
+        None => accept.await?,
+    };
+
+    let certificate = tls::peer_identity(&stream);
+    handle_connection(id, addr, stream, certificate, state).await
+}
+
+/// Handles a single connection from an external service. Responsible for
+/// parsing incoming messages, converting them into `Action`s, and sending them
+/// to the queue. `certificate` is the name on the service's verified client
+/// certificate, if it presented one.
+async fn handle_connection<S>(
+    id: ConnectionId,
+    addr: SocketAddr,
+    stream: S,
+    certificate: Option<String>,
+    state: ServerState,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut frame = Framed::new(stream, FrameCodec::new());
+
+    // Connections which don't send anything within the idle timeout are closed
+    // so that dead clients don't hold sockets open forever.
+    let mut idle_timeout = state.config.read().await.get_idle_timeout();
+    let mut last_frame = Instant::now();
+
+    // Services must identify themselves before sending any actions.
+    let handshake = match read_handshake(&mut frame, idle_timeout).await? {
+        Some(Ok(handshake)) => handshake,
+        // Nothing past the version can be read, so whether the service
+        // understands responses isn't known; it is told why either way.
+        Some(Err(ProtocolError::UnsupportedVersion(version))) => {
+            let reason = format!(
+                "Service speaks protocol version {version}, but harpd speaks {PROTOCOL_VERSION}"
+            );
+            tracing::warn!("Refused service from {addr}: {reason}");
+            nack(&mut frame, true, NackCode::UnsupportedVersion, None, reason).await?;
+            return Ok(());
+        }
+        Some(Err(e)) => return Err(e.into()),
+        None => {
+            tracing::info!(%addr, "Service disconnected before handshake");
+            return Ok(());
+        }
+    };
+    let checksums = handshake.checksums;
+    let responses = handshake.responses;
+
+    // The authenticator decides what the service is known as, which may not
+    // be the name it announced.
+    let request = AuthRequest {
+        addr,
+        service: handshake.service.as_deref(),
+        credentials: handshake.credentials.as_ref(),
+        certificate: certificate.as_deref(),
+    };
+    let service = match state.authenticator.authenticate(&request) {
+        Ok(service) => service,
+        Err(e) => {
+            tracing::warn!(service = ?handshake.service, "Refused service from {addr}: {e}");
+            state.metrics.record_auth_failure();
+            nack(&mut frame, responses, NackCode::Unauthorized, None, e.to_string()).await?;
+            return Ok(());
+        }
+    };
+
+    // Signed frames can only be checked if harpd has the key, and unsigned
+    // ones are refused if signing is required.
+    let frame_key = match &state.frame_key {
+        Some(key) if handshake.signed => Some(Arc::clone(key)),
+        None if handshake.signed => {
+            let reason = "Service signs its frames, but signing.key_file is not set";
+            tracing::warn!("Refused service from {addr}: {reason}");
+            nack(&mut frame, responses, NackCode::SigningUnavailable, None, reason.into()).await?;
+            return Ok(());
+        }
+        _ if state.config.read().await.signing.required => {
+            let reason = "Service does not sign its frames, which signing.required demands";
+            tracing::warn!("Refused service from {addr}: {reason}");
+            nack(&mut frame, responses, NackCode::BadSignature, None, reason.into()).await?;
+            return Ok(());
+        }
+        _ => None,
+    };
+
+    // Frames up to 64KB are always read in full, so that oversized ones can be
+    // answered with a NACK; beyond that, no more than the packet size limit is
+    // buffered, whatever the service announced.
+    let max_packet_size = state.config.read().await.get_max_packet_size();
+    let max_frame_size =
+        (handshake.max_frame_size as usize).min(max_packet_size.max(usize::from(u16::MAX)));
+    frame.codec_mut().negotiate(handshake.length_field, max_frame_size);
+
+    // Let the service know the real limit, so it can drop oversized actions
+    // itself rather than sending them.
+    if responses && max_packet_size < handshake.max_frame_size as usize {
+        let settings = vec![("max_frame_size".to_string(), max_packet_size.to_string())];
+        frame.send(Response::Config(settings).encode()?).await?;
+    }
+    let name = service.as_deref().unwrap_or("<unnamed>");
+    Span::current().record("service", name);
+    tracing::info!("Service identified");
+
+    // Unnamed services can't be told apart, so only named ones are checked
+    // for duplicates.
+    let registration = match &service {
+        Some(name) => {
+            let policy = state.config.read().await.listener.duplicate_services;
+            match state.services.register(name, id, policy) {
+                Ok((guard, None)) => Some(guard),
+                Ok((guard, Some(existing))) => {
+                    match policy {
+                        DuplicatePolicy::KickOld => {
+                            tracing::warn!("Duplicate service; closing its connection {existing}")
+                        }
+                        _ => tracing::warn!("Duplicate service; also connected as {existing}"),
+                    }
+                    Some(guard)
+                }
+                Err(existing) => {
+                    tracing::warn!("Rejected duplicate service, already connected as {existing}");
+                    let reason = format!("Service {name} is already connected");
+                    nack(&mut frame, responses, NackCode::DuplicateService, None, reason).await?;
+                    return Ok(());
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut sequence = SequenceTracker::default();
+
+    // Every frame after the handshake has an index, which signed frames are
+    // verified against, whether or not it makes it that far.
+    let mut frames_read = 0_u64;
+
+    // The number of old actions caught up since the service last sent a live
+    // one, as it does when it reconnects and resends everything it buffered.
+    let mut caught_up = 0_u64;
+
+    loop {
+        let idle_deadline = last_frame + idle_timeout.unwrap_or_default();
+
+        tokio::select! {
+            _ = sleep_until(idle_deadline), if idle_timeout.is_some() => {
+                tracing::info!("Closing idle connection: {addr}");
+                break;
+            }
+            _ = kicked(registration.as_ref()) => {
+                tracing::warn!("Closing connection replaced by a newer one for the service");
+                break;
+            }
+            result = frame.next() => match result {
+                Some(Ok(bytes)) => {
+                    last_frame = Instant::now();
+
+                    // Drop connections that send packets larger than the
+                    // assigned limit in order to prevent DoS attacks. The
+                    // limits are read per-packet so that reloads apply to
+                    // existing connections.
+                    let max_packet_size = {
+                        let config = state.config.read().await;
+                        idle_timeout = config.get_idle_timeout();
+                        config.get_max_packet_size()
+                    };
+                    let length = bytes.len();
+                    if length > max_packet_size {
+                        tracing::warn!("Packet size exceeds limit: {length} bytes from {addr}");
+                        let reason = format!("{length} bytes exceeds {max_packet_size}");
+                        nack(&mut frame, responses, NackCode::TooLarge, None, reason).await?;
+                        break;
+                    }
+
+                    let index = frames_read;
+                    frames_read += 1;
+
+                    // Corrupt frames are discarded before parsing, as they
+                    // could otherwise decode into a nonsense action.
+                    let bytes = if checksums {
+                        match strip_checksum(bytes) {
+                            Ok(bytes) => bytes,
+                            Err(e) => {
+                                tracing::warn!("Discarded frame from {addr}: {e}");
+                                state.metrics.record_corrupt();
+                                let reason = e.to_string();
+                                nack(&mut frame, responses, NackCode::Corrupt, None, reason).await?;
+                                continue;
+                            }
+                        }
+                    } else {
+                        bytes
+                    };
+
+                    // Frames which fail their signature may have been
+                    // tampered with in transit, so they are never parsed.
+                    let bytes = match &frame_key {
+                        Some(key) => match strip_signature(key, index, bytes) {
+                            Ok(bytes) => bytes,
+                            Err(e) => {
+                                tracing::warn!("Rejected frame from {addr}: {e}");
+                                state.metrics.record_bad_signature();
+                                let (code, reason) = (NackCode::BadSignature, e.to_string());
+                                nack(&mut frame, responses, code, None, reason).await?;
+                                continue;
+                            }
+                        },
+                        None => bytes,
+                    };
+
+                    // While ingest is paused, actions are handed straight back
+                    // to the service, which keeps them in its reserve queue
+                    // until ingest resumes.
+                    if state.is_refusing_ingest().await {
+                        requeue(&mut frame, responses, bytes.freeze()).await?;
+                        continue;
+                    }
+
+                    let mut action = match Action::decode(&bytes) {
+                        Ok(action) => action,
+                        Err(e) => {
+                            tracing::error!("{e}");
+                            let reason = e.to_string();
+                            nack(&mut frame, responses, NackCode::ParseError, None, reason).await?;
+                            continue;
+                        }
+                    };
+
+                    if let Some(gap) = action.sequence.and_then(|seq| sequence.observe(seq)) {
+                        let missing = gap.end() - gap.start() + 1;
+                        tracing::warn!("Sequence gap from {addr}: {missing} actions missing ({gap:?})");
+                        state.metrics.record_sequence_gap(missing);
+                    }
+
+                    // Actions retried long after they happened can be worse
+                    // than no data, so stale ones are dropped.
+                    let (max_age, catch_up_age, catch_up_max) = {
+                        let config = state.config.read().await;
+                        let max_age = config.get_max_age(&action.kind);
+                        (max_age, config.get_catch_up_age(), config.get_catch_up_max_queued())
+                    };
+                    let age = OffsetDateTime::now_utc() - action.created;
+                    if max_age.is_some_and(|max_age| age > max_age) {
+                        tracing::debug!("Dropped expired {} action from {addr}", action.kind);
+                        state.metrics.record_expired();
+                        continue;
+                    }
+
+                    // Old actions are queued behind live ones, so that a
+                    // service catching up after a long disconnect doesn't
+                    // hold up everyone else's. High priority actions still
+                    // go first, however old.
+                    let catch_up = action.priority == Priority::Normal
+                        && catch_up_age.is_some_and(|catch_up_age| age > catch_up_age);
+                    if catch_up {
+                        if caught_up == 0 {
+                            tracing::info!("Catching up on old actions from {addr}");
+                        }
+                        caught_up += 1;
+                    } else if caught_up > 0 {
+                        tracing::info!("Caught up on {caught_up} old actions from {addr}");
+                        caught_up = 0;
+                    }
+
+                    // Caught up actions have their own room in the queue.
+                    // Once it's full, they go back to the service to be
+                    // resent later, rather than crowding out live ones.
+                    if catch_up && state.metrics.catch_up() >= catch_up_max as u64 {
+                        tracing::debug!("Catch-up lane is full; returning action to {addr}");
+                        requeue(&mut frame, responses, bytes.freeze()).await?;
+                        continue;
+                    }
+
+                    if !state.schemas.is_known(&action.kind) {
+                        tracing::warn!("Rejected {} action from {addr}: unknown kind", action.kind);
+                        state.metrics.record_rejected();
+                        let (code, sequence) = (NackCode::UnknownKind, action.sequence);
+                        let reason = format!("{} is not a known kind", action.kind);
+                        nack(&mut frame, responses, code, sequence, reason).await?;
+                        continue;
+                    }
+
+                    if let Err(reason) = state.schemas.validate(&action) {
+                        tracing::warn!("Rejected {} action from {addr}: {reason}", action.kind);
+                        state.metrics.record_rejected();
+                        let (code, sequence) = (NackCode::SchemaInvalid, action.sequence);
+                        let reason = format!("{} action: {reason}", action.kind);
+                        nack(&mut frame, responses, code, sequence, reason).await?;
+                        continue;
+                    }
+
+                    // Checked after validation, so that schemas see the
+                    // detail as it was sent.
+                    let detail_limit = state.config.read().await.get_detail_limit();
+                    if let Some((max_size, policy)) = detail_limit {
+                        let kind = &action.kind;
+                        match check_detail_size(&mut action.detail, max_size, policy) {
+                            DetailSize::Within => {}
+                            DetailSize::Truncated(size) => {
+                                tracing::debug!("Truncated {size} byte {kind} detail from {addr}");
+                                state.metrics.record_truncated();
+                            }
+                            DetailSize::TooLarge(size) => {
+                                tracing::warn!("Rejected {size} byte {kind} detail from {addr}");
+                                state.metrics.record_rejected();
+                                let (code, sequence) = (NackCode::TooLarge, action.sequence);
+                                let reason = format!("{size} byte detail exceeds {max_size}");
+                                nack(&mut frame, responses, code, sequence, reason).await?;
+                                continue;
+                            }
+                        }
+                    }
+
+                    action.source = service.clone();
+                    state.geoip.enrich(&mut action);
+
+                    if let Some(counters) = &state.counters {
+                        counters.observe(&mut action);
+                    }
+
+                    let Some(action) = state.transforms.apply(action) else {
+                        continue;
+                    };
+
+                    state.subscriptions.publish(&action);
+                    state.alerts.observe(&action);
+
+                    match state.queue.try_send(Queued { catch_up, ..Queued::new(id, action) }) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(Queued { action, .. })) => {
+                            tracing::debug!("Queue is full; returning action to {addr}");
+
+                            // We'll reconstruct the Bufferfish from the failing
+                            // Action and send it back to the service where it
+                            // will be stored in a reserve queue to resend
+                            // later.
+                            let bf = Bufferfish::try_from(action)?;
+                            requeue(&mut frame, responses, bf.into()).await?;
+                        }
+                        Err(TrySendError::Closed(_)) => {
+                            return Err("Queue processor has stopped".into());
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    tracing::error!("Error reading from service stream: {e}");
+                    continue;
+                }
+                None => {
+                    tracing::info!(%addr, "Service disconnected");
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Hands an action back to a service to be retried later, wrapped in a
+/// `Response` if the service understands them.
+async fn requeue<S>(frame: &mut Framed<S, FrameCodec>, responses: bool, action: Bytes) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let action = if responses { Response::Requeue(action).encode()? } else { action };
+    frame.send(action).await?;
+
+    Ok(())
+}
+
+/// Tells a service why one of its frames was rejected. Services which don't
+/// understand responses aren't told, and the frame is dropped silently.
+async fn nack<S>(
+    frame: &mut Framed<S, FrameCodec>,
+    responses: bool,
+    code: NackCode,
+    sequence: Option<u64>,
+    reason: String,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if responses {
+        frame.send(Response::Nack(Nack { code, sequence, reason }).encode()?).await?;
+    }
+
+    Ok(())
+}
+
+/// Logs the busiest (ip, id) pairs at the end of each rate counter window.
+async fn report_top_talkers(counters: Arc<RateCounters>, window: Duration, top: usize) {
+    let mut interval = interval(window);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let talkers = counters.top_talkers(top);
+        if talkers.is_empty() {
+            continue;
+        }
+
+        let talkers = talkers
+            .iter()
+            .map(|((ip, id), count)| format!("{ip}/{id}: {count}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        tracing::info!("Top talkers over the last {}s: {talkers}", window.as_secs());
+    }
+}
+
+/// Accepts subscriber connections until the listener fails.
+async fn accept_subscribers(listener: TcpListener, state: ServerState) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let addr = canonical(addr);
+                tracing::info!("Subscriber connected: {addr}");
+
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_subscriber(addr, stream, state).await {
+                        tracing::error!("Error handling subscriber: {e}");
+                    }
+                });
+            }
+            Err(e) => tracing::error!("Error accepting subscriber: {e}"),
+        }
+    }
+}
+
+/// Handles a single subscriber connection: reads its subscription, then
+/// forwards matching actions to it until it disconnects. Anything else the
+/// subscriber sends is ignored.
+async fn handle_subscriber(addr: SocketAddr, stream: TcpStream, state: ServerState) -> Result<()> {
+    let mut frame = LengthDelimitedCodec::builder().length_field_type::<u16>().new_framed(stream);
+
+    let idle_timeout = state.config.read().await.get_idle_timeout();
+    let subscribe = match read_opening_frame(&mut frame, idle_timeout).await? {
+        Some(bytes) => Subscribe::try_from(Bufferfish::from(bytes))?,
+        None => {
+            tracing::info!("Subscriber disconnected before subscribing: {addr}");
+            return Ok(());
+        }
+    };
+    tracing::info!("Subscriber {addr} subscribed to {:?}", subscribe.kinds);
+
+    let buffer = state.config.read().await.get_subscriber_buffer();
+    let mut actions = state.subscriptions.subscribe(subscribe.kinds, buffer);
+
+    loop {
+        tokio::select! {
+            Some(bytes) = actions.recv() => frame.send(bytes).await?,
+            result = frame.next() => match result {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            }
+        }
+    }
+
+    tracing::info!("Subscriber disconnected: {addr}");
+
+    Ok(())
+}
+
+/// Reads the handshake frame which opens every service connection, leaving
+/// it to the caller to answer one which can't be decoded. Returns `Ok(None)`
+/// if the service disconnects or times out before sending one.
+async fn read_handshake<S>(
+    frame: &mut Framed<S, FrameCodec>,
+    idle_timeout: Option<Duration>,
+) -> Result<Option<std::result::Result<Handshake, ProtocolError>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let bytes = read_opening_frame(frame, idle_timeout).await?;
+    Ok(bytes.map(|bytes| Handshake::try_from(Bufferfish::from(bytes))))
+}
+
+/// Reads the first frame of a connection. Returns `Ok(None)` if the peer
+/// disconnects or times out before sending one.
+async fn read_opening_frame<S, C>(
+    frame: &mut Framed<S, C>,
+    idle_timeout: Option<Duration>,
+) -> Result<Option<BytesMut>>
+where
+    S: AsyncRead + Unpin,
+    C: Decoder<Item = BytesMut, Error = std::io::Error>,
+{
+    let next = match idle_timeout {
+        Some(duration) => match timeout(duration, frame.next()).await {
+            Ok(next) => next,
+            Err(_) => return Ok(None),
+        },
+        None => frame.next().await,
+    };
+
+    match next {
+        Some(Ok(bytes)) => Ok(Some(bytes)),
+        Some(Err(e)) => Err(e.into()),
+        None => Ok(None),
+    }
+}
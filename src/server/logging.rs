@@ -0,0 +1,133 @@
+//! Sets up tracing output for harpd: plain text or JSON on stdout, optionally
+//! copied to rotating log files.
+use std::num::NonZeroUsize;
+
+use tracing::Subscriber;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, reload, util::SubscriberInitExt,
+    Layer,
+};
+
+#[cfg(feature = "otel")]
+use crate::server::otel;
+use crate::{
+    server::{
+        config::{LogFormat, LogRotation, LoggingConfig},
+        reload::{build_env_filter, LogHandle},
+    },
+    Result,
+};
+
+/// The installed tracing subscriber. Log files are written from a background
+/// thread, which is flushed and stopped when this is dropped, so it should be
+/// held until the process exits.
+pub struct Logging {
+    handle: LogHandle,
+    _guard: Option<WorkerGuard>,
+    #[cfg(feature = "otel")]
+    tracer: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Logging {
+    /// Returns a handle to the tracing filter, for `ServerBuilder::log_handle`.
+    pub fn handle(&self) -> LogHandle {
+        self.handle.clone()
+    }
+}
+
+/// Installs the global tracing subscriber described by `config`. The filter
+/// starts from `RUST_LOG` and is wrapped in a reload layer, so that the log
+/// level can be changed later from the config file or the admin socket.
+pub fn init(config: &LoggingConfig) -> Result<Logging> {
+    let (filter, handle) = reload::Layer::new(build_env_filter(None));
+
+    let (file, guard) = match &config.directory {
+        Some(directory) => {
+            let mut builder = RollingFileAppender::builder()
+                .rotation(config.rotation.into())
+                .filename_prefix("harpd")
+                .filename_suffix("log");
+            if let Some(max_files) = config.max_files.map(NonZeroUsize::get) {
+                builder = builder.max_log_files(max_files);
+            }
+
+            let (writer, guard) = tracing_appender::non_blocking(builder.build(directory)?);
+            (Some(fmt_layer(config.format, writer, false)), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    #[cfg(feature = "otel")]
+    let (otel, tracer) = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let (layer, tracer) = otel::layer(endpoint, config.get_otlp_service_name())?;
+            (Some(layer), Some(tracer))
+        }
+        None => (None, None),
+    };
+
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer(config.format, std::io::stdout, true))
+        .with(file);
+
+    #[cfg(feature = "otel")]
+    registry.with(otel).try_init()?;
+    #[cfg(not(feature = "otel"))]
+    registry.try_init()?;
+
+    #[cfg(not(feature = "otel"))]
+    if config.otlp_endpoint.is_some() {
+        tracing::warn!(
+            "logging.otlp_endpoint is set, but harpd was built without the otel feature"
+        );
+    }
+
+    Ok(Logging {
+        handle,
+        _guard: guard,
+        #[cfg(feature = "otel")]
+        tracer,
+    })
+}
+
+/// Builds an output layer in the given format. JSON events include the fields
+/// of the span they were logged in, such as the connection they came from.
+fn fmt_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi);
+
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).with_span_list(false).boxed(),
+    }
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Logging {
+    fn drop(&mut self) {
+        // Send any spans still buffered by the exporter.
+        if let Some(tracer) = self.tracer.take() {
+            if let Err(e) = tracer.shutdown() {
+                eprintln!("Failed to shut down trace exporter: {e}");
+            }
+        }
+    }
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(value: LogRotation) -> Self {
+        match value {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+/// The most kinds counted separately. Actions of any further kinds are counted
+/// together under `OTHER_KINDS`, so that a misbehaving service can't grow the
+/// map without bound.
+const MAX_TRACKED_KINDS: usize = 1000;
+
+/// The key actions of untracked kinds are counted under.
+const OTHER_KINDS: &str = "_other";
+
+/// Counters describing harpd's activity since it started. Shared between tasks
+/// behind an `Arc`; all updates are relaxed atomics, so values read together
+/// may be very slightly out of sync.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    /// Number of successful batch inserts.
+    flushes: AtomicU64,
+    /// Number of actions written to the database.
+    rows_inserted: AtomicU64,
+    /// Sum of all batch insert latencies, in microseconds.
+    insert_latency_total_micros: AtomicU64,
+    /// Slowest batch insert observed, in microseconds.
+    insert_latency_max_micros: AtomicU64,
+    /// Number of gaps detected in service sequence numbers.
+    sequence_gaps: AtomicU64,
+    /// Number of actions missing across all sequence gaps.
+    sequence_missing: AtomicU64,
+    /// Number of actions rejected for failing validation.
+    rejected: AtomicU64,
+    /// Number of actions dropped for arriving after their maximum age.
+    expired: AtomicU64,
+    /// Number of frames discarded because their checksum did not match.
+    corrupt: AtomicU64,
+    /// Number of frames rejected because their signature did not match.
+    bad_signatures: AtomicU64,
+    /// Number of actions stored with their oversized detail truncated.
+    truncated: AtomicU64,
+    /// Number of service connection tasks which panicked.
+    connection_panics: AtomicU64,
+    /// Number of services refused because their credentials were missing or
+    /// not accepted.
+    auth_failures: AtomicU64,
+    /// Number of times the queue processor was restarted after panicking or
+    /// stalling.
+    processor_restarts: AtomicU64,
+    /// Number of actions written to the database, by kind.
+    kinds: Mutex<HashMap<String, u64>>,
+    /// Number of actions waiting in the queue to be written.
+    queue_depth: AtomicU64,
+    /// Approximate memory used by the actions waiting in the queue, in bytes.
+    queue_bytes: AtomicU64,
+    /// When the live action which has been waiting in the queue longest
+    /// arrived.
+    oldest_queued: Mutex<Option<Instant>>,
+    /// Number of caught up actions waiting in the queue, behind live ones.
+    catch_up: AtomicU64,
+    /// Most actions currently inserted in a single transaction.
+    batch_size: AtomicU64,
+    /// Number of actions spilled to disk waiting to be read back.
+    spilled: AtomicU64,
+}
+
+impl Metrics {
+    /// Records a successful batch insert of `rows` actions which took
+    /// `latency` to complete.
+    pub(crate) fn record_insert(&self, rows: usize, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.rows_inserted.fetch_add(rows as u64, Ordering::Relaxed);
+        self.insert_latency_total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.insert_latency_max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Records a service connection task which panicked.
+    pub(crate) fn record_connection_panic(&self) {
+        self.connection_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a service which was refused by the authenticator.
+    pub(crate) fn record_auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a restart of the queue processor.
+    pub(crate) fn record_processor_restart(&self) {
+        self.processor_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds the number of actions written to the database for each kind.
+    pub(crate) fn record_kinds<'a>(&self, counts: impl IntoIterator<Item = (&'a str, u64)>) {
+        let mut kinds = self.kinds.lock().unwrap_or_else(|e| e.into_inner());
+        for (kind, count) in counts {
+            if let Some(total) = kinds.get_mut(kind) {
+                *total += count;
+            } else if kinds.len() < MAX_TRACKED_KINDS {
+                kinds.insert(kind.to_string(), count);
+            } else {
+                *kinds.entry(OTHER_KINDS.to_string()).or_default() += count;
+            }
+        }
+    }
+
+    /// Records the most actions currently inserted in a single transaction.
+    pub(crate) fn record_batch_size(&self, size: usize) {
+        self.batch_size.store(size as u64, Ordering::Relaxed);
+    }
+
+    /// Records the number of actions spilled to disk.
+    pub(crate) fn record_spilled(&self, spilled: usize) {
+        self.spilled.store(spilled as u64, Ordering::Relaxed);
+    }
+
+    /// Records the number of caught up actions waiting in the queue.
+    pub(crate) fn record_catch_up(&self, waiting: usize) {
+        self.catch_up.store(waiting as u64, Ordering::Relaxed);
+    }
+
+    /// Records the number of actions waiting in the queue, the approximate
+    /// memory they use, and when the oldest live one arrived.
+    pub(crate) fn record_queue(&self, depth: usize, bytes: usize, oldest: Option<Instant>) {
+        self.queue_depth.store(depth as u64, Ordering::Relaxed);
+        self.queue_bytes.store(bytes as u64, Ordering::Relaxed);
+        *self.oldest_queued.lock().unwrap_or_else(|e| e.into_inner()) = oldest;
+    }
+
+    /// Records a gap of `missing` actions in a service's sequence numbers.
+    pub(crate) fn record_sequence_gap(&self, missing: u64) {
+        self.sequence_gaps.fetch_add(1, Ordering::Relaxed);
+        self.sequence_missing.fetch_add(missing, Ordering::Relaxed);
+    }
+
+    /// Records an action which was rejected for failing validation.
+    pub(crate) fn record_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an action which was dropped for arriving after its maximum age.
+    pub(crate) fn record_expired(&self) {
+        self.expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a frame which was discarded because its checksum did not match.
+    pub(crate) fn record_corrupt(&self) {
+        self.corrupt.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a frame which was rejected because its signature did not match.
+    pub(crate) fn record_bad_signature(&self) {
+        self.bad_signatures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an action whose oversized detail was truncated.
+    pub(crate) fn record_truncated(&self) {
+        self.truncated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of successful batch inserts.
+    pub(crate) fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of gaps detected in service sequence numbers.
+    pub(crate) fn sequence_gaps(&self) -> u64 {
+        self.sequence_gaps.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of actions missing across all sequence gaps.
+    pub(crate) fn sequence_missing(&self) -> u64 {
+        self.sequence_missing.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of actions rejected for failing validation.
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of actions dropped for arriving after their maximum
+    /// age.
+    pub(crate) fn expired(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of frames discarded because their checksum did not
+    /// match.
+    pub(crate) fn corrupt(&self) -> u64 {
+        self.corrupt.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of frames rejected because their signature did not
+    /// match.
+    pub(crate) fn bad_signatures(&self) -> u64 {
+        self.bad_signatures.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of actions stored with their oversized detail
+    /// truncated.
+    pub(crate) fn truncated(&self) -> u64 {
+        self.truncated.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of actions written to the database.
+    pub(crate) fn rows_inserted(&self) -> u64 {
+        self.rows_inserted.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of service connection tasks which panicked.
+    pub(crate) fn connection_panics(&self) -> u64 {
+        self.connection_panics.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of services refused by the authenticator.
+    pub(crate) fn auth_failures(&self) -> u64 {
+        self.auth_failures.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of times the queue processor was restarted.
+    pub(crate) fn processor_restarts(&self) -> u64 {
+        self.processor_restarts.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of actions written to the database for each kind.
+    pub(crate) fn kinds(&self) -> HashMap<String, u64> {
+        self.kinds.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Returns the number of actions waiting in the queue.
+    pub(crate) fn queue_depth(&self) -> u64 {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn queue_bytes(&self) -> u64 {
+        self.queue_bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn batch_size(&self) -> u64 {
+        self.batch_size.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn spilled(&self) -> u64 {
+        self.spilled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn catch_up(&self) -> u64 {
+        self.catch_up.load(Ordering::Relaxed)
+    }
+
+    /// Returns how long the oldest action in the queue has been waiting, if
+    /// any are.
+    pub(crate) fn oldest_queued_age(&self) -> Option<Duration> {
+        self.oldest_queued.lock().unwrap_or_else(|e| e.into_inner()).map(|oldest| oldest.elapsed())
+    }
+
+    /// Returns the mean latency of all batch inserts.
+    pub(crate) fn average_insert_latency(&self) -> Duration {
+        let flushes = self.flushes.load(Ordering::Relaxed);
+        if flushes == 0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_micros(self.insert_latency_total_micros.load(Ordering::Relaxed) / flushes)
+    }
+
+    /// Returns the slowest batch insert latency.
+    pub(crate) fn max_insert_latency(&self) -> Duration {
+        Duration::from_micros(self.insert_latency_max_micros.load(Ordering::Relaxed))
+    }
+
+    /// Returns every counter as a JSON object, for the admin socket.
+    pub(crate) fn snapshot(&self) -> Value {
+        json!({
+            "flushes": self.flushes(),
+            "rows_inserted": self.rows_inserted(),
+            "average_insert_latency_ms": self.average_insert_latency().as_secs_f64() * 1000.0,
+            "max_insert_latency_ms": self.max_insert_latency().as_secs_f64() * 1000.0,
+            "sequence_gaps": self.sequence_gaps(),
+            "sequence_missing": self.sequence_missing(),
+            "rejected": self.rejected(),
+            "expired": self.expired(),
+            "corrupt": self.corrupt(),
+            "bad_signatures": self.bad_signatures(),
+            "truncated": self.truncated(),
+            "connection_panics": self.connection_panics(),
+            "auth_failures": self.auth_failures(),
+            "processor_restarts": self.processor_restarts(),
+            "kinds": self.kinds(),
+            "queue_depth": self.queue_depth(),
+            "queue_bytes": self.queue_bytes(),
+            "oldest_queued_age_ms": self.oldest_queued_age().map(|age| age.as_secs_f64() * 1000.0),
+            "batch_size": self.batch_size(),
+            "spilled_actions": self.spilled(),
+            "catch_up_actions": self.catch_up(),
+        })
+    }
+}
@@ -0,0 +1,122 @@
+//! Exports harpd's traces and metrics over OTLP, so the ingest pipeline shows
+//! up alongside the game servers in an existing observability stack. Enabled
+//! with the `otel` feature and `logging.otlp_endpoint`.
+use std::sync::Arc;
+
+use opentelemetry::{global, metrics::Meter, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    metrics::{PeriodicReader, SdkMeterProvider},
+    runtime,
+    trace::TracerProvider,
+    Resource,
+};
+use tracing::Subscriber;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+use crate::{server::metrics::Metrics, Result};
+
+fn resource(service_name: &str) -> Resource {
+    Resource::new([KeyValue::new("service.name", service_name.to_string())])
+}
+
+/// Builds a tracing layer which exports spans, such as each connection and
+/// each flush, to the collector at `endpoint`. The returned provider must be
+/// shut down before exiting so that buffered spans are sent.
+pub(crate) fn layer<S>(
+    endpoint: &str,
+    service_name: &str,
+) -> Result<(Box<dyn Layer<S> + Send + Sync>, TracerProvider)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let exporter = SpanExporter::builder().with_tonic().with_endpoint(endpoint).build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(resource(service_name))
+        .build();
+
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("harpd"));
+
+    Ok((layer.boxed(), provider))
+}
+
+/// Periodically exports the counters in `metrics` to the collector at
+/// `endpoint`, for as long as the process runs.
+pub(crate) fn export_metrics(
+    endpoint: &str,
+    service_name: &str,
+    metrics: Arc<Metrics>,
+) -> Result<()> {
+    let exporter = MetricExporter::builder().with_tonic().with_endpoint(endpoint).build()?;
+    let provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(exporter, runtime::Tokio).build())
+        .with_resource(resource(service_name))
+        .build();
+    global::set_meter_provider(provider);
+
+    register(&global::meter("harpd"), metrics);
+
+    Ok(())
+}
+
+/// Registers an observable instrument for each counter, read whenever the
+/// exporter collects.
+fn register(meter: &Meter, metrics: Arc<Metrics>) {
+    let counters: [(&'static str, &'static str, fn(&Metrics) -> u64); 12] = [
+        ("harpd.flushes", "Successful batch inserts", Metrics::flushes),
+        ("harpd.rows_inserted", "Actions written to the database", Metrics::rows_inserted),
+        ("harpd.sequence_gaps", "Gaps in service sequence numbers", Metrics::sequence_gaps),
+        (
+            "harpd.sequence_missing",
+            "Actions missing across sequence gaps",
+            Metrics::sequence_missing,
+        ),
+        ("harpd.rejected", "Actions rejected by validation", Metrics::rejected),
+        ("harpd.expired", "Actions dropped for exceeding their maximum age", Metrics::expired),
+        ("harpd.corrupt", "Frames discarded for failing their checksum", Metrics::corrupt),
+        (
+            "harpd.bad_signatures",
+            "Frames rejected for failing their signature",
+            Metrics::bad_signatures,
+        ),
+        (
+            "harpd.truncated",
+            "Actions stored with an oversized detail truncated",
+            Metrics::truncated,
+        ),
+        (
+            "harpd.connection_panics",
+            "Service connection tasks which panicked",
+            Metrics::connection_panics,
+        ),
+        (
+            "harpd.auth_failures",
+            "Services refused for missing or rejected credentials",
+            Metrics::auth_failures,
+        ),
+        (
+            "harpd.processor_restarts",
+            "Queue processor restarts after panicking or stalling",
+            Metrics::processor_restarts,
+        ),
+    ];
+
+    for (name, description, read) in counters {
+        let metrics = Arc::clone(&metrics);
+        meter
+            .u64_observable_counter(name)
+            .with_description(description)
+            .with_callback(move |observer| observer.observe(read(&metrics), &[]))
+            .build();
+    }
+
+    meter
+        .f64_observable_gauge("harpd.max_insert_latency")
+        .with_description("Slowest batch insert, in seconds")
+        .with_unit("s")
+        .with_callback(move |observer| {
+            observer.observe(metrics.max_insert_latency().as_secs_f64(), &[])
+        })
+        .build();
+}
@@ -0,0 +1,166 @@
+use std::{
+    fmt::Display,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Opens every PROXY protocol v2 header.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Length of the fixed part of a header, before the addresses.
+const HEADER_LEN: usize = 16;
+
+/// Longest a load balancer may take to send its header. The idle timeout
+/// applies instead when it is shorter.
+pub(crate) const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most headers each listener waits for at once. Further connections are
+/// refused until one arrives or times out, so that clients which never send
+/// one can't hold open an unbounded number of sockets.
+pub(crate) const MAX_PENDING_HEADERS: usize = 256;
+
+/// Reads the PROXY protocol v2 header a load balancer sends ahead of the
+/// connection it is forwarding, and returns the address of the client it
+/// forwarded. Returns `None` for connections the load balancer made itself,
+/// such as health checks, and for clients without an IP address. Exactly the
+/// header is read, so the stream is left at the service's first frame.
+pub(crate) async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0; HEADER_LEN];
+    stream.read_exact(&mut header).await.map_err(|_| ProxyError::Missing)?;
+    let (command, family, len) = parse_header(&header)?;
+
+    let mut addresses = vec![0; len];
+    stream.read_exact(&mut addresses).await.map_err(|_| ProxyError::Malformed)?;
+
+    match command {
+        Command::Local => Ok(None),
+        Command::Proxy => parse_source(family, &addresses),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    /// Sent by the load balancer on its own behalf.
+    Local,
+    /// Forwarded on behalf of a client.
+    Proxy,
+}
+
+/// Checks the fixed part of a header, returning its command, address family
+/// byte, and the length of the addresses which follow.
+fn parse_header(header: &[u8; HEADER_LEN]) -> Result<(Command, u8, usize), ProxyError> {
+    if header[..12] != SIGNATURE {
+        return Err(ProxyError::Missing);
+    }
+
+    if header[12] >> 4 != 2 {
+        return Err(ProxyError::UnsupportedVersion(header[12] >> 4));
+    }
+
+    let command = match header[12] & 0x0F {
+        0 => Command::Local,
+        1 => Command::Proxy,
+        _ => return Err(ProxyError::Malformed),
+    };
+
+    let len = u16::from_be_bytes([header[14], header[15]]);
+
+    Ok((command, header[13], usize::from(len)))
+}
+
+/// Returns the source address from the addresses of a header. Anything after
+/// them, such as TLVs, is ignored.
+fn parse_source(family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>, ProxyError> {
+    // The high nibble is the address family, and the low nibble the transport.
+    match family >> 4 {
+        // IPv4: source, destination, source port, destination port.
+        1 => {
+            let block = addresses.get(..12).ok_or(ProxyError::Malformed)?;
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let port = u16::from_be_bytes([block[8], block[9]]);
+
+            Ok(Some(SocketAddr::from((ip, port))))
+        }
+        // IPv6, laid out in the same way.
+        2 => {
+            let block = addresses.get(..36).ok_or(ProxyError::Malformed)?;
+            let octets: [u8; 16] = block[..16].try_into().expect("slice is 16 bytes");
+            let port = u16::from_be_bytes([block[32], block[33]]);
+
+            Ok(Some(SocketAddr::from((Ipv6Addr::from(octets), port))))
+        }
+        // Unspecified, or Unix sockets.
+        _ => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ProxyError {
+    /// The connection didn't open with a PROXY protocol v2 header.
+    Missing,
+    /// The header is for a version other than 2.
+    UnsupportedVersion(u8),
+    /// The header's command or addresses are invalid.
+    Malformed,
+}
+
+impl std::error::Error for ProxyError {}
+
+impl Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::Missing => write!(f, "Missing PROXY protocol v2 header"),
+            ProxyError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported PROXY protocol version {version}")
+            }
+            ProxyError::Malformed => write!(f, "Malformed PROXY protocol header"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn read_proxy_headers() {
+        // 203.0.113.7:51000 to 10.0.0.5:7777, followed by the first frame.
+        let mut stream = header(1, 0x11, &[203, 0, 113, 7, 10, 0, 0, 5, 0xC7, 0x38, 0x1E, 0x61]);
+        stream.extend(b"frame");
+        let mut reader = stream.as_slice();
+        let addr = read_header(&mut reader).await.unwrap();
+        assert_eq!(addr, Some(SocketAddr::from(([203, 0, 113, 7], 51000))));
+        assert_eq!(reader, b"frame");
+
+        let mut addresses = [0; 36];
+        addresses[15] = 1;
+        addresses[32..34].copy_from_slice(&443u16.to_be_bytes());
+        let stream = header(1, 0x21, &addresses);
+        let addr = read_header(&mut stream.as_slice()).await.unwrap();
+        assert_eq!(addr, Some(SocketAddr::from((Ipv6Addr::LOCALHOST, 443))));
+
+        // Health checks from the load balancer itself.
+        let stream = header(0, 0x00, &[]);
+        assert_eq!(read_header(&mut stream.as_slice()).await, Ok(None));
+
+        // A service connecting directly, without a header.
+        let stream = b"\x00\x10 a handshake frame".to_vec();
+        assert_eq!(read_header(&mut stream.as_slice()).await, Err(ProxyError::Missing));
+
+        let truncated = header(1, 0x11, &[203, 0, 113, 7]);
+        assert_eq!(read_header(&mut truncated.as_slice()).await, Err(ProxyError::Malformed));
+    }
+}
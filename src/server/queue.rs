@@ -0,0 +1,1538 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    io::Write,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::{PgArguments, PgRow},
+    query::Query,
+    types::ipnetwork::IpNetwork,
+    PgPool, Postgres, Row, Transaction,
+};
+use tokio::{
+    sync::{mpsc, oneshot, watch, Mutex},
+    task::JoinSet,
+    time::{interval, interval_at, sleep, Instant},
+};
+use tracing::Instrument;
+
+use crate::{
+    action::{Action, Priority, SESSION_END},
+    server::{
+        config::SharedConfig,
+        ephemeral::EphemeralStore,
+        metrics::Metrics,
+        spill::Spill,
+        sql::{chunk_sizes, Field, InsertStatements, ACTION_COLUMNS},
+    },
+    HarpId, Result,
+};
+
+/// The send half of the queue. Cheap to clone; each connection holds one.
+pub(crate) type QueueSender = mpsc::Sender<Queued>;
+
+/// A short identifier for an accepted service connection, included in its
+/// logs and in the logs of every flush containing its actions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// Returns the next unused ID.
+    pub(crate) fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "c{}", self.0)
+    }
+}
+
+/// An action waiting in the queue, along with the connection it arrived on.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Queued {
+    pub(crate) connection: ConnectionId,
+    pub(crate) action: Action,
+    /// When the action was accepted, for reporting how long it has waited.
+    /// Actions read back from a spill count from when they were read.
+    #[serde(skip, default = "Instant::now")]
+    pub(crate) received: Instant,
+    /// Set for actions old enough to be caught up, which wait behind live
+    /// ones. See `CatchUpConfig`.
+    #[serde(default)]
+    pub(crate) catch_up: bool,
+}
+
+impl Queued {
+    pub(crate) fn new(connection: ConnectionId, action: Action) -> Self {
+        Self { connection, action, received: Instant::now(), catch_up: false }
+    }
+}
+
+/// Requests an immediate flush of the whole queue, ignoring the flush time
+/// budget. The reply is sent once the queue has been written, or with the
+/// reason it couldn't be.
+pub(crate) type FlushSender = mpsc::Sender<FlushReply>;
+
+/// Answers a requested flush.
+pub(crate) type FlushReply = oneshot::Sender<std::result::Result<(), String>>;
+
+const POSTGRES_BIND_LIMIT: usize = 65535;
+/// The most actions which fit in a single batch insert.
+pub(crate) const LIMIT: usize = POSTGRES_BIND_LIMIT / ACTION_COLUMNS.len();
+
+/// The fewest actions in a batch once adaptive batching has shrunk it, if not
+/// configured.
+pub(crate) const DEFAULT_MIN_BATCH_SIZE: usize = 100;
+
+/// The number of actions to grow the queue by when it is full.
+const QUEUE_GROWTH: usize = 100;
+
+/// How many times a batch is attempted before it is put back in the queue.
+const INSERT_ATTEMPTS: u32 = 3;
+
+/// The delay before retrying a failed insert, doubled after each attempt.
+const INSERT_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// The longest flushes are paused for while the database is unavailable.
+const MAX_BREAKER_DELAY: Duration = Duration::from_secs(60);
+
+/// How often the watchdog checks that the queue processor is making progress.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The delay before a failed queue processor is restarted.
+const PROCESSOR_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Spawns the queue processor task and returns the channel used to feed it,
+/// along with a channel for requesting an immediate flush. Nothing is
+/// flushed until `ready` is true.
+///
+/// The processor owns the queue outright: connections send actions over a
+/// bounded channel, and the processor moves them into the queue between
+/// flushes. If the queue is full or cannot grow, the processor stops
+/// receiving, the channel fills up, and connections return actions to their
+/// services.
+///
+/// The processor is supervised, and restarted if it panics or stops making
+/// progress. The queue, and the actions still waiting in the channel, are
+/// kept for the new processor; only batches it was inserting at the time are
+/// lost.
+///
+/// When running ephemerally, each flush stores the queue in SQLite and
+/// prints it to stdout as JSON lines instead.
+pub(crate) async fn spawn_processor(
+    config: SharedConfig,
+    store: Store,
+    ready: watch::Receiver<bool>,
+    metrics: Arc<Metrics>,
+    watchdog: Arc<Watchdog>,
+) -> Result<(QueueSender, FlushSender)> {
+    let (tx, rx) = mpsc::channel::<Queued>(config.read().await.get_queue_capacity());
+    let (flush_tx, flush_rx) = mpsc::channel::<FlushReply>(16);
+
+    // The tables, rollups, and detail encryption can't be changed without a
+    // restart, so the insert statements are rendered once here.
+    let statements = Arc::new(config.read().await.get_insert_statements()?);
+
+    let sink = match store {
+        Store::Postgres(pg) => Sink::Postgres(Inserter {
+            pg: Arc::new(pg),
+            statements,
+            metrics: Arc::clone(&metrics),
+            watchdog: Arc::clone(&watchdog),
+        }),
+        Store::Ephemeral(store) => Sink::Ephemeral(store),
+    };
+
+    let processor = Processor {
+        config,
+        sink,
+        ready,
+        metrics,
+        watchdog,
+        rx: Arc::new(Mutex::new(rx)),
+        flush_rx: Arc::new(Mutex::new(flush_rx)),
+        lanes: Arc::default(),
+    };
+    tokio::spawn(supervise(processor));
+
+    Ok((tx, flush_tx))
+}
+
+/// Everything the queue processor needs, kept by its supervisor so that it
+/// can be restarted.
+#[derive(Clone)]
+struct Processor {
+    config: SharedConfig,
+    sink: Sink,
+    ready: watch::Receiver<bool>,
+    metrics: Arc<Metrics>,
+    watchdog: Arc<Watchdog>,
+    // Locked by the running processor, and released when it panics or is
+    // aborted.
+    rx: Arc<Mutex<mpsc::Receiver<Queued>>>,
+    flush_rx: Arc<Mutex<mpsc::Receiver<FlushReply>>>,
+    lanes: Arc<Mutex<Lanes>>,
+}
+
+/// Where actions are written, given to the queue processor.
+#[derive(Clone)]
+pub(crate) enum Store {
+    Postgres(PgPool),
+    /// An in-process SQLite database, for servers running ephemerally.
+    Ephemeral(EphemeralStore),
+}
+
+impl Store {
+    /// Returns the Postgres pool, unless running ephemerally.
+    pub(crate) fn postgres(&self) -> Option<PgPool> {
+        match self {
+            Self::Postgres(pg) => Some(pg.clone()),
+            Self::Ephemeral(_) => None,
+        }
+    }
+}
+
+/// How the queue processor flushes actions to their store.
+#[derive(Clone)]
+enum Sink {
+    Postgres(Inserter),
+    Ephemeral(EphemeralStore),
+}
+
+/// The actions moved out of the channel and waiting to be flushed. High
+/// priority actions are kept in their own lane, which is always inserted
+/// first, and caught up actions in another, which is inserted last and only a
+/// little at a time.
+#[derive(Debug, Default)]
+struct Lanes {
+    priority: Vec<Queued>,
+    queue: Vec<Queued>,
+    catch_up: Vec<Queued>,
+}
+
+/// Tracks whether the queue processor is making progress. Shared with
+/// connections, which may refuse actions while it is being restarted.
+#[derive(Debug)]
+pub(crate) struct Watchdog {
+    last_beat: std::sync::Mutex<Instant>,
+    healthy: AtomicBool,
+}
+
+impl Watchdog {
+    pub(crate) fn new() -> Self {
+        Self { last_beat: std::sync::Mutex::new(Instant::now()), healthy: AtomicBool::new(true) }
+    }
+
+    /// Returns false between the processor failing and its replacement
+    /// starting.
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Called by the processor on every pass through its loop, and after
+    /// every chunk and batch it inserts.
+    fn beat(&self) {
+        *self.last_beat.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+        self.healthy.store(true, Ordering::Relaxed);
+    }
+
+    fn last_beat(&self) -> Instant {
+        *self.last_beat.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_unhealthy(&self) {
+        self.healthy.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Runs the queue processor, restarting it whenever it panics or stalls.
+async fn supervise(processor: Processor) {
+    loop {
+        let started = Instant::now();
+        let mut task = tokio::spawn(run_processor(processor.clone()));
+
+        let reason = tokio::select! {
+            result = &mut task => match result {
+                Err(e) if e.is_panic() => "panicked",
+                _ => "stopped",
+            },
+            () = stalled(&processor, started) => {
+                task.abort();
+                "stalled"
+            }
+        };
+
+        processor.watchdog.set_unhealthy();
+        processor.metrics.record_processor_restart();
+        let kept = processor.metrics.queue_depth();
+        tracing::error!(
+            "Queue processor {reason}; restarting it. {kept} queued actions were kept, but any \
+             batches being inserted were lost"
+        );
+
+        sleep(PROCESSOR_RESTART_DELAY).await;
+    }
+}
+
+/// Resolves once the processor has gone longer than the process interval and
+/// the stall timeout without making progress.
+async fn stalled(processor: &Processor, started: Instant) {
+    loop {
+        sleep(WATCHDOG_INTERVAL).await;
+
+        let limit = {
+            let config = processor.config.read().await;
+            Duration::from_secs(config.get_process_interval_secs()) + config.get_stall_timeout()
+        };
+
+        if processor.watchdog.last_beat().max(started).elapsed() > limit {
+            return;
+        }
+    }
+}
+
+/// Moves actions from the channel into the queue and flushes it, until the
+/// task is aborted.
+async fn run_processor(processor: Processor) {
+    let Processor { config, sink, ready, metrics, watchdog, rx, flush_rx, lanes } = processor;
+    let mut rx = rx.lock_owned().await;
+    let mut flush_rx = flush_rx.lock_owned().await;
+    let mut lanes = lanes.lock_owned().await;
+    let Lanes { priority, queue, catch_up } = &mut *lanes;
+
+    let mut interval_secs = config.read().await.get_process_interval_secs();
+    let mut interval = interval(Duration::from_secs(interval_secs));
+    let mut thresholds = config.read().await.get_flush_thresholds();
+    let mut budget = config.read().await.get_flush_time_budget();
+    let mut limits = config.read().await.get_queue_limits();
+    let mut workers = config.read().await.get_flush_workers();
+    let mut catch_up_per_flush = config.read().await.get_catch_up_per_flush();
+    let mut sizer = BatchSizer::new(config.read().await.get_batch_sizing());
+    metrics.record_batch_size(sizer.size());
+
+    // Actions which don't fit in the queue are spilled to disk, if enabled.
+    let mut spill = match config.read().await.get_spill_options() {
+        Some(options) => {
+            let dir = options.dir.display().to_string();
+            Spill::open(options)
+                .map_err(|e| tracing::error!("Failed to open spill directory {dir}: {e}"))
+                .ok()
+        }
+        None => None,
+    };
+
+    // Initially, we will allocate space for 100 Actions. This will be
+    // resized as needed. After a restart, the lanes still hold whatever the
+    // last processor left in them.
+    queue.reserve(100);
+    let mut catch_up_bytes = catch_up.iter().map(|q| q.action.approximate_size()).sum::<usize>();
+    let mut queue_bytes =
+        priority.iter().chain(&*queue).map(|q| q.action.approximate_size()).sum::<usize>();
+    queue_bytes += catch_up_bytes;
+    let mut breaker = Breaker::default();
+
+    loop {
+        watchdog.beat();
+        let depth = priority.len() + queue.len() + catch_up.len();
+        metrics.record_queue(depth, queue_bytes, oldest(priority, queue));
+        metrics.record_catch_up(catch_up.len());
+        metrics.record_spilled(spill.as_ref().map_or(0, Spill::len));
+        let has_room = has_room(queue, priority, catch_up, queue_bytes - catch_up_bytes, limits);
+
+        // Once anything has spilled, new actions follow it onto disk until it
+        // has all been read back, so that they keep their order.
+        let spilling = spill
+            .as_ref()
+            .is_some_and(|spill| spill.has_room() && (!has_room || !spill.is_empty()));
+
+        // The queue is flushed on every tick, or early if it grows past
+        // either threshold, whichever comes first.
+        let mut requested = None;
+        let mut left_waiting = false;
+        let flush = tokio::select! {
+            _ = interval.tick() => true,
+            Some(reply) = flush_rx.recv() => {
+                // Move everything already waiting in the channel into the
+                // queue, or the spill once the queue is full, so that the
+                // flush covers it too.
+                loop {
+                    let live_bytes = queue_bytes - catch_up_bytes;
+                    let has_room = has_room(queue, priority, catch_up, live_bytes, limits);
+                    let spilling = spill.as_ref().is_some_and(|spill| {
+                        spill.has_room() && (!has_room || !spill.is_empty())
+                    });
+                    if !has_room && !spilling {
+                        left_waiting = true;
+                        break;
+                    }
+
+                    let Ok(queued) = rx.try_recv() else {
+                        break;
+                    };
+
+                    let queued = match &mut spill {
+                        Some(spill) if spilling => spill_action(spill, queued),
+                        _ => Some(queued),
+                    };
+                    if let Some(queued) = queued {
+                        queue_bytes += enqueue(queued, queue, priority, catch_up);
+                    }
+                }
+
+                requested = Some(reply);
+                true
+            }
+            Some(queued) = rx.recv(), if has_room || spilling => {
+                let queued = match &mut spill {
+                    Some(spill) if spilling => spill_action(spill, queued),
+                    _ => Some(queued),
+                };
+
+                // Spilled actions are read back on the next flush.
+                let Some(queued) = queued else {
+                    continue;
+                };
+
+                let waiting = catch_up.len();
+                let size = enqueue(queued, queue, priority, catch_up);
+                queue_bytes += size;
+
+                // Caught up actions are written a little on every flush, so
+                // they never bring one forward.
+                if catch_up.len() > waiting {
+                    catch_up_bytes += size;
+                    continue;
+                }
+
+                let live = queue.len() + priority.len();
+                let reached = thresholds.reached(live, queue_bytes - catch_up_bytes);
+                if reached {
+                    tracing::debug!("Flush threshold reached; processing queue early");
+                    interval.reset();
+                }
+
+                reached
+            }
+        };
+
+        if !flush {
+            continue;
+        }
+
+        // While the database is down, or not yet ready after starting
+        // without it, actions wait in the queue rather than failing
+        // against it on every tick.
+        if breaker.is_open() || !*ready.borrow() {
+            tracing::debug!("Database unavailable; skipping flush");
+            if let Some(reply) = requested {
+                let _ = reply.send(Err("the database is unavailable; nothing was written".into()));
+            }
+            continue;
+        }
+
+        // Spilled actions are read back as the queue makes room for them.
+        if let Some(spill) = &mut spill {
+            let lanes = [&mut *priority, &mut *queue, &mut *catch_up];
+            queue_bytes += refill(spill, lanes, queue_bytes - catch_up_bytes, limits);
+        }
+
+        // Requested flushes drain the whole queue, however long it takes,
+        // caught up actions included.
+        let (flush_budget, catch_up_limit) = match requested {
+            Some(_) => (None, None),
+            None => (budget, Some(catch_up_per_flush)),
+        };
+        let actions = priority.len() + queue.len();
+        let result = match &sink {
+            Sink::Postgres(inserter) => {
+                process_queue(
+                    [&mut *priority, &mut *queue, &mut *catch_up],
+                    inserter,
+                    &mut breaker,
+                    flush_budget,
+                    catch_up_limit,
+                    workers,
+                    &mut sizer,
+                )
+                .instrument(tracing::info_span!("process_queue", actions))
+                .await
+            }
+            Sink::Ephemeral(store) => {
+                store_ephemerally([&mut *priority, &mut *queue, &mut *catch_up], store).await
+            }
+        };
+        if let Err(e) = &result {
+            tracing::error!("Error processing queue: {e}");
+        }
+
+        // A requested flush only succeeds once nothing is left in memory;
+        // spilled actions are safe on disk, and read back later.
+        if let Some(reply) = requested {
+            let left = priority.len() + queue.len() + catch_up.len();
+            let outcome = match &result {
+                Err(e) => Err(format!("flush failed: {e}")),
+                Ok(()) if left > 0 => Err(format!("{left} actions are still queued")),
+                Ok(()) if left_waiting => {
+                    Err("the queue was full, so actions may still be waiting for it".into())
+                }
+                Ok(()) => Ok(()),
+            };
+            let _ = reply.send(outcome);
+        }
+
+        // Anything left over was deferred by the time budget; it's usually
+        // nothing, so recounting is cheap.
+        catch_up_bytes = catch_up.iter().map(|q| q.action.approximate_size()).sum();
+        queue_bytes = priority.iter().chain(&*queue).map(|q| q.action.approximate_size()).sum();
+        queue_bytes += catch_up_bytes;
+
+        // The interval, thresholds, budget, queue limit, workers, catch-up
+        // throttle, and batch sizes may have been changed by a config reload.
+        let config = config.read().await;
+        thresholds = config.get_flush_thresholds();
+        budget = config.get_flush_time_budget();
+        limits = config.get_queue_limits();
+        workers = config.get_flush_workers();
+        catch_up_per_flush = config.get_catch_up_per_flush();
+        sizer.configure(config.get_batch_sizing());
+
+        let secs = config.get_process_interval_secs();
+        if secs != interval_secs {
+            tracing::info!("Process interval changed to {secs}s");
+
+            interval_secs = secs;
+            let period = Duration::from_secs(secs);
+            interval = interval_at(Instant::now() + period, period);
+        }
+
+        // Keep reading back spilled actions without waiting for the next
+        // tick, as long as the database is keeping up.
+        let drained =
+            result.is_ok() && priority.is_empty() && queue.is_empty() && catch_up.is_empty();
+        if drained && spill.as_ref().is_some_and(|spill| !spill.is_empty()) {
+            interval.reset_immediately();
+        }
+    }
+}
+
+/// Writes an action to the spill, handing it back to be queued in memory if
+/// it can't be written.
+fn spill_action(spill: &mut Spill, queued: Queued) -> Option<Queued> {
+    match spill.push(&queued) {
+        Ok(()) => None,
+        Err(e) => {
+            tracing::error!("Failed to spill action; keeping it in memory: {e}");
+            Some(queued)
+        }
+    }
+}
+
+/// Moves spilled actions back into the queue a segment at a time, oldest
+/// first, while the queue has room for the whole segment, returning their
+/// approximate size in bytes. An empty queue always takes the next segment,
+/// however large. `queue_bytes` and the limits only cover live actions.
+fn refill(
+    spill: &mut Spill,
+    [priority, queue, catch_up]: [&mut Vec<Queued>; 3],
+    queue_bytes: usize,
+    limits: QueueLimits,
+) -> usize {
+    let mut bytes = 0;
+    while !spill.is_empty() {
+        let len = queue.len() + priority.len();
+        let next = spill.next_len();
+        let fits =
+            !limits.reached(len + next, queue_bytes + bytes) && queue.try_reserve(next).is_ok();
+        if len > 0 && !fits {
+            break;
+        }
+
+        match spill.pop() {
+            Ok(actions) => {
+                for queued in actions {
+                    bytes += enqueue(queued, queue, priority, catch_up);
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to read spilled actions: {e}");
+                break;
+            }
+        }
+    }
+
+    bytes
+}
+
+/// Returns true if the live lanes, holding approximately `bytes`, are below
+/// the queue's limits and every lane has room for another action, growing
+/// them if needed. We utilize `try_reserve` to avoid panicking if we would
+/// exceed system memory. Caught up actions have their own cap, checked as
+/// they arrive, so that a backlog of them never keeps live actions out.
+fn has_room(
+    queue: &mut Vec<Queued>,
+    priority: &mut Vec<Queued>,
+    catch_up: &mut Vec<Queued>,
+    bytes: usize,
+    limits: QueueLimits,
+) -> bool {
+    if limits.reached(queue.len() + priority.len(), bytes) {
+        return false;
+    }
+
+    [queue, priority, catch_up]
+        .into_iter()
+        .all(|lane| lane.len() < lane.capacity() || lane.try_reserve(QUEUE_GROWTH).is_ok())
+}
+
+/// Returns when the live action which has waited longest arrived. Lanes are
+/// kept in arrival order, so it's at the front of one of them. Caught up
+/// actions are expected to wait, so they aren't counted.
+fn oldest(priority: &[Queued], queue: &[Queued]) -> Option<std::time::Instant> {
+    let oldest = [priority, queue].into_iter().filter_map(|lane| lane.first()).map(|q| q.received);
+    oldest.min().map(Instant::into_std)
+}
+
+/// Moves an action into the lane for its priority, returning its approximate
+/// size in bytes. Normal priority actions being caught up go in the catch-up
+/// lane; high priority ones never wait behind anything.
+fn enqueue(
+    queued: Queued,
+    queue: &mut Vec<Queued>,
+    priority: &mut Vec<Queued>,
+    catch_up: &mut Vec<Queued>,
+) -> usize {
+    let size = queued.action.approximate_size();
+    match queued.action.priority {
+        Priority::High => priority.push(queued),
+        Priority::Normal if queued.catch_up => catch_up.push(queued),
+        Priority::Normal => queue.push(queued),
+    }
+
+    size
+}
+
+/// The most the queue may hold before new actions are spilled, or left in
+/// the channel.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct QueueLimits {
+    /// Hold at most this many actions.
+    pub actions: Option<usize>,
+    /// Hold actions using at most approximately this many bytes.
+    pub bytes: Option<usize>,
+}
+
+impl QueueLimits {
+    /// Returns true if a queue of `len` actions using `bytes` bytes is full.
+    fn reached(&self, len: usize, bytes: usize) -> bool {
+        self.actions.is_some_and(|max| len >= max) || self.bytes.is_some_and(|max| bytes >= max)
+    }
+}
+
+/// Queue sizes which trigger a flush before the next interval tick.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FlushThresholds {
+    /// Flush once this many actions are queued.
+    pub actions: Option<usize>,
+    /// Flush once the queued actions use approximately this many bytes.
+    pub bytes: Option<usize>,
+}
+
+impl FlushThresholds {
+    /// Returns true if a queue of `len` actions using `bytes` bytes has
+    /// reached either threshold.
+    fn reached(&self, len: usize, bytes: usize) -> bool {
+        self.actions.is_some_and(|max| len >= max) || self.bytes.is_some_and(|max| bytes >= max)
+    }
+}
+
+/// Limits on the number of actions inserted in a single transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BatchSizing {
+    pub min: usize,
+    pub max: usize,
+    /// The insert latency batches are sized towards, or `None` to always use
+    /// `max`.
+    pub target_latency: Option<Duration>,
+}
+
+/// Chooses how many actions go in each batch from how quickly the database
+/// inserts them. Batches grow by a quarter after each full batch inserted
+/// within the target latency, and halve after one which took longer or
+/// failed, so a struggling database is backed off from quickly and only
+/// loaded up again gradually.
+#[derive(Debug)]
+struct BatchSizer {
+    sizing: BatchSizing,
+    size: usize,
+}
+
+impl BatchSizer {
+    /// Starts at the smallest batch when adapting, so that a database of
+    /// unknown size isn't sent the largest batches first.
+    fn new(sizing: BatchSizing) -> Self {
+        let size = if sizing.target_latency.is_some() { sizing.min } else { sizing.max };
+        Self { sizing, size }
+    }
+
+    /// Applies new limits after a config reload, keeping the current size if
+    /// it is still within them.
+    fn configure(&mut self, sizing: BatchSizing) {
+        if sizing != self.sizing {
+            self.size = match sizing.target_latency {
+                Some(_) => self.size.clamp(sizing.min, sizing.max),
+                None => sizing.max,
+            };
+            self.sizing = sizing;
+        }
+    }
+
+    /// Returns the most actions to put in the next batch.
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Adjusts the size after `rows` actions were inserted in `latency`.
+    /// Only full batches grow it, so that a quiet queue doesn't.
+    fn record_insert(&mut self, rows: usize, latency: Duration) {
+        let Some(target) = self.sizing.target_latency else {
+            return;
+        };
+
+        if latency > target {
+            self.shrink();
+        } else if rows >= self.size {
+            self.size = (self.size + self.size.div_ceil(4)).min(self.sizing.max);
+        }
+    }
+
+    /// Shrinks the size after a batch failed to insert.
+    fn record_failure(&mut self) {
+        if self.sizing.target_latency.is_some() {
+            self.shrink();
+        }
+    }
+
+    fn shrink(&mut self) {
+        self.size = (self.size / 2).max(self.sizing.min);
+    }
+}
+
+/// Tracks whether the database is reachable. Once a batch can't be inserted
+/// because the database is down, the breaker opens and flushes are paused,
+/// for longer after each consecutive failure.
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Breaker {
+    /// Returns true if flushes are paused.
+    fn is_open(&self) -> bool {
+        self.retry_at.is_some_and(|retry_at| Instant::now() < retry_at)
+    }
+
+    /// Opens the breaker after a failed flush, returning how long flushes are
+    /// paused for.
+    fn record_failure(&mut self) -> Duration {
+        let delay = Duration::from_secs(1u64 << self.failures.min(6)).min(MAX_BREAKER_DELAY);
+        self.failures += 1;
+        self.retry_at = Some(Instant::now() + delay);
+
+        delay
+    }
+
+    /// Closes the breaker after a successful insert.
+    fn record_success(&mut self) {
+        if self.failures > 0 {
+            tracing::info!("Database available again after {} failed flushes", self.failures);
+        }
+
+        *self = Self::default();
+    }
+}
+
+/// Everything an insert worker needs, cloned into each one.
+#[derive(Clone)]
+struct Inserter {
+    pg: Arc<PgPool>,
+    statements: Arc<InsertStatements>,
+    metrics: Arc<Metrics>,
+    /// Beaten after every chunk inserted, so that a long but healthy flush
+    /// isn't mistaken for a stalled one.
+    watchdog: Arc<Watchdog>,
+}
+
+/// How a batch given to an insert worker ended.
+enum Outcome {
+    /// The batch was inserted, taking the given time including retries.
+    Inserted(Duration),
+    /// The database couldn't be reached, so the batch should be put back.
+    Unavailable(String),
+    /// The database rejected the batch, so it was dropped.
+    Rejected(String),
+}
+
+impl Inserter {
+    /// Inserts a batch, handing it back along with how the insert went so
+    /// that it can be returned to the queue if the database is unavailable.
+    /// `index` is the order the batch was taken from its lane.
+    async fn insert(self, index: usize, batch: Vec<Queued>) -> (usize, Vec<Queued>, Outcome) {
+        let connections = connection_list(&batch);
+        let start = Instant::now();
+
+        // Errors name the connections whose actions were in the batch, so a
+        // bad service can be traced from the database error alone.
+        let result = insert_with_retry(&batch, &self)
+            .instrument(tracing::info_span!("flush", %connections))
+            .await;
+
+        let outcome = match result {
+            Ok(()) => Outcome::Inserted(start.elapsed()),
+            Err(e) if is_unavailable(&*e) => {
+                Outcome::Unavailable(format!("{e} (connections {connections})"))
+            }
+            Err(e) => Outcome::Rejected(format!("{e} (connections {connections})")),
+        };
+
+        (index, batch, outcome)
+    }
+}
+
+/// Drains the priority lane, the normal queue, and then up to
+/// `catch_up_limit` actions from the catch-up lane in batches of at most
+/// `sizer`'s size, until they are empty or `budget` has elapsed. Batches are
+/// handed to up to `workers` insert tasks, each inserting its batch in its
+/// own transaction on the pool, so that encoding and round trips overlap.
+/// Each lane is written in full before any batch from the next is started, so
+/// high priority actions are never deferred behind normal ones, nor live
+/// actions behind caught up ones. Anything left over is processed on the
+/// next flush.
+///
+/// If the database is unavailable, the failing batches are put back at the
+/// front of their lane in their original order, and the breaker is opened.
+/// Batches the database rejects for any other reason are dropped, as retrying
+/// them would fail forever. Either way, no further batches are started, but
+/// those already running are waited for.
+async fn process_queue(
+    [priority, queue, catch_up]: [&mut Vec<Queued>; 3],
+    inserter: &Inserter,
+    breaker: &mut Breaker,
+    budget: Option<Duration>,
+    catch_up_limit: Option<usize>,
+    workers: usize,
+    sizer: &mut BatchSizer,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut error = None;
+    let mut unavailable = false;
+
+    let lanes = [(&mut *priority, None), (&mut *queue, None), (&mut *catch_up, catch_up_limit)];
+    for (lane, limit) in lanes {
+        let mut running = JoinSet::new();
+        let mut returned = Vec::new();
+        let mut next = 0;
+        let mut remaining = limit.unwrap_or(usize::MAX);
+
+        loop {
+            // Keep every worker busy until the lane is empty or throttled,
+            // something has failed, or the budget runs out.
+            while error.is_none()
+                && running.len() < workers
+                && !lane.is_empty()
+                && remaining > 0
+                && !budget.is_some_and(|budget| start.elapsed() >= budget)
+            {
+                // The size is never more than the postgres bind limit /
+                // struct fields.
+                let count = lane.len().min(sizer.size()).min(remaining);
+                remaining -= count;
+                let batch = lane.drain(..count).collect::<Vec<_>>();
+                running.spawn(inserter.clone().insert(next, batch).in_current_span());
+                next += 1;
+            }
+
+            let Some(joined) = running.join_next().await else {
+                break;
+            };
+            inserter.watchdog.beat();
+
+            match joined {
+                Ok((_, batch, Outcome::Inserted(latency))) => {
+                    breaker.record_success();
+                    sizer.record_insert(batch.len(), latency);
+                }
+                Ok((index, batch, Outcome::Unavailable(e))) => {
+                    sizer.record_failure();
+                    unavailable = true;
+                    returned.push((index, batch));
+                    error.get_or_insert(e);
+                }
+                Ok((_, _, Outcome::Rejected(e))) => {
+                    sizer.record_failure();
+                    error.get_or_insert(e);
+                }
+                Err(e) => {
+                    error.get_or_insert(format!("Insert worker failed: {e}"));
+                }
+            }
+        }
+
+        // Put the batches back where they were, so that nothing is lost and
+        // the order is kept.
+        returned.sort_unstable_by_key(|(index, _)| *index);
+        lane.splice(0..0, returned.into_iter().flat_map(|(_, batch)| batch));
+
+        if error.is_some() {
+            break;
+        }
+    }
+
+    inserter.metrics.record_batch_size(sizer.size());
+
+    if unavailable {
+        let delay = breaker.record_failure();
+        tracing::warn!("Database unavailable; pausing flushes for {delay:?}");
+    }
+
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+
+    let deferred = priority.len() + queue.len();
+    if deferred > 0 {
+        tracing::warn!("Flush time budget exceeded; {deferred} actions deferred");
+    }
+
+    if !catch_up.is_empty() {
+        tracing::debug!("{} caught up actions held for later flushes", catch_up.len());
+    }
+
+    Ok(())
+}
+
+/// Stores the priority lane, the normal queue, and then the catch-up lane in
+/// SQLite, then drains them to stdout, one JSON action per line, for servers
+/// running ephemerally. Nothing is drained if they can't be stored.
+async fn store_ephemerally(
+    [priority, queue, catch_up]: [&mut Vec<Queued>; 3],
+    store: &EphemeralStore,
+) -> Result<()> {
+    let actions = priority.iter().chain(&*queue).chain(&*catch_up);
+    store.insert(actions.map(|queued| &queued.action)).await?;
+
+    let mut stdout = std::io::stdout().lock();
+    for queued in priority.drain(..).chain(queue.drain(..)).chain(catch_up.drain(..)) {
+        serde_json::to_writer(&mut stdout, &queued.action)?;
+        stdout.write_all(b"\n")?;
+    }
+    stdout.flush()?;
+
+    Ok(())
+}
+
+/// Returns a comma-separated list of the connections a batch arrived on.
+fn connection_list(batch: &[Queued]) -> String {
+    let connections = batch.iter().map(|queued| queued.connection).collect::<BTreeSet<_>>();
+    connections.iter().map(ToString::to_string).collect::<Vec<_>>().join(",")
+}
+
+/// Inserts a batch, retrying a few times with backoff if the database is
+/// unavailable, such as while it restarts.
+async fn insert_with_retry(batch: &[Queued], inserter: &Inserter) -> Result<()> {
+    let Inserter { pg, statements, metrics, watchdog } = inserter;
+    let mut attempt = 1;
+    let mut delay = INSERT_RETRY_DELAY;
+
+    loop {
+        let actions = batch.iter().map(|queued| &queued.action);
+        match insert_batch(actions, pg, statements, metrics, Some(watchdog)).await {
+            Err(e) if attempt < INSERT_ATTEMPTS && is_unavailable(&*e) => {
+                tracing::warn!("Insert failed ({attempt}/{INSERT_ATTEMPTS}): {e}; retrying");
+                sleep(delay).await;
+
+                attempt += 1;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns true if an insert failed because the database couldn't be reached,
+/// rather than because it rejected the actions.
+fn is_unavailable(e: &(dyn std::error::Error + 'static)) -> bool {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed,
+        ) => true,
+        // Connection exceptions, and the server shutting down or starting up.
+        Some(sqlx::Error::Database(e)) => {
+            e.code().is_some_and(|code| code.starts_with("08") || code.starts_with("57P"))
+        }
+        _ => false,
+    }
+}
+
+/// Inserts a batch of actions in a single transaction on the database. The
+/// batch is split by the table each action is routed to, and then into
+/// fixed-size chunks so that each insert reuses a prepared statement. Actions
+/// of aggregated kinds are folded into counts instead, and added to the
+/// aggregates table in the same transaction, as are the sessions of any
+/// actions which belong to one, if sessions are tracked.
+pub(crate) async fn insert_batch<'a>(
+    actions: impl ExactSizeIterator<Item = &'a Action>,
+    pg: &PgPool,
+    statements: &InsertStatements,
+    metrics: &Metrics,
+    watchdog: Option<&Watchdog>,
+) -> Result<()> {
+    // TODO: Possibly rewrite this to use PostgreSQL UNNEST() instead of
+    // multi-row inserts. It looks like that would take a lot more memory
+    // versus this option, as the benefit of much higher performance. See:
+    // https://github.com/launchbadge/sqlx/blob/main/FAQ.md#how-can-i-bind-an-array-to-a-values-clause-how-can-i-do-bulk-inserts
+    let count = actions.len();
+    tracing::debug!(count, "Logging actions");
+
+    let mut tables = (0..statements.tables()).map(|_| Vec::new()).collect::<Vec<_>>();
+    let mut kinds = HashMap::<&str, u64>::new();
+    let mut aggregates = HashMap::<(&str, u32, i64, Option<u64>), i64>::new();
+    let mut aggregated_keys = HashSet::new();
+    let mut session_actions = Vec::new();
+    let track_sessions = statements.session_statement().is_some();
+    for action in actions {
+        *kinds.entry(action.kind.as_ref()).or_default() += 1;
+
+        if track_sessions && action.session_id.is_some() {
+            session_actions.push(action);
+        }
+
+        match statements.aggregate_window(&action.kind) {
+            Some(window) => {
+                // An action resent within the batch is only counted once; the
+                // aggregate keys table catches those resent across batches.
+                let key = action.idempotency_key;
+                if key.is_some_and(|key| !aggregated_keys.insert((action.id, key))) {
+                    continue;
+                }
+
+                let bucket = window_start(action.created, window);
+                *aggregates.entry((action.kind.as_ref(), action.id, bucket, key)).or_default() += 1;
+            }
+            None => tables[statements.table_for(&action.kind)].push(action),
+        }
+    }
+
+    for actions in &mut tables {
+        if statements.sorts_by_created() {
+            sort_for_insert(actions);
+        }
+        order_by_ordinal(actions);
+    }
+
+    if let Some(registry) = statements.kinds() {
+        registry.register(pg, kinds.keys().copied()).await?;
+    }
+
+    let start = Instant::now();
+    let mut tx = pg.begin().await?;
+    let mut stored = HashSet::new();
+    for (table, actions) in tables.into_iter().enumerate() {
+        insert_chunks(actions, table, &mut tx, statements, &mut stored, watchdog).await?;
+    }
+    if !aggregates.is_empty() {
+        insert_aggregates(aggregates, &mut tx, statements, &mut stored).await?;
+    }
+    let sessions = session_rows(session_actions, stored, statements);
+    if !sessions.is_empty() {
+        upsert_sessions(sessions, &mut tx, statements).await?;
+    }
+    tx.commit().await?;
+
+    metrics.record_insert(count, start.elapsed());
+    metrics.record_kinds(kinds);
+    tracing::debug!(
+        count,
+        elapsed = ?start.elapsed(),
+        avg = ?metrics.average_insert_latency(),
+        max = ?metrics.max_insert_latency(),
+        total = metrics.rows_inserted(),
+        "Inserted actions"
+    );
+
+    Ok(())
+}
+
+/// Returns the start of the `window` second window `created` falls in, as a
+/// Unix timestamp.
+fn window_start(created: time::OffsetDateTime, window: i64) -> i64 {
+    let secs = created.unix_timestamp();
+    secs - secs.rem_euclid(window)
+}
+
+/// Adds the counts of aggregated actions, keyed by kind, unique ID, window
+/// start, and idempotency key, to the aggregates table in a single statement.
+/// Counts of actions with a key the database has already seen are skipped,
+/// and the unique ID and key of every other keyed action is added to `stored`.
+async fn insert_aggregates(
+    counts: HashMap<(&str, u32, i64, Option<u64>), i64>,
+    tx: &mut Transaction<'_, Postgres>,
+    statements: &InsertStatements,
+    stored: &mut HashSet<(u32, u64)>,
+) -> Result<()> {
+    let mut kinds = Vec::with_capacity(counts.len());
+    let mut ids = Vec::with_capacity(counts.len());
+    let mut buckets = Vec::with_capacity(counts.len());
+    let mut keys = Vec::with_capacity(counts.len());
+    let mut totals = Vec::with_capacity(counts.len());
+    for ((kind, id, bucket, key), count) in counts {
+        kinds.push(kind);
+        ids.push(i64::from(id));
+        buckets.push(time::OffsetDateTime::from_unix_timestamp(bucket)?);
+        // Stored with the same bits, as in the actions table.
+        keys.push(key.map(|key| key as i64));
+        totals.push(count);
+    }
+
+    let fresh = sqlx::query(statements.aggregate_statement())
+        .bind(kinds)
+        .bind(ids)
+        .bind(buckets)
+        .bind(keys)
+        .bind(totals)
+        .fetch_all(&mut **tx)
+        .await?;
+    add_stored_keys(&fresh, stored)?;
+
+    Ok(())
+}
+
+/// Adds the unique ID and idempotency key of each row in `rows`, as returned
+/// by a statement which stores actions, to `stored`.
+fn add_stored_keys(rows: &[PgRow], stored: &mut HashSet<(u32, u64)>) -> Result<()> {
+    for row in rows {
+        let (id, key): (i64, Option<i64>) = (row.try_get(0)?, row.try_get(1)?);
+        if let Some(key) = key {
+            stored.insert((id as u32, key as u64));
+        }
+    }
+
+    Ok(())
+}
+
+/// Groups the session actions of a batch by their login session. An action
+/// with an idempotency key is left out unless `stored` says this batch stored
+/// it, so that a resent action isn't counted again; insert statements which
+/// don't return keys can't say, so their actions are always counted.
+fn session_rows(
+    actions: Vec<&Action>,
+    mut stored: HashSet<(u32, u64)>,
+    statements: &InsertStatements,
+) -> HashMap<u64, SessionRow> {
+    let mut sessions = HashMap::<u64, SessionRow>::new();
+    for action in actions {
+        let Some(session_id) = action.session_id else {
+            continue;
+        };
+
+        let known =
+            statements.returns_keys() || statements.aggregate_window(&action.kind).is_some();
+        if let Some(key) = action.idempotency_key.filter(|_| known) {
+            // Removed, so that a copy resent within the batch isn't counted
+            // either.
+            if !stored.remove(&(action.id, key)) {
+                continue;
+            }
+        }
+
+        sessions.entry(session_id).or_insert_with(|| SessionRow::new(action)).add(action);
+    }
+
+    sessions
+}
+
+/// What one batch knows about a login session, merged into its row in the
+/// sessions table.
+#[derive(Debug, PartialEq)]
+struct SessionRow {
+    id: u32,
+    addr: IpAddr,
+    started: time::OffsetDateTime,
+    last_seen: time::OffsetDateTime,
+    ended: Option<time::OffsetDateTime>,
+    actions: i64,
+}
+
+impl SessionRow {
+    fn new(action: &Action) -> Self {
+        Self {
+            id: action.id,
+            addr: action.addr,
+            started: action.created,
+            last_seen: action.created,
+            ended: None,
+            actions: 0,
+        }
+    }
+
+    fn add(&mut self, action: &Action) {
+        self.started = self.started.min(action.created);
+        self.last_seen = self.last_seen.max(action.created);
+        if action.kind == SESSION_END {
+            self.ended = Some(action.created);
+        }
+        self.actions += 1;
+    }
+}
+
+/// Merges the sessions seen in a batch into the sessions table in a single
+/// statement.
+async fn upsert_sessions(
+    sessions: HashMap<u64, SessionRow>,
+    tx: &mut Transaction<'_, Postgres>,
+    statements: &InsertStatements,
+) -> Result<()> {
+    let Some(statement) = statements.session_statement() else {
+        return Ok(());
+    };
+
+    let mut session_ids = Vec::with_capacity(sessions.len());
+    let mut ids = Vec::with_capacity(sessions.len());
+    let mut addrs = Vec::with_capacity(sessions.len());
+    let mut started = Vec::with_capacity(sessions.len());
+    let mut last_seen = Vec::with_capacity(sessions.len());
+    let mut ended = Vec::with_capacity(sessions.len());
+    let mut actions = Vec::with_capacity(sessions.len());
+    for (session_id, row) in sessions {
+        session_ids.push(session_id as i64);
+        ids.push(i64::from(row.id));
+        addrs.push(IpNetwork::from(row.addr));
+        started.push(row.started);
+        last_seen.push(row.last_seen);
+        ended.push(row.ended);
+        actions.push(row.actions);
+    }
+
+    sqlx::query(statement)
+        .bind(session_ids)
+        .bind(ids)
+        .bind(addrs)
+        .bind(started)
+        .bind(last_seen)
+        .bind(ended)
+        .bind(actions)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Orders actions by when they were created, then by kind. The sort is
+/// stable, so actions created at the same moment keep their arrival order.
+fn sort_for_insert(actions: &mut [&Action]) {
+    actions.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.kind.cmp(&b.kind)));
+}
+
+/// Puts each identifier's actions which carry an ordinal back in the order
+/// they were sent, within the positions they already hold, so that the ids
+/// Postgres assigns follow it even when retries delivered them out of order.
+fn order_by_ordinal(actions: &mut [&Action]) {
+    let mut positions = HashMap::<HarpId, Vec<usize>>::new();
+    for (i, action) in actions.iter().enumerate() {
+        if action.ordinal.is_some() {
+            positions.entry((action.addr, action.id)).or_default().push(i);
+        }
+    }
+
+    for positions in positions.into_values() {
+        let mut ordered = positions.iter().map(|&i| actions[i]).collect::<Vec<_>>();
+        ordered.sort_by_key(|action| action.ordinal);
+
+        for (i, action) in positions.into_iter().zip(ordered) {
+            actions[i] = action;
+        }
+    }
+}
+
+/// Inserts actions routed to the table at `table` in fixed-size chunks,
+/// beating `watchdog` after each one. If the statements return the keys they
+/// store, each is added to `stored`.
+async fn insert_chunks(
+    actions: Vec<&Action>,
+    table: usize,
+    tx: &mut Transaction<'_, Postgres>,
+    statements: &InsertStatements,
+    stored: &mut HashSet<(u32, u64)>,
+    watchdog: Option<&Watchdog>,
+) -> Result<()> {
+    let chunks = chunk_sizes(actions.len());
+    let mut actions = actions.into_iter();
+    for size in chunks {
+        let mut query = sqlx::query(statements.get(table, size));
+        for action in actions.by_ref().take(size) {
+            query = bind_action(query, action, statements)?;
+        }
+
+        if statements.returns_keys() {
+            add_stored_keys(&query.fetch_all(&mut **tx).await?, stored)?;
+        } else {
+            query.execute(&mut **tx).await?;
+        }
+
+        if let Some(watchdog) = watchdog {
+            watchdog.beat();
+        }
+    }
+
+    Ok(())
+}
+
+/// Binds the fields of `action` which are written, in the order the
+/// statements expect. Details are encrypted here if a key is configured.
+fn bind_action<'q>(
+    mut query: Query<'q, Postgres, PgArguments>,
+    action: &'q Action,
+    statements: &InsertStatements,
+) -> Result<Query<'q, Postgres, PgArguments>> {
+    for field in statements.fields() {
+        query = match field {
+            Field::UniqueId => query.bind(i64::from(action.id)),
+            Field::IpAddress => query.bind(IpNetwork::from(action.addr)),
+            Field::Kind => match statements.kinds() {
+                Some(registry) => query.bind(
+                    registry
+                        .id(&action.kind)
+                        .ok_or_else(|| format!("Kind \"{}\" has no id", action.kind))?,
+                ),
+                None => query.bind(action.kind.as_ref()),
+            },
+            Field::Detail => match (statements.cipher(), &action.detail) {
+                (Some(cipher), Some(detail)) => query.bind(cipher.encrypt(detail)?),
+                _ => query.bind(action.detail.as_ref()),
+            },
+            Field::Created => query.bind(action.created),
+            Field::Source => query.bind(action.source.as_deref()),
+            // Postgres has no unsigned types, so the key is stored with the
+            // same bits as a signed integer.
+            Field::IdempotencyKey => query.bind(action.idempotency_key.map(|key| key as i64)),
+            Field::SampleRate => query.bind(action.sample_rate),
+            Field::Country => query.bind(action.country.as_deref()),
+            Field::Asn => query.bind(action.asn.map(i64::from)),
+            Field::RateExceeded => query.bind(action.rate_exceeded),
+            // Ordinals are microseconds since the epoch, well within an i64.
+            Field::Ordinal => query.bind(action.ordinal.map(|ordinal| ordinal as i64)),
+            Field::SessionId => query.bind(action.session_id.map(|id| id as i64)),
+            Field::TraceId => query.bind(action.trace_id.as_deref()),
+        };
+    }
+
+    Ok(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        action::SESSION_START,
+        test_fixtures::{Target, TestKind},
+    };
+
+    #[test]
+    fn list_each_connection_once() {
+        let batch = [3, 1, 3, 2]
+            .map(|id| Queued::new(ConnectionId(id), Action::new(TestKind("login"), &Target)));
+
+        assert_eq!(connection_list(&batch), "c1,c2,c3");
+    }
+
+    #[test]
+    fn sort_by_created_then_kind() {
+        let at = |kind, secs| {
+            let mut action = Action::new(TestKind(kind), &Target);
+            action.created = time::OffsetDateTime::UNIX_EPOCH + Duration::from_secs(secs);
+            action
+        };
+        let actions = [at("logout", 2), at("login", 1), at("chat", 2), at("login", 2)];
+
+        let mut sorted = actions.iter().collect::<Vec<_>>();
+        sort_for_insert(&mut sorted);
+
+        let order = sorted.iter().map(|a| (a.kind.as_ref(), a.created.unix_timestamp()));
+        assert_eq!(
+            order.collect::<Vec<_>>(),
+            [("login", 1), ("chat", 2), ("login", 2), ("logout", 2)]
+        );
+    }
+
+    #[test]
+    fn restore_ordinal_order() {
+        let sent = |id, ordinal| {
+            let mut action = Action::new(TestKind("move"), &Target);
+            action.id = id;
+            action.ordinal = ordinal;
+            action
+        };
+        let actions = [sent(1, Some(3)), sent(2, Some(9)), sent(1, Some(1)), sent(1, None)];
+
+        let mut ordered = actions.iter().collect::<Vec<_>>();
+        order_by_ordinal(&mut ordered);
+
+        // Only player 1's stamped actions trade places.
+        let order = ordered.iter().map(|a| (a.id, a.ordinal));
+        assert_eq!(
+            order.collect::<Vec<_>>(),
+            [(1, Some(1)), (2, Some(9)), (1, Some(3)), (1, None)]
+        );
+    }
+
+    #[test]
+    fn merge_session_rows() {
+        let at = |kind, secs| {
+            let mut action = Action::new(kind, &Target);
+            action.created = time::OffsetDateTime::UNIX_EPOCH + Duration::from_secs(secs);
+            action
+        };
+
+        // Arrival order doesn't matter, as retries can reorder a session.
+        let mut row = SessionRow::new(&at("chat", 5));
+        for action in [at("chat", 5), at(SESSION_END, 9), at(SESSION_START, 2)] {
+            row.add(&action);
+        }
+
+        assert_eq!(row.started.unix_timestamp(), 2);
+        assert_eq!(row.last_seen.unix_timestamp(), 9);
+        assert_eq!(row.ended.map(|ended| ended.unix_timestamp()), Some(9));
+        assert_eq!(row.actions, 3);
+    }
+
+    #[test]
+    fn count_resent_session_actions_once() {
+        let statements = InsertStatements::new("harp.actions", None).track_sessions();
+        let keyed = |key| {
+            let mut action = Action::new("chat", &Target).with_session(7);
+            action.idempotency_key = key;
+            action
+        };
+
+        // Key 1 arrives twice in the batch, and key 2 was stored by an earlier
+        // batch, so neither copy of it was stored by this one.
+        let actions = [keyed(Some(1)), keyed(Some(1)), keyed(Some(2)), keyed(None)];
+        let stored = HashSet::from([(1, 1)]);
+
+        let sessions = session_rows(actions.iter().collect(), stored, &statements);
+        assert_eq!(sessions[&7].actions, 2);
+    }
+
+    #[test]
+    fn queue_caught_up_actions_separately() {
+        let queued = |priority, catch_up| {
+            let action = Action::new(TestKind("move"), &Target).with_priority(priority);
+            Queued { catch_up, ..Queued::new(ConnectionId(1), action) }
+        };
+
+        let (mut queue, mut priority, mut catch_up) = (Vec::new(), Vec::new(), Vec::new());
+        let actions = [
+            (Priority::Normal, true),
+            (Priority::Normal, false),
+            (Priority::High, true),
+            (Priority::High, false),
+        ];
+        for (level, caught_up) in actions {
+            enqueue(queued(level, caught_up), &mut queue, &mut priority, &mut catch_up);
+        }
+        assert_eq!((priority.len(), queue.len(), catch_up.len()), (2, 1, 1));
+
+        // Caught up actions don't count towards the queue's limits, so a
+        // backlog of them can't keep live actions out.
+        let limits = QueueLimits { actions: Some(4), bytes: None };
+        assert!(has_room(&mut queue, &mut priority, &mut catch_up, 0, limits));
+        catch_up.extend((0..10).map(|_| queued(Priority::Normal, true)));
+        assert!(has_room(&mut queue, &mut priority, &mut catch_up, 0, limits));
+
+        queue.push(queued(Priority::Normal, false));
+        assert!(!has_room(&mut queue, &mut priority, &mut catch_up, 0, limits));
+    }
+
+    #[test]
+    fn windows_align_to_epoch() {
+        let at = |secs| time::OffsetDateTime::UNIX_EPOCH + Duration::from_secs(secs);
+
+        assert_eq!(window_start(at(0), 60), 0);
+        assert_eq!(window_start(at(59), 60), 0);
+        assert_eq!(window_start(at(61), 60), 60);
+        assert_eq!(window_start(at(7_205), 3_600), 7_200);
+
+        // Before the epoch, windows still start on a multiple of their length.
+        let before = time::OffsetDateTime::UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(window_start(before, 60), -60);
+    }
+
+    #[test]
+    fn batch_size_adapts() {
+        let target = Duration::from_millis(100);
+        let sizing = BatchSizing { min: 100, max: 1000, target_latency: Some(target) };
+        let mut sizer = BatchSizer::new(sizing);
+        assert_eq!(sizer.size(), 100);
+
+        // Full, fast batches grow it up to the ceiling.
+        sizer.record_insert(100, Duration::from_millis(10));
+        assert_eq!(sizer.size(), 125);
+        for _ in 0..20 {
+            sizer.record_insert(sizer.size(), Duration::from_millis(10));
+        }
+        assert_eq!(sizer.size(), 1000);
+
+        // Partial batches don't.
+        sizer.size = 400;
+        sizer.record_insert(50, Duration::from_millis(10));
+        assert_eq!(sizer.size(), 400);
+
+        // Slow inserts and failures shrink it down to the floor.
+        sizer.record_insert(400, Duration::from_millis(500));
+        assert_eq!(sizer.size(), 200);
+        for _ in 0..5 {
+            sizer.record_failure();
+        }
+        assert_eq!(sizer.size(), 100);
+
+        // Without a target, every batch is as large as allowed.
+        sizer.configure(BatchSizing { target_latency: None, ..sizing });
+        sizer.record_failure();
+        assert_eq!(sizer.size(), 1000);
+    }
+
+    #[test]
+    fn breaker_backs_off() {
+        let mut breaker = Breaker::default();
+        assert!(!breaker.is_open());
+
+        assert_eq!(breaker.record_failure(), Duration::from_secs(1));
+        assert_eq!(breaker.record_failure(), Duration::from_secs(2));
+        assert!(breaker.is_open());
+
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert_eq!(breaker.record_failure(), MAX_BREAKER_DELAY);
+
+        breaker.record_success();
+        assert!(!breaker.is_open());
+        assert_eq!(breaker.record_failure(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,84 @@
+#[cfg(unix)]
+use std::{path::PathBuf, sync::Arc};
+
+#[cfg(unix)]
+use sqlx::PgPool;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::{
+    server::config::{Config, SharedConfig},
+    Result,
+};
+
+/// Handle used to swap the active tracing filter at runtime.
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Builds a tracing filter from a config directive, falling back to `RUST_LOG`
+/// (and then "info") if no directive is given or it cannot be parsed.
+pub fn build_env_filter(directive: Option<&str>) -> EnvFilter {
+    if let Some(directive) = directive {
+        match EnvFilter::try_new(directive) {
+            Ok(filter) => return filter,
+            Err(e) => tracing::warn!("Invalid log level \"{directive}\": {e}"),
+        }
+    }
+
+    EnvFilter::builder()
+        .with_default_directive(tracing::metadata::LevelFilter::INFO.into())
+        .from_env_lossy()
+}
+
+/// Listens for SIGHUP and reloads the config file when received. Only settings
+/// which are safe to change at runtime are applied; see
+/// [Config::reload_from]. The GeoIP databases and the database password file
+/// are read again, so updated files take effect. Existing connections are left
+/// untouched.
+#[cfg(unix)]
+pub(crate) async fn watch_sighup(
+    path: Option<PathBuf>,
+    config: SharedConfig,
+    pg: Option<PgPool>,
+    geoip: Arc<GeoIp>,
+    log_handle: Option<LogHandle>,
+) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    while hangup.recv().await.is_some() {
+        tracing::info!("Received SIGHUP; reloading configuration");
+
+        let new = match Config::load_from_file(path.as_ref()) {
+            Ok(new) => new,
+            Err(e) => {
+                tracing::error!("Failed to reload configuration: {e}");
+                continue;
+            }
+        };
+
+        let mut config = config.write().await;
+        config.reload_from(new);
+
+        if let Err(e) = geoip.load(&config.geoip) {
+            tracing::error!("Failed to reload GeoIP databases: {e}");
+        }
+
+        // New connections use the rotated password; open ones keep working.
+        if let Some(pg) = pg.as_ref().filter(|_| config.has_pass_file()) {
+            match config.get_connect_options() {
+                Ok(options) => pg.set_connect_options(options),
+                Err(e) => tracing::error!("Failed to reload database password: {e}"),
+            }
+        }
+
+        if let Some(log_handle) = &log_handle {
+            if let Err(e) = log_handle.reload(build_env_filter(config.log_level.as_deref())) {
+                tracing::error!("Failed to reload log level: {e}");
+            }
+        }
+
+        tracing::info!("Configuration reloaded");
+    }
+
+    Ok(())
+}
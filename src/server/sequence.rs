@@ -0,0 +1,54 @@
+use std::ops::RangeInclusive;
+
+/// Tracks the sequence numbers stamped on actions by a single connection in
+/// order to detect actions which were lost in transit.
+#[derive(Debug, Default)]
+pub(crate) struct SequenceTracker {
+    last: Option<u64>,
+}
+
+impl SequenceTracker {
+    /// Records an incoming sequence number. Returns the range of sequence
+    /// numbers which were skipped, if any.
+    ///
+    /// The first sequence number seen on a connection becomes the baseline, and
+    /// numbers at or below the highest seen so far are treated as
+    /// retransmissions rather than gaps.
+    pub(crate) fn observe(&mut self, sequence: u64) -> Option<RangeInclusive<u64>> {
+        let Some(last) = self.last else {
+            self.last = Some(sequence);
+            return None;
+        };
+
+        if sequence <= last {
+            return None;
+        }
+
+        self.last = Some(sequence);
+
+        if sequence > last + 1 {
+            Some(last + 1..=sequence - 1)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_sequence_gaps() {
+        let mut tracker = SequenceTracker::default();
+
+        // The first number is the baseline, even if it isn't 1.
+        assert_eq!(tracker.observe(5), None);
+        assert_eq!(tracker.observe(6), None);
+        assert_eq!(tracker.observe(9), Some(7..=8));
+
+        // Retransmissions don't move the baseline backwards.
+        assert_eq!(tracker.observe(7), None);
+        assert_eq!(tracker.observe(10), None);
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::server::queue::Queued;
+
+/// The file extension of spill segments.
+const SEGMENT_EXTENSION: &str = "jsonl";
+
+/// Where, and how much, the queue spills to disk.
+#[derive(Debug, Clone)]
+pub(crate) struct SpillOptions {
+    pub dir: PathBuf,
+    /// The most bytes held on disk, if limited.
+    pub max_bytes: Option<u64>,
+    /// The number of actions written to each segment.
+    pub segment_actions: usize,
+}
+
+/// Overflow for the queue, holding actions on disk once the queue is full or
+/// can't grow. Actions are appended to numbered segment files as JSON lines,
+/// and read back a whole segment at a time, oldest first, as the queue makes
+/// room for them.
+///
+/// Segments are not synced, so a crash can lose the newest spilled actions,
+/// but those left behind by a clean shutdown or a restarted processor are
+/// picked up again when the spill is next opened.
+#[derive(Debug)]
+pub(crate) struct Spill {
+    options: SpillOptions,
+    /// Complete segments, oldest first.
+    segments: VecDeque<Segment>,
+    /// The segment being appended to, if any.
+    current: Option<(Segment, BufWriter<File>)>,
+    next_id: u64,
+    len: usize,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    len: usize,
+    bytes: u64,
+}
+
+impl Spill {
+    /// Opens the spill directory, creating it if needed, and finds any
+    /// segments already in it.
+    pub(crate) fn open(options: SpillOptions) -> io::Result<Self> {
+        fs::create_dir_all(&options.dir)?;
+
+        let mut found = Vec::new();
+        for entry in fs::read_dir(&options.dir)? {
+            let path = entry?.path();
+            if let Some(id) = segment_id(&path) {
+                found.push((id, path));
+            }
+        }
+        found.sort_unstable();
+
+        let next_id = found.last().map_or(0, |(id, _)| id + 1);
+        let mut segments = VecDeque::with_capacity(found.len());
+        for (_, path) in found {
+            let bytes = fs::metadata(&path)?.len();
+            let len = BufReader::new(File::open(&path)?).lines().count();
+            segments.push_back(Segment { path, len, bytes });
+        }
+
+        let len = segments.iter().map(|segment| segment.len).sum();
+        let bytes = segments.iter().map(|segment| segment.bytes).sum();
+        if len > 0 {
+            tracing::info!("Found {len} spilled actions in {}", options.dir.display());
+        }
+
+        Ok(Self { options, segments, current: None, next_id, len, bytes })
+    }
+
+    /// Returns the number of actions on disk.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns true if the spill is below its size limit.
+    pub(crate) fn has_room(&self) -> bool {
+        !self.options.max_bytes.is_some_and(|max| self.bytes >= max)
+    }
+
+    /// Returns the number of actions `pop` would return next.
+    pub(crate) fn next_len(&self) -> usize {
+        self.segments
+            .front()
+            .or(self.current.as_ref().map(|(segment, _)| segment))
+            .map_or(0, |segment| segment.len)
+    }
+
+    /// Appends an action to the newest segment, starting a new one once it
+    /// is full.
+    pub(crate) fn push(&mut self, queued: &Queued) -> io::Result<()> {
+        let mut line = serde_json::to_vec(queued)?;
+        line.push(b'\n');
+
+        let (segment, writer) = match &mut self.current {
+            Some(current) => current,
+            None => {
+                let path = self
+                    .options
+                    .dir
+                    .join(format!("spill-{:010}.{SEGMENT_EXTENSION}", self.next_id));
+                let writer = BufWriter::new(File::create(&path)?);
+                self.next_id += 1;
+
+                self.current.insert((Segment { path, len: 0, bytes: 0 }, writer))
+            }
+        };
+
+        writer.write_all(&line)?;
+        segment.len += 1;
+        segment.bytes += line.len() as u64;
+        self.len += 1;
+        self.bytes += line.len() as u64;
+
+        if segment.len >= self.options.segment_actions {
+            self.finish_current()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads back and removes the oldest segment, including the one being
+    /// appended to once it is the only one left. Lines which can't be parsed,
+    /// such as one torn by a crash, are skipped, and a segment which can't be
+    /// read at all is lost.
+    pub(crate) fn pop(&mut self) -> io::Result<Vec<Queued>> {
+        if self.segments.is_empty() {
+            self.finish_current()?;
+        }
+
+        let Some(segment) = self.segments.pop_front() else {
+            return Ok(Vec::new());
+        };
+
+        // A segment which can't be read is given up on, rather than blocking
+        // those behind it forever.
+        self.len -= segment.len;
+        self.bytes -= segment.bytes;
+
+        let mut actions = Vec::with_capacity(segment.len);
+        for line in BufReader::new(File::open(&segment.path)?).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(queued) => actions.push(queued),
+                Err(e) => tracing::warn!(
+                    "Skipped an unreadable action in {}: {e}",
+                    segment.path.display()
+                ),
+            }
+        }
+
+        fs::remove_file(&segment.path)?;
+
+        Ok(actions)
+    }
+
+    /// Flushes the segment being appended to and closes it, so that it can
+    /// be read back.
+    fn finish_current(&mut self) -> io::Result<()> {
+        if let Some((segment, writer)) = self.current.take() {
+            writer.into_inner().map_err(io::IntoInnerError::into_error)?;
+            self.segments.push_back(segment);
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the id of the segment at `path`, if it is one.
+fn segment_id(path: &Path) -> Option<u64> {
+    if path.extension()? != SEGMENT_EXTENSION {
+        return None;
+    }
+
+    path.file_stem()?.to_str()?.strip_prefix("spill-")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        action::{Action, Kind},
+        server::queue::ConnectionId,
+        test_fixtures::Player,
+    };
+
+    struct Login;
+
+    impl Kind for Login {
+        fn key(&self) -> &str {
+            "login"
+        }
+    }
+
+    #[test]
+    fn spill_round_trip() {
+        let dir = std::env::temp_dir().join(format!("harp-spill-{}", fastrand::u64(..)));
+        let options = SpillOptions { dir: dir.clone(), max_bytes: None, segment_actions: 2 };
+
+        let mut spill = Spill::open(options.clone()).unwrap();
+        for id in 0..3 {
+            let queued = Queued::new(ConnectionId::next(), Action::new(Login, &Player(id)));
+            spill.push(&queued).unwrap();
+        }
+        assert_eq!(spill.len(), 3);
+        assert_eq!(spill.next_len(), 2);
+        drop(spill);
+
+        // Segments outlive the spill which wrote them.
+        let mut spill = Spill::open(options).unwrap();
+        assert_eq!(spill.len(), 3);
+
+        let ids = |actions: Vec<Queued>| actions.iter().map(|q| q.action.id).collect::<Vec<_>>();
+        assert_eq!(ids(spill.pop().unwrap()), [0, 1]);
+        assert_eq!(ids(spill.pop().unwrap()), [2]);
+        assert!(spill.is_empty());
+        assert!(spill.pop().unwrap().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
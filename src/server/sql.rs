@@ -0,0 +1,920 @@
+use std::{borrow::Cow, collections::HashMap, future::Future, pin::Pin, time::Duration};
+
+use serde::Deserialize;
+use sqlx::{
+    error::BoxDynError,
+    migrate::{Migration, MigrationSource, MigrationType, Migrator},
+    Executor, PgPool,
+};
+
+use crate::{
+    encryption::DetailCipher,
+    server::kinds::{kind_expr, KindRegistry},
+    Result,
+};
+
+/// The columns written for each action, in bind order.
+pub const ACTION_COLUMNS: &[&str] = &[
+    "unique_id",
+    "ip_address",
+    "kind",
+    "detail",
+    "created",
+    "source",
+    "idempotency_key",
+    "sample_rate",
+    "country",
+    "asn",
+    "rate_exceeded",
+    "ordinal",
+    "session_id",
+    "trace_id",
+];
+
+/// An action field written to the database, named after its default column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    UniqueId,
+    IpAddress,
+    Kind,
+    Detail,
+    Created,
+    Source,
+    IdempotencyKey,
+    SampleRate,
+    Country,
+    Asn,
+    RateExceeded,
+    Ordinal,
+    SessionId,
+    TraceId,
+}
+
+impl Field {
+    /// Every field, in the same order as `ACTION_COLUMNS`.
+    pub const ALL: [Field; 14] = [
+        Field::UniqueId,
+        Field::IpAddress,
+        Field::Kind,
+        Field::Detail,
+        Field::Created,
+        Field::Source,
+        Field::IdempotencyKey,
+        Field::SampleRate,
+        Field::Country,
+        Field::Asn,
+        Field::RateExceeded,
+        Field::Ordinal,
+        Field::SessionId,
+        Field::TraceId,
+    ];
+
+    /// Returns the column the field is written to by default.
+    pub fn column(self) -> &'static str {
+        ACTION_COLUMNS[self as usize]
+    }
+
+    /// Returns true if the field was added after tables could be mapped, so
+    /// that a mapped table only has it written if it asks for it.
+    pub fn is_opt_in(self) -> bool {
+        matches!(self, Field::Ordinal | Field::SessionId | Field::TraceId)
+    }
+}
+
+/// The columns actions are written to, for tables which don't use Harp's own
+/// column names, such as an existing audit log.
+#[derive(Debug, Clone)]
+pub struct ColumnMapping {
+    /// The fields written, in bind order, and their columns.
+    fields: Vec<(Field, String)>,
+    /// Columns given the same value in every row, rendered as literals.
+    constants: Vec<(String, String)>,
+    /// The table kinds are looked up in, if they are normalized.
+    kinds_table: Option<String>,
+}
+
+impl Default for ColumnMapping {
+    /// Writes every field which isn't opt-in to its default column, for an
+    /// existing table.
+    fn default() -> Self {
+        let fields = Field::ALL
+            .iter()
+            .filter(|field| !field.is_opt_in())
+            .map(|&field| (field, field.column().to_string()))
+            .collect();
+        Self { fields, constants: Vec::new(), kinds_table: None }
+    }
+}
+
+impl ColumnMapping {
+    /// Writes every field to its default column, for Harp's own tables.
+    pub fn all() -> Self {
+        Field::ALL.iter().fold(Self::default(), |mapping, &field| mapping.include(field))
+    }
+
+    /// Writes `field` to its default column too, if it isn't written already.
+    pub fn include(mut self, field: Field) -> Self {
+        if !self.fields.iter().any(|(f, _)| *f == field) {
+            self.fields.push((field, field.column().to_string()));
+        }
+
+        self
+    }
+
+    /// Writes `field` to `column` instead of its default column, including
+    /// it if it is opt-in. `column` must be a validated identifier.
+    pub fn rename(self, field: Field, column: &str) -> Self {
+        let mut mapping = self.include(field);
+        if let Some((_, name)) = mapping.fields.iter_mut().find(|(f, _)| *f == field) {
+            *name = column.to_string();
+        }
+
+        mapping
+    }
+
+    /// Stops writing `field`, for tables with no column for it.
+    pub fn omit(mut self, field: Field) -> Self {
+        self.fields.retain(|(f, _)| *f != field);
+        self
+    }
+
+    /// Writes `value` to `column` in every row. `column` must be a validated
+    /// identifier; `value` is quoted, so it may be anything Postgres can cast
+    /// from text.
+    pub fn constant(mut self, column: &str, value: &str) -> Self {
+        self.constants.push((column.to_string(), value.to_string()));
+        self
+    }
+
+    /// Writes the id of each action's kind, from `kinds_table`, to the
+    /// `kind_id` column rather than the kind itself. `kinds_table` must be a
+    /// validated, schema-qualified table name.
+    pub fn normalize_kinds(self, kinds_table: &str) -> Self {
+        let mut mapping = self.rename(Field::Kind, "kind_id");
+        mapping.kinds_table = Some(kinds_table.to_string());
+        mapping
+    }
+
+    /// Returns the table kinds are looked up in, if they are normalized.
+    pub fn kinds_table(&self) -> Option<&str> {
+        self.kinds_table.as_deref()
+    }
+
+    /// Returns the fields written, in bind order.
+    pub fn fields(&self) -> impl Iterator<Item = Field> + '_ {
+        self.fields.iter().map(|(field, _)| *field)
+    }
+
+    /// Returns the column `field` is written to, if it is written at all.
+    pub fn column(&self, field: Field) -> Option<&str> {
+        self.fields.iter().find(|(f, _)| *f == field).map(|(_, column)| column.as_str())
+    }
+}
+
+/// Batch sizes which have a dedicated insert statement, largest first. Every
+/// batch is split into chunks of these sizes so that the database only ever
+/// sees a handful of distinct statements, which sqlx prepares once per
+/// connection and then reuses.
+const CHUNK_SIZES: [usize; 4] = [1000, 100, 10, 1];
+
+/// The expression bucketing an action's creation time into its UTC hour.
+const ROLLUP_HOUR: &str = "date_trunc('hour', created AT TIME ZONE 'UTC')";
+
+/// Database migrations, embedded into the binary at compile time. The SQL is
+/// templated with `{schema}` and `{table}` placeholders, which are replaced
+/// with the configured names before running. Each must be safe to run more
+/// than once, as they are rerun whenever harpd migrates.
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (1, "create actions", include_str!("../../migrations/0001_create_actions.sql")),
+    (2, "add source", include_str!("../../migrations/0002_add_source.sql")),
+    (3, "add idempotency key", include_str!("../../migrations/0003_add_idempotency_key.sql")),
+    (4, "add sample rate", include_str!("../../migrations/0004_add_sample_rate.sql")),
+    (5, "add geoip", include_str!("../../migrations/0005_add_geoip.sql")),
+    (6, "create hourly rollups", include_str!("../../migrations/0006_create_hourly_rollups.sql")),
+    (7, "create alerts", include_str!("../../migrations/0007_create_alerts.sql")),
+    (8, "add rate exceeded", include_str!("../../migrations/0008_add_rate_exceeded.sql")),
+    (9, "create kinds", include_str!("../../migrations/0009_create_kinds.sql")),
+    (10, "create aggregates", include_str!("../../migrations/0010_create_aggregates.sql")),
+    (11, "add ordinal", include_str!("../../migrations/0011_add_ordinal.sql")),
+    (12, "add session id", include_str!("../../migrations/0012_add_session_id.sql")),
+    (13, "create sessions", include_str!("../../migrations/0013_create_sessions.sql")),
+    (14, "add trace id", include_str!("../../migrations/0014_add_trace_id.sql")),
+    (15, "create aggregate keys", include_str!("../../migrations/0015_create_aggregate_keys.sql")),
+];
+
+/// The migrations which shape an actions table, rerun on every startup for
+/// each routed table.
+const ROUTED_MIGRATIONS: [i64; 10] = [1, 2, 3, 4, 5, 8, 9, 11, 12, 14];
+
+/// Indexes for the common query shapes, created on every actions table unless
+/// index management is turned off: by kind over time, by IP address, and by
+/// player over time. Each is a name suffix and its columns.
+const QUERY_INDEXES: [(&str, &str); 3] = [
+    ("kind_created", "kind, created"),
+    ("ip_address", "ip_address"),
+    ("unique_id_created", "unique_id, created"),
+];
+
+/// Runs any pending migrations against the database, creating the configured
+/// schema and table if needed, then creates or updates each routed table in
+/// the same schema. Every table gets an index on each of `detail_keys`, and
+/// the `QUERY_INDEXES` if `manage_indexes` is set, along with one on
+/// `(kind_id, created)` if `normalize_kinds` is also set. Only when kinds are
+/// normalized is each table's `kind` column made nullable, so that rows can be
+/// stored with just their `kind_id`.
+pub async fn migrate(
+    pg: &PgPool,
+    schema: &str,
+    table: &str,
+    routed_tables: &[&str],
+    detail_keys: &[String],
+    manage_indexes: bool,
+    normalize_kinds: bool,
+) -> Result<()> {
+    let migrator = Migrator::new(EmbeddedMigrations { schema, table }).await?;
+    migrator.run(pg).await?;
+
+    // The migrator's history doesn't say which schema and table it migrated,
+    // so every migration is run again in case either has since changed.
+    rerun_migrations(pg, schema, table, |_| true).await?;
+
+    for routed_table in routed_tables {
+        rerun_migrations(pg, schema, routed_table, |v| ROUTED_MIGRATIONS.contains(v)).await?;
+    }
+
+    for table in std::iter::once(&table).chain(routed_tables) {
+        if normalize_kinds {
+            pg.execute(
+                format!("ALTER TABLE {schema}.{table} ALTER COLUMN kind DROP NOT NULL").as_str(),
+            )
+            .await?;
+        }
+
+        if manage_indexes {
+            for (suffix, columns) in QUERY_INDEXES {
+                ensure_index(pg, schema, table, &format!("{table}_{suffix}_idx"), columns).await?;
+            }
+
+            if normalize_kinds {
+                let name = format!("{table}_kind_id_created_idx");
+                ensure_index(pg, schema, table, &name, "kind_id, created").await?;
+            }
+        }
+
+        // Expression indexes, so that queries such as
+        // `detail->>'match_id' = $1` don't scan the whole table.
+        for key in detail_keys {
+            let name = format!("{table}_detail_{key}_idx");
+            ensure_index(pg, schema, table, &name, &format!("(detail->>'{key}')")).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the index `name` on `columns` of `table` if it doesn't exist. The
+/// index is built concurrently, so services can keep writing to a large,
+/// backfilled table while it is. A concurrent build which was interrupted
+/// leaves an invalid index behind, which is dropped and built again. Every
+/// name must be a validated identifier.
+async fn ensure_index(
+    pg: &PgPool,
+    schema: &str,
+    table: &str,
+    name: &str,
+    columns: &str,
+) -> Result<()> {
+    let valid: Option<bool> = sqlx::query_scalar(
+        "SELECT i.indisvalid FROM pg_index i \
+         JOIN pg_class c ON c.oid = i.indexrelid \
+         JOIN pg_namespace n ON n.oid = c.relnamespace \
+         WHERE n.nspname = $1 AND c.relname = $2",
+    )
+    .bind(schema)
+    .bind(name)
+    .fetch_optional(pg)
+    .await?;
+
+    match valid {
+        Some(true) => return Ok(()),
+        Some(false) => {
+            tracing::warn!("Rebuilding invalid index {schema}.{name}");
+            pg.execute(format!("DROP INDEX CONCURRENTLY IF EXISTS {schema}.{name}").as_str())
+                .await?;
+        }
+        None => tracing::info!("Creating index {schema}.{name}"),
+    }
+
+    pg.execute(
+        format!("CREATE INDEX CONCURRENTLY IF NOT EXISTS {name} ON {schema}.{table} ({columns})")
+            .as_str(),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Runs the migrations whose versions match `filter` again against `table`,
+/// bringing it up to date whether or not the migrator has seen it.
+async fn rerun_migrations(
+    pg: &PgPool,
+    schema: &str,
+    table: &str,
+    filter: impl Fn(&i64) -> bool,
+) -> Result<()> {
+    let mut tx = pg.begin().await?;
+    for (_, _, sql) in MIGRATIONS.iter().filter(|(v, ..)| filter(v)) {
+        (&mut *tx).execute(render_migration(sql, schema, table).as_str()).await?;
+    }
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Pre-rendered insert statements for each of the fixed chunk sizes, for the
+/// actions table and any tables specific kinds are routed to.
+#[derive(Debug)]
+pub struct InsertStatements {
+    tables: Vec<TableStatements>,
+    routes: HashMap<String, usize>,
+    rollup_table: Option<String>,
+    columns: ColumnMapping,
+    fields: Vec<Field>,
+    kinds: Option<KindRegistry>,
+    cipher: Option<DetailCipher>,
+    sort_by_created: bool,
+    /// The window, in seconds, of each kind stored only as counts.
+    aggregates: HashMap<String, i64>,
+    aggregate_statement: String,
+    /// Set if each action's login session is kept up to date in the sessions
+    /// table.
+    session_statement: Option<String>,
+    /// Set if the insert statements return the unique ID and idempotency key
+    /// of each row they store.
+    returns_keys: bool,
+}
+
+#[derive(Debug)]
+struct TableStatements {
+    table: String,
+    statements: Vec<(usize, String)>,
+}
+
+impl TableStatements {
+    fn new(
+        table: &str,
+        rollup_table: Option<&str>,
+        columns: &ColumnMapping,
+        returning_keys: bool,
+    ) -> Self {
+        let statements = CHUNK_SIZES
+            .iter()
+            .map(|&size| {
+                (size, insert_statement(table, size, rollup_table, columns, returning_keys))
+            })
+            .collect();
+
+        Self { table: table.to_string(), statements }
+    }
+}
+
+impl InsertStatements {
+    /// Renders the insert statements for `table`, which must be a validated,
+    /// schema-qualified table name. If a rollup table is given, each statement
+    /// also adds the rows it inserts to the hourly counts.
+    pub fn new(table: &str, rollup_table: Option<&str>) -> Self {
+        Self::with_columns(table, rollup_table, ColumnMapping::all())
+    }
+
+    /// Renders the insert statements for `table` as `new` does, writing
+    /// actions to the columns given by `columns`. Routed tables use the same
+    /// columns.
+    pub fn with_columns(table: &str, rollup_table: Option<&str>, columns: ColumnMapping) -> Self {
+        Self {
+            tables: vec![TableStatements::new(table, rollup_table, &columns, false)],
+            routes: HashMap::new(),
+            rollup_table: rollup_table.map(String::from),
+            fields: columns.fields().collect(),
+            kinds: columns.kinds_table().map(KindRegistry::new),
+            columns,
+            cipher: None,
+            sort_by_created: false,
+            aggregates: HashMap::new(),
+            aggregate_statement: aggregate_statement(table),
+            session_statement: None,
+            returns_keys: false,
+        }
+    }
+
+    /// Writes actions of `kind` to `table` rather than the actions table.
+    /// `table` must be a validated, schema-qualified table name. Routed
+    /// actions are counted in the same rollup table as every other action.
+    pub fn route(mut self, kind: &str, table: &str) -> Self {
+        let index = match self.tables.iter().position(|t| t.table == table) {
+            Some(index) => index,
+            None => {
+                let rollup_table = self.rollup_table.as_deref();
+                let statements =
+                    TableStatements::new(table, rollup_table, &self.columns, self.returns_keys);
+                self.tables.push(statements);
+                self.tables.len() - 1
+            }
+        };
+
+        self.routes.insert(kind.to_string(), index);
+        self
+    }
+
+    /// Encrypts the detail of every action with `cipher` as it is bound.
+    /// Details which were already encrypted by their service are stored as
+    /// they are.
+    pub fn encrypt_detail(mut self, cipher: DetailCipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Inserts the actions of each batch ordered by when they were created,
+    /// then by kind, rather than in the order they arrived. Rows then land in
+    /// the `created` and `(kind, created)` indexes in order, touching fewer
+    /// index pages per batch.
+    pub fn sort_by_created(mut self) -> Self {
+        self.sort_by_created = true;
+        self
+    }
+
+    /// Stores actions of `kind` only as a count for each player in every
+    /// `window`, in the aggregates table, rather than as rows. Windows are
+    /// whole seconds, aligned to the Unix epoch.
+    pub fn aggregate(mut self, kind: &str, window: Duration) -> Self {
+        let secs = i64::try_from(window.as_secs()).unwrap_or(i64::MAX).max(1);
+        self.aggregates.insert(kind.to_string(), secs);
+        self
+    }
+
+    /// Returns the window, in seconds, actions of `kind` are counted over, if
+    /// they are aggregated.
+    pub fn aggregate_window(&self, kind: &str) -> Option<i64> {
+        self.aggregates.get(kind).copied()
+    }
+
+    /// Returns the statement which adds counts to the aggregates table, bound
+    /// with arrays of kinds, unique IDs, window starts, idempotency keys, and
+    /// counts.
+    pub fn aggregate_statement(&self) -> &str {
+        &self.aggregate_statement
+    }
+
+    /// Keeps a row for each login session in the sessions table, recording
+    /// when it started, was last seen, and ended, and how many actions were
+    /// sent during it. Resent actions are only counted once, so the insert
+    /// statements return the keys they store, unless keys aren't written.
+    pub fn track_sessions(mut self) -> Self {
+        self.session_statement = Some(session_statement(&self.tables[0].table));
+        self.returns_keys = self.columns.column(Field::IdempotencyKey).is_some();
+
+        let rollup_table = self.rollup_table.as_deref();
+        for table in &mut self.tables {
+            *table =
+                TableStatements::new(&table.table, rollup_table, &self.columns, self.returns_keys);
+        }
+
+        self
+    }
+
+    /// Returns true if the insert statements return the unique ID and
+    /// idempotency key of every row they store.
+    pub fn returns_keys(&self) -> bool {
+        self.returns_keys
+    }
+
+    /// Returns the statement which upserts rows into the sessions table, bound
+    /// with arrays of session ids, unique IDs, IP addresses, start times, last
+    /// seen times, end times, and action counts, if sessions are tracked.
+    pub fn session_statement(&self) -> Option<&str> {
+        self.session_statement.as_deref()
+    }
+
+    /// Returns true if each batch is sorted before it is inserted.
+    pub fn sorts_by_created(&self) -> bool {
+        self.sort_by_created
+    }
+
+    /// Returns the registry kinds are mapped to ids with, if they are
+    /// normalized.
+    pub(crate) fn kinds(&self) -> Option<&KindRegistry> {
+        self.kinds.as_ref()
+    }
+
+    /// Returns the cipher details are encrypted with, if any.
+    pub fn cipher(&self) -> Option<&DetailCipher> {
+        self.cipher.as_ref()
+    }
+
+    /// Returns the number of tables actions are written to.
+    pub fn tables(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// Returns the fields each statement expects to be bound for every action,
+    /// in order.
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// Returns the index of the table actions of `kind` are written to.
+    pub fn table_for(&self, kind: &str) -> usize {
+        self.routes.get(kind).copied().unwrap_or(0)
+    }
+
+    /// Returns the statement which inserts exactly `size` actions into the
+    /// table at `table`. `size` must be one of the sizes returned by
+    /// [chunk_sizes].
+    pub fn get(&self, table: usize, size: usize) -> &str {
+        self.tables[table]
+            .statements
+            .iter()
+            .find(|(s, _)| *s == size)
+            .map(|(_, sql)| sql.as_str())
+            .expect("chunk size should have a prepared statement")
+    }
+}
+
+/// Splits a batch of `len` actions into the fixed chunk sizes, largest first.
+pub fn chunk_sizes(mut len: usize) -> Vec<usize> {
+    let mut chunks = Vec::new();
+
+    for size in CHUNK_SIZES {
+        while len >= size {
+            chunks.push(size);
+            len -= size;
+        }
+    }
+
+    chunks
+}
+
+/// Builds a multi-row insert statement for `rows` actions. Actions which
+/// collide with an already stored idempotency key are skipped, and so are not
+/// counted in the rollup table. If `returning_keys` is set, the statement
+/// returns the unique ID and idempotency key of every row it stores.
+fn insert_statement(
+    table: &str,
+    rows: usize,
+    rollup_table: Option<&str>,
+    columns: &ColumnMapping,
+    returning_keys: bool,
+) -> String {
+    let params = columns.fields.len();
+    let constants = columns
+        .constants
+        .iter()
+        .map(|(_, value)| format!("'{}'", value.replace('\'', "''")))
+        .collect::<Vec<_>>();
+    let values = (0..rows)
+        .map(|row| {
+            let values = (1..=params)
+                .map(|param| format!("${}", row * params + param))
+                .chain(constants.iter().cloned())
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("({values})")
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let names = columns
+        .fields
+        .iter()
+        .map(|(_, column)| column.as_str())
+        .chain(columns.constants.iter().map(|(column, _)| column.as_str()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let insert = format!("INSERT INTO {table} ({names}) VALUES {values} ON CONFLICT DO NOTHING");
+
+    // Rollups need both the kind and creation time; config validation makes
+    // sure neither is omitted when they are enabled.
+    let returning = |field: Field| match columns.column(field) {
+        Some(column) if column != field.column() => format!("{column} AS {}", field.column()),
+        _ => field.column().to_string(),
+    };
+
+    // Rollups are counted by kind, so normalized kinds are looked up again.
+    let (returning_kind, counted) = match &columns.kinds_table {
+        Some(kinds_table) => (
+            "kind_id".to_string(),
+            format!(
+                "kinds.kind, {ROLLUP_HOUR}, count(*) FROM inserted \
+                 JOIN {kinds_table} AS kinds ON kinds.id = inserted.kind_id"
+            ),
+        ),
+        None => (returning(Field::Kind), format!("kind, {ROLLUP_HOUR}, count(*) FROM inserted")),
+    };
+
+    let keys = (returning_keys && columns.column(Field::IdempotencyKey).is_some())
+        .then(|| format!("{}, {}", returning(Field::UniqueId), returning(Field::IdempotencyKey)));
+
+    let rollup = |rollup_table: &str| {
+        format!(
+            "INSERT INTO {rollup_table} AS rollup (kind, hour, count) \
+             SELECT {counted} GROUP BY 1, 2 \
+             ON CONFLICT (kind, hour) DO UPDATE SET count = rollup.count + EXCLUDED.count"
+        )
+    };
+
+    let created = returning(Field::Created);
+    match (rollup_table, keys) {
+        (Some(rollup_table), None) => format!(
+            "WITH inserted AS ({insert} RETURNING {returning_kind}, {created}) {}",
+            rollup(rollup_table),
+        ),
+        (Some(rollup_table), Some(keys)) => format!(
+            "WITH inserted AS ({insert} RETURNING {returning_kind}, {created}, {keys}), \
+             rollup AS ({}) SELECT unique_id, idempotency_key FROM inserted",
+            rollup(rollup_table),
+        ),
+        (None, Some(keys)) => format!("{insert} RETURNING {keys}"),
+        (None, None) => insert,
+    }
+}
+
+/// Renders the statement which adds counts to the aggregates table of
+/// `table`, all rows at once from arrays. Like rows of the actions table,
+/// counts of actions with an idempotency key are only added the first time
+/// the key is seen, which `<table>_aggregate_keys` remembers. Returns the
+/// unique ID and key of each action seen for the first time.
+fn aggregate_statement(table: &str) -> String {
+    format!(
+        "WITH incoming AS (SELECT * FROM unnest($1::varchar[], $2::bigint[], $3::timestamptz[], \
+         $4::bigint[], $5::bigint[]) AS t (kind, unique_id, bucket, idempotency_key, count)), \
+         fresh AS (INSERT INTO {table}_aggregate_keys (unique_id, idempotency_key, bucket) \
+         SELECT unique_id, idempotency_key, bucket FROM incoming WHERE idempotency_key IS NOT NULL \
+         ON CONFLICT DO NOTHING RETURNING unique_id, idempotency_key), \
+         counted AS (INSERT INTO {table}_aggregates AS agg (kind, unique_id, bucket, count) \
+         SELECT kind, unique_id, bucket, sum(count)::bigint FROM incoming \
+         WHERE idempotency_key IS NULL OR (unique_id, idempotency_key) IN (SELECT * FROM fresh) \
+         GROUP BY 1, 2, 3 \
+         ON CONFLICT (kind, unique_id, bucket) DO UPDATE SET count = agg.count + EXCLUDED.count) \
+         SELECT unique_id, idempotency_key FROM fresh"
+    )
+}
+
+/// Builds the statement which upserts rows into `table`'s sessions table. A
+/// session spread over several batches widens its row with each, and is ended
+/// by whichever batch holds its `session_end` action.
+fn session_statement(table: &str) -> String {
+    format!(
+        "INSERT INTO {table}_sessions AS s \
+         (session_id, unique_id, ip_address, started, last_seen, ended, actions) \
+         SELECT * FROM unnest($1::bigint[], $2::bigint[], $3::inet[], $4::timestamptz[], \
+         $5::timestamptz[], $6::timestamptz[], $7::bigint[]) \
+         ON CONFLICT (session_id) DO UPDATE SET \
+         started = LEAST(s.started, EXCLUDED.started), \
+         last_seen = GREATEST(s.last_seen, EXCLUDED.last_seen), \
+         ended = COALESCE(EXCLUDED.ended, s.ended), \
+         actions = s.actions + EXCLUDED.actions"
+    )
+}
+
+/// Recomputes the hourly counts in `rollup_table` from every action stored in
+/// `tables`, returning the number of rollup rows written. Services may keep
+/// sending actions while this runs, but counts for actions inserted during the
+/// backfill can be lost, so it is best run before enabling rollups.
+///
+/// If kinds are normalized, the kind of each row is looked up in
+/// `kinds_table`.
+pub async fn backfill_rollups(
+    pg: &PgPool,
+    tables: &[String],
+    rollup_table: &str,
+    kinds_table: Option<&str>,
+) -> Result<u64> {
+    let kind = kinds_table.map_or_else(|| "kind".to_string(), kind_expr);
+    let actions = tables
+        .iter()
+        .map(|table| format!("SELECT {kind} AS kind, created FROM {table} AS actions"))
+        .collect::<Vec<_>>()
+        .join(" UNION ALL ");
+
+    let result = sqlx::query(&format!(
+        "INSERT INTO {rollup_table} (kind, hour, count) \
+         SELECT kind, {ROLLUP_HOUR}, count(*) FROM ({actions}) AS actions GROUP BY 1, 2 \
+         ON CONFLICT (kind, hour) DO UPDATE SET count = EXCLUDED.count"
+    ))
+    .execute(pg)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Returns true if `name` is safe to interpolate into a query as an unquoted
+/// Postgres identifier.
+pub fn is_valid_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    name.len() <= 63
+        && chars.next().is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// A migration source which renders the embedded migrations for a specific
+/// schema and table.
+#[derive(Debug)]
+struct EmbeddedMigrations<'a> {
+    schema: &'a str,
+    table: &'a str,
+}
+
+impl<'s> MigrationSource<'s> for EmbeddedMigrations<'s> {
+    fn resolve(
+        self,
+    ) -> Pin<Box<dyn Future<Output = std::result::Result<Vec<Migration>, BoxDynError>> + Send + 's>>
+    {
+        Box::pin(async move {
+            let migrations = MIGRATIONS
+                .iter()
+                .map(|(version, description, sql)| {
+                    let mut migration = Migration::new(
+                        *version,
+                        Cow::Borrowed(*description),
+                        MigrationType::Simple,
+                        Cow::Owned(render_migration(sql, self.schema, self.table)),
+                    );
+
+                    // Checksum the template rather than the rendered SQL, so
+                    // that changing the schema or table doesn't look like an
+                    // edited migration to the migrator.
+                    migration.checksum = Migration::new(
+                        *version,
+                        Cow::Borrowed(*description),
+                        MigrationType::Simple,
+                        Cow::Borrowed(*sql),
+                    )
+                    .checksum;
+
+                    migration
+                })
+                .collect();
+
+            Ok(migrations)
+        })
+    }
+}
+
+/// Fills in the `{schema}` and `{table}` placeholders of a migration.
+fn render_migration(sql: &str, schema: &str, table: &str) -> String {
+    sql.replace("{schema}", schema).replace("{table}", table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_batches_into_fixed_chunks() {
+        assert_eq!(chunk_sizes(0), Vec::<usize>::new());
+        assert_eq!(chunk_sizes(1), vec![1]);
+        assert_eq!(chunk_sizes(2112), vec![1000, 1000, 100, 10, 1, 1]);
+    }
+
+    #[test]
+    fn render_insert_statement() {
+        assert_eq!(
+            insert_statement("harp.actions", 2, None, &ColumnMapping::all(), false),
+            "INSERT INTO harp.actions \
+             (unique_id, ip_address, kind, detail, created, source, idempotency_key, sample_rate, country, asn, \
+             rate_exceeded, ordinal, session_id, trace_id) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14), \
+             ($15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25, $26, $27, $28) \
+             ON CONFLICT DO NOTHING"
+        );
+
+        assert_eq!(
+            insert_statement(
+                "harp.actions",
+                1,
+                Some("harp.actions_hourly"),
+                &ColumnMapping::all(),
+                false,
+            ),
+            "WITH inserted AS (INSERT INTO harp.actions \
+             (unique_id, ip_address, kind, detail, created, source, idempotency_key, sample_rate, country, asn, \
+             rate_exceeded, ordinal, session_id, trace_id) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14) \
+             ON CONFLICT DO NOTHING RETURNING kind, created) \
+             INSERT INTO harp.actions_hourly AS rollup (kind, hour, count) \
+             SELECT kind, date_trunc('hour', created AT TIME ZONE 'UTC'), count(*) FROM inserted GROUP BY 1, 2 \
+             ON CONFLICT (kind, hour) DO UPDATE SET count = rollup.count + EXCLUDED.count"
+        );
+    }
+
+    #[test]
+    fn render_statement_returning_keys() {
+        let columns = ColumnMapping::default().omit(Field::Detail).omit(Field::Source);
+        assert_eq!(
+            insert_statement("harp.actions", 1, Some("harp.actions_hourly"), &columns, true),
+            "WITH inserted AS (INSERT INTO harp.actions \
+             (unique_id, ip_address, kind, created, idempotency_key, sample_rate, country, asn, \
+             rate_exceeded) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) \
+             ON CONFLICT DO NOTHING RETURNING kind, created, unique_id, idempotency_key), \
+             rollup AS (INSERT INTO harp.actions_hourly AS rollup (kind, hour, count) \
+             SELECT kind, date_trunc('hour', created AT TIME ZONE 'UTC'), count(*) FROM inserted \
+             GROUP BY 1, 2 \
+             ON CONFLICT (kind, hour) DO UPDATE SET count = rollup.count + EXCLUDED.count) \
+             SELECT unique_id, idempotency_key FROM inserted"
+        );
+
+        // Without a key column, nothing can be told apart from a resent row.
+        let columns = columns.omit(Field::IdempotencyKey);
+        assert!(!insert_statement("harp.actions", 1, None, &columns, true).contains("RETURNING"));
+    }
+
+    #[test]
+    fn render_mapped_columns() {
+        let columns = ColumnMapping::default()
+            .rename(Field::UniqueId, "user_id")
+            .rename(Field::Kind, "event")
+            .omit(Field::Detail)
+            .omit(Field::IdempotencyKey)
+            .omit(Field::SampleRate)
+            .omit(Field::Country)
+            .omit(Field::Asn)
+            .omit(Field::RateExceeded)
+            .constant("application", "harp's");
+
+        assert_eq!(
+            insert_statement("public.audit_log", 2, Some("harp.actions_hourly"), &columns, false),
+            "WITH inserted AS (INSERT INTO public.audit_log \
+             (user_id, ip_address, event, created, source, application) \
+             VALUES ($1, $2, $3, $4, $5, 'harp''s'), ($6, $7, $8, $9, $10, 'harp''s') \
+             ON CONFLICT DO NOTHING RETURNING event AS kind, created) \
+             INSERT INTO harp.actions_hourly AS rollup (kind, hour, count) \
+             SELECT kind, date_trunc('hour', created AT TIME ZONE 'UTC'), count(*) FROM inserted GROUP BY 1, 2 \
+             ON CONFLICT (kind, hour) DO UPDATE SET count = rollup.count + EXCLUDED.count"
+        );
+    }
+
+    #[test]
+    fn opt_in_fields_need_including() {
+        let columns = ColumnMapping::default();
+        assert_eq!(columns.column(Field::RateExceeded), Some("rate_exceeded"));
+        assert_eq!(columns.column(Field::Ordinal), None);
+
+        let columns = columns.include(Field::Ordinal).rename(Field::SessionId, "login_id");
+        assert_eq!(columns.column(Field::Ordinal), Some("ordinal"));
+        assert_eq!(columns.column(Field::SessionId), Some("login_id"));
+        assert_eq!(columns.column(Field::TraceId), None);
+    }
+
+    #[test]
+    fn render_normalized_kinds() {
+        let columns = ColumnMapping::default()
+            .omit(Field::Detail)
+            .omit(Field::IdempotencyKey)
+            .omit(Field::SampleRate)
+            .omit(Field::Country)
+            .omit(Field::Asn)
+            .omit(Field::RateExceeded)
+            .normalize_kinds("harp.kinds");
+
+        assert_eq!(
+            insert_statement("harp.actions", 1, Some("harp.actions_hourly"), &columns, false),
+            "WITH inserted AS (INSERT INTO harp.actions \
+             (unique_id, ip_address, kind_id, created, source) \
+             VALUES ($1, $2, $3, $4, $5) \
+             ON CONFLICT DO NOTHING RETURNING kind_id, created) \
+             INSERT INTO harp.actions_hourly AS rollup (kind, hour, count) \
+             SELECT kinds.kind, date_trunc('hour', created AT TIME ZONE 'UTC'), count(*) \
+             FROM inserted JOIN harp.kinds AS kinds ON kinds.id = inserted.kind_id GROUP BY 1, 2 \
+             ON CONFLICT (kind, hour) DO UPDATE SET count = rollup.count + EXCLUDED.count"
+        );
+    }
+
+    #[test]
+    fn route_kinds_to_tables() {
+        let statements = InsertStatements::new("harp.actions", None)
+            .route("chat", "harp.chat_actions")
+            .route("whisper", "harp.chat_actions")
+            .route("combat_hit", "harp.combat_actions");
+
+        assert_eq!(statements.tables(), 3);
+        assert_eq!(statements.table_for("login"), 0);
+        assert_eq!(statements.table_for("chat"), statements.table_for("whisper"));
+        assert!(statements
+            .get(statements.table_for("combat_hit"), 1)
+            .starts_with("INSERT INTO harp.combat_actions "));
+    }
+
+    #[test]
+    fn validate_identifiers() {
+        assert!(is_valid_identifier("harp"));
+        assert!(is_valid_identifier("_telemetry_2"));
+
+        assert!(!is_valid_identifier(""));
+        assert!(!is_valid_identifier("2fast"));
+        assert!(!is_valid_identifier("Actions"));
+        assert!(!is_valid_identifier("actions; DROP TABLE users"));
+        assert!(!is_valid_identifier(&"a".repeat(64)));
+    }
+}
@@ -0,0 +1,107 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use bufferfish::Bufferfish;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_util::bytes::Bytes;
+
+use crate::action::Action;
+
+/// The subscribers currently receiving live actions. Each subscriber has its
+/// own bounded buffer, so a slow subscriber only loses its own actions rather
+/// than holding up ingest or other subscribers.
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+struct Subscriber {
+    /// The kinds this subscriber receives; every kind if empty.
+    kinds: HashSet<String>,
+    tx: mpsc::Sender<Bytes>,
+}
+
+impl Subscriber {
+    fn wants(&self, kind: &str) -> bool {
+        self.kinds.is_empty() || self.kinds.contains(kind)
+    }
+}
+
+impl Subscriptions {
+    /// Registers a subscriber to the given kinds, returning the receiving half
+    /// of its buffer. The subscriber is removed once the receiver is dropped.
+    pub(crate) fn subscribe(&self, kinds: Vec<String>, buffer: usize) -> mpsc::Receiver<Bytes> {
+        let (tx, rx) = mpsc::channel(buffer);
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Subscriber { kinds: kinds.into_iter().collect(), tx });
+
+        rx
+    }
+
+    /// Sends an action to every subscriber which wants its kind. The action is
+    /// encoded at most once, and is dropped for subscribers whose buffer is
+    /// full.
+    pub(crate) fn publish(&self, action: &Action) {
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let mut encoded: Option<Bytes> = None;
+        subscribers.retain(|subscriber| {
+            if !subscriber.wants(&action.kind) {
+                return !subscriber.tx.is_closed();
+            }
+
+            let frame = match &encoded {
+                Some(frame) => frame.clone(),
+                None => match Bufferfish::try_from(action) {
+                    Ok(bf) => encoded.insert(bf.into()).clone(),
+                    Err(e) => {
+                        tracing::error!("Failed to encode action for subscribers: {e}");
+                        return true;
+                    }
+                },
+            };
+
+            match subscriber.tx.try_send(frame) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    tracing::debug!("Subscriber buffer is full; dropping action");
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{Target, TestKind};
+
+    #[test]
+    fn publish_to_matching_subscribers() {
+        let subscriptions = Subscriptions::default();
+        let mut trades = subscriptions.subscribe(vec!["trade".into()], 1);
+        let mut everything = subscriptions.subscribe(Vec::new(), 8);
+
+        subscriptions.publish(&Action::new(TestKind("trade"), &Target));
+        subscriptions.publish(&Action::new(TestKind("trade"), &Target));
+        subscriptions.publish(&Action::new(TestKind("login"), &Target));
+
+        // The second trade doesn't fit in the buffer and is dropped.
+        assert!(trades.try_recv().is_ok());
+        assert!(trades.try_recv().is_err());
+
+        for _ in 0..3 {
+            assert!(everything.try_recv().is_ok());
+        }
+
+        drop(trades);
+        subscriptions.publish(&Action::new(TestKind("trade"), &Target));
+        assert_eq!(subscriptions.subscribers.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,27 @@
+use listenfd::ListenFd;
+use sd_notify::NotifyState;
+use tokio::net::TcpListener;
+
+use crate::Result;
+
+/// Takes the first listener passed in by systemd socket activation, if any.
+/// Returns `Ok(None)` when harpd was not started via a `.socket` unit.
+pub(crate) fn take_listener() -> Result<Option<TcpListener>> {
+    let mut fds = ListenFd::from_env();
+
+    match fds.take_tcp_listener(0)? {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            Ok(Some(TcpListener::from_std(listener)?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Notifies systemd that harpd has finished starting up. This is a no-op when
+/// not running under a `Type=notify` unit.
+pub(crate) fn notify_ready() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        tracing::warn!("Failed to notify systemd of readiness: {e}");
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{server::WebPkiClientVerifier, RootCertStore, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::{
+    server::config::TlsConfig,
+    tls::{crypto_provider, load_certs, load_private_key},
+    Result,
+};
+
+/// Builds the acceptor for service connections from the `[tls]` section, or
+/// returns `None` if TLS is disabled.
+pub(crate) fn acceptor(config: &TlsConfig) -> Result<Option<TlsAcceptor>> {
+    let (Some(cert), Some(key)) = (&config.cert, &config.key) else {
+        return Ok(None);
+    };
+
+    let provider = crypto_provider();
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if config.require_client_cert {
+                verifier.build()?
+            } else {
+                verifier.allow_unauthenticated().build()?
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_private_key(key)?)?;
+
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// Returns the common name (CN) of the client certificate the service
+/// presented, which has already been verified against `tls.client_ca`.
+pub(crate) fn peer_identity(stream: &TlsStream<TcpStream>) -> Option<String> {
+    let (_, connection) = stream.get_ref();
+    let cert = connection.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+
+    Some(name.to_string())
+}
@@ -0,0 +1,60 @@
+use crate::action::Action;
+
+/// A compiled-in processor applied to every action after it has been decoded
+/// and validated, but before it is queued for insertion. Transforms can enrich
+/// an action (such as adding a GeoIP lookup to its detail), redact fields, or
+/// rename kinds, and can drop an action entirely by returning `None`.
+///
+/// Closures with the signature `Fn(Action) -> Option<Action>` implement this
+/// trait.
+pub trait Transform: Send + Sync + 'static {
+    /// Returns the action to queue, or `None` to drop it.
+    fn transform(&self, action: Action) -> Option<Action>;
+}
+
+impl<F> Transform for F
+where
+    F: Fn(Action) -> Option<Action> + Send + Sync + 'static,
+{
+    fn transform(&self, action: Action) -> Option<Action> {
+        self(action)
+    }
+}
+
+/// An ordered chain of transforms.
+#[derive(Default)]
+pub struct Transforms(Vec<Box<dyn Transform>>);
+
+impl Transforms {
+    /// Appends a transform to the end of the chain.
+    pub fn push(&mut self, transform: impl Transform) {
+        self.0.push(Box::new(transform));
+    }
+
+    /// Runs an action through every transform, stopping early if one of them
+    /// drops it.
+    pub(crate) fn apply(&self, action: Action) -> Option<Action> {
+        self.0.iter().try_fold(action, |action, transform| transform.transform(action))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{Target, TestKind};
+
+    #[test]
+    fn transforms_rename_and_drop() {
+        let mut transforms = Transforms::default();
+        transforms.push(|action: Action| (action.kind != "debug").then_some(action));
+        transforms.push(|mut action: Action| {
+            action.kind = action.kind.replace("login", "session_start").into();
+            Some(action)
+        });
+
+        let action = transforms.apply(Action::new(TestKind("login"), &Target)).unwrap();
+        assert_eq!(action.kind, "session_start");
+
+        assert!(transforms.apply(Action::new(TestKind("debug"), &Target)).is_none());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::Path,
+};
+
+use jsonschema::Validator;
+use serde_json::{json, Value};
+
+use crate::{action::Action, server::config::OversizePolicy, Result};
+
+/// JSON Schemas for action details, keyed by kind. Each schema is loaded from
+/// a `<kind>.json` file in the configured schema directory; kinds without a
+/// schema are not validated. If known kinds are set, actions of any other kind
+/// are rejected, and should be checked with `is_known` before validating.
+#[derive(Default)]
+pub(crate) struct SchemaRegistry {
+    validators: HashMap<String, Validator>,
+    known_kinds: Option<HashSet<String>>,
+}
+
+impl SchemaRegistry {
+    /// Loads and compiles every `.json` file in `dir`.
+    pub(crate) fn load(dir: &Path) -> Result<Self> {
+        let mut validators = HashMap::new();
+
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+
+            let Some(kind) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+
+            let schema: Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            let validator = jsonschema::validator_for(&schema)
+                .map_err(|e| format!("Invalid schema {}: {e}", path.display()))?;
+
+            validators.insert(kind.to_string(), validator);
+        }
+
+        tracing::info!("Loaded {} detail schemas from {}", validators.len(), dir.display());
+
+        Ok(Self { validators, known_kinds: None })
+    }
+
+    /// Rejects actions of any kind not in `kinds`.
+    pub(crate) fn with_known_kinds(mut self, kinds: &[String]) -> Self {
+        self.known_kinds = Some(kinds.iter().cloned().collect());
+        self
+    }
+
+    /// Returns true if actions of `kind` are accepted, which every kind is
+    /// unless known kinds are set.
+    pub(crate) fn is_known(&self, kind: &str) -> bool {
+        self.known_kinds.as_ref().is_none_or(|kinds| kinds.contains(kind))
+    }
+
+    /// Validates an action's detail against the schema for its kind. Actions
+    /// without a detail are validated as `null`. Returns a description of the
+    /// first violation if the detail is invalid.
+    pub(crate) fn validate(&self, action: &Action) -> std::result::Result<(), String> {
+        let Some(validator) = self.validators.get(action.kind.as_ref()) else {
+            return Ok(());
+        };
+
+        let detail = action.detail.as_ref().unwrap_or(&Value::Null);
+        match validator.iter_errors(detail).next() {
+            Some(e) => Err(format!("{e} at \"{}\"", e.instance_path)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The outcome of checking an action's detail against the size limit.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum DetailSize {
+    Within,
+    /// The detail was this many bytes, and has been replaced.
+    Truncated(usize),
+    /// The detail is this many bytes, and the action should be refused.
+    TooLarge(usize),
+}
+
+/// Measures an action's detail as JSON, applying `policy` if it is larger than
+/// `max_size` bytes. Truncated details are replaced with a small object noting
+/// the original size, so it's clear something was lost.
+pub(crate) fn check_detail_size(
+    detail: &mut Option<Value>,
+    max_size: usize,
+    policy: OversizePolicy,
+) -> DetailSize {
+    let Some(value) = detail else {
+        return DetailSize::Within;
+    };
+
+    let mut counter = ByteCounter(0);
+    if serde_json::to_writer(&mut counter, value).is_err() || counter.0 <= max_size {
+        return DetailSize::Within;
+    }
+
+    let size = counter.0;
+    match policy {
+        OversizePolicy::Reject => DetailSize::TooLarge(size),
+        OversizePolicy::Truncate => {
+            *detail = Some(json!({ "truncated": true, "size": size }));
+            DetailSize::Truncated(size)
+        }
+    }
+}
+
+/// Counts the bytes written to it, so details can be measured without
+/// allocating.
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_unknown_kinds() {
+        let schemas = SchemaRegistry::default();
+        assert!(schemas.is_known("trade"));
+
+        let schemas = schemas.with_known_kinds(&["login".into(), "chat".into()]);
+        assert!(schemas.is_known("chat"));
+        assert!(!schemas.is_known("trade"));
+    }
+
+    #[test]
+    fn enforce_detail_size() {
+        let detail = json!({ "message": "a".repeat(100) });
+        let size = detail.to_string().len();
+
+        let mut within = Some(detail.clone());
+        assert_eq!(
+            check_detail_size(&mut within, size, OversizePolicy::Reject),
+            DetailSize::Within
+        );
+        assert_eq!(check_detail_size(&mut None, 0, OversizePolicy::Reject), DetailSize::Within);
+
+        let mut rejected = Some(detail.clone());
+        assert_eq!(
+            check_detail_size(&mut rejected, 64, OversizePolicy::Reject),
+            DetailSize::TooLarge(size)
+        );
+        assert_eq!(rejected, Some(detail.clone()));
+
+        let mut truncated = Some(detail);
+        assert_eq!(
+            check_detail_size(&mut truncated, 64, OversizePolicy::Truncate),
+            DetailSize::Truncated(size)
+        );
+        assert_eq!(truncated, Some(json!({ "truncated": true, "size": size })));
+    }
+}
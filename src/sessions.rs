@@ -0,0 +1,145 @@
+//! Login sessions, which group a player's actions between a `session_start`
+//! and a `session_end` action. See `Sender::start_session`.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{action::Action, HarpId};
+
+/// How long a session may go without an action before it is closed, for
+/// players who left without their session being ended.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// The most sessions held open at once. Starting another closes every idle
+/// session, or failing that, the one which has gone longest without an
+/// action.
+const MAX_OPEN_SESSIONS: usize = 100_000;
+
+/// The session open for each (ip, id) pair, shared by every clone of a
+/// `Sender`. Sessions are held until they are ended, so every
+/// `Sender::start_session` should be paired with a `Sender::end_session`,
+/// though sessions left open are closed once idle, or to make room for more.
+#[derive(Debug, Default)]
+pub(crate) struct Sessions {
+    open: Mutex<HashMap<HarpId, OpenSession>>,
+    /// Set once any session has been started, so that services which never
+    /// use sessions don't take the lock for every action.
+    used: AtomicBool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OpenSession {
+    id: u64,
+    last_used: Instant,
+}
+
+impl OpenSession {
+    fn is_idle(&self, now: Instant) -> bool {
+        now.duration_since(self.last_used) >= SESSION_IDLE_TIMEOUT
+    }
+}
+
+impl Sessions {
+    /// Opens a session for `identifier`, replacing any already open, and
+    /// returns its id.
+    pub(crate) fn start(&self, identifier: HarpId) -> u64 {
+        // Random rather than counted, so that ids from different services, or
+        // from before a restart, don't collide.
+        let session_id = fastrand::u64(1..);
+
+        self.used.store(true, Ordering::Relaxed);
+
+        let now = Instant::now();
+        let mut open = self.lock();
+        if open.len() >= MAX_OPEN_SESSIONS && !open.contains_key(&identifier) {
+            open.retain(|_, session| !session.is_idle(now));
+        }
+        if open.len() >= MAX_OPEN_SESSIONS && !open.contains_key(&identifier) {
+            let oldest = open.iter().min_by_key(|(_, session)| session.last_used);
+            if let Some(&oldest) = oldest.map(|(identifier, _)| identifier) {
+                open.remove(&oldest);
+            }
+        }
+        open.insert(identifier, OpenSession { id: session_id, last_used: now });
+
+        session_id
+    }
+
+    /// Closes the session open for `identifier`, returning its id. A session
+    /// which was closed for being idle is not returned.
+    pub(crate) fn end(&self, identifier: HarpId) -> Option<u64> {
+        let session = self.lock().remove(&identifier)?;
+        (!session.is_idle(Instant::now())).then_some(session.id)
+    }
+
+    /// Stamps `action` with the session open for its identifier, unless it
+    /// already belongs to one.
+    pub(crate) fn stamp(&self, action: &mut Action) {
+        if action.session_id.is_some() || !self.used.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut open = self.lock();
+        let identifier = (action.addr, action.id);
+        match open.get_mut(&identifier) {
+            Some(session) if session.is_idle(now) => {
+                open.remove(&identifier);
+            }
+            Some(session) => {
+                session.last_used = now;
+                action.session_id = Some(session.id);
+            }
+            None => {}
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<HarpId, OpenSession>> {
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+
+    fn player(id: u32) -> HarpId {
+        (IpAddr::from([127, 0, 0, 1]), id)
+    }
+
+    #[test]
+    fn sessions_go_idle() {
+        let now = Instant::now();
+        let session = OpenSession { id: 1, last_used: now };
+        assert!(!session.is_idle(now + Duration::from_secs(60)));
+        assert!(session.is_idle(now + SESSION_IDLE_TIMEOUT));
+    }
+
+    #[test]
+    fn open_sessions_are_capped() {
+        let sessions = Sessions::default();
+        for id in 0..MAX_OPEN_SESSIONS as u32 {
+            sessions.start(player(id));
+        }
+
+        // Every session but the first has sent an action since.
+        let later = Instant::now() + Duration::from_secs(1);
+        for (identifier, session) in sessions.lock().iter_mut() {
+            if *identifier != player(0) {
+                session.last_used = later;
+            }
+        }
+
+        sessions.start(player(u32::MAX));
+        assert_eq!(sessions.lock().len(), MAX_OPEN_SESSIONS);
+        assert_eq!(sessions.end(player(0)), None);
+        assert!(sessions.end(player(1)).is_some());
+    }
+}
@@ -0,0 +1,60 @@
+//! A client for watching actions live as `harpd` receives them, such as for
+//! moderation or anti-cheat tooling.
+use bufferfish::Bufferfish;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::{action::Action, protocol::Subscribe, Harp, Result};
+
+/// A live feed of actions from a Harp server's subscription port. Actions are
+/// received after the server has validated and transformed them, but before
+/// they are written to the database. A subscriber which falls behind misses
+/// actions rather than slowing the server down.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use harp::subscriber::Subscriber;
+/// #
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut subscriber = Subscriber::connect("127.0.0.1", 7778, vec!["login_failed".into()]).await?;
+///
+/// while let Some(action) = subscriber.next().await {
+///     println!("{}", action?);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Subscriber {
+    stream: Framed<TcpStream, LengthDelimitedCodec>,
+}
+
+impl Subscriber {
+    /// Connects to a Harp server's subscription port and subscribes to the
+    /// given kinds. An empty list subscribes to every kind.
+    pub async fn connect(hostname: &str, port: u16, kinds: Vec<String>) -> Result<Self> {
+        let addr = Harp::create_addr(Some(hostname), Some(port));
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+
+        let mut stream =
+            LengthDelimitedCodec::builder().length_field_type::<u16>().new_framed(stream);
+        let bf = Bufferfish::try_from(Subscribe::new(kinds))?;
+        stream.send(bf.into()).await?;
+
+        Ok(Self { stream })
+    }
+
+    /// Waits for the next action. Returns `None` once the server closes the
+    /// connection.
+    pub async fn next(&mut self) -> Option<Result<Action>> {
+        let bytes = match self.stream.next().await? {
+            Ok(bytes) => bytes,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        Some(Action::try_from(Bufferfish::from(bytes)).map_err(Into::into))
+    }
+}
@@ -0,0 +1,33 @@
+//! Fixtures shared by the unit tests.
+use std::net::IpAddr;
+
+use crate::{action::Kind, HarpId, Loggable};
+
+/// An actor with the ID 1, at 127.0.0.1.
+pub(crate) struct Target;
+
+impl Loggable for Target {
+    fn identifier(&self) -> HarpId {
+        (IpAddr::from([127, 0, 0, 1]), 1)
+    }
+}
+
+/// An actor with the given ID, at 127.0.0.1, for tests which need more than
+/// one.
+pub(crate) struct Player(pub u32);
+
+impl Loggable for Player {
+    fn identifier(&self) -> HarpId {
+        (IpAddr::from([127, 0, 0, 1]), self.0)
+    }
+}
+
+/// A kind with the given key. Unlike a `&'static str`, it has no
+/// `static_key`, so actions of it own their kind.
+pub(crate) struct TestKind(pub &'static str);
+
+impl Kind for TestKind {
+    fn key(&self) -> &str {
+        self.0
+    }
+}
@@ -0,0 +1,262 @@
+//! Helpers for testing services which log to Harp, without running `harpd` or
+//! PostgreSQL. Enabled with the `testing` feature, usually as a
+//! dev-dependency.
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bufferfish::Bufferfish;
+use futures_util::StreamExt;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Notify,
+    task::JoinHandle,
+    time::{timeout_at, Instant},
+};
+use tokio_util::codec::Framed;
+
+use crate::{
+    action::Action,
+    protocol::{strip_checksum, FrameCodec, Handshake},
+    Result,
+};
+
+/// An in-memory stand-in for `harpd`. It listens on an ephemeral port, speaks
+/// the same wire protocol, and keeps every action it receives so that tests
+/// can make assertions about them. Nothing is validated, transformed, or
+/// stored, and the server stops when it is dropped.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use harp::{action::{Action, Kind}, testing::MockServer, Harp, HarpId, Loggable};
+/// # use std::{net::IpAddr, time::Duration};
+/// # struct Player;
+/// # impl Loggable for Player {
+/// #     fn identifier(&self) -> HarpId {
+/// #         (IpAddr::from([127, 0, 0, 1]), 1)
+/// #     }
+/// # }
+/// # struct Login;
+/// # impl Kind for Login {
+/// #     fn key(&self) -> &str {
+/// #         "login"
+/// #     }
+/// # }
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let server = MockServer::start().await?;
+/// let harp = Harp::create_service_with_options("127.0.0.1", server.port()).await?;
+///
+/// harp.send(Action::new(Login, &Player))?;
+///
+/// let actions = server.wait_for(1, Duration::from_secs(5)).await;
+/// assert_eq!(actions[0].kind, "login");
+/// # Ok(())
+/// # }
+/// ```
+pub struct MockServer {
+    addr: SocketAddr,
+    received: Arc<Received>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct Received {
+    actions: Mutex<Vec<Action>>,
+    notify: Notify,
+}
+
+impl MockServer {
+    /// Binds to an ephemeral port on localhost and starts accepting services.
+    pub async fn start() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let received = Arc::new(Received::default());
+
+        let task = tokio::spawn({
+            let received = Arc::clone(&received);
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(handle_connection(stream, Arc::clone(&received)));
+                }
+            }
+        });
+
+        Ok(Self { addr, received, task })
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the port the server is listening on.
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// Returns the number of actions received and not yet taken.
+    pub fn len(&self) -> usize {
+        self.received.actions.lock().unwrap().len()
+    }
+
+    /// Returns true if no actions have been received since they were last
+    /// taken.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes and returns every action received so far, in the order they
+    /// arrived.
+    pub fn take_actions(&self) -> Vec<Action> {
+        std::mem::take(&mut *self.received.actions.lock().unwrap())
+    }
+
+    /// Waits until at least `count` actions have been received, or `timeout`
+    /// has elapsed, then removes and returns every action received so far.
+    /// Services send actions from a background task, so tests should wait for
+    /// them rather than checking immediately.
+    pub async fn wait_for(&self, count: usize, timeout: Duration) -> Vec<Action> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            // Register for a notification before checking, so an action which
+            // arrives in between is not missed.
+            let notified = self.received.notify.notified();
+            if self.len() >= count {
+                break;
+            }
+
+            if timeout_at(deadline, notified).await.is_err() {
+                break;
+            }
+        }
+
+        self.take_actions()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Reads the handshake, then stores every action sent on the connection until
+/// the service disconnects or sends something malformed.
+async fn handle_connection(stream: TcpStream, received: Arc<Received>) {
+    let mut frame = Framed::new(stream, FrameCodec::new());
+
+    let Some(Ok(bytes)) = frame.next().await else {
+        return;
+    };
+    let Ok(handshake) = Handshake::try_from(Bufferfish::from(bytes)) else {
+        return;
+    };
+    frame.codec_mut().negotiate(handshake.length_field, handshake.max_frame_size as usize);
+
+    while let Some(Ok(mut bytes)) = frame.next().await {
+        if handshake.checksums {
+            let Ok(verified) = strip_checksum(bytes) else {
+                return;
+            };
+            bytes = verified;
+        }
+
+        let Ok(mut action) = Action::try_from(Bufferfish::from(bytes)) else {
+            return;
+        };
+        action.source = handshake.service.clone();
+
+        received.actions.lock().unwrap().push(action);
+        received.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_fixtures::{Target, TestKind},
+        Harp,
+    };
+
+    #[tokio::test]
+    async fn receive_actions_from_service() {
+        let server = MockServer::start().await.unwrap();
+        let harp = Harp::builder()
+            .hostname("127.0.0.1")
+            .port(server.port())
+            .service_name("world-1")
+            .create_service()
+            .await
+            .unwrap();
+
+        harp.send(Action::new(TestKind("login"), &Target)).unwrap();
+        harp.send(Action::new(TestKind("logout"), &Target)).unwrap();
+
+        let actions = server.wait_for(2, Duration::from_secs(5)).await;
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].kind, "login");
+        assert_eq!(actions[1].kind, "logout");
+        assert_eq!(actions[0].source.as_deref(), Some("world-1"));
+        assert!(server.is_empty());
+    }
+
+    #[tokio::test]
+    async fn flush_batched_actions() {
+        let server = MockServer::start().await.unwrap();
+        let harp = Harp::builder()
+            .hostname("127.0.0.1")
+            .port(server.port())
+            .batching(Duration::from_secs(60), 100)
+            .create_service()
+            .await
+            .unwrap();
+
+        harp.send(Action::new(TestKind("login"), &Target)).unwrap();
+        harp.flush(Duration::from_secs(5)).await.unwrap();
+
+        // Without the flush, the batch would be held for a minute.
+        let actions = server.wait_for(1, Duration::from_secs(5)).await;
+        assert_eq!(actions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn oversize_actions_leave_no_sequence_gap() {
+        let server = MockServer::start().await.unwrap();
+        let harp = Harp::builder()
+            .hostname("127.0.0.1")
+            .port(server.port())
+            .max_frame_size(256)
+            .create_service()
+            .await
+            .unwrap();
+
+        let detail = serde_json::json!("x".repeat(512));
+        harp.send(Action::with_detail(TestKind("oversize"), detail, &Target)).unwrap();
+        harp.send(Action::new(TestKind("login"), &Target)).unwrap();
+
+        // The oversize action is dropped, and its sequence number reused.
+        let actions = server.wait_for(1, Duration::from_secs(5)).await;
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].kind, "login");
+        assert_eq!(actions[0].sequence, Some(1));
+    }
+
+    #[tokio::test]
+    async fn lazy_service_drains_once_connected() {
+        let server = MockServer::start().await.unwrap();
+        let harp = Harp::builder().hostname("127.0.0.1").port(server.port()).create_service_lazy();
+
+        // Sent before the connection is made, so held until it is.
+        harp.send(Action::new(TestKind("login"), &Target)).unwrap();
+
+        let actions = server.wait_for(1, Duration::from_secs(5)).await;
+        assert_eq!(actions.len(), 1);
+        assert!(harp.status().is_connected());
+    }
+}
@@ -0,0 +1,214 @@
+//! TLS for connections to Harp servers, enabled with the `tls` feature. Pass
+//! `TlsOptions` to `HarpBuilder::tls` to verify the server against a CA, and
+//! optionally present a client certificate to servers which require one.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use harp::{tls::TlsOptions, Harp};
+//! #
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let tls = TlsOptions::new("/etc/harp/ca.pem")
+//!     .server_name("harp.internal")
+//!     .client_cert("/etc/harp/shard-1.pem", "/etc/harp/shard-1.key");
+//!
+//! let harp = Harp::builder().hostname("10.0.0.5").tls(tls).create_service().await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    fs::File,
+    future::Future,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use stubborn_io::tokio::UnderlyingIo;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        crypto::{ring, CryptoProvider},
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+
+#[cfg(feature = "proxy")]
+use crate::proxy::Proxy;
+use crate::Result;
+
+/// The CA, and optional client certificate, used to connect to a Harp server
+/// over TLS. Files are read when the service connects.
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    ca: PathBuf,
+    server_name: Option<String>,
+    client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsOptions {
+    /// Verifies the server's certificate against the CA certificates in the
+    /// PEM file at `ca`.
+    pub fn new(ca: impl Into<PathBuf>) -> Self {
+        Self { ca: ca.into(), server_name: None, client_cert: None }
+    }
+
+    /// Sets the name the server's certificate must be valid for, such as
+    /// "harp.internal". Defaults to the builder's hostname, which is an IP
+    /// address, so the certificate must otherwise list that address.
+    pub fn server_name(mut self, server_name: impl Into<String>) -> Self {
+        self.server_name = Some(server_name.into());
+        self
+    }
+
+    /// Presents the certificate chain and private key in the PEM files at
+    /// `cert` and `key` to the server. Servers which verify client
+    /// certificates use the certificate's common name as the service's name.
+    pub fn client_cert(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.client_cert = Some((cert.into(), key.into()));
+        self
+    }
+
+    /// Reads the configured files and returns what's needed to connect to
+    /// `addr`, which is known as `hostname` unless a server name is set.
+    pub(crate) fn target(&self, addr: SocketAddr, hostname: &str) -> Result<TlsTarget> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&self.ca)? {
+            roots.add(cert)?;
+        }
+
+        let builder = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        let config = match &self.client_cert {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_private_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        // IPv6 hosts may be written with brackets, which aren't part of the
+        // name.
+        let name = self.server_name.as_deref().unwrap_or(hostname);
+        let name = name.trim_start_matches('[').trim_end_matches(']');
+
+        Ok(TlsTarget {
+            addr,
+            server_name: ServerName::try_from(name.to_string())?,
+            connector: TlsConnector::from(Arc::new(config)),
+            #[cfg(feature = "proxy")]
+            proxy: None,
+        })
+    }
+}
+
+/// Everything needed to open, or reopen, a TLS connection to a Harp server.
+#[derive(Clone)]
+pub(crate) struct TlsTarget {
+    addr: SocketAddr,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    #[cfg(feature = "proxy")]
+    proxy: Option<Proxy>,
+}
+
+impl TlsTarget {
+    /// Returns the address of the Harp server.
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Tunnels every connection through `proxy` before the TLS handshake.
+    #[cfg(feature = "proxy")]
+    pub(crate) fn through(mut self, proxy: Option<Proxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+}
+
+/// A TLS connection to a Harp server, which `StubbornIo` reconnects in the
+/// same way as a plain TCP stream.
+pub(crate) struct TlsConnection(TlsStream<TcpStream>);
+
+impl UnderlyingIo<TlsTarget> for TlsConnection {
+    fn establish(target: TlsTarget) -> Pin<Box<dyn Future<Output = io::Result<Self>> + Send>> {
+        Box::pin(async move {
+            #[cfg(feature = "proxy")]
+            let stream = match &target.proxy {
+                Some(proxy) => proxy.connect(target.addr).await?,
+                None => TcpStream::connect(target.addr).await?,
+            };
+            #[cfg(not(feature = "proxy"))]
+            let stream = TcpStream::connect(target.addr).await?;
+            stream.set_nodelay(true)?;
+
+            let stream = target.connector.connect(target.server_name, stream).await?;
+
+            Ok(Self(stream))
+        })
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Returns the cryptography used for every TLS connection, chosen explicitly
+/// so that it doesn't depend on which `rustls` features other crates enable.
+pub(crate) fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Reads every certificate from a PEM file.
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path.display()).into());
+    }
+
+    Ok(certs)
+}
+
+/// Reads the first private key from a PEM file.
+pub(crate) fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| format!("No private key found in {}", path.display()).into())
+}
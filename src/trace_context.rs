@@ -0,0 +1,22 @@
+//! Tagging actions with the OpenTelemetry trace they were sent from, enabled
+//! with the `trace-context` feature. The game server must install a
+//! `tracing-opentelemetry` layer for its spans to belong to a trace.
+use opentelemetry::trace::TraceContextExt;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::action::Action;
+
+/// Stamps `action` with the trace ID of the current span, unless it already
+/// has one or the span isn't part of a trace.
+pub(crate) fn stamp(action: &mut Action) {
+    if action.trace_id.is_some() {
+        return;
+    }
+
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    if span_context.is_valid() {
+        action.trace_id = Some(span_context.trace_id().to_string());
+    }
+}
@@ -0,0 +1,169 @@
+//! The reconnecting stream a service talks to the Harp server over.
+use std::{
+    fmt::Display,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use stubborn_io::{tokio::StubbornIo, ReconnectOptions, StubbornTcpStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+#[cfg(all(windows, feature = "named-pipe"))]
+use crate::pipe::PipeConnection;
+#[cfg(feature = "proxy")]
+use crate::proxy::{ProxyConnection, ProxyTarget};
+#[cfg(feature = "tls")]
+use crate::tls::{TlsConnection, TlsTarget};
+
+/// Where a service connects to the Harp server.
+pub(crate) enum Endpoint {
+    Tcp(SocketAddr),
+    #[cfg(feature = "tls")]
+    Tls(TlsTarget),
+    #[cfg(feature = "proxy")]
+    Proxy(ProxyTarget),
+    /// The name of a named pipe, such as `\\.\pipe\harp`.
+    #[cfg(all(windows, feature = "named-pipe"))]
+    Pipe(String),
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(feature = "tls")]
+            Self::Tls(target) => write!(f, "{}", target.addr()),
+            #[cfg(feature = "proxy")]
+            Self::Proxy(target) => write!(f, "{} via {}", target.addr, target.proxy.addr()),
+            #[cfg(all(windows, feature = "named-pipe"))]
+            Self::Pipe(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+pub(crate) enum Transport {
+    Tcp(StubbornIo<TcpStream, SocketAddr>),
+    #[cfg(feature = "tls")]
+    Tls(StubbornIo<TlsConnection, TlsTarget>),
+    #[cfg(feature = "proxy")]
+    Proxy(StubbornIo<ProxyConnection, ProxyTarget>),
+    #[cfg(all(windows, feature = "named-pipe"))]
+    Pipe(StubbornIo<PipeConnection, String>),
+}
+
+impl Transport {
+    /// Connects to `endpoint`, reconnecting to it as `options` allow.
+    pub(crate) async fn connect(endpoint: Endpoint, options: ReconnectOptions) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Tcp(addr) => Self::connect_tcp(addr, options).await,
+            #[cfg(feature = "tls")]
+            Endpoint::Tls(target) => Self::connect_tls(target, options).await,
+            #[cfg(feature = "proxy")]
+            Endpoint::Proxy(target) => Self::connect_proxy(target, options).await,
+            #[cfg(all(windows, feature = "named-pipe"))]
+            Endpoint::Pipe(name) => Self::connect_pipe(name, options).await,
+        }
+    }
+
+    /// Connects over plain TCP.
+    pub(crate) async fn connect_tcp(
+        addr: SocketAddr,
+        options: ReconnectOptions,
+    ) -> io::Result<Self> {
+        let stream = StubbornTcpStream::connect_with_options(addr, options).await?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self::Tcp(stream))
+    }
+
+    /// Connects over TLS. Every connection, including reconnects, is made
+    /// with the same certificates.
+    #[cfg(feature = "tls")]
+    pub(crate) async fn connect_tls(
+        target: TlsTarget,
+        options: ReconnectOptions,
+    ) -> io::Result<Self> {
+        Ok(Self::Tls(StubbornIo::connect_with_options(target, options).await?))
+    }
+
+    /// Connects over TCP, tunneled through a proxy.
+    #[cfg(feature = "proxy")]
+    pub(crate) async fn connect_proxy(
+        target: ProxyTarget,
+        options: ReconnectOptions,
+    ) -> io::Result<Self> {
+        Ok(Self::Proxy(StubbornIo::connect_with_options(target, options).await?))
+    }
+
+    /// Connects over a named pipe on the same machine. Busy pipes are waited
+    /// on rather than counted as failed attempts.
+    #[cfg(all(windows, feature = "named-pipe"))]
+    pub(crate) async fn connect_pipe(name: String, options: ReconnectOptions) -> io::Result<Self> {
+        Ok(Self::Pipe(StubbornIo::connect_with_options(name, options).await?))
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "proxy")]
+            Self::Proxy(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(all(windows, feature = "named-pipe"))]
+            Self::Pipe(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "proxy")]
+            Self::Proxy(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(all(windows, feature = "named-pipe"))]
+            Self::Pipe(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "proxy")]
+            Self::Proxy(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(all(windows, feature = "named-pipe"))]
+            Self::Pipe(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "proxy")]
+            Self::Proxy(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(all(windows, feature = "named-pipe"))]
+            Self::Pipe(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}